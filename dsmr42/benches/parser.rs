@@ -0,0 +1,111 @@
+//! Host benchmarks for the parser, the CRC and serialization, using the
+//! telegrams from the test corpus. Run with `cargo bench --features std`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use dsmr42::{Crc16, SerializeOptions};
+
+static TELEGRAMS: [(&str, &[u8]); 3] = [
+    (
+        "dsmr42",
+        include_bytes!("../tests/corpus/dsmr42_landis_gyr.txt"),
+    ),
+    (
+        "dsmr50",
+        include_bytes!("../tests/corpus/dsmr50_sagemcom.txt"),
+    ),
+    (
+        "belgian",
+        include_bytes!("../tests/corpus/belgian_fluvius.txt"),
+    ),
+];
+
+// Size of the reader's receive buffer.
+const GARBAGE_LEN: usize = 2048;
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, telegram) in TELEGRAMS.iter() {
+        group.throughput(Throughput::Bytes(telegram.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), telegram, |b, t| {
+            b.iter(|| dsmr42::parse_lenient(black_box(t)))
+        });
+    }
+    group.finish();
+}
+
+fn crc16(c: &mut Criterion) {
+    let mut group = c.benchmark_group("crc16");
+    for (name, telegram) in TELEGRAMS.iter() {
+        group.throughput(Throughput::Bytes(telegram.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), telegram, |b, t| {
+            b.iter(|| {
+                let mut crc = Crc16::new();
+                crc.update(black_box(t));
+                crc.finish()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+    for (name, telegram) in TELEGRAMS.iter() {
+        let (_, res) = dsmr42::parse_lenient(telegram);
+        let telegram = res.unwrap();
+        let mut json = String::with_capacity(1024);
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                json.clear();
+                black_box(&telegram).serialize(&mut json, &SerializeOptions::ALL);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Line noise, like data received at the wrong baud rate: mostly printable,
+/// with line endings, the odd `/` that looks like the start of a telegram and
+/// invalid UTF-8.
+fn garbage() -> Vec<u8> {
+    let mut state = 0x2545_F491_u32;
+    (0..GARBAGE_LEN)
+        .map(|_| {
+            // xorshift32
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            match state % 64 {
+                0 => b'/',
+                1 => b'\r',
+                2 => b'\n',
+                3 => 0xFF,
+                n => b' ' + (n as u8) + 16,
+            }
+        })
+        .collect()
+}
+
+/// Skips through a full buffer of garbage, the way the reader does: parsing
+/// from the start, and discarding what the parser says to.
+fn resync(c: &mut Criterion) {
+    let garbage = garbage();
+    let mut group = c.benchmark_group("resync");
+    group.throughput(Throughput::Bytes(garbage.len() as u64));
+    group.bench_function("garbage", |b| {
+        b.iter(|| {
+            let mut input = black_box(&garbage[..]);
+            loop {
+                match dsmr42::parse_lenient(input) {
+                    (0, _) => break,
+                    (read, _) => input = &input[read.min(input.len())..],
+                }
+            }
+            input.len()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, crc16, serialize, resync);
+criterion_main!(benches);
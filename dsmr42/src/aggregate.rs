@@ -0,0 +1,328 @@
+//! Summarises the instantaneous values in telegrams over an interval, so that
+//! only some of the telegrams need to be published without losing the peaks
+//! in between.
+
+use core::fmt::{self, Write};
+
+use arrayvec::ArrayVec;
+
+use crate::{Field, Line, ObisCode, SerializeOptions, Telegram};
+
+/// Samples kept per value for the percentile. At one telegram a second, that
+/// covers five minutes; over longer intervals, the last ones are used.
+const MAX_SAMPLES: usize = 300;
+/// Instantaneous values in a three-phase telegram: total power in both
+/// directions, and per phase the current, the power in both directions and
+/// the voltage.
+const MAX_SERIES: usize = 14;
+
+/// Statistics of a value over an interval.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Summary {
+    pub min: u32,
+    pub max: u32,
+    pub mean: u32,
+    /// The 95th percentile, by the nearest-rank method.
+    pub p95: u32,
+    pub samples: u32,
+}
+
+/// The summaries of an interval, one for each value in the telegrams.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Summaries {
+    fields: SerializeOptions,
+    // With the line each value was last seen in, for its name.
+    values: ArrayVec<(Line, Summary), MAX_SERIES>,
+}
+
+impl Default for Summaries {
+    fn default() -> Self {
+        Self {
+            fields: SerializeOptions::NONE,
+            values: ArrayVec::new(),
+        }
+    }
+}
+
+impl Summaries {
+    /// The fields that were summarised.
+    pub fn fields(&self) -> SerializeOptions {
+        self.fields
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Line, Summary)> {
+        self.values.iter()
+    }
+
+    /// Writes the summaries as JSON object members, named like
+    /// `total_consuming_p95` after the values in `Telegram::serialize()`, and
+    /// in the same units. `separator` goes before the first one.
+    pub fn serialize<W: Write>(&self, writer: &mut W, mut separator: &str) -> fmt::Result {
+        for (line, summary) in self.values.iter() {
+            for (stat, value) in [
+                ("min", summary.min),
+                ("max", summary.max),
+                ("mean", summary.mean),
+                ("p95", summary.p95),
+            ]
+            .iter()
+            {
+                write!(writer, "{}\"", separator)?;
+                write_name(writer, line)?;
+                write!(writer, "_{}\": ", stat)?;
+                match line {
+                    Line::Voltage(..) => write!(writer, "{}.{}", value / 10, value % 10)?,
+                    _ => write!(writer, "{}", value)?,
+                }
+                separator = ",";
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Series {
+    line: Line,
+    obis: ObisCode,
+    min: u32,
+    max: u32,
+    sum: u64,
+    count: u32,
+    // A ring of the last samples, `next` being the oldest once it's full.
+    samples: ArrayVec<u32, MAX_SAMPLES>,
+    next: usize,
+}
+
+impl Series {
+    fn new(line: &Line, value: u32) -> Self {
+        let mut samples = ArrayVec::new();
+        samples.push(value);
+        Self {
+            line: line.clone(),
+            obis: line.obis_code(),
+            min: value,
+            max: value,
+            sum: value as u64,
+            count: 1,
+            samples,
+            next: 0,
+        }
+    }
+
+    fn add(&mut self, line: &Line, value: u32) {
+        self.line = line.clone();
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value as u64;
+        self.count += 1;
+        if self.samples.is_full() {
+            self.samples[self.next] = value;
+            self.next = (self.next + 1) % MAX_SAMPLES;
+        } else {
+            self.samples.push(value);
+        }
+    }
+
+    fn summary(&self) -> Summary {
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        // The smallest sample that at least 95% of them don't exceed.
+        let rank = (sorted.len() * 95).div_ceil(100);
+        Summary {
+            min: self.min,
+            max: self.max,
+            mean: ((self.sum + self.count as u64 / 2) / self.count as u64) as u32,
+            p95: sorted[rank.max(1) - 1],
+            samples: self.count,
+        }
+    }
+}
+
+/// Collects the instantaneous values of the fields it's created with, from
+/// every telegram until `finish()` is called.
+#[derive(Clone, Debug)]
+pub struct Aggregator {
+    fields: SerializeOptions,
+    series: ArrayVec<Series, MAX_SERIES>,
+}
+
+impl Aggregator {
+    /// Only the `power`, `current`, `phase_power` and `phase_voltage` fields
+    /// hold instantaneous values; the others are ignored.
+    pub fn new(fields: SerializeOptions) -> Self {
+        let fields = Field::ALL
+            .iter()
+            .filter(|field| fields.includes(**field) && is_instantaneous(**field))
+            .fold(SerializeOptions::NONE, |fields, field| fields.with(*field));
+        Self {
+            fields,
+            series: ArrayVec::new(),
+        }
+    }
+
+    /// Whether any values are collected at all.
+    pub fn is_enabled(&self) -> bool {
+        self.fields != SerializeOptions::NONE
+    }
+
+    pub fn observe(&mut self, telegram: &Telegram) {
+        for line in telegram.lines.iter() {
+            match Field::of(line) {
+                Some(field) if self.fields.includes(field) => {}
+                _ => continue,
+            }
+            let value = match instantaneous_value(line) {
+                Some(value) => value,
+                None => continue,
+            };
+            let obis = line.obis_code();
+            match self.series.iter_mut().find(|series| series.obis == obis) {
+                Some(series) => series.add(line, value),
+                None => {
+                    let _ = self.series.try_push(Series::new(line, value));
+                }
+            }
+        }
+    }
+
+    /// Returns the summaries of the values seen so far, and starts a new
+    /// interval.
+    pub fn finish(&mut self) -> Summaries {
+        let values = self
+            .series
+            .drain(..)
+            .map(|series| (series.line.clone(), series.summary()))
+            .collect();
+        Summaries {
+            fields: self.fields,
+            values,
+        }
+    }
+}
+
+fn is_instantaneous(field: Field) -> bool {
+    matches!(
+        field,
+        Field::Power | Field::Current | Field::PhasePower | Field::PhaseVoltage
+    )
+}
+
+fn instantaneous_value(line: &Line) -> Option<u32> {
+    match line {
+        Line::TotalConsuming(value)
+        | Line::TotalProducing(value)
+        | Line::Current(_, value)
+        | Line::Consuming(_, value)
+        | Line::Producing(_, value)
+        | Line::Voltage(_, value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn write_name<W: Write>(writer: &mut W, line: &Line) -> fmt::Result {
+    match line {
+        Line::TotalConsuming(_) => write!(writer, "total_consuming"),
+        Line::TotalProducing(_) => write!(writer, "total_producing"),
+        Line::Current(phase, _) => write!(writer, "{}_current", phase),
+        Line::Consuming(phase, _) => write!(writer, "{}_consuming", phase),
+        Line::Producing(phase, _) => write!(writer, "{}_producing", phase),
+        Line::Voltage(phase, _) => write!(writer, "{}_voltage", phase),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{string::String, vec::Vec};
+
+    use arrayvec::ArrayString;
+
+    use super::*;
+    use crate::Phase;
+
+    fn telegram(lines: &[Line]) -> Telegram {
+        Telegram {
+            device_id: ArrayString::new(),
+            lines: lines.iter().cloned().collect(),
+            crc: 0,
+        }
+    }
+
+    #[test]
+    fn summarises_each_value() {
+        let mut aggregator = Aggregator::new(SerializeOptions::NONE.with(Field::Power));
+        for power in 1..=100 {
+            aggregator.observe(&telegram(&[
+                Line::TotalConsuming(power * 10),
+                Line::TotalProducing(0),
+                Line::Consumed(1, 1000),
+            ]));
+        }
+        let summaries = aggregator.finish();
+        let values: Vec<_> = summaries.iter().map(|(_, s)| *s).collect();
+        assert_eq!(
+            values,
+            [
+                Summary {
+                    min: 10,
+                    max: 1000,
+                    mean: 505,
+                    p95: 950,
+                    samples: 100
+                },
+                Summary {
+                    min: 0,
+                    max: 0,
+                    mean: 0,
+                    p95: 0,
+                    samples: 100
+                },
+            ]
+        );
+        assert!(aggregator.finish().is_empty());
+    }
+
+    #[test]
+    fn percentile_uses_the_last_samples() {
+        let mut aggregator = Aggregator::new(SerializeOptions::NONE.with(Field::Power));
+        for power in 0..MAX_SAMPLES as u32 {
+            aggregator.observe(&telegram(&[Line::TotalConsuming(10_000 + power)]));
+        }
+        for _ in 0..MAX_SAMPLES {
+            aggregator.observe(&telegram(&[Line::TotalConsuming(100)]));
+        }
+        let summaries = aggregator.finish();
+        let (_, summary) = summaries.iter().next().unwrap();
+        assert_eq!(summary.max, 10_000 + MAX_SAMPLES as u32 - 1);
+        assert_eq!(summary.p95, 100);
+        assert_eq!(summary.samples, 2 * MAX_SAMPLES as u32);
+    }
+
+    #[test]
+    fn serializes_like_telegram() {
+        let mut aggregator = Aggregator::new(SerializeOptions::ALL);
+        aggregator.observe(&telegram(&[
+            Line::Voltage(Phase::L1, 2301),
+            Line::Current(Phase::L2, 3),
+        ]));
+        aggregator.observe(&telegram(&[Line::Voltage(Phase::L1, 2295)]));
+        assert!(aggregator.is_enabled());
+        let mut json = String::new();
+        aggregator.finish().serialize(&mut json, "").unwrap();
+        assert_eq!(
+            json,
+            "\"l1_voltage_min\": 229.5,\"l1_voltage_max\": 230.1,\
+             \"l1_voltage_mean\": 229.8,\"l1_voltage_p95\": 230.1,\
+             \"l2_current_min\": 3,\"l2_current_max\": 3,\
+             \"l2_current_mean\": 3,\"l2_current_p95\": 3"
+        );
+        let counters = Aggregator::new(SerializeOptions::NONE.with(Field::Consumed));
+        assert!(!counters.is_enabled());
+        assert_eq!(counters.clone().finish().fields(), SerializeOptions::NONE);
+    }
+}
@@ -0,0 +1,381 @@
+//! Compact binary encoding of a telegram, for links where the JSON form is
+//! too large.
+//!
+//! An encoded telegram starts with a format version, the length of the device
+//! ID, the device ID itself and the telegram CRC (little-endian). It is followed
+//! by one record per line, each consisting of a tag byte and a fixed-size value
+//! that depends on the tag. Multi-byte values are little-endian. Lines without
+//! a value (equipment ID, power failure log, unknown and oversized lines) are
+//! left out, as are registered lines, which can't be decoded without their
+//! table.
+
+use crate::{Line, Phase, Telegram, Timestamp, MAX_LINES_PER_TELEGRAM};
+
+pub const FORMAT_VERSION: u8 = 2;
+/// Version, device ID length, device ID and CRC.
+const HEADER_LEN: usize = 1 + 1 + 32 + 2;
+/// Tag and the largest value, a gas reading: its channel, timestamp and volume.
+const MAX_RECORD_LEN: usize = 1 + 1 + TIMESTAMP_LEN + 4;
+const TIMESTAMP_LEN: usize = 12;
+/// Upper bound on the size of an encoded telegram.
+pub const MAX_ENCODED_LEN: usize = HEADER_LEN + MAX_LINES_PER_TELEGRAM * MAX_RECORD_LEN;
+
+const TAG_VERSION: u8 = 1;
+const TAG_TIMESTAMP: u8 = 2;
+const TAG_CONSUMED: u8 = 3;
+const TAG_PRODUCED: u8 = 4;
+const TAG_HISTORICAL_CONSUMED: u8 = 5;
+const TAG_HISTORICAL_PRODUCED: u8 = 6;
+const TAG_ACTIVE_TARIFF: u8 = 7;
+const TAG_TOTAL_CONSUMING: u8 = 8;
+const TAG_TOTAL_PRODUCING: u8 = 9;
+const TAG_POWER_FAILURES: u8 = 10;
+const TAG_LONG_POWER_FAILURES: u8 = 11;
+const TAG_VOLTAGE_SAGS: u8 = 12;
+const TAG_VOLTAGE_SWELLS: u8 = 13;
+const TAG_CURRENT: u8 = 14;
+const TAG_CONSUMING: u8 = 15;
+const TAG_PRODUCING: u8 = 16;
+const TAG_VOLTAGE: u8 = 17;
+const TAG_GAS_DELIVERED: u8 = 18;
+
+/// The output buffer was too small to hold the encoded telegram.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BufferTooSmall;
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), BufferTooSmall> {
+        let end = self.pos + bytes.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(BufferTooSmall)?
+            .copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), BufferTooSmall> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), BufferTooSmall> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Result<(), BufferTooSmall> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn timestamp(&mut self, ts: &Timestamp) -> Result<(), BufferTooSmall> {
+        self.u16(ts.year)?;
+        self.bytes(&[ts.month, ts.day, ts.hour, ts.minute, ts.second])?;
+        self.u8(ts.dst as u8)?;
+        self.u16(ts.zone.standard_offset_mins as u16)?;
+        self.u16(ts.zone.dst_offset_mins as u16)
+    }
+}
+
+fn phase_code(phase: &Phase) -> u8 {
+    match phase {
+        Phase::L1 => 1,
+        Phase::L2 => 2,
+        Phase::L3 => 3,
+    }
+}
+
+impl Telegram {
+    /// Encodes the telegram into `buf`, returning the number of bytes written.
+    /// A buffer of `MAX_ENCODED_LEN` bytes is always large enough.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, BufferTooSmall> {
+        let mut w = Writer { buf, pos: 0 };
+        w.u8(FORMAT_VERSION)?;
+        w.u8(self.device_id.len() as u8)?;
+        w.bytes(self.device_id.as_bytes())?;
+        w.u16(self.crc)?;
+        for line in self.lines.iter() {
+            match line {
+                Line::Version(version) => {
+                    w.u8(TAG_VERSION)?;
+                    w.u8(*version)?;
+                }
+                Line::Timestamp(ts) => {
+                    w.u8(TAG_TIMESTAMP)?;
+                    w.timestamp(ts)?;
+                }
+                Line::Consumed(tariff, energy) => {
+                    w.bytes(&[TAG_CONSUMED, *tariff])?;
+                    w.u32(*energy)?;
+                }
+                Line::Produced(tariff, energy) => {
+                    w.bytes(&[TAG_PRODUCED, *tariff])?;
+                    w.u32(*energy)?;
+                }
+                Line::HistoricalConsumed(period, tariff, energy) => {
+                    w.bytes(&[TAG_HISTORICAL_CONSUMED, *period, *tariff])?;
+                    w.u32(*energy)?;
+                }
+                Line::HistoricalProduced(period, tariff, energy) => {
+                    w.bytes(&[TAG_HISTORICAL_PRODUCED, *period, *tariff])?;
+                    w.u32(*energy)?;
+                }
+                Line::ActiveTariff(tariff) => {
+                    w.bytes(&[TAG_ACTIVE_TARIFF, *tariff])?;
+                }
+                Line::TotalConsuming(power) => {
+                    w.u8(TAG_TOTAL_CONSUMING)?;
+                    w.u32(*power)?;
+                }
+                Line::TotalProducing(power) => {
+                    w.u8(TAG_TOTAL_PRODUCING)?;
+                    w.u32(*power)?;
+                }
+                Line::PowerFailures(count) => {
+                    w.u8(TAG_POWER_FAILURES)?;
+                    w.u32(*count)?;
+                }
+                Line::LongPowerFailures(count) => {
+                    w.u8(TAG_LONG_POWER_FAILURES)?;
+                    w.u32(*count)?;
+                }
+                Line::VoltageSags(count) => {
+                    w.u8(TAG_VOLTAGE_SAGS)?;
+                    w.u32(*count)?;
+                }
+                Line::VoltageSwells(count) => {
+                    w.u8(TAG_VOLTAGE_SWELLS)?;
+                    w.u32(*count)?;
+                }
+                Line::Current(phase, current) => {
+                    w.bytes(&[TAG_CURRENT, phase_code(phase)])?;
+                    w.u32(*current)?;
+                }
+                Line::Consuming(phase, power) => {
+                    w.bytes(&[TAG_CONSUMING, phase_code(phase)])?;
+                    w.u32(*power)?;
+                }
+                Line::Producing(phase, power) => {
+                    w.bytes(&[TAG_PRODUCING, phase_code(phase)])?;
+                    w.u32(*power)?;
+                }
+                Line::Voltage(phase, voltage) => {
+                    w.bytes(&[TAG_VOLTAGE, phase_code(phase)])?;
+                    w.u32(*voltage)?;
+                }
+                Line::GasDelivered(channel, ts, volume) => {
+                    w.bytes(&[TAG_GAS_DELIVERED, *channel])?;
+                    w.timestamp(ts)?;
+                    w.u32(*volume)?;
+                }
+                Line::EquipmentId
+                | Line::PowerFailureLog
+                | Line::UnknownObis(_)
+                | Line::Registered(..)
+                | Line::Oversized(_) => {}
+            }
+        }
+        Ok(w.pos)
+    }
+}
+
+#[cfg(feature = "std")]
+pub use self::decode::{decode, DecodeError};
+
+#[cfg(feature = "std")]
+mod decode {
+    use super::*;
+    use crate::TimeZone;
+    use arrayvec::{ArrayString, ArrayVec};
+    use core::fmt::Display;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum DecodeError {
+        UnsupportedVersion(u8),
+        UnexpectedEnd,
+        UnknownTag(u8),
+        InvalidValue,
+        TooManyLines,
+    }
+
+    impl Display for DecodeError {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            match self {
+                DecodeError::UnsupportedVersion(v) => write!(f, "unsupported format version {}", v),
+                DecodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+                DecodeError::UnknownTag(t) => write!(f, "unknown tag {}", t),
+                DecodeError::InvalidValue => write!(f, "invalid value"),
+                DecodeError::TooManyLines => write!(f, "too many lines"),
+            }
+        }
+    }
+
+    impl std::error::Error for DecodeError {}
+
+    struct Reader<'a> {
+        buf: &'a [u8],
+    }
+
+    impl<'a> Reader<'a> {
+        fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+            if self.buf.len() < len {
+                return Err(DecodeError::UnexpectedEnd);
+            }
+            let (bytes, rest) = self.buf.split_at(len);
+            self.buf = rest;
+            Ok(bytes)
+        }
+
+        fn u8(&mut self) -> Result<u8, DecodeError> {
+            Ok(self.bytes(1)?[0])
+        }
+
+        fn u16(&mut self) -> Result<u16, DecodeError> {
+            let b = self.bytes(2)?;
+            Ok(u16::from_le_bytes([b[0], b[1]]))
+        }
+
+        fn u32(&mut self) -> Result<u32, DecodeError> {
+            let b = self.bytes(4)?;
+            Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        }
+
+        fn phase(&mut self) -> Result<Phase, DecodeError> {
+            match self.u8()? {
+                1 => Ok(Phase::L1),
+                2 => Ok(Phase::L2),
+                3 => Ok(Phase::L3),
+                _ => Err(DecodeError::InvalidValue),
+            }
+        }
+
+        fn timestamp(&mut self) -> Result<Timestamp, DecodeError> {
+            Ok(Timestamp {
+                year: self.u16()?,
+                month: self.u8()?,
+                day: self.u8()?,
+                hour: self.u8()?,
+                minute: self.u8()?,
+                second: self.u8()?,
+                dst: self.u8()? != 0,
+                zone: TimeZone {
+                    standard_offset_mins: self.u16()? as i16,
+                    dst_offset_mins: self.u16()? as i16,
+                },
+            })
+        }
+    }
+
+    /// Decodes a telegram produced by `Telegram::encode()`.
+    pub fn decode(input: &[u8]) -> Result<Telegram, DecodeError> {
+        let mut r = Reader { buf: input };
+        let version = r.u8()?;
+        if version != FORMAT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let id_len = r.u8()? as usize;
+        let device_id = core::str::from_utf8(r.bytes(id_len)?)
+            .ok()
+            .and_then(|id| ArrayString::from(id).ok())
+            .ok_or(DecodeError::InvalidValue)?;
+        let crc = r.u16()?;
+
+        let mut lines = ArrayVec::new();
+        while !r.buf.is_empty() {
+            let line = match r.u8()? {
+                TAG_VERSION => Line::Version(r.u8()?),
+                TAG_TIMESTAMP => Line::Timestamp(r.timestamp()?),
+                TAG_CONSUMED => Line::Consumed(r.u8()?, r.u32()?),
+                TAG_PRODUCED => Line::Produced(r.u8()?, r.u32()?),
+                TAG_HISTORICAL_CONSUMED => Line::HistoricalConsumed(r.u8()?, r.u8()?, r.u32()?),
+                TAG_HISTORICAL_PRODUCED => Line::HistoricalProduced(r.u8()?, r.u8()?, r.u32()?),
+                TAG_ACTIVE_TARIFF => Line::ActiveTariff(r.u8()?),
+                TAG_TOTAL_CONSUMING => Line::TotalConsuming(r.u32()?),
+                TAG_TOTAL_PRODUCING => Line::TotalProducing(r.u32()?),
+                TAG_POWER_FAILURES => Line::PowerFailures(r.u32()?),
+                TAG_LONG_POWER_FAILURES => Line::LongPowerFailures(r.u32()?),
+                TAG_VOLTAGE_SAGS => Line::VoltageSags(r.u32()?),
+                TAG_VOLTAGE_SWELLS => Line::VoltageSwells(r.u32()?),
+                TAG_CURRENT => Line::Current(r.phase()?, r.u32()?),
+                TAG_CONSUMING => Line::Consuming(r.phase()?, r.u32()?),
+                TAG_PRODUCING => Line::Producing(r.phase()?, r.u32()?),
+                TAG_VOLTAGE => Line::Voltage(r.phase()?, r.u32()?),
+                TAG_GAS_DELIVERED => Line::GasDelivered(r.u8()?, r.timestamp()?, r.u32()?),
+                tag => return Err(DecodeError::UnknownTag(tag)),
+            };
+            lines
+                .try_push(line)
+                .map_err(|_| DecodeError::TooManyLines)?;
+        }
+
+        Ok(Telegram {
+            device_id,
+            lines,
+            crc,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{parse, SerializeOptions};
+    use std::string::String;
+
+    const TELEGRAM: &[u8] = b"/XMX5LGBBFFB231237741\r\n\r\n\
+    1-3:0.2.8(42)\r\n\
+    0-0:1.0.0(200208153516W)\r\n\
+    1-0:1.8.1(004436.791*kWh)\r\n\
+    1-0:1.8.1*01(004000.000*kWh)\r\n\
+    0-0:96.14.0(0002)\r\n\
+    1-0:1.7.0(00.329*kW)\r\n\
+    1-0:31.7.0(002*A)\r\n\
+    0-1:24.2.1(200208153000W)(12785.123*m3)\r\n\
+    !2AFD\r\n";
+
+    #[test]
+    fn encoded_telegram_round_trips() {
+        let (_, res) = parse(TELEGRAM);
+        let telegram = res.unwrap();
+        let mut buf = [0; MAX_ENCODED_LEN];
+        let len = telegram.encode(&mut buf).unwrap();
+        let decoded = decode(&buf[..len]).unwrap();
+        assert_eq!(telegram, decoded);
+
+        let mut expected = String::new();
+        let mut actual = String::new();
+        telegram.serialize(&mut expected, &SerializeOptions::ALL);
+        decoded.serialize(&mut actual, &SerializeOptions::ALL);
+        assert_eq!(expected, actual);
+        assert_eq!(telegram.crc, decoded.crc);
+        assert!(matches!(
+            decoded.lines[3],
+            Line::HistoricalConsumed(1, 1, 4000000)
+        ));
+        assert!(matches!(
+            &decoded.lines[7],
+            Line::GasDelivered(1, ts, 12785123) if ts.unix_time() == 1581172200
+        ));
+    }
+
+    #[test]
+    fn encode_fails_when_buffer_is_too_small() {
+        let (_, res) = parse(TELEGRAM);
+        let mut buf = [0; 16];
+        assert!(res.unwrap().encode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn truncated_input_fails_to_decode() {
+        let (_, res) = parse(TELEGRAM);
+        let mut buf = [0; MAX_ENCODED_LEN];
+        let len = res.unwrap().encode(&mut buf).unwrap();
+        assert_eq!(
+            Err(DecodeError::UnexpectedEnd),
+            decode(&buf[..len - 1]).map(|_| ())
+        );
+    }
+}
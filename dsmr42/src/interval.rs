@@ -0,0 +1,91 @@
+//! Estimates how often a meter sends telegrams, from their timestamps.
+
+use arrayvec::ArrayVec;
+
+use crate::{Line, Telegram};
+
+/// Number of intervals between telegrams to base the estimate on.
+const WINDOW: usize = 8;
+/// Intervals needed before an estimate is given.
+const MIN_SAMPLES: usize = 3;
+/// Gaps longer than this are outages rather than the meter's interval.
+const MAX_INTERVAL_SECS: i64 = 300;
+
+/// Estimates the interval at which the meter sends telegrams: 1 second for
+/// DSMR 5 meters, 10 seconds for DSMR 4.
+///
+/// The estimate is the most common interval among the last few telegrams,
+/// so a dropped telegram or clock adjustment doesn't throw it off.
+#[derive(Clone, Debug, Default)]
+pub struct IntervalEstimator {
+    last: Option<i64>,
+    intervals: ArrayVec<u32, WINDOW>,
+}
+
+impl IntervalEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a telegram, if it has a timestamp, and returns the new estimate.
+    pub fn observe_telegram(&mut self, telegram: &Telegram) -> Option<u32> {
+        for line in telegram.lines.iter() {
+            if let Line::Timestamp(ts) = line {
+                return self.observe(ts.unix_time());
+            }
+        }
+        self.interval_secs()
+    }
+
+    /// Records a telegram timestamp, in seconds, and returns the new estimate.
+    pub fn observe(&mut self, unix_time: i64) -> Option<u32> {
+        if let Some(last) = self.last.replace(unix_time) {
+            let interval = unix_time - last;
+            if interval > 0 && interval <= MAX_INTERVAL_SECS {
+                if self.intervals.is_full() {
+                    self.intervals.remove(0);
+                }
+                self.intervals.push(interval as u32);
+            }
+        }
+        self.interval_secs()
+    }
+
+    /// The estimated interval between telegrams in seconds, once enough of
+    /// them have been seen.
+    pub fn interval_secs(&self) -> Option<u32> {
+        if self.intervals.len() < MIN_SAMPLES {
+            return None;
+        }
+        let count = |i: u32| self.intervals.iter().filter(|x| **x == i).count();
+        // On a tie, prefer the shorter interval; longer ones are more likely
+        // caused by missed telegrams.
+        self.intervals
+            .iter()
+            .copied()
+            .max_by(|a, b| count(*a).cmp(&count(*b)).then(b.cmp(a)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn needs_a_few_telegrams() {
+        let mut estimator = IntervalEstimator::new();
+        assert_eq!(None, estimator.observe(1000));
+        assert_eq!(None, estimator.observe(1010));
+        assert_eq!(None, estimator.observe(1020));
+        assert_eq!(Some(10), estimator.observe(1030));
+    }
+
+    #[test]
+    fn ignores_missed_telegrams_and_outages() {
+        let mut estimator = IntervalEstimator::new();
+        for t in [0, 1, 2, 4, 5, 6, 1000, 1001, 1003] {
+            estimator.observe(t);
+        }
+        assert_eq!(Some(1), estimator.interval_secs());
+    }
+}
@@ -21,10 +21,25 @@ use nom::{
     Compare, IResult, InputLength, InputTake, Parser,
 };
 
-const MAX_COSEM_PER_LINE: usize = 16;
-const MAX_LINES_PER_TELEGRAM: usize = 32;
+pub mod aggregate;
+pub mod binary;
+pub mod interval;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 
-#[derive(Debug)]
+const MAX_COSEM_PER_LINE: usize = 16;
+// Three-phase DSMR 5 meters send 35 lines, and Belgian ones more, with room
+// left for other M-Bus devices.
+const MAX_LINES_PER_TELEGRAM: usize = 48;
+/// Longest line `parse_split()` can parse when it straddles the two slices.
+/// When the header straddles them, this includes the header and the line
+/// after it.
+pub const MAX_SPLIT_LINE: usize = 256;
+/// Value group F indexes historical billing periods up to this value; above it,
+/// the meaning depends on the register.
+const MAX_BILLING_PERIOD: u8 = 99;
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Telegram {
     pub device_id: ArrayString<32>,
     pub lines: ArrayVec<Line, MAX_LINES_PER_TELEGRAM>,
@@ -32,11 +47,19 @@ pub struct Telegram {
 }
 
 impl Telegram {
-    pub fn serialize<W: Write>(&self, writer: &mut W) {
+    /// Writes the telegram as a JSON object, leaving out the fields that
+    /// `options` excludes.
+    pub fn serialize<W: Write>(&self, writer: &mut W, options: &SerializeOptions) {
         // Poor man's JSON
         write!(writer, "{{");
         let mut separator = "";
         for line in self.lines.iter() {
+            match Field::of(line) {
+                Some(field) if options.includes(field) => {}
+                // The caller asked for these when parsing.
+                None if matches!(line, Line::Registered(..)) => {}
+                _ => continue,
+            }
             match line {
                 Line::Version(version) => {
                     write!(writer, "{}\"dsmr_version\": {}", separator, version);
@@ -88,6 +111,19 @@ impl Telegram {
                 Line::Producing(phase, power) => {
                     write!(writer, "{}\"{}_producing\": {}", separator, phase, power);
                 }
+                Line::Voltage(phase, voltage) => {
+                    write!(
+                        writer,
+                        "{}\"{}_voltage\": {}.{}",
+                        separator,
+                        phase,
+                        voltage / 10,
+                        voltage % 10
+                    );
+                }
+                Line::Registered(register, value) => {
+                    write!(writer, "{}\"{}\": {}", separator, register.key, value);
+                }
                 _ => {
                     // Do not write unknown lines
                 }
@@ -98,13 +134,176 @@ impl Telegram {
     }
 }
 
-#[derive(Debug)]
+/// A one-line summary of the telegram, for logging.
+impl Display for Telegram {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut consuming = 0;
+        let mut producing = 0;
+        // Summed as u64, since the tariffs can add up to more than u32 holds.
+        let mut consumed = 0u64;
+        let mut produced = 0u64;
+        write!(f, "{}", self.device_id)?;
+        for line in self.lines.iter() {
+            match line {
+                Line::Timestamp(ts) => write!(f, " @ {}", ts)?,
+                Line::TotalConsuming(power) => consuming = *power,
+                Line::TotalProducing(power) => producing = *power,
+                Line::Consumed(_, energy) => consumed += u64::from(*energy),
+                Line::Produced(_, energy) => produced += u64::from(*energy),
+                _ => {}
+            }
+        }
+        write!(
+            f,
+            ": +{} W -{} W, consumed {} Wh, produced {} Wh",
+            consuming, producing, consumed, produced
+        )
+    }
+}
+
+/// Groups of related telegram fields, which can be left out when serializing.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Field {
+    Version,
+    Timestamp,
+    /// Cumulative energy consumed, per tariff.
+    Consumed,
+    /// Cumulative energy produced, per tariff.
+    Produced,
+    ActiveTariff,
+    /// Total instantaneous power consumed and produced.
+    Power,
+    /// Number of short and long power failures.
+    PowerFailures,
+    /// Number of voltage sags and swells.
+    VoltageEvents,
+    /// Instantaneous current, per phase.
+    Current,
+    /// Instantaneous power consumed and produced, per phase.
+    PhasePower,
+    /// Instantaneous voltage, per phase.
+    PhaseVoltage,
+}
+
+impl Field {
+    pub const ALL: [Field; 11] = [
+        Field::Version,
+        Field::Timestamp,
+        Field::Consumed,
+        Field::Produced,
+        Field::ActiveTariff,
+        Field::Power,
+        Field::PowerFailures,
+        Field::VoltageEvents,
+        Field::Current,
+        Field::PhasePower,
+        Field::PhaseVoltage,
+    ];
+
+    /// The field a line is serialized as, if any.
+    pub fn of(line: &Line) -> Option<Field> {
+        Some(match line {
+            Line::Version(_) => Field::Version,
+            Line::Timestamp(_) => Field::Timestamp,
+            Line::Consumed(..) => Field::Consumed,
+            Line::Produced(..) => Field::Produced,
+            Line::ActiveTariff(_) => Field::ActiveTariff,
+            Line::TotalConsuming(_) | Line::TotalProducing(_) => Field::Power,
+            Line::PowerFailures(_) | Line::LongPowerFailures(_) => Field::PowerFailures,
+            Line::VoltageSags(_) | Line::VoltageSwells(_) => Field::VoltageEvents,
+            Line::Current(..) => Field::Current,
+            Line::Consuming(..) | Line::Producing(..) => Field::PhasePower,
+            Line::Voltage(..) => Field::PhaseVoltage,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Version => "version",
+            Field::Timestamp => "timestamp",
+            Field::Consumed => "consumed",
+            Field::Produced => "produced",
+            Field::ActiveTariff => "tariff",
+            Field::Power => "power",
+            Field::PowerFailures => "failures",
+            Field::VoltageEvents => "voltage",
+            Field::Current => "current",
+            Field::PhasePower => "phase_power",
+            Field::PhaseVoltage => "phase_voltage",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Field> {
+        Field::ALL
+            .iter()
+            .copied()
+            .find(|field| field.name() == name)
+    }
+
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// Which fields `Telegram::serialize()` writes. By default, all of them.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SerializeOptions {
+    fields: u16,
+}
+
+impl Default for SerializeOptions {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl SerializeOptions {
+    pub const ALL: SerializeOptions = SerializeOptions {
+        fields: (1 << Field::ALL.len()) - 1,
+    };
+    pub const NONE: SerializeOptions = SerializeOptions { fields: 0 };
+
+    pub fn with(self, field: Field) -> Self {
+        Self {
+            fields: self.fields | field.bit(),
+        }
+    }
+
+    pub fn without(self, field: Field) -> Self {
+        Self {
+            fields: self.fields & !field.bit(),
+        }
+    }
+
+    pub fn includes(&self, field: Field) -> bool {
+        self.fields & field.bit() != 0
+    }
+
+    /// The fields as a bit mask, for storing them.
+    pub fn bits(&self) -> u16 {
+        self.fields
+    }
+
+    /// Restores fields from `bits()`, ignoring unknown ones.
+    pub fn from_bits(bits: u16) -> Self {
+        Self {
+            fields: bits & Self::ALL.fields,
+        }
+    }
+}
+
+/// The OBIS code of a line, as its value groups A to F. Group F is 255 if the
+/// line leaves it out.
+pub type ObisCode = [u8; 6];
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct RawLine<'a> {
-    obis: [u8; 6],
+    obis: ObisCode,
     cosem: ArrayVec<&'a str, MAX_COSEM_PER_LINE>,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Timestamp {
     year: u16,
     month: u8,
@@ -113,6 +312,50 @@ pub struct Timestamp {
     minute: u8,
     second: u8,
     dst: bool,
+    zone: TimeZone,
+}
+
+/// The offsets from UTC of the local time a meter reports its timestamps in.
+/// Meters only tell whether DST is in effect, so the offsets come from here.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct TimeZone {
+    /// Offset in minutes during standard (winter) time.
+    pub standard_offset_mins: i16,
+    /// Offset in minutes while DST is in effect.
+    pub dst_offset_mins: i16,
+}
+
+impl TimeZone {
+    /// Central European Time, used in the Netherlands, Belgium and
+    /// Luxembourg.
+    pub const CET: TimeZone = TimeZone {
+        standard_offset_mins: 60,
+        dst_offset_mins: 120,
+    };
+    /// Western European Time, as in Portugal, Ireland and the UK.
+    pub const WET: TimeZone = TimeZone {
+        standard_offset_mins: 0,
+        dst_offset_mins: 60,
+    };
+    /// Eastern European Time, as in Finland and the Baltic states.
+    pub const EET: TimeZone = TimeZone {
+        standard_offset_mins: 120,
+        dst_offset_mins: 180,
+    };
+
+    fn offset_mins(&self, dst: bool) -> i16 {
+        if dst {
+            self.dst_offset_mins
+        } else {
+            self.standard_offset_mins
+        }
+    }
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        TimeZone::CET
+    }
 }
 
 impl Display for Timestamp {
@@ -122,15 +365,54 @@ impl Display for Timestamp {
             "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
             self.year, self.month, self.day, self.hour, self.minute, self.second
         )?;
-        if self.dst {
-            write!(f, "+02:00")
-        } else {
-            write!(f, "+01:00")
-        }
+        let offset = self.utc_offset_mins();
+        let sign = if offset < 0 { '-' } else { '+' };
+        let offset = offset.unsigned_abs();
+        write!(f, "{}{:02}:{:02}", sign, offset / 60, offset % 60)
+    }
+}
+
+impl Timestamp {
+    /// Whether the meter reported DST to be in effect.
+    pub fn dst(&self) -> bool {
+        self.dst
+    }
+
+    /// The time zone the timestamp was read in, from `ParseOptions::time_zone`.
+    pub fn zone(&self) -> TimeZone {
+        self.zone
+    }
+
+    /// Offset of the local time from UTC in minutes, going by the zone and
+    /// the DST flag.
+    pub fn utc_offset_mins(&self) -> i16 {
+        self.zone.offset_mins(self.dst)
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn unix_time(&self) -> i64 {
+        let offset = self.utc_offset_mins() as i64 * 60;
+        days_from_civil(self.year, self.month, self.day) * 86400
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+            - offset
     }
 }
 
-#[derive(Debug)]
+/// Number of days between 1970-01-01 and the given date.
+fn days_from_civil(year: u16, month: u8, day: u8) -> i64 {
+    // Count years from March, so the leap day is at the end of the year.
+    let year = if month <= 2 { year - 1 } else { year } as i64;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Phase {
     L1,
     L2,
@@ -147,7 +429,7 @@ impl Display for Phase {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Line {
     Version(u8),
     Timestamp(Timestamp), // YYYY, MM, DD, HH, MM, SS
@@ -155,6 +437,12 @@ pub enum Line {
     PowerFailureLog,      // Same here
     Consumed(u8, u32),    // tariff, Wh
     Produced(u8, u32),    // tariff, Wh
+    /// Energy consumed at the end of an earlier billing period, as
+    /// `period, tariff, Wh`. Period 1 is the most recent one.
+    HistoricalConsumed(u8, u8, u32),
+    /// Energy produced at the end of an earlier billing period, as
+    /// `period, tariff, Wh`.
+    HistoricalProduced(u8, u8, u32),
     ActiveTariff(u8),
     TotalConsuming(u32),    // W
     TotalProducing(u32),    // W
@@ -165,24 +453,163 @@ pub enum Line {
     Current(Phase, u32),    // phase number, A
     Consuming(Phase, u32),  // phase number, A
     Producing(Phase, u32),  // phase number, A
-    UnknownObis([u8; 6]),
+    Voltage(Phase, u32),    // phase number, 0.1 V
+    /// Gas delivered, as read from the gas meter on an M-Bus channel, as
+    /// `channel, time of the reading, dm³`. The gas meter only reports every
+    /// few minutes, so the same reading is repeated in the telegrams in
+    /// between. It is not part of `Telegram::serialize()`.
+    GasDelivered(u8, Timestamp, u32),
+    UnknownObis(ObisCode),
+    /// A line of one of the `ParseOptions::registers`, with its value.
+    Registered(&'static Register, u32),
+    /// A line that couldn't be parsed, because it had too many values, or a
+    /// value didn't fit. Only produced by `parse_lenient()`.
+    Oversized(ObisCode),
 }
 
-#[derive(Debug)]
+impl Line {
+    /// The OBIS code the line was parsed from.
+    pub fn obis_code(&self) -> ObisCode {
+        // The power lines follow the codes the parser assigns to them.
+        let phase_offset = |phase: &Phase| match phase {
+            Phase::L1 => 0,
+            Phase::L2 => 20,
+            Phase::L3 => 40,
+        };
+        match self {
+            Line::Version(_) => [1, 3, 0, 2, 8, 255],
+            Line::Timestamp(_) => [0, 0, 1, 0, 0, 255],
+            Line::EquipmentId => [0, 0, 96, 1, 1, 255],
+            Line::PowerFailureLog => [1, 0, 99, 97, 0, 255],
+            Line::Consumed(tariff, _) => [1, 0, 1, 8, *tariff, 255],
+            Line::Produced(tariff, _) => [1, 0, 2, 8, *tariff, 255],
+            Line::HistoricalConsumed(period, tariff, _) => [1, 0, 1, 8, *tariff, *period],
+            Line::HistoricalProduced(period, tariff, _) => [1, 0, 2, 8, *tariff, *period],
+            Line::ActiveTariff(_) => [0, 0, 96, 14, 0, 255],
+            Line::TotalConsuming(_) => [1, 0, 1, 7, 0, 255],
+            Line::TotalProducing(_) => [1, 0, 2, 7, 0, 255],
+            Line::PowerFailures(_) => [0, 0, 96, 7, 21, 255],
+            Line::LongPowerFailures(_) => [0, 0, 96, 7, 9, 255],
+            Line::VoltageSags(_) => [1, 0, 32, 32, 0, 255],
+            Line::VoltageSwells(_) => [1, 0, 32, 36, 0, 255],
+            Line::Current(phase, _) => [1, 0, 31 + phase_offset(phase), 7, 0, 255],
+            Line::Producing(phase, _) => [1, 0, 21 + phase_offset(phase), 7, 0, 255],
+            Line::Consuming(phase, _) => [1, 0, 22 + phase_offset(phase), 7, 0, 255],
+            Line::Voltage(phase, _) => [1, 0, 32 + phase_offset(phase), 7, 0, 255],
+            Line::GasDelivered(channel, ..) => [0, *channel, 24, 2, 1, 255],
+            Line::Registered(register, _) => register.obis,
+            Line::UnknownObis(obis) | Line::Oversized(obis) => *obis,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct CrcMismatch {
-    calculated: u16,
-    read: u16,
+    pub calculated: u16,
+    /// The CRC at the end of the telegram.
+    pub read: u16,
+}
+
+/// Controls how `parse_with_options()` handles telegrams that aren't quite
+/// right.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ParseOptions {
+    /// Record lines that are too large to parse as `Line::Oversized`, instead
+    /// of failing the whole telegram.
+    pub lenient: bool,
+    /// The CRC of the telegram, if it was already computed with `Crc16` while
+    /// receiving it, so it doesn't need to be computed again.
+    pub crc: Option<u16>,
+    /// Reject telegrams in which an OBIS code occurs more than once, which a
+    /// misbehaving meter or a corrupted telegram with a matching CRC may
+    /// contain, with `TelegramParseError::DuplicateLine`.
+    pub strict: bool,
+    /// Give up on telegrams longer than this many bytes with
+    /// `TelegramParseError::TooLong`, instead of waiting for their end. Keeps
+    /// a stream that never ends a telegram, like data received at the wrong
+    /// baud rate, from filling the caller's buffer.
+    pub max_length: Option<usize>,
+    /// Registers to parse as `Line::Registered`, which would otherwise be
+    /// `Line::UnknownObis`.
+    pub registers: &'static [Register],
+    /// The time zone the meter's timestamps are in. Defaults to CET.
+    pub time_zone: TimeZone,
 }
 
-#[derive(Debug)]
+/// A register that `Line` doesn't cover, such as a country-specific one, to
+/// parse and serialize anyway. Pass a table of them in
+/// `ParseOptions::registers`.
+///
+/// The value is read from the first value of the line, like `(02.351*kW)`,
+/// and kept as an integer, like the values of other lines. With 3 decimals,
+/// that value is stored and serialized as `2351`.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Register {
+    pub obis: ObisCode,
+    /// The JSON key to serialize the value as.
+    pub key: &'static str,
+    /// Number of digits before the decimal point.
+    pub digits: u8,
+    /// Number of digits after it, or 0 if the value has no decimal point.
+    pub decimals: u8,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum TelegramParseError {
     CrcMismatch(CrcMismatch),
     InvalidUtf8,
     Incomplete,
     ParseError(usize, nom::error::ErrorKind),
+    /// The OBIS code occurs more than once. Only returned in strict mode.
+    DuplicateLine(ObisCode),
+    /// The telegram is longer than `ParseOptions::max_length`. The returned
+    /// length skips to the start of the next telegram.
+    TooLong,
 }
 
 pub fn parse(input: &[u8]) -> (usize, Result<Telegram, TelegramParseError>) {
+    parse_with_options(input, &ParseOptions::default())
+}
+
+/// Like `parse()`, but records lines that are too large to parse as
+/// `Line::Oversized` and carries on, instead of failing the whole telegram.
+pub fn parse_lenient(input: &[u8]) -> (usize, Result<Telegram, TelegramParseError>) {
+    let options = ParseOptions {
+        lenient: true,
+        ..ParseOptions::default()
+    };
+    parse_with_options(input, &options)
+}
+
+pub fn parse_with_options(
+    input: &[u8],
+    options: &ParseOptions,
+) -> (usize, Result<Telegram, TelegramParseError>) {
+    let next_start = || next_start(input.iter());
+    let (read, res) = parse_contiguous(input, options);
+    let res = res.and_then(|unverified| unverified.verify(options));
+    limit_length((read, res), input.len(), next_start, options)
+}
+
+/// Like `parse_with_options()`, but returns the telegram even if its CRC
+/// doesn't match, for meters that are known to send bad CRCs. The returned
+/// flag is set if the CRC matched.
+pub fn parse_unverified(
+    input: &[u8],
+    options: &ParseOptions,
+) -> (usize, Result<Telegram, TelegramParseError>, bool) {
+    let next_start = || next_start(input.iter());
+    let (read, res) = parse_contiguous(input, options);
+    let crc_ok = matches!(&res, Ok(unverified) if unverified.crc_ok());
+    let res = res.and_then(|unverified| check_duplicates(unverified.telegram, options));
+    let (read, res) = limit_length((read, res), input.len(), next_start, options);
+    (read, res, crc_ok)
+}
+
+fn parse_contiguous(
+    input: &[u8],
+    options: &ParseOptions,
+) -> (usize, Result<Unverified, TelegramParseError>) {
     let input_str = match core::str::from_utf8(input) {
         Ok(res) => res,
         Err(err) => {
@@ -195,77 +622,374 @@ pub fn parse(input: &[u8]) -> (usize, Result<Telegram, TelegramParseError>) {
             );
         }
     };
-    let line_buffer = ArrayVec::<Line, MAX_LINES_PER_TELEGRAM>::new();
-    match telegram(input_str, line_buffer) {
+    match telegram(input_str, options) {
         Ok((remaining, telegram)) => {
             let telegram_length = input_str.len() - remaining.len();
+            let calculated = options
+                .crc
+                .unwrap_or_else(|| crc16(&input[..telegram_length - 6]));
+            (
+                telegram_length,
+                Ok(Unverified {
+                    telegram,
+                    calculated,
+                }),
+            )
+        }
+        Err(err) => parse_error(0, input_str, err),
+    }
+}
+
+/// Like `parse_with_options()`, for a telegram that may wrap around the end
+/// of a ring buffer: `head` holds the oldest bytes, and `tail` the ones that
+/// follow them. The returned length counts from the start of `head`.
+///
+/// Lines are parsed where they are, except for the line that straddles the
+/// two slices, which is copied into a buffer of `MAX_SPLIT_LINE` bytes. If it
+/// doesn't fit, this fails with `ErrorKind::TooLarge`.
+pub fn parse_split(
+    head: &[u8],
+    tail: &[u8],
+    options: &ParseOptions,
+) -> (usize, Result<Telegram, TelegramParseError>) {
+    let next_start = || next_start(head.iter().chain(tail.iter()));
+    let available = head.len() + tail.len();
+    let (read, res) = parse_split_contiguous(head, tail, options);
+    let res = res.and_then(|unverified| unverified.verify(options));
+    limit_length((read, res), available, next_start, options)
+}
+
+fn parse_split_contiguous(
+    head: &[u8],
+    tail: &[u8],
+    options: &ParseOptions,
+) -> (usize, Result<Unverified, TelegramParseError>) {
+    if tail.is_empty() {
+        return parse_contiguous(head, options);
+    }
+    if head.is_empty() {
+        return parse_contiguous(tail, options);
+    }
+
+    // Split `head` after its last complete line, unless the header isn't
+    // complete by then, as it's parsed in one go.
+    let mut split = head
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |pos| pos + 1);
+    if !head[..split].windows(4).any(|w| w == b"\r\n\r\n") {
+        split = 0;
+    }
+    let header_in_bridge = split == 0;
+
+    let mut lines = ArrayVec::new();
+    let mut device_id = None;
+    if !header_in_bridge {
+        let first = match utf8_segment(0, &head[..split]) {
+            Ok(first) => first,
+            Err(read) => return (read, Err(TelegramParseError::InvalidUtf8)),
+        };
+        match header(first).and_then(|(input, id)| {
+            device_id = Some(id);
+            lines_until_crc(input, &mut lines, options, true)
+        }) {
+            Ok((remaining, Some(crc))) => {
+                let length = first.len() - remaining.len();
+                let telegram = Telegram {
+                    device_id: device_id.unwrap_or_default(),
+                    lines,
+                    crc,
+                };
+                let calculated = options.crc.unwrap_or_else(|| crc16(&head[..length - 6]));
+                return (
+                    length,
+                    Ok(Unverified {
+                        telegram,
+                        calculated,
+                    }),
+                );
+            }
+            Ok((_, None)) => {}
+            Err(err) => return parse_error(0, first, err),
+        }
+    }
 
-            let crc = crc16(&input[..telegram_length - 6]);
+    // Copy the straddling line, up to and including its line ending.
+    let mut bridge = ArrayVec::<u8, MAX_SPLIT_LINE>::new();
+    let too_large = (
+        1,
+        Err(TelegramParseError::ParseError(
+            split,
+            nom::error::ErrorKind::TooLarge,
+        )),
+    );
+    if bridge.try_extend_from_slice(&head[split..]).is_err() {
+        return too_large;
+    }
+    let mut rest = tail;
+    while let Some((byte, next)) = rest.split_first() {
+        if bridge.try_push(*byte).is_err() {
+            return too_large;
+        }
+        rest = next;
+        if *byte == b'\n' && (!header_in_bridge || bridge.windows(4).any(|w| w == b"\r\n\r\n")) {
+            break;
+        }
+    }
+    let rest_offset = head.len() + tail.len() - rest.len();
 
-            let res = if telegram.crc != crc {
-                Err(TelegramParseError::CrcMismatch(CrcMismatch {
-                    calculated: crc,
-                    read: telegram.crc,
-                }))
-            } else {
-                Ok(telegram)
+    let bridge_str = match utf8_segment(split, &bridge) {
+        Ok(bridge_str) => bridge_str,
+        Err(read) => return (read, Err(TelegramParseError::InvalidUtf8)),
+    };
+    let mut input = bridge_str;
+    if header_in_bridge {
+        match header(input) {
+            Ok((remaining, id)) => {
+                device_id = Some(id);
+                input = remaining;
+            }
+            Err(err) => return parse_error(split, bridge_str, err),
+        }
+    }
+    let more = !rest.is_empty();
+    let (remaining, crc) = match lines_until_crc(input, &mut lines, options, more) {
+        Ok(res) => res,
+        Err(err) => return parse_error(split, bridge_str, err),
+    };
+    let (length, crc) = match crc {
+        Some(crc) => (split + bridge_str.len() - remaining.len(), crc),
+        None => {
+            let last = match utf8_segment(rest_offset, rest) {
+                Ok(last) => last,
+                Err(read) => return (read, Err(TelegramParseError::InvalidUtf8)),
             };
+            match lines_until_crc(last, &mut lines, options, false) {
+                Ok((remaining, Some(crc))) => (rest_offset + last.len() - remaining.len(), crc),
+                Ok((_, None)) => return (0, Err(TelegramParseError::Incomplete)),
+                Err(err) => return parse_error(rest_offset, last, err),
+            }
+        }
+    };
 
-            (input_str.len() - remaining.len(), res)
+    let telegram = Telegram {
+        device_id: device_id.unwrap_or_default(),
+        lines,
+        crc,
+    };
+    let crc_length = length - 6;
+    let calculated = options.crc.unwrap_or_else(|| {
+        let mut crc = Crc16::new();
+        if crc_length <= head.len() {
+            crc.update(&head[..crc_length]);
+        } else {
+            crc.update(head);
+            crc.update(&tail[..crc_length - head.len()]);
         }
-        Err(nom::Err::Incomplete(err)) => (0, Err(TelegramParseError::Incomplete)),
-        Err(nom::Err::Failure(err)) | Err(nom::Err::Error(err)) => {
-            let pos = input_str.len() - err.input.len();
-            (1, Err(TelegramParseError::ParseError(pos, err.code)))
+        crc.finish()
+    });
+    (
+        length,
+        Ok(Unverified {
+            telegram,
+            calculated,
+        }),
+    )
+}
+
+/// Applies `ParseOptions::max_length` to the result of parsing, with
+/// `available` bytes of input.
+fn limit_length<T>(
+    (read, res): (usize, Result<T, TelegramParseError>),
+    available: usize,
+    next_start: impl FnOnce() -> Option<usize>,
+    options: &ParseOptions,
+) -> (usize, Result<T, TelegramParseError>) {
+    let max_length = match options.max_length {
+        Some(max_length) => max_length,
+        None => return (read, res),
+    };
+    match res {
+        // It won't be complete by the time it fits, so skip to the start of
+        // the next telegram, or discard everything if there isn't one yet.
+        Err(TelegramParseError::Incomplete) if available > max_length => (
+            next_start().unwrap_or(available),
+            Err(TelegramParseError::TooLong),
+        ),
+        Err(TelegramParseError::ParseError(..)) | Err(TelegramParseError::InvalidUtf8) => {
+            (read, res)
+        }
+        _ if read > max_length => (read, Err(TelegramParseError::TooLong)),
+        res => (read, res),
+    }
+}
+
+/// The position of the first `/` after the start of the input.
+fn next_start<'a>(input: impl Iterator<Item = &'a u8>) -> Option<usize> {
+    input.skip(1).position(|b| *b == b'/').map(|pos| pos + 1)
+}
+
+/// Checks `input`, a part of a telegram that starts `offset` bytes into it,
+/// for invalid UTF-8. On failure, returns how many bytes to discard, like
+/// `parse_with_options()` does.
+fn utf8_segment(offset: usize, input: &[u8]) -> Result<&str, usize> {
+    core::str::from_utf8(input).map_err(|err| {
+        err.error_len()
+            .map(|e| e + err.valid_up_to() + offset)
+            .unwrap_or(0)
+    })
+}
+
+/// A parsed telegram, along with the CRC calculated over it.
+struct Unverified {
+    telegram: Telegram,
+    calculated: u16,
+}
+
+impl Unverified {
+    fn crc_ok(&self) -> bool {
+        self.telegram.crc == self.calculated
+    }
+
+    /// Returns the telegram if its CRC matches, and in strict mode, it has no
+    /// duplicate lines.
+    fn verify(self, options: &ParseOptions) -> Result<Telegram, TelegramParseError> {
+        if !self.crc_ok() {
+            return Err(TelegramParseError::CrcMismatch(CrcMismatch {
+                calculated: self.calculated,
+                read: self.telegram.crc,
+            }));
         }
+        check_duplicates(self.telegram, options)
     }
 }
 
-fn telegram(
+/// Returns the telegram, unless it has duplicate lines in strict mode.
+fn check_duplicates(
+    telegram: Telegram,
+    options: &ParseOptions,
+) -> Result<Telegram, TelegramParseError> {
+    let duplicate = if options.strict {
+        duplicate_line(&telegram)
+    } else {
+        None
+    };
+    match duplicate {
+        Some(obis) => Err(TelegramParseError::DuplicateLine(obis)),
+        None => Ok(telegram),
+    }
+}
+
+/// The first OBIS code that occurs more than once in the telegram.
+fn duplicate_line(telegram: &Telegram) -> Option<ObisCode> {
+    let lines = &telegram.lines;
+    lines.iter().enumerate().find_map(|(i, line)| {
+        let obis = line.obis_code();
+        if lines[..i].iter().any(|l| l.obis_code() == obis) {
+            Some(obis)
+        } else {
+            None
+        }
+    })
+}
+
+/// Converts an error from parsing `input`, which starts `offset` bytes into
+/// the telegram.
+fn parse_error<T>(
+    offset: usize,
     input: &str,
-    mut line_buffer: ArrayVec<Line, MAX_LINES_PER_TELEGRAM>,
-) -> IResult<&str, Telegram> {
-    let (input, device_id) = device_id(input)?;
+    err: nom::Err<nom::error::Error<&str>>,
+) -> (usize, Result<T, TelegramParseError>) {
+    match err {
+        nom::Err::Incomplete(_) => (0, Err(TelegramParseError::Incomplete)),
+        nom::Err::Failure(err) | nom::Err::Error(err) => {
+            let pos = offset + input.len() - err.input.len();
+            (1, Err(TelegramParseError::ParseError(pos, err.code)))
+        }
+    }
+}
+
+fn telegram<'a>(input: &'a str, options: &ParseOptions) -> IResult<&'a str, Telegram> {
+    let (input, device_id) = header(input)?;
+    let mut lines = ArrayVec::new();
+    match lines_until_crc(input, &mut lines, options, false)? {
+        (input, Some(crc)) => Ok((
+            input,
+            Telegram {
+                device_id,
+                lines,
+                crc,
+            },
+        )),
+        // Without more input to come, this always finds a CRC or fails.
+        (input, None) => Err(nom::Err::Incomplete(nom::Needed::Unknown)),
+    }
+}
 
+fn header(input: &str) -> IResult<&str, ArrayString<32>> {
+    let (input, device_id) = device_id(input)?;
     let device_id = ArrayString::from(device_id).map_err(|_| {
         nom::Err::Error(nom::error::Error {
             input,
             code: nom::error::ErrorKind::TooLarge,
         })
     })?;
+    Ok((input, device_id))
+}
 
-    let crc_val: u16;
-    let mut next_input = input;
+/// Parses lines into `lines` up to and including the CRC, which is returned.
+/// If `more` input follows this part of the telegram, running out of input
+/// at the end of a line returns `None` instead of being incomplete.
+fn lines_until_crc<'a>(
+    mut input: &'a str,
+    lines: &mut ArrayVec<Line, MAX_LINES_PER_TELEGRAM>,
+    options: &ParseOptions,
+    more: bool,
+) -> IResult<&'a str, Option<u16>> {
     loop {
-        if let (inp, Some(crc)) = opt(crc)(next_input)? {
-            crc_val = crc;
-            next_input = inp;
-            break;
+        if more && input.is_empty() {
+            return Ok((input, None));
         }
-        match line(next_input) {
-            Ok((i, o)) => {
-                next_input = i;
-                line_buffer.try_push(o).map_err(|_| {
-                    nom::Err::Error(nom::error::Error {
-                        input,
-                        code: nom::error::ErrorKind::TooLarge,
-                    })
-                })?;
-            }
-            Err(err) => {
-                return Err(err);
-            }
+        if let (input, Some(crc)) = opt(crc)(input)? {
+            return Ok((input, Some(crc)));
         }
+        let res = line(input).and_then(|(next_input, line)| match line {
+            Line::UnknownObis(obis) => match options.registers.iter().find(|r| r.obis == obis) {
+                Some(register) => Ok((next_input, registered_line(input, register)?)),
+                None => Ok((next_input, line)),
+            },
+            Line::Timestamp(ts) => Ok((
+                next_input,
+                Line::Timestamp(Timestamp {
+                    zone: options.time_zone,
+                    ..ts
+                }),
+            )),
+            Line::GasDelivered(channel, ts, volume) => Ok((
+                next_input,
+                Line::GasDelivered(
+                    channel,
+                    Timestamp {
+                        zone: options.time_zone,
+                        ..ts
+                    },
+                    volume,
+                ),
+            )),
+            line => Ok((next_input, line)),
+        });
+        let (next_input, line) = match res {
+            Err(nom::Err::Error(_)) if options.lenient => oversized_line(input),
+            res => res,
+        }?;
+        lines.try_push(line).map_err(|_| {
+            nom::Err::Error(nom::error::Error {
+                input,
+                code: nom::error::ErrorKind::TooLarge,
+            })
+        })?;
+        input = next_input;
     }
-
-    Ok((
-        next_input,
-        Telegram {
-            device_id,
-            lines: line_buffer,
-            crc: crc_val,
-        },
-    ))
 }
 
 fn device_id(input: &str) -> IResult<&str, &str> {
@@ -310,6 +1034,16 @@ fn line(input: &str) -> IResult<&str, Line> {
         [1, 0, 2, 8, tariff, 255] => {
             Line::Produced(tariff, map_cosem(raw.cosem.get(0), fixed_point(6, 3))?)
         }
+        [1, 0, 1, 8, tariff, period] if period <= MAX_BILLING_PERIOD => Line::HistoricalConsumed(
+            period,
+            tariff,
+            map_cosem(raw.cosem.get(0), fixed_point(6, 3))?,
+        ),
+        [1, 0, 2, 8, tariff, period] if period <= MAX_BILLING_PERIOD => Line::HistoricalProduced(
+            period,
+            tariff,
+            map_cosem(raw.cosem.get(0), fixed_point(6, 3))?,
+        ),
         [0, 0, 96, 14, 0, 255] => Line::ActiveTariff(map_cosem(raw.cosem.get(0), u8_complete(4))?),
         [1, 0, 1, 7, 0, 255] => {
             Line::TotalConsuming(map_cosem(raw.cosem.get(0), fixed_point(2, 3))?)
@@ -337,11 +1071,49 @@ fn line(input: &str) -> IResult<&str, Line> {
         [1, 0, 22, 7, 0, 255] => {
             Line::Consuming(Phase::L1, map_cosem(raw.cosem.get(0), fixed_point(2, 3))?)
         }
+        [1, 0, 32, 7, 0, 255] => {
+            Line::Voltage(Phase::L1, map_cosem(raw.cosem.get(0), fixed_point(3, 1))?)
+        }
+        [1, 0, 52, 7, 0, 255] => {
+            Line::Voltage(Phase::L2, map_cosem(raw.cosem.get(0), fixed_point(3, 1))?)
+        }
+        [1, 0, 72, 7, 0, 255] => {
+            Line::Voltage(Phase::L3, map_cosem(raw.cosem.get(0), fixed_point(3, 1))?)
+        }
+        [0, channel, 24, 2, 1, 255] => Line::GasDelivered(
+            channel,
+            map_cosem(raw.cosem.first(), timestamp)?,
+            map_cosem(raw.cosem.get(1), fixed_point(5, 3))?,
+        ),
         obis => Line::UnknownObis(obis),
     };
     Ok((input, line))
 }
 
+/// Parses the value of a line that `line()` doesn't know, but the caller
+/// registered.
+fn registered_line<'a>(
+    input: &'a str,
+    register: &'static Register,
+) -> Result<Line, nom::Err<nom::error::Error<&'a str>>> {
+    let (_, raw) = raw_line(input)?;
+    let cosem = raw.cosem.first().copied().unwrap_or_default();
+    let digits = register.digits as usize;
+    let res: IResult<&str, u32> = match register.decimals {
+        0 => u32_complete(digits)(cosem),
+        decimals => fixed_point(digits, decimals as usize)(cosem),
+    };
+    let (_, value) = res?;
+    Ok(Line::Registered(register, value))
+}
+
+/// Skips over a line that `line()` couldn't parse, keeping only its OBIS code.
+fn oversized_line(input: &str) -> IResult<&str, Line> {
+    let (_, obis) = obis_code(input)?;
+    let (input, _) = terminated(take_until("\r\n"), crlf)(input)?;
+    Ok((input, Line::Oversized(obis)))
+}
+
 fn timestamp(input: &str) -> IResult<&str, Timestamp> {
     let (input, year) = u8_complete(2)(input)?;
     let (input, month) = u8_complete(2)(input)?;
@@ -361,6 +1133,7 @@ fn timestamp(input: &str) -> IResult<&str, Timestamp> {
             minute,
             second,
             dst: dst == 'S',
+            zone: TimeZone::default(),
         },
     ))
 }
@@ -400,7 +1173,7 @@ fn raw_line(input: &str) -> IResult<&str, RawLine> {
     ))
 }
 
-fn obis_code(input: &str) -> IResult<&str, [u8; 6]> {
+fn obis_code(input: &str) -> IResult<&str, ObisCode> {
     let (input, obis_a) = terminated(u8, tag("-"))(input)?;
     let (input, obis_b) = terminated(u8, tag(":"))(input)?;
     let (input, obis_c) = terminated(u8, tag("."))(input)?;
@@ -408,7 +1181,8 @@ fn obis_code(input: &str) -> IResult<&str, [u8; 6]> {
     let (input, obis_e) = u8(input)?;
 
     // According to the OBIS spec, value group F is optional and should be interpreted as 255 if missing.
-    let (input, obis_f) = opt(preceded(tag("."), u8))(input)?;
+    // Billing period registers write it as `*FF` rather than `.FF`.
+    let (input, obis_f) = opt(preceded(alt((tag("."), tag("*"))), u8))(input)?;
     let obis_f = obis_f.unwrap_or(255);
 
     Ok((input, [obis_a, obis_b, obis_c, obis_d, obis_e, obis_f]))
@@ -488,24 +1262,82 @@ fn decode_hex<'a>(data: &'a str, out: &mut [u8]) -> Result<(), nom::error::Error
     Ok(())
 }
 
-fn crc16(data: &[u8]) -> u16 {
-    let mut crc = 0u16;
-    for byte in data {
-        crc ^= *byte as u16;
-        for _ in 0..8 {
-            if crc & 1 != 0 {
-                crc >>= 1;
-                crc ^= 0xA001;
-            } else {
-                crc >>= 1;
+/// The CRC16 at the end of a telegram, which covers everything from the `/`
+/// up to and including the `!`. It can be computed as the telegram comes in,
+/// and passed to the parser through `ParseOptions::crc`.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Crc16 {
+    crc: u16,
+}
+
+impl Crc16 {
+    pub const fn new() -> Self {
+        Self { crc: 0 }
+    }
+
+    /// Continues the calculation over more data.
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.crc ^= *byte as u16;
+            for _ in 0..8 {
+                if self.crc & 1 != 0 {
+                    self.crc >>= 1;
+                    self.crc ^= 0xA001;
+                } else {
+                    self.crc >>= 1;
+                }
             }
         }
     }
-    crc
+
+    /// The CRC of the data so far. More data can still be added afterwards.
+    pub fn finish(&self) -> u16 {
+        self.crc
+    }
 }
 
-#[cfg(test)]
-#[macro_use]
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = Crc16::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// Longest equipment identifier `equipment_id()` returns. DSMR allows 96 hex
+/// digits, which decode to this many characters.
+pub const MAX_EQUIPMENT_ID_LEN: usize = 48;
+
+/// Finds the equipment identifier (`0-0:96.1.1`) in a raw telegram. It isn't
+/// kept in `Line::EquipmentId`, to keep lines small. Meters send it as
+/// hex-encoded ASCII, which is decoded; if it doesn't decode to printable
+/// characters, it is returned as it was sent.
+pub fn equipment_id(telegram: &[u8]) -> Option<ArrayString<MAX_EQUIPMENT_ID_LEN>> {
+    const PREFIX: &[u8] = b"\n0-0:96.1.1(";
+    let start = telegram.windows(PREFIX.len()).position(|w| w == PREFIX)? + PREFIX.len();
+    let rest = &telegram[start..];
+    let value = core::str::from_utf8(&rest[..rest.iter().position(|b| *b == b')')?]).ok()?;
+    if value.is_empty() {
+        return None;
+    }
+
+    let decoded = value.as_bytes().chunks(2).map(|pair| {
+        let pair = core::str::from_utf8(pair).ok()?;
+        u8::from_str_radix(pair, 16)
+            .ok()
+            .filter(u8::is_ascii_graphic)
+    });
+    let mut id = ArrayString::new();
+    if value.len() % 2 == 0 && decoded.clone().all(|b| b.is_some()) {
+        for b in decoded.flatten() {
+            id.push(b as char);
+        }
+    } else {
+        id.try_push_str(value).ok()?;
+    }
+    Some(id)
+}
+
+#[cfg(any(test, feature = "std"))]
+#[cfg_attr(test, macro_use)]
 extern crate std;
 
 #[cfg(test)]
@@ -587,10 +1419,122 @@ mod tests {
         let (read, res) = parse(EXAMPLE_TELEGRAM);
         let res = res.unwrap();
         let mut s = String::new();
-        res.serialize(&mut s);
+        res.serialize(&mut s, &SerializeOptions::default());
         println!("{}", s);
     }
 
+    #[test]
+    fn display_summarizes_telegram() {
+        let (_, res) = parse(EXAMPLE_TELEGRAM);
+        let mut summary = String::new();
+        write!(summary, "{}", res.unwrap());
+        assert_eq!(
+            "XMX5LGBBFFB231237741 @ 2020-02-08T15:35:16+01:00: \
+             +329 W -0 W, consumed 8671274 Wh, produced 0 Wh",
+            summary
+        );
+    }
+
+    #[test]
+    fn display_sums_tariffs_beyond_u32() {
+        let mut telegram = parse(EXAMPLE_TELEGRAM).1.unwrap();
+        telegram.lines.clear();
+        telegram.lines.push(Line::Consumed(1, u32::MAX));
+        telegram.lines.push(Line::Consumed(2, u32::MAX));
+        let mut summary = String::new();
+        write!(summary, "{}", telegram);
+        assert!(summary.ends_with("consumed 8589934590 Wh, produced 0 Wh"));
+    }
+
+    #[test]
+    fn parse_split_matches_parse_at_every_split() {
+        let mut expected = String::new();
+        let (_, res) = parse(EXAMPLE_TELEGRAM);
+        res.unwrap()
+            .serialize(&mut expected, &SerializeOptions::default());
+
+        for split in 0..=EXAMPLE_TELEGRAM.len() {
+            let (head, tail) = EXAMPLE_TELEGRAM.split_at(split);
+            let (read, res) = parse_split(head, tail, &ParseOptions::default());
+            let mut actual = String::new();
+            match res {
+                Ok(telegram) => telegram.serialize(&mut actual, &SerializeOptions::default()),
+                Err(err) => panic!("Split at {}: {:?}", split, err),
+            }
+            assert_eq!(EXAMPLE_TELEGRAM.len(), read, "split at {}", split);
+            assert_eq!(expected, actual, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn parse_split_waits_for_the_rest() {
+        let (head, tail) = EXAMPLE_TELEGRAM.split_at(100);
+        let (read, res) = parse_split(head, &tail[..200], &ParseOptions::default());
+        assert_eq!(0, read);
+        assert!(matches!(res, Err(TelegramParseError::Incomplete)));
+    }
+
+    #[test]
+    fn parse_split_checks_crc_over_both_slices() {
+        let mut corrupted = EXAMPLE_TELEGRAM.to_vec();
+        // The last digit of the power being consumed.
+        let pos = corrupted.windows(6).position(|w| w == b"00.329").unwrap() + 5;
+        corrupted[pos] = b'8';
+        let (head, tail) = corrupted.split_at(200);
+        let (read, res) = parse_split(head, tail, &ParseOptions::default());
+        assert_eq!(corrupted.len(), read);
+        match res {
+            Err(TelegramParseError::CrcMismatch(mismatch)) => {
+                assert_eq!(0x6130, mismatch.read);
+                assert_eq!(crc16(&corrupted[..read - 6]), mismatch.calculated);
+            }
+            res => panic!("Expected CRC mismatch, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn serialize_leaves_out_excluded_fields() {
+        let (_, res) = parse(EXAMPLE_TELEGRAM);
+        let options = SerializeOptions::NONE
+            .with(Field::Power)
+            .with(Field::ActiveTariff);
+        let mut s = String::new();
+        res.unwrap().serialize(&mut s, &options);
+        assert_eq!(
+            "{\"active_tariff\": 1,\"total_consuming\": 329,\"total_producing\": 0}",
+            s
+        );
+    }
+
+    #[test]
+    fn serialize_options_round_trip() {
+        let options = SerializeOptions::ALL.without(Field::VoltageEvents);
+        assert!(!options.includes(Field::VoltageEvents));
+        assert!(options.includes(Field::PhasePower));
+        assert_eq!(options, SerializeOptions::from_bits(options.bits()));
+        assert_eq!(SerializeOptions::ALL, SerializeOptions::from_bits(u16::MAX));
+        for field in Field::ALL.iter() {
+            assert_eq!(Some(*field), Field::from_name(field.name()));
+        }
+    }
+
+    #[test]
+    fn equipment_id_is_decoded() {
+        assert_eq!(
+            Some("E0004001844004214"),
+            equipment_id(EXAMPLE_TELEGRAM).as_deref()
+        );
+        assert_eq!(
+            Some("not hex"),
+            equipment_id(b"/X\r\n\r\n0-0:96.1.1(not hex)\r\n!0000\r\n").as_deref()
+        );
+        assert_eq!(None, equipment_id(b"/X\r\n\r\n0-0:96.1.1()\r\n!0000\r\n"));
+        assert_eq!(
+            None,
+            equipment_id(b"/X\r\n\r\n1-0:1.8.1(000001.000*kWh)\r\n")
+        );
+    }
+
     #[test]
     fn telegram_parses() {
         let (read, res) = parse(EXAMPLE_TELEGRAM);
@@ -607,6 +1551,8 @@ mod tests {
         let telegram2 = res.unwrap();
 
         assert_eq!(TWO_TELEGRAMS.len(), read1 + read2);
+        // The same telegram, sent twice.
+        assert_eq!(telegram1, telegram2);
     }
 
     #[test]
@@ -625,10 +1571,9 @@ mod tests {
 
     #[test]
     fn simple_telegram_parses() {
-        let mut line_buffer = ArrayVec::<_, 32>::new();
         let res: TestResult<Telegram> = telegram(
             "/XMX1000\r\n\r\n1-3:0.2.8(42)\r\n0-0:1.0.0(200208153506W)\r\n!FFFF\r\n",
-            line_buffer,
+            &ParseOptions::default(),
         );
         let (rem, tel) = res.unwrap();
         assert_eq!("XMX1000", tel.device_id.as_str());
@@ -636,6 +1581,21 @@ mod tests {
         assert_eq!(65535, tel.crc);
     }
 
+    #[test]
+    fn voltage_line_parses() {
+        let res: TestResult<Line> = line("1-0:52.7.0(230.1*V)\r\n");
+        let (_, line) = res.unwrap();
+        let mut telegram = Telegram {
+            device_id: ArrayString::new(),
+            lines: ArrayVec::new(),
+            crc: 0,
+        };
+        telegram.lines.push(line);
+        let mut s = String::new();
+        telegram.serialize(&mut s, &SerializeOptions::ALL);
+        assert_eq!("{\"l2_voltage\": 230.1}", s);
+    }
+
     #[test]
     fn single_value_line_parses() {
         let res: TestResult<Line> = line("1-3:0.2.8(42)\r\n");
@@ -667,6 +1627,236 @@ mod tests {
         assert_eq!("", rem);
     }
 
+    /// A telegram with a power failure log that has more entries than a line
+    /// can hold, followed by a regular line.
+    fn telegram_with_oversized_line() -> String {
+        let mut telegram = String::from("/XMX5LGBBFFB231237741\r\n\r\n1-0:99.97.0(9)(0-0:96.7.19)");
+        for _ in 0..9 {
+            telegram.push_str("(180726223917S)(0000006462*s)");
+        }
+        telegram.push_str("\r\n1-0:1.7.0(00.329*kW)\r\n!");
+        let crc = crc16(telegram.as_bytes());
+        telegram.push_str(&format!("{:04X}\r\n", crc));
+        telegram
+    }
+
+    /// A telegram with the tariff 1 reading twice, and a matching CRC.
+    fn telegram_with_duplicate_line() -> String {
+        let mut telegram = String::from(
+            "/XMX5LGBBFFB231237741\r\n\r\n\
+             1-0:1.8.1(001234.567*kWh)\r\n\
+             1-0:1.7.0(00.329*kW)\r\n\
+             1-0:1.8.1(007654.321*kWh)\r\n!",
+        );
+        let crc = crc16(telegram.as_bytes());
+        telegram.push_str(&format!("{:04X}\r\n", crc));
+        telegram
+    }
+
+    #[test]
+    fn duplicate_line_is_accepted_by_default() {
+        let telegram = telegram_with_duplicate_line();
+        let (_, res) = parse(telegram.as_bytes());
+        assert_eq!(3, res.unwrap().lines.len());
+    }
+
+    #[test]
+    fn duplicate_line_fails_strict_telegram() {
+        let telegram = telegram_with_duplicate_line();
+        let options = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+        let (read, res) = parse_with_options(telegram.as_bytes(), &options);
+        assert_eq!(telegram.len(), read);
+        match res {
+            Err(TelegramParseError::DuplicateLine(obis)) => assert_eq!([1, 0, 1, 8, 1, 255], obis),
+            res => panic!("Expected duplicate line, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn example_telegram_passes_strict_mode() {
+        let options = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+        let (_, res) = parse_with_options(EXAMPLE_TELEGRAM, &options);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn obis_code_matches_parsed_line() {
+        for input in [
+            "1-0:1.8.2(001234.567*kWh)\r\n",
+            "1-0:2.8.1*03(001234.567*kWh)\r\n",
+            "1-0:21.7.0(00.329*kW)\r\n",
+            "1-0:52.7.0(230.1*V)\r\n",
+            "0-1:24.2.1(101209112500W)(12785.123*m3)\r\n",
+        ]
+        .iter()
+        {
+            let res: TestResult<Line> = line(input);
+            let (_, parsed) = res.unwrap();
+            let res: TestResult<ObisCode> = obis_code(input);
+            let (_, obis) = res.unwrap();
+            assert_eq!(obis, parsed.obis_code(), "{}", input);
+        }
+    }
+
+    // Belgian meters report the average demand in the current quarter hour.
+    static REGISTERS: [Register; 2] = [
+        Register {
+            obis: [1, 0, 1, 4, 0, 255],
+            key: "average_demand",
+            digits: 2,
+            decimals: 3,
+        },
+        Register {
+            obis: [0, 0, 96, 7, 22, 255],
+            key: "custom_count",
+            digits: 5,
+            decimals: 0,
+        },
+    ];
+
+    #[test]
+    fn registered_lines_are_parsed_and_serialized() {
+        let mut telegram = String::from(
+            "/FLU5253769484_A\r\n\r\n\
+             1-0:1.4.0(02.351*kW)\r\n\
+             0-0:96.7.22(00042)\r\n\
+             0-0:96.7.23(00001)\r\n!",
+        );
+        let crc = crc16(telegram.as_bytes());
+        telegram.push_str(&format!("{:04X}\r\n", crc));
+        let options = ParseOptions {
+            registers: &REGISTERS,
+            ..ParseOptions::default()
+        };
+        let (_, res) = parse_with_options(telegram.as_bytes(), &options);
+        let parsed = res.unwrap();
+        match parsed.lines.as_slice() {
+            [Line::Registered(demand, 2351), Line::Registered(count, 42), Line::UnknownObis(_)] => {
+                assert_eq!("average_demand", demand.key);
+                assert_eq!("custom_count", count.key);
+            }
+            lines => panic!("Unexpected lines: {:?}", lines),
+        }
+
+        let mut json = String::new();
+        parsed.serialize(&mut json, &SerializeOptions::NONE);
+        assert_eq!("{\"average_demand\": 2351,\"custom_count\": 42}", json);
+
+        // Without the registers, they're just unknown.
+        let (_, res) = parse(telegram.as_bytes());
+        assert!(res
+            .unwrap()
+            .lines
+            .iter()
+            .all(|line| matches!(line, Line::UnknownObis(_))));
+    }
+
+    #[test]
+    fn endless_telegram_is_too_long() {
+        let mut garbage = String::from("/XMX5LGBBFFB231237741\r\n\r\n");
+        while garbage.len() < 200 {
+            garbage.push_str("1-0:1.8.1(001234.567*kWh)\r\n");
+        }
+        let options = ParseOptions {
+            max_length: Some(100),
+            ..ParseOptions::default()
+        };
+        let (read, res) = parse_with_options(garbage.as_bytes(), &options);
+        assert!(matches!(res, Err(TelegramParseError::TooLong)));
+        assert_eq!(garbage.len(), read);
+
+        // Without a limit, it's just incomplete.
+        let (read, res) = parse(garbage.as_bytes());
+        assert!(matches!(res, Err(TelegramParseError::Incomplete)));
+        assert_eq!(0, read);
+    }
+
+    #[test]
+    fn too_long_skips_to_next_telegram() {
+        // A text message that never ends, until the next telegram starts.
+        let mut input = String::from("/XMX5LGBBFFB231237741\r\n\r\n0-0:96.13.0(");
+        input.push_str(&"A".repeat(100));
+        let next = input.len();
+        input.push_str("/XMX5LGBBFFB231237741\r\n\r\n");
+        let options = ParseOptions {
+            max_length: Some(100),
+            ..ParseOptions::default()
+        };
+        let (head, tail) = input.as_bytes().split_at(60);
+        let (read, res) = parse_split(head, tail, &options);
+        assert!(matches!(res, Err(TelegramParseError::TooLong)));
+        assert_eq!(next, read);
+    }
+
+    #[test]
+    fn complete_telegram_over_maximum_is_too_long() {
+        let options = ParseOptions {
+            max_length: Some(EXAMPLE_TELEGRAM.len() - 1),
+            ..ParseOptions::default()
+        };
+        let (read, res) = parse_with_options(EXAMPLE_TELEGRAM, &options);
+        assert!(matches!(res, Err(TelegramParseError::TooLong)));
+        assert_eq!(EXAMPLE_TELEGRAM.len(), read);
+    }
+
+    #[test]
+    fn oversized_line_fails_telegram() {
+        let telegram = telegram_with_oversized_line();
+        let (_, res) = parse(telegram.as_bytes());
+        match res {
+            Err(TelegramParseError::ParseError(_, ErrorKind::TooLarge)) => {}
+            res => panic!("Expected TooLarge, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn oversized_line_is_skipped_when_lenient() {
+        let telegram = telegram_with_oversized_line();
+        let (read, res) = parse_lenient(telegram.as_bytes());
+        let res = res.unwrap();
+        assert_eq!(telegram.len(), read);
+        match res.lines.as_slice() {
+            [Line::Oversized([1, 0, 99, 97, 0, 255]), Line::TotalConsuming(329)] => {}
+            lines => panic!("Unexpected lines: {:?}", lines),
+        }
+    }
+
+    #[test]
+    fn crc_mismatch_reports_both_crcs() {
+        let mut telegram = EXAMPLE_TELEGRAM.to_vec();
+        let len = telegram.len();
+        telegram[len - 6..len - 2].copy_from_slice(b"1234");
+        let (_, res) = parse(&telegram);
+        match res {
+            Err(TelegramParseError::CrcMismatch(CrcMismatch {
+                calculated: 0x6130,
+                read: 0x1234,
+            })) => {}
+            res => panic!("Expected CRC mismatch, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn unverified_telegram_is_returned_despite_crc_mismatch() {
+        let mut telegram = EXAMPLE_TELEGRAM.to_vec();
+        let len = telegram.len();
+        telegram[len - 6..len - 2].copy_from_slice(b"1234");
+        let (read, res, crc_ok) = parse_unverified(&telegram, &ParseOptions::default());
+        assert_eq!(len, read);
+        assert_eq!(20, res.unwrap().lines.len());
+        assert!(!crc_ok);
+
+        let (_, res, crc_ok) = parse_unverified(EXAMPLE_TELEGRAM, &ParseOptions::default());
+        assert!(res.is_ok());
+        assert!(crc_ok);
+    }
+
     #[test]
     fn invalid_cosem_fails() {
         let res: TestResult<&str> = cosem()("invalid string");
@@ -699,6 +1889,76 @@ mod tests {
         assert_eq!([255, 255, 0, 1, 0, 18], obis)
     }
 
+    #[test]
+    fn obis_with_starred_tag_f_parses() {
+        let res: TestResult<[u8; 6]> = obis_code("1-0:1.8.1*01()");
+        let (rem, obis) = res.unwrap();
+        assert_eq!("()", rem);
+        assert_eq!([1, 0, 1, 8, 1, 1], obis)
+    }
+
+    #[test]
+    fn billing_period_lines_parse() {
+        let res: TestResult<Line> = line("1-0:1.8.2*03(001234.567*kWh)\r\n");
+        match res.unwrap().1 {
+            Line::HistoricalConsumed(3, 2, 1234567) => {}
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+        let res: TestResult<Line> = line("1-0:2.8.1*12(000012.345*kWh)\r\n");
+        match res.unwrap().1 {
+            Line::HistoricalProduced(12, 1, 12345) => {}
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+    }
+
+    #[test]
+    fn timestamp_converts_to_unix_time() {
+        let res: TestResult<Timestamp> = timestamp("200208153516W");
+        let (_, winter) = res.unwrap();
+        assert_eq!(1581172516, winter.unix_time());
+
+        let res: TestResult<Timestamp> = timestamp("180726223917S");
+        let (_, summer) = res.unwrap();
+        assert_eq!(1532637557, summer.unix_time());
+    }
+
+    #[test]
+    fn gas_line_parses() {
+        let res: TestResult<Line> = line("0-1:24.2.1(101209112500W)(12785.123*m3)\r\n");
+        match res.unwrap() {
+            ("", Line::GasDelivered(1, ts, 12785123)) => assert_eq!(1291890300, ts.unix_time()),
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+    }
+
+    #[test]
+    fn timestamp_renders_cet_offset_by_default() {
+        let (_, summer) = timestamp("180726223917S").unwrap();
+        assert_eq!("2018-07-26T22:39:17+02:00", format!("{}", summer));
+        assert!(summer.dst());
+    }
+
+    #[test]
+    fn timestamp_uses_configured_time_zone() {
+        let options = ParseOptions {
+            time_zone: TimeZone::WET,
+            ..ParseOptions::default()
+        };
+        let (_, res) = parse_with_options(EXAMPLE_TELEGRAM, &options);
+        let telegram = res.unwrap();
+        let ts = telegram
+            .lines
+            .iter()
+            .find_map(|line| match line {
+                Line::Timestamp(ts) => Some(ts),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(TimeZone::WET, ts.zone());
+        assert_eq!("2020-02-08T15:35:16+00:00", format!("{}", ts));
+        assert_eq!(1581176116, ts.unix_time());
+    }
+
     #[test]
     fn u8_complete_parses() {
         let res: TestResult<u8> = u8_complete(2)("38");
@@ -727,4 +1987,28 @@ mod tests {
         let crc = crc16(&EXAMPLE_TELEGRAM[..EXAMPLE_TELEGRAM.len() - TRAILER]);
         assert_eq!(0x6130, crc);
     }
+
+    #[test]
+    fn crc16_updates_incrementally() {
+        let mut crc = Crc16::new();
+        for byte in b"123456789".iter() {
+            crc.update(&[*byte]);
+        }
+        assert_eq!(0xbb3d, crc.finish());
+    }
+
+    #[test]
+    fn precomputed_crc_is_used() {
+        let options = ParseOptions {
+            crc: Some(0x1234),
+            ..ParseOptions::default()
+        };
+        let (_, res) = parse_with_options(EXAMPLE_TELEGRAM, &options);
+        match res {
+            Err(TelegramParseError::CrcMismatch(mismatch)) => {
+                assert_eq!(0x1234, mismatch.calculated)
+            }
+            res => panic!("Expected CRC mismatch, got {:?}", res),
+        }
+    }
 }
@@ -0,0 +1,389 @@
+//! Utilities for building telegrams and rendering them back to P1 text, so
+//! tests and fuzzers can round-trip arbitrary telegrams through the parser.
+
+use core::fmt::{self, Write};
+
+use arrayvec::{ArrayString, ArrayVec};
+
+use crate::{Crc16, Line, Phase, Telegram, TimeZone, Timestamp};
+
+/// Builds a `Telegram` line by line. The CRC is filled in by `build()`.
+pub struct TelegramBuilder {
+    telegram: Telegram,
+}
+
+impl TelegramBuilder {
+    /// Panics if the device ID is longer than 32 bytes.
+    pub fn new(device_id: &str) -> Self {
+        Self {
+            telegram: Telegram {
+                device_id: ArrayString::from(device_id).expect("device ID too long"),
+                lines: ArrayVec::new(),
+                crc: 0,
+            },
+        }
+    }
+
+    /// Panics if the telegram already holds the maximum number of lines.
+    pub fn line(mut self, line: Line) -> Self {
+        self.telegram.lines.push(line);
+        self
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn timestamp(
+        self,
+        year: u16,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+        dst: bool,
+    ) -> Self {
+        self.line(Line::Timestamp(Timestamp {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            dst,
+            zone: TimeZone::default(),
+        }))
+    }
+
+    /// Finishes the telegram, setting its CRC to the one `render()` writes.
+    pub fn build(mut self) -> Telegram {
+        let mut crc = CrcWriter {
+            inner: Discard,
+            crc: Crc16::new(),
+        };
+        // Discard never fails.
+        let _ = render_body(&self.telegram, &mut crc);
+        self.telegram.crc = crc.crc.finish();
+        self.telegram
+    }
+}
+
+/// Produces pseudo-random telegrams containing every line the parser
+/// understands, with values that fit their P1 representation.
+pub struct TelegramGenerator {
+    state: u32,
+}
+
+impl TelegramGenerator {
+    pub fn new(seed: u32) -> Self {
+        // Xorshift gets stuck on zero.
+        Self { state: seed | 1 }
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    fn below(&mut self, bound: u32) -> u32 {
+        self.next_u32() % bound
+    }
+
+    pub fn telegram(&mut self) -> Telegram {
+        let mut builder = TelegramBuilder::new("XMX5LGBBFFB231237741")
+            .line(Line::Version(42))
+            .timestamp(
+                2000 + self.below(100) as u16,
+                1 + self.below(12) as u8,
+                1 + self.below(28) as u8,
+                self.below(24) as u8,
+                self.below(60) as u8,
+                self.below(60) as u8,
+                self.below(2) == 1,
+            );
+        for tariff in 1..=2 {
+            builder = builder
+                .line(Line::Consumed(tariff, self.below(1_000_000_000)))
+                .line(Line::Produced(tariff, self.below(1_000_000_000)));
+        }
+        builder
+            .line(Line::ActiveTariff(1 + self.below(2) as u8))
+            .line(Line::TotalConsuming(self.below(100_000)))
+            .line(Line::TotalProducing(self.below(100_000)))
+            .line(Line::PowerFailures(self.below(100_000)))
+            .line(Line::LongPowerFailures(self.below(100_000)))
+            .line(Line::VoltageSags(self.below(100_000)))
+            .line(Line::VoltageSwells(self.below(100_000)))
+            .line(Line::Current(Phase::L1, self.below(1000)))
+            .line(Line::Consuming(Phase::L1, self.below(100_000)))
+            .line(Line::Producing(Phase::L1, self.below(100_000)))
+            .line(Line::Voltage(Phase::L1, self.below(10_000)))
+            .line(Line::Voltage(Phase::L2, self.below(10_000)))
+            .line(Line::Voltage(Phase::L3, self.below(10_000)))
+            .line(Line::GasDelivered(
+                1,
+                Timestamp {
+                    year: 2000 + self.below(100) as u16,
+                    month: 1 + self.below(12) as u8,
+                    day: 1 + self.below(28) as u8,
+                    hour: self.below(24) as u8,
+                    minute: 5 * self.below(12) as u8,
+                    second: 0,
+                    dst: self.below(2) == 1,
+                    zone: TimeZone::default(),
+                },
+                self.below(100_000_000),
+            ))
+            .build()
+    }
+}
+
+/// Writes the telegram as P1 text, ending with a CRC calculated over the
+/// rendered text rather than the one stored in the telegram. Unknown and
+/// oversized lines are left out, since their values weren't kept. The parser
+/// only understands phase L1 for current and power; other phases render as
+/// their OBIS codes, but parse back as unknown lines.
+pub fn render<W: Write>(telegram: &Telegram, out: &mut W) -> fmt::Result {
+    let mut crc = CrcWriter {
+        inner: out,
+        crc: Crc16::new(),
+    };
+    render_body(telegram, &mut crc)?;
+    write!(crc.inner, "{:04X}\r\n", crc.crc.finish())
+}
+
+/// Everything up to and including the `!` that precedes the CRC.
+fn render_body<W: Write>(telegram: &Telegram, out: &mut W) -> fmt::Result {
+    write!(out, "/{}\r\n\r\n", telegram.device_id)?;
+    for line in telegram.lines.iter() {
+        render_line(line, out)?;
+    }
+    write!(out, "!")
+}
+
+fn render_line<W: Write>(line: &Line, out: &mut W) -> fmt::Result {
+    match line {
+        Line::Version(version) => write!(out, "1-3:0.2.8({:02})", version)?,
+        Line::Timestamp(ts) => {
+            write!(out, "0-0:1.0.0")?;
+            timestamp(out, ts)?;
+        }
+        Line::EquipmentId => write!(out, "0-0:96.1.1(00)")?,
+        Line::PowerFailureLog => write!(out, "1-0:99.97.0(0)(0-0:96.7.19)")?,
+        Line::Consumed(tariff, energy) => {
+            write!(out, "1-0:1.8.{}(", tariff)?;
+            fixed_point(out, *energy, 6, "kWh")?;
+        }
+        Line::Produced(tariff, energy) => {
+            write!(out, "1-0:2.8.{}(", tariff)?;
+            fixed_point(out, *energy, 6, "kWh")?;
+        }
+        Line::HistoricalConsumed(period, tariff, energy) => {
+            write!(out, "1-0:1.8.{}*{:02}(", tariff, period)?;
+            fixed_point(out, *energy, 6, "kWh")?;
+        }
+        Line::HistoricalProduced(period, tariff, energy) => {
+            write!(out, "1-0:2.8.{}*{:02}(", tariff, period)?;
+            fixed_point(out, *energy, 6, "kWh")?;
+        }
+        Line::ActiveTariff(tariff) => write!(out, "0-0:96.14.0({:04})", tariff)?,
+        Line::TotalConsuming(power) => {
+            write!(out, "1-0:1.7.0(")?;
+            fixed_point(out, *power, 2, "kW")?;
+        }
+        Line::TotalProducing(power) => {
+            write!(out, "1-0:2.7.0(")?;
+            fixed_point(out, *power, 2, "kW")?;
+        }
+        Line::PowerFailures(count) => write!(out, "0-0:96.7.21({:05})", count)?,
+        Line::LongPowerFailures(count) => write!(out, "0-0:96.7.9({:05})", count)?,
+        Line::VoltageSags(count) => write!(out, "1-0:32.32.0({:05})", count)?,
+        Line::VoltageSwells(count) => write!(out, "1-0:32.36.0({:05})", count)?,
+        Line::Current(phase, current) => write!(
+            out,
+            "1-0:{}.7.0({:03}*A)",
+            31 + phase_offset(phase),
+            current
+        )?,
+        // These follow the OBIS codes the parser assigns to them.
+        Line::Producing(phase, power) => {
+            write!(out, "1-0:{}.7.0(", 21 + phase_offset(phase))?;
+            fixed_point(out, *power, 2, "kW")?;
+        }
+        Line::Consuming(phase, power) => {
+            write!(out, "1-0:{}.7.0(", 22 + phase_offset(phase))?;
+            fixed_point(out, *power, 2, "kW")?;
+        }
+        Line::Voltage(phase, voltage) => write!(
+            out,
+            "1-0:{}.7.0({:03}.{}*V)",
+            32 + phase_offset(phase),
+            voltage / 10,
+            voltage % 10
+        )?,
+        Line::Registered(register, value) => {
+            let [a, b, c, d, e, f] = register.obis;
+            write!(out, "{}-{}:{}.{}.{}", a, b, c, d, e)?;
+            if f != 255 {
+                write!(out, ".{}", f)?;
+            }
+            match register.decimals as usize {
+                0 => write!(
+                    out,
+                    "({:0digits$})",
+                    value,
+                    digits = register.digits as usize
+                )?,
+                decimals => {
+                    let scale = 10u32.pow(decimals as u32);
+                    write!(
+                        out,
+                        "({:0digits$}.{:0decimals$})",
+                        value / scale,
+                        value % scale,
+                        digits = register.digits as usize,
+                        decimals = decimals
+                    )?
+                }
+            }
+        }
+        Line::GasDelivered(channel, ts, volume) => {
+            write!(out, "0-{}:24.2.1", channel)?;
+            timestamp(out, ts)?;
+            write!(out, "(")?;
+            fixed_point(out, *volume, 5, "m3")?;
+        }
+        Line::UnknownObis(_) | Line::Oversized(_) => return Ok(()),
+    }
+    write!(out, "\r\n")
+}
+
+fn phase_offset(phase: &Phase) -> u8 {
+    match phase {
+        Phase::L1 => 0,
+        Phase::L2 => 20,
+        Phase::L3 => 40,
+    }
+}
+
+fn timestamp<W: Write>(out: &mut W, ts: &Timestamp) -> fmt::Result {
+    write!(
+        out,
+        "({:02}{:02}{:02}{:02}{:02}{:02}{})",
+        ts.year % 100,
+        ts.month,
+        ts.day,
+        ts.hour,
+        ts.minute,
+        ts.second,
+        if ts.dst { 'S' } else { 'W' }
+    )
+}
+
+/// Writes a value with three implied decimals, followed by its unit and the
+/// closing parenthesis.
+fn fixed_point<W: Write>(out: &mut W, value: u32, digits: usize, unit: &str) -> fmt::Result {
+    write!(
+        out,
+        "{:0digits$}.{:03}*{})",
+        value / 1000,
+        value % 1000,
+        unit,
+        digits = digits
+    )
+}
+
+struct CrcWriter<W> {
+    inner: W,
+    crc: Crc16,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.crc.update(s.as_bytes());
+        self.inner.write_str(s)
+    }
+}
+
+struct Discard;
+
+impl Write for Discard {
+    fn write_str(&mut self, _: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, parse_with_options, ParseOptions, Register, SerializeOptions};
+    use std::string::String;
+
+    fn json(telegram: &Telegram) -> String {
+        let mut s = String::new();
+        telegram.serialize(&mut s, &SerializeOptions::ALL);
+        s
+    }
+
+    #[test]
+    fn generated_telegrams_round_trip() {
+        let mut generator = TelegramGenerator::new(0x5EED);
+        for _ in 0..100 {
+            let telegram = generator.telegram();
+            let mut p1 = String::new();
+            render(&telegram, &mut p1).unwrap();
+
+            let (read, res) = parse(p1.as_bytes());
+            let parsed = res.unwrap();
+            assert_eq!(p1.len(), read);
+            assert_eq!(telegram.crc, parsed.crc);
+            assert_eq!(json(&telegram), json(&parsed));
+            // Gas isn't serialized, so compare it separately.
+            assert_eq!(
+                format!("{:?}", telegram.lines.last()),
+                format!("{:?}", parsed.lines.last())
+            );
+        }
+    }
+
+    #[test]
+    fn built_telegram_renders_as_p1() {
+        let telegram = TelegramBuilder::new("TEST")
+            .line(Line::Version(42))
+            .line(Line::HistoricalConsumed(1, 2, 1234567))
+            .line(Line::TotalConsuming(329))
+            .build();
+        let mut p1 = String::new();
+        render(&telegram, &mut p1).unwrap();
+        assert!(p1.starts_with(
+            "/TEST\r\n\r\n\
+             1-3:0.2.8(42)\r\n\
+             1-0:1.8.2*01(001234.567*kWh)\r\n\
+             1-0:1.7.0(00.329*kW)\r\n!"
+        ));
+        assert!(p1.ends_with(&format!("!{:04X}\r\n", telegram.crc)));
+    }
+
+    #[test]
+    fn registered_line_round_trips() {
+        static REGISTERS: [Register; 1] = [Register {
+            obis: [1, 0, 1, 4, 0, 255],
+            key: "average_demand",
+            digits: 2,
+            decimals: 3,
+        }];
+        let telegram = TelegramBuilder::new("TEST")
+            .line(Line::Registered(&REGISTERS[0], 2351))
+            .build();
+        let mut p1 = String::new();
+        render(&telegram, &mut p1).unwrap();
+        assert!(p1.contains("\r\n1-0:1.4.0(02.351)\r\n"));
+
+        let options = ParseOptions {
+            registers: &REGISTERS,
+            ..ParseOptions::default()
+        };
+        let (_, res) = parse_with_options(p1.as_bytes(), &options);
+        assert_eq!(json(&telegram), json(&res.unwrap()));
+    }
+}
@@ -0,0 +1,183 @@
+use smoltcp::{
+    socket::{SocketHandle, SocketRef, TcpSocket, UdpPacketMetadata, UdpSocket},
+    time::{Duration, Instant},
+    wire::IpEndpoint,
+};
+
+use crate::random::Random;
+
+pub const DEFAULT_RX_BUF_SZ: usize = 4096;
+pub const DEFAULT_TX_BUF_SZ: usize = 4096;
+// Datagrams each socket can hold in either direction, regardless of size.
+const UDP_PACKETS: usize = 4;
+
+/// A client of a TCP connection, polled by `NetworkStack::poll_client`.
+///
+/// Clients exchange data through the `TcpConnection` they're handed, and
+/// return what should happen to the connection itself, which the stack then
+/// takes care of.
+pub trait TcpClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle);
+    fn get_socket_handle(&mut self) -> SocketHandle;
+    fn poll(
+        &mut self,
+        socket: &mut dyn TcpConnection,
+        timestamp: Instant,
+        random: &mut Random,
+    ) -> TcpAction;
+}
+
+/// What a `TcpClient` wants to happen to its connection after a poll.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TcpAction {
+    /// Leave the connection as it is.
+    Nothing,
+    /// Accept a connection on this port.
+    Listen(u16),
+    /// Connect to a remote endpoint, from a random local port.
+    Connect {
+        remote: IpEndpoint,
+        timeout: Option<Duration>,
+        keep_alive: Option<Duration>,
+    },
+    /// Close the connection once everything queued has been sent.
+    Close,
+    /// Reset the connection, dropping anything still queued.
+    Abort,
+}
+
+/// The state of a TCP connection, and the data going in and out of it, as
+/// seen by a `TcpClient`. Implemented by smoltcp's `TcpSocket`, of which the
+/// methods by the same name are documented in more detail.
+pub trait TcpConnection {
+    fn is_open(&self) -> bool;
+    fn is_active(&self) -> bool;
+    fn may_send(&self) -> bool;
+    fn may_recv(&self) -> bool;
+    fn can_send(&self) -> bool;
+    fn can_recv(&self) -> bool;
+    fn local_endpoint(&self) -> IpEndpoint;
+    fn remote_endpoint(&self) -> IpEndpoint;
+    /// Size of the send buffer, the most that can be queued at once.
+    fn send_capacity(&self) -> usize;
+    /// Number of bytes that can be queued for sending.
+    fn send_free(&self) -> usize;
+    /// Number of bytes received, but not consumed yet.
+    fn recv_queue(&self) -> usize;
+    fn send_slice(&mut self, data: &[u8]) -> smoltcp::Result<usize>;
+    /// Hands the free part of the send buffer to `f`, which returns how many
+    /// bytes it wrote.
+    fn send_with(&mut self, f: &mut dyn FnMut(&mut [u8]) -> usize) -> smoltcp::Result<usize>;
+    fn recv_slice(&mut self, data: &mut [u8]) -> smoltcp::Result<usize>;
+    /// Received data, without consuming it. If the receive buffer wraps
+    /// around, this is only the part up to its end.
+    fn peek(&mut self) -> smoltcp::Result<&[u8]>;
+    /// Consumes up to `len` bytes of received data, as returned by `peek()`.
+    fn consume(&mut self, len: usize) -> smoltcp::Result<usize>;
+}
+
+impl TcpConnection for TcpSocket<'_> {
+    fn is_open(&self) -> bool {
+        TcpSocket::is_open(self)
+    }
+    fn is_active(&self) -> bool {
+        TcpSocket::is_active(self)
+    }
+    fn may_send(&self) -> bool {
+        TcpSocket::may_send(self)
+    }
+    fn may_recv(&self) -> bool {
+        TcpSocket::may_recv(self)
+    }
+    fn can_send(&self) -> bool {
+        TcpSocket::can_send(self)
+    }
+    fn can_recv(&self) -> bool {
+        TcpSocket::can_recv(self)
+    }
+    fn local_endpoint(&self) -> IpEndpoint {
+        TcpSocket::local_endpoint(self)
+    }
+    fn remote_endpoint(&self) -> IpEndpoint {
+        TcpSocket::remote_endpoint(self)
+    }
+    fn send_capacity(&self) -> usize {
+        TcpSocket::send_capacity(self)
+    }
+    fn send_free(&self) -> usize {
+        TcpSocket::send_capacity(self) - self.send_queue()
+    }
+    fn recv_queue(&self) -> usize {
+        TcpSocket::recv_queue(self)
+    }
+    fn send_slice(&mut self, data: &[u8]) -> smoltcp::Result<usize> {
+        TcpSocket::send_slice(self, data)
+    }
+    fn send_with(&mut self, f: &mut dyn FnMut(&mut [u8]) -> usize) -> smoltcp::Result<usize> {
+        self.send(|buf| {
+            let len = f(buf);
+            (len, len)
+        })
+    }
+    fn recv_slice(&mut self, data: &mut [u8]) -> smoltcp::Result<usize> {
+        TcpSocket::recv_slice(self, data)
+    }
+    fn peek(&mut self) -> smoltcp::Result<&[u8]> {
+        TcpSocket::peek(self, usize::MAX)
+    }
+    fn consume(&mut self, len: usize) -> smoltcp::Result<usize> {
+        self.recv(|buf| {
+            let len = len.min(buf.len());
+            (len, len)
+        })
+    }
+}
+
+/// A client that sends or receives datagrams. Unlike a `TcpClient`, it has
+/// no connection, so it's only polled once the stack has an address.
+pub trait UdpClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle);
+    fn get_socket_handle(&mut self) -> SocketHandle;
+    fn poll(&mut self, socket: SocketRef<UdpSocket>, timestamp: Instant, random: &mut Random);
+}
+
+/// Socket buffers for a single TCP client.
+///
+/// The buffer sizes bound the TCP window we can advertise and the amount of
+/// data a client can queue, so they should be sized to the client's workload.
+pub struct TcpClientStore<
+    const RX_BUF_SZ: usize = DEFAULT_RX_BUF_SZ,
+    const TX_BUF_SZ: usize = DEFAULT_TX_BUF_SZ,
+> {
+    pub rx_buffer: [u8; RX_BUF_SZ],
+    pub tx_buffer: [u8; TX_BUF_SZ],
+}
+
+impl<const RX_BUF_SZ: usize, const TX_BUF_SZ: usize> TcpClientStore<RX_BUF_SZ, TX_BUF_SZ> {
+    pub fn new() -> Self {
+        TcpClientStore {
+            rx_buffer: [0; RX_BUF_SZ],
+            tx_buffer: [0; TX_BUF_SZ],
+        }
+    }
+}
+
+/// Socket buffers for a single UDP client. Each buffer holds up to
+/// `UDP_PACKETS` datagrams, as long as their combined size fits.
+pub struct UdpClientStore<const RX_BUF_SZ: usize, const TX_BUF_SZ: usize> {
+    pub rx_metadata: [UdpPacketMetadata; UDP_PACKETS],
+    pub rx_buffer: [u8; RX_BUF_SZ],
+    pub tx_metadata: [UdpPacketMetadata; UDP_PACKETS],
+    pub tx_buffer: [u8; TX_BUF_SZ],
+}
+
+impl<const RX_BUF_SZ: usize, const TX_BUF_SZ: usize> UdpClientStore<RX_BUF_SZ, TX_BUF_SZ> {
+    pub fn new() -> Self {
+        UdpClientStore {
+            rx_metadata: [UdpPacketMetadata::EMPTY; UDP_PACKETS],
+            rx_buffer: [0; RX_BUF_SZ],
+            tx_metadata: [UdpPacketMetadata::EMPTY; UDP_PACKETS],
+            tx_buffer: [0; TX_BUF_SZ],
+        }
+    }
+}
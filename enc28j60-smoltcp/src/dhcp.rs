@@ -0,0 +1,293 @@
+use arrayvec::ArrayVec;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address, Ipv4Packet, UdpPacket,
+};
+
+// smoltcp's DHCP client only hands us the options it knows about, so the ones
+// we need on top of that are picked out of the replies as they arrive.
+
+pub const MAX_NTP_SERVERS: usize = 3;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+// Offsets of the fixed BOOTP fields we use.
+const XID_OFFSET: usize = 4;
+const CHADDR_OFFSET: usize = 28;
+// Fixed BOOTP fields, followed by the magic cookie.
+const OPTIONS_OFFSET: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OPT_PAD: u8 = 0;
+const OPT_END: u8 = 255;
+const OPT_NTP_SERVERS: u8 = 42;
+const OPT_MESSAGE_TYPE: u8 = 53;
+// The TFTP server name, which we only accept as a dotted-quad address.
+const OPT_TFTP_SERVER_NAME: u8 = 66;
+// Cisco's list of TFTP server addresses, of which the first one is used.
+const OPT_TFTP_SERVER_ADDRESS: u8 = 150;
+const DHCPACK: u8 = 5;
+
+pub type NtpServers = ArrayVec<Ipv4Address, MAX_NTP_SERVERS>;
+
+/// Identifies the exchange a DHCP request starts, so replies meant for another
+/// client, or for a transaction we've given up on, are ignored.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Transaction {
+    xid: u32,
+    chaddr: [u8; 6],
+}
+
+/// The options we use from a DHCPACK.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct DhcpOptions {
+    /// Option 42, empty if it was absent.
+    pub ntp_servers: NtpServers,
+    /// Option 150, or else option 66, to fetch the configuration from.
+    pub tftp_server: Option<Ipv4Address>,
+}
+
+/// Returns the transaction a DHCP request we send starts, if the frame is one.
+pub fn request(frame: &[u8]) -> Option<Transaction> {
+    bootp(frame, DHCP_SERVER_PORT, BOOTREQUEST).map(|(transaction, _)| transaction)
+}
+
+/// Returns the options in a DHCPACK, if the frame is one, and it belongs to
+/// `transaction`.
+pub fn options(frame: &[u8], transaction: &Transaction) -> Option<DhcpOptions> {
+    let (reply, options) = bootp(frame, DHCP_CLIENT_PORT, BOOTREPLY)?;
+    if reply != *transaction {
+        return None;
+    }
+
+    let mut ack = false;
+    let mut found = DhcpOptions::default();
+    let mut tftp_server_name = None;
+    let mut options = options;
+    while let Some((&kind, rest)) = options.split_first() {
+        match kind {
+            OPT_END => break,
+            OPT_PAD => {
+                options = rest;
+                continue;
+            }
+            _ => {}
+        }
+        let (&len, rest) = rest.split_first()?;
+        let data = rest.get(..len as usize)?;
+        match kind {
+            OPT_MESSAGE_TYPE => ack = data == [DHCPACK],
+            OPT_NTP_SERVERS => {
+                // The option may be repeated, so this fills up across them.
+                let room = found.ntp_servers.remaining_capacity();
+                for addr in data.chunks_exact(4).take(room) {
+                    found.ntp_servers.push(Ipv4Address::from_bytes(addr));
+                }
+            }
+            OPT_TFTP_SERVER_ADDRESS => {
+                found.tftp_server = data.chunks_exact(4).next().map(Ipv4Address::from_bytes)
+            }
+            OPT_TFTP_SERVER_NAME => {
+                tftp_server_name = core::str::from_utf8(data)
+                    .ok()
+                    .and_then(|name| name.trim_end_matches('\0').parse().ok())
+            }
+            _ => {}
+        }
+        options = &rest[len as usize..];
+    }
+    if ack {
+        found.tftp_server = found.tftp_server.or(tftp_server_name);
+        Some(found)
+    } else {
+        None
+    }
+}
+
+/// Returns the transaction and options of a BOOTP message with `op`, if the
+/// frame holds one sent to `port`.
+fn bootp(frame: &[u8], port: u16, op: u8) -> Option<(Transaction, &[u8])> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+    let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+    if packet.protocol() != IpProtocol::Udp {
+        return None;
+    }
+    let datagram = UdpPacket::new_checked(packet.payload()).ok()?;
+    if datagram.dst_port() != port {
+        return None;
+    }
+    let dhcp = datagram.payload();
+    if dhcp.len() < OPTIONS_OFFSET
+        || dhcp[0] != op
+        || dhcp[OPTIONS_OFFSET - 4..OPTIONS_OFFSET] != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let mut xid = [0; 4];
+    xid.copy_from_slice(&dhcp[XID_OFFSET..XID_OFFSET + 4]);
+    let mut chaddr = [0; 6];
+    chaddr.copy_from_slice(&dhcp[CHADDR_OFFSET..CHADDR_OFFSET + 6]);
+    let transaction = Transaction {
+        xid: u32::from_be_bytes(xid),
+        chaddr,
+    };
+    Some((transaction, &dhcp[OPTIONS_OFFSET..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DHCPOFFER: u8 = 2;
+    const ACK: &[u8] = &[OPT_MESSAGE_TYPE, 1, DHCPACK];
+    const OURS: Transaction = Transaction {
+        xid: 0x1234_5678,
+        chaddr: [0x02, 0, 0, 0, 0, 2],
+    };
+
+    type Frame = ArrayVec<u8, 512>;
+
+    /// A DHCP reply to us, broadcast, holding the given options.
+    fn reply(options: &[&[u8]]) -> Frame {
+        bootp_frame(BOOTREPLY, DHCP_CLIENT_PORT, &OURS, options)
+    }
+
+    /// A broadcast BOOTP message with `op`, sent to `port`.
+    fn bootp_frame(op: u8, port: u16, transaction: &Transaction, options: &[&[u8]]) -> Frame {
+        let options_len: usize = options.iter().map(|o| o.len()).sum();
+        let udp_len = (8 + OPTIONS_OFFSET + options_len + 1) as u16;
+        let ip_len = 20 + udp_len;
+        let mut frame = Frame::new();
+        let mut push = |bytes: &[u8]| frame.try_extend_from_slice(bytes).unwrap();
+        // Ethernet
+        push(&[0xFF; 6]);
+        push(&[0x02, 0, 0, 0, 0, 1]);
+        push(&[0x08, 0x00]);
+        // IPv4, without a checksum, which isn't checked.
+        push(&[0x45, 0]);
+        push(&ip_len.to_be_bytes());
+        push(&[0, 0, 0, 0, 64, 17, 0, 0]);
+        push(&[10, 0, 0, 1]);
+        push(&[255, 255, 255, 255]);
+        // UDP
+        push(&(DHCP_CLIENT_PORT + DHCP_SERVER_PORT - port).to_be_bytes());
+        push(&port.to_be_bytes());
+        push(&udp_len.to_be_bytes());
+        push(&[0, 0]);
+        // DHCP
+        push(&[op, 1, 6, 0]);
+        push(&transaction.xid.to_be_bytes());
+        push(&[0; CHADDR_OFFSET - XID_OFFSET - 4]);
+        push(&transaction.chaddr);
+        push(&[0; OPTIONS_OFFSET - CHADDR_OFFSET - 6 - 4]);
+        push(&MAGIC_COOKIE);
+        for option in options {
+            push(option);
+        }
+        push(&[OPT_END]);
+        frame
+    }
+
+    #[test]
+    fn ntp_servers_are_read() {
+        let frame = reply(&[
+            ACK,
+            &[OPT_PAD],
+            &[OPT_NTP_SERVERS, 8, 10, 0, 0, 1, 10, 0, 0, 2],
+        ]);
+        let options = options(&frame, &OURS).unwrap();
+        assert_eq!(
+            &[Ipv4Address::new(10, 0, 0, 1), Ipv4Address::new(10, 0, 0, 2)],
+            &options.ntp_servers[..]
+        );
+        assert_eq!(None, options.tftp_server);
+    }
+
+    #[test]
+    fn tftp_server_address_is_preferred() {
+        let frame = reply(&[
+            ACK,
+            &[OPT_TFTP_SERVER_NAME, 8],
+            b"10.0.0.3",
+            &[OPT_TFTP_SERVER_ADDRESS, 4, 10, 0, 0, 4],
+        ]);
+        assert_eq!(
+            Some(Ipv4Address::new(10, 0, 0, 4)),
+            options(&frame, &OURS).unwrap().tftp_server
+        );
+    }
+
+    #[test]
+    fn tftp_server_name_must_be_an_address() {
+        let frame = reply(&[ACK, &[OPT_TFTP_SERVER_NAME, 9], b"10.0.0.3\0"]);
+        assert_eq!(
+            Some(Ipv4Address::new(10, 0, 0, 3)),
+            options(&frame, &OURS).unwrap().tftp_server
+        );
+
+        let frame = reply(&[ACK, &[OPT_TFTP_SERVER_NAME, 4], b"tftp"]);
+        assert_eq!(None, options(&frame, &OURS).unwrap().tftp_server);
+    }
+
+    #[test]
+    fn only_acknowledgements_are_read() {
+        let frame = reply(&[
+            &[OPT_MESSAGE_TYPE, 1, DHCPOFFER],
+            &[OPT_NTP_SERVERS, 4, 10, 0, 0, 1],
+        ]);
+        assert_eq!(None, options(&frame, &OURS));
+    }
+
+    #[test]
+    fn truncated_options_are_rejected() {
+        let frame = reply(&[ACK, &[OPT_NTP_SERVERS, 8, 10, 0, 0, 1]]);
+        assert_eq!(None, options(&frame, &OURS));
+    }
+
+    #[test]
+    fn ntp_servers_stop_at_capacity() {
+        let frame = reply(&[
+            ACK,
+            &[OPT_NTP_SERVERS, 16, 10, 0, 0, 1, 10, 0, 0, 2],
+            &[10, 0, 0, 3, 10, 0, 0, 4],
+            &[OPT_NTP_SERVERS, 4, 10, 0, 0, 5],
+        ]);
+        let options = options(&frame, &OURS).unwrap();
+        assert_eq!(
+            &[
+                Ipv4Address::new(10, 0, 0, 1),
+                Ipv4Address::new(10, 0, 0, 2),
+                Ipv4Address::new(10, 0, 0, 3)
+            ],
+            &options.ntp_servers[..]
+        );
+    }
+
+    #[test]
+    fn replies_to_other_transactions_are_ignored() {
+        let ntp = &[OPT_NTP_SERVERS, 4, 10, 0, 0, 1][..];
+        let other_xid = Transaction {
+            xid: OURS.xid + 1,
+            ..OURS
+        };
+        let other_client = Transaction {
+            chaddr: [0x02, 0, 0, 0, 0, 3],
+            ..OURS
+        };
+        for transaction in [other_xid, other_client] {
+            let frame = bootp_frame(BOOTREPLY, DHCP_CLIENT_PORT, &transaction, &[ACK, ntp]);
+            assert_eq!(None, options(&frame, &OURS));
+        }
+    }
+
+    #[test]
+    fn requests_start_a_transaction() {
+        let frame = bootp_frame(BOOTREQUEST, DHCP_SERVER_PORT, &OURS, &[]);
+        assert_eq!(Some(OURS), request(&frame));
+        assert_eq!(None, request(&reply(&[ACK])));
+    }
+}
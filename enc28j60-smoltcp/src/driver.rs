@@ -0,0 +1,468 @@
+use core::{fmt::Debug, result::Result};
+
+use smoltcp::{
+    phy::{self, Checksum, ChecksumCapabilities, DeviceCapabilities},
+    time::Instant,
+};
+
+use crate::{
+    dhcp::{self, DhcpOptions, Transaction},
+    filter::FrameFilter,
+    log_throttle,
+    logging::{Debug2Format, Display2Format},
+    neighbours::{self, Neighbours},
+};
+
+/// The longest Ethernet frame, including its CRC, which is also the longest
+/// the ENC28J60 accepts.
+pub const MAX_FRAME_LENGTH: usize = 1518;
+pub const TX_BUF: usize = MAX_FRAME_LENGTH;
+// The ENC28J60 drops frames longer than MAX_FRAME_LENGTH, so that's all the
+// room a received frame needs.
+const FRAME_BUF: usize = MAX_FRAME_LENGTH;
+// Frames pulled from the ENC28J60 in one go. Draining its buffer quickly keeps
+// it from overflowing when a burst of broadcasts arrives.
+const RX_RING_LEN: usize = 4;
+
+// Transient SPI errors are retried this many times before giving up.
+const SPI_RETRIES: u32 = 3;
+// Cycles to wait before the first retry, doubled for every next attempt.
+// At 600 MHz, this is 10 µs.
+const SPI_RETRY_BACKOFF_CYCLES: u32 = 6000;
+
+/// A network controller that sends and receives whole Ethernet frames.
+///
+/// This isn't meant to be a generic abstraction over any network driver, it's
+/// just here so the smoltcp glue can be programmed against a simple trait
+/// instead of the generic soup resulting from Enc28j60 and its trait bounds.
+pub trait Driver: 'static {
+    /// Errors communicating with the device.
+    type Error: Debug;
+    /// Errors transmitting a frame, which the device itself may reject as well.
+    type TransmitError: Debug;
+
+    fn pending_packets(&mut self) -> Result<u8, Self::Error>;
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<u16, Self::Error>;
+
+    fn transmit(&mut self, buffer: &[u8]) -> Result<(), Self::TransmitError>;
+
+    fn set_frame_filter(&mut self, filter: &FrameFilter) -> Result<(), Self::Error>;
+
+    fn is_link_up(&mut self) -> Result<bool, Self::Error>;
+}
+
+/// Frames received from the driver, waiting to be handed to smoltcp.
+struct FrameRing {
+    frames: [[u8; FRAME_BUF]; RX_RING_LEN],
+    lengths: [usize; RX_RING_LEN],
+    head: usize,
+    count: usize,
+}
+
+impl FrameRing {
+    fn new() -> Self {
+        Self {
+            frames: [[0; FRAME_BUF]; RX_RING_LEN],
+            lengths: [0; RX_RING_LEN],
+            head: 0,
+            count: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The slot to receive the next frame into. Only becomes part of the ring
+    /// once `push` is called with the length received.
+    fn next_slot(&mut self) -> &mut [u8; FRAME_BUF] {
+        &mut self.frames[(self.head + self.count) % RX_RING_LEN]
+    }
+
+    fn push(&mut self, len: usize) {
+        self.lengths[(self.head + self.count) % RX_RING_LEN] = len;
+        self.count += 1;
+    }
+
+    /// Takes the oldest frame. Its slot isn't reused until the ring is refilled.
+    fn pop(&mut self) -> Option<&mut [u8]> {
+        if self.is_empty() {
+            return None;
+        }
+        let index = self.head;
+        self.head = (self.head + 1) % RX_RING_LEN;
+        self.count -= 1;
+        Some(&mut self.frames[index][..self.lengths[index]])
+    }
+}
+
+/// Checksums are always computed for outgoing packets. With the
+/// `trust-rx-checksums` feature, those of incoming packets aren't verified.
+fn checksum_capabilities() -> ChecksumCapabilities {
+    let mut caps = ChecksumCapabilities::default();
+    if cfg!(feature = "trust-rx-checksums") {
+        caps.ipv4 = Checksum::Tx;
+        caps.udp = Checksum::Tx;
+        caps.tcp = Checksum::Tx;
+        caps.icmpv4 = Checksum::Tx;
+    }
+    caps
+}
+
+/// Runs `op`, retrying it with exponential backoff if it fails.
+///
+/// Only use this for operations that can safely be repeated; an interrupted
+/// receive for instance may already have advanced the chip's read pointer.
+fn with_retry<T, E: Debug>(what: &str, mut op: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    let mut backoff = SPI_RETRY_BACKOFF_CYCLES;
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Ok(res) => return Ok(res),
+            Err(err) if attempt < SPI_RETRIES => {
+                attempt += 1;
+                debug!(
+                    "{} failed ({:?}), retry {}",
+                    what,
+                    Debug2Format(&err),
+                    attempt
+                );
+                // Host drivers don't need time to settle.
+                #[cfg(feature = "cortex-m")]
+                cortex_m::asm::delay(backoff);
+                backoff *= 2;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Counts the consecutive failures of the driver, after its retries.
+#[derive(Debug, Default)]
+struct Failures(u32);
+
+impl Failures {
+    fn failed<E: Debug>(&mut self, err: &E) {
+        self.0 += 1;
+        debug!("Driver failure {}: {:?}", self.0, Debug2Format(err));
+    }
+
+    fn succeeded(&mut self) {
+        self.0 = 0;
+    }
+}
+
+/// A smoltcp `phy::Device` for a `Driver`.
+///
+/// Received frames are read from the driver straight into the ring they're
+/// handed to smoltcp from. Frames to transmit are built in `tx_buffer`, and
+/// streamed to the driver from there, see `Enc28j60TxToken`.
+pub struct Enc28j60Phy<D: Driver> {
+    rx_frames: FrameRing,
+    tx_buffer: [u8; TX_BUF],
+    driver: D,
+    failures: Failures,
+    dhcp_options: DhcpOptions,
+    // The DHCP request we sent last, which acknowledgements must answer.
+    dhcp_transaction: Option<Transaction>,
+    neighbours: Neighbours,
+}
+
+impl<D: Driver> Enc28j60Phy<D> {
+    pub fn new(driver: D) -> Self {
+        Self {
+            rx_frames: FrameRing::new(),
+            tx_buffer: [0; TX_BUF],
+            driver,
+            failures: Failures::default(),
+            dhcp_options: DhcpOptions::default(),
+            dhcp_transaction: None,
+            neighbours: Neighbours::default(),
+        }
+    }
+
+    pub fn driver(&self) -> &D {
+        &self.driver
+    }
+
+    pub fn driver_mut(&mut self) -> &mut D {
+        &mut self.driver
+    }
+
+    /// Number of driver operations that failed in a row, even after retrying
+    /// them. Zero once one succeeds.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.failures.0
+    }
+
+    /// NTP servers from the last DHCP acknowledgement received.
+    pub fn ntp_servers(&self) -> &[smoltcp::wire::Ipv4Address] {
+        &self.dhcp_options.ntp_servers
+    }
+
+    /// TFTP server from the last DHCP acknowledgement received.
+    pub fn tftp_server(&self) -> Option<smoltcp::wire::Ipv4Address> {
+        self.dhcp_options.tftp_server
+    }
+
+    /// Hosts that sent ARP traffic, the most recent one first.
+    pub fn neighbours(&self) -> &Neighbours {
+        &self.neighbours
+    }
+
+    /// Pulls up to `RX_RING_LEN` pending frames from the driver.
+    fn fill_rx_frames(&mut self) {
+        let driver = &mut self.driver;
+        let failures = &mut self.failures;
+        let pending = match with_retry("Reading pending packet count", || driver.pending_packets())
+        {
+            Ok(pending) => pending,
+            Err(e) => {
+                warn_throttled!(
+                    log_throttle::now_ms(),
+                    "Failed to retrieve pending packet count: {:?}",
+                    Debug2Format(&e)
+                );
+                failures.failed(&e);
+                return;
+            }
+        };
+        failures.succeeded();
+        if pending > 0 {
+            trace!("We have {} pending packets", pending);
+        }
+        for _ in 0..(pending as usize).min(RX_RING_LEN) {
+            let slot = self.rx_frames.next_slot();
+            let len = match self.driver.receive(slot) {
+                Ok(len) => len as usize,
+                Err(e) => {
+                    warn_throttled!(
+                        log_throttle::now_ms(),
+                        "Failed to receive packet from driver: {:?}",
+                        Debug2Format(&e)
+                    );
+                    self.failures.failed(&e);
+                    break;
+                }
+            };
+            let options = self
+                .dhcp_transaction
+                .and_then(|transaction| dhcp::options(&slot[..len], &transaction));
+            if let Some(options) = options.filter(|o| *o != self.dhcp_options) {
+                if options.ntp_servers != self.dhcp_options.ntp_servers {
+                    info!(
+                        "DHCP advertised NTP servers {:?}",
+                        Debug2Format(&options.ntp_servers)
+                    );
+                }
+                if let Some(server) = options.tftp_server {
+                    info!("DHCP advertised TFTP server {}", Display2Format(&server));
+                }
+                self.dhcp_options = options;
+            }
+            if let Some(neighbour) = neighbours::sender(&slot[..len]) {
+                self.neighbours.heard_from(neighbour);
+            }
+            self.rx_frames.push(len);
+        }
+    }
+}
+
+impl<'a, D: 'a + Driver> phy::Device<'a> for Enc28j60Phy<D> {
+    type RxToken = Enc28j60RxToken<'a>;
+    type TxToken = Enc28j60TxToken<'a, D>;
+
+    // DeviceCapabilities contains a private field, so we can't apply this suggestion
+    #[allow(clippy::field_reassign_with_default)]
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = TX_BUF;
+        caps.max_burst_size = Some(RX_RING_LEN);
+        caps.checksum = checksum_capabilities();
+        caps
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        if self.rx_frames.is_empty() {
+            self.fill_rx_frames();
+        }
+        // Only hand out the part of the buffer we actually received, so
+        // smoltcp never sees stale bytes from a previous frame.
+        let buffer = self.rx_frames.pop()?;
+        Some((
+            Enc28j60RxToken { buffer },
+            Enc28j60TxToken {
+                buffer: &mut self.tx_buffer,
+                driver: &mut self.driver,
+                failures: &mut self.failures,
+                dhcp_transaction: &mut self.dhcp_transaction,
+            },
+        ))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        Some(Enc28j60TxToken {
+            buffer: &mut self.tx_buffer,
+            driver: &mut self.driver,
+            failures: &mut self.failures,
+            dhcp_transaction: &mut self.dhcp_transaction,
+        })
+    }
+}
+
+pub struct Enc28j60RxToken<'a> {
+    buffer: &'a mut [u8],
+}
+
+impl<'a> phy::RxToken for Enc28j60RxToken<'a> {
+    fn consume<R, F>(self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(self.buffer)
+    }
+}
+
+pub struct Enc28j60TxToken<'a, D> {
+    buffer: &'a mut [u8],
+    driver: &'a mut D,
+    failures: &'a mut Failures,
+    dhcp_transaction: &'a mut Option<Transaction>,
+}
+
+impl<'a, D: Driver> phy::TxToken for Enc28j60TxToken<'a, D> {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        if len > self.buffer.len() {
+            warn!(
+                "Packet length ({}) exceeds Tx buffer size ({})",
+                len,
+                self.buffer.len()
+            );
+            return Err(smoltcp::Error::Exhausted);
+        }
+        // smoltcp writes the frame in place, after which it is streamed to the
+        // ENC28J60 straight from this buffer. Its transmit buffer can't take
+        // the place of this one: it's only reachable by writing to it over
+        // SPI, front to back, while smoltcp fills in lengths and checksums
+        // after writing what follows them. So this is the one copy we can't
+        // avoid.
+        f(&mut self.buffer[..len]).and_then(|r| {
+            let (driver, buffer, failures) = (self.driver, &self.buffer[..len], self.failures);
+            with_retry("Transmit", || driver.transmit(buffer)).map_err(|e| {
+                warn_throttled!(
+                    log_throttle::now_ms(),
+                    "Transmit error: {:?}",
+                    Debug2Format(&e)
+                );
+                failures.failed(&e);
+                smoltcp::Error::Illegal
+            })?;
+            failures.succeeded();
+            if let Some(transaction) = dhcp::request(buffer) {
+                *self.dhcp_transaction = Some(transaction);
+            }
+            Ok(r)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::phy::{Device, RxToken, TxToken};
+
+    use super::*;
+    use crate::mock::MockDriver;
+
+    fn now() -> Instant {
+        Instant::from_millis(0)
+    }
+
+    /// Receives the next frame, returning its first byte and length.
+    fn receive(phy: &mut Enc28j60Phy<MockDriver>) -> Option<(u8, usize)> {
+        let (rx, _) = phy.receive()?;
+        rx.consume(now(), |frame| Ok((frame[0], frame.len()))).ok()
+    }
+
+    fn transmit(phy: &mut Enc28j60Phy<MockDriver>, frame: &[u8]) -> smoltcp::Result<()> {
+        let tx = phy.transmit().unwrap();
+        tx.consume(now(), frame.len(), |buf| {
+            buf.copy_from_slice(frame);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn frame_ring_keeps_order() {
+        let mut ring = FrameRing::new();
+        for (i, len) in [60, 64, 1518].iter().enumerate() {
+            ring.next_slot()[0] = i as u8;
+            ring.push(*len);
+        }
+        for (i, len) in [60, 64, 1518].iter().enumerate() {
+            let frame = ring.pop().unwrap();
+            assert_eq!((i as u8, *len), (frame[0], frame.len()));
+        }
+        assert!(ring.pop().is_none());
+    }
+
+    #[test]
+    fn frames_are_received_in_order() {
+        let mut driver = MockDriver::new();
+        // More than fit in the ring at once.
+        for i in 0..6 {
+            driver.inject(&[i; 60][..60 - i as usize]);
+        }
+        let mut phy = Enc28j60Phy::new(driver);
+        for i in 0..6 {
+            assert_eq!(Some((i, 60 - i as usize)), receive(&mut phy));
+        }
+        assert_eq!(None, receive(&mut phy));
+    }
+
+    #[test]
+    fn frames_are_transmitted() {
+        let mut phy = Enc28j60Phy::new(MockDriver::new());
+        transmit(&mut phy, &[1, 2, 3]).unwrap();
+        transmit(&mut phy, &[4, 5]).unwrap();
+        let transmitted = phy.driver().transmitted();
+        assert_eq!(&[1, 2, 3], &transmitted[0][..]);
+        assert_eq!(&[4, 5], &transmitted[1][..]);
+    }
+
+    #[test]
+    fn oversized_frames_are_not_transmitted() {
+        let mut phy = Enc28j60Phy::new(MockDriver::new());
+        let tx = phy.transmit().unwrap();
+        let res = tx.consume(now(), TX_BUF + 1, |_| Ok(()));
+        assert_eq!(Err(smoltcp::Error::Exhausted), res);
+        assert!(phy.driver().transmitted().is_empty());
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let mut driver = MockDriver::new();
+        driver.fail_next(SPI_RETRIES);
+        let mut phy = Enc28j60Phy::new(driver);
+        transmit(&mut phy, &[1]).unwrap();
+        assert_eq!(0, phy.consecutive_failures());
+    }
+
+    #[test]
+    fn persistent_failures_are_counted() {
+        let mut driver = MockDriver::new();
+        driver.fail_next(u32::MAX);
+        driver.inject(&[1; 60]);
+        let mut phy = Enc28j60Phy::new(driver);
+        assert!(transmit(&mut phy, &[1]).is_err());
+        assert_eq!(None, receive(&mut phy));
+        assert_eq!(2, phy.consecutive_failures());
+
+        phy.driver_mut().fail_next(0);
+        assert_eq!(Some((1, 60)), receive(&mut phy));
+        assert_eq!(0, phy.consecutive_failures());
+    }
+}
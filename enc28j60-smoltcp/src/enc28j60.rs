@@ -0,0 +1,202 @@
+#![allow(deprecated)] // Required because enc28j60 depends on v1.
+
+use core::fmt::Debug;
+
+use ::enc28j60::Enc28j60;
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v1::OutputPin,
+};
+
+use crate::{
+    driver::Driver,
+    errata::{Revision, Workarounds},
+    filter::FrameFilter,
+    logging::{Debug2Format, Display2Format},
+    registers::{
+        Bus, BusNcs, BusSpi, ECON1, ECON1_RXEN, ECON1_RXRST, ECON1_TXRST, EHT0, EIR, EIR_TXERIF,
+        EREVID, ERXFCON, PHSTAT2, PHSTAT2_LSTAT,
+    },
+};
+
+/// The ENC28J60 driven by an `Enc28j60Driver`, on the bus it shares with it.
+pub type Chip<SPI, NCS, INT, RESET> =
+    Enc28j60<BusSpi<'static, SPI, NCS>, BusNcs<'static, SPI, NCS>, INT, RESET>;
+
+/// Reads the silicon revision from EREVID. Call this before handing the bus
+/// to `Enc28j60::new`, to tell a missing chip apart from one that fails to
+/// initialise.
+pub fn read_revision<SPI, E, NCS>(bus: &Bus<SPI, NCS>) -> Result<Revision, E>
+where
+    SPI: Transfer<u8, Error = E>,
+    NCS: OutputPin,
+{
+    bus.registers(|regs| regs.read(EREVID))
+        .map(Revision::from_erevid)
+}
+
+/// An ENC28J60 along with the errata workarounds for its revision.
+pub struct Enc28j60Driver<SPI: 'static, NCS: 'static, INT, RESET> {
+    chip: Chip<SPI, NCS, INT, RESET>,
+    // For the registers `chip` has no methods for.
+    bus: &'static Bus<SPI, NCS>,
+    revision: Revision,
+    workarounds: Workarounds,
+}
+
+impl<SPI, E, NCS, INT, RESET> Enc28j60Driver<SPI, NCS, INT, RESET>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E> + 'static,
+    E: Debug,
+    NCS: OutputPin + 'static,
+    INT: ::enc28j60::IntPin,
+    RESET: ::enc28j60::ResetPin,
+{
+    /// Takes the chip, along with the bus it was created on.
+    pub fn new(
+        chip: Chip<SPI, NCS, INT, RESET>,
+        bus: &'static Bus<SPI, NCS>,
+        revision: Revision,
+    ) -> Self {
+        let workarounds = Workarounds::for_revision(revision);
+        info!("ENC28J60 silicon revision {}", Display2Format(&revision));
+        for name in workarounds.names() {
+            debug!("Applying errata workaround {}", name);
+        }
+        Self {
+            chip,
+            bus,
+            revision,
+            workarounds,
+        }
+    }
+
+    pub fn revision(&self) -> Revision {
+        self.revision
+    }
+
+    pub fn workarounds(&self) -> Workarounds {
+        self.workarounds
+    }
+
+    /// Resets the receive logic, which disables receiving, and enables it
+    /// again.
+    fn reset_rx(&mut self) -> Result<(), E> {
+        self.bus.registers(|regs| {
+            regs.set_bits(ECON1, ECON1_RXRST)?;
+            regs.clear_bits(ECON1, ECON1_RXRST)?;
+            regs.set_bits(ECON1, ECON1_RXEN)
+        })
+    }
+
+    /// Resets the transmit logic, and clears the error that called for it.
+    fn reset_tx(&mut self) -> Result<(), E> {
+        self.bus.registers(|regs| {
+            regs.set_bits(ECON1, ECON1_TXRST)?;
+            regs.clear_bits(ECON1, ECON1_TXRST)?;
+            regs.clear_bits(EIR, EIR_TXERIF)
+        })
+    }
+}
+
+impl<SPI, E, NCS, INT, RESET> Driver for Enc28j60Driver<SPI, NCS, INT, RESET>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E> + 'static,
+    E: Debug + 'static,
+    NCS: OutputPin + 'static,
+    INT: ::enc28j60::IntPin + 'static,
+    RESET: ::enc28j60::ResetPin + 'static,
+{
+    type Error = E;
+    type TransmitError = ::enc28j60::Error<E>;
+
+    #[inline]
+    fn pending_packets(&mut self) -> Result<u8, E> {
+        self.chip.pending_packets()
+    }
+
+    #[inline]
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<u16, E> {
+        trace!("Requesting next packet from device");
+        match self.chip.receive(buffer) {
+            Ok(recv) => {
+                let frame = &buffer[..recv as usize];
+                // The hex dump is where defmt saves the most, since it sends
+                // the bytes as they are.
+                #[cfg(not(feature = "defmt-log"))]
+                log::trace!(
+                    "Got next packet from device, {} bytes: \n{:02x?}",
+                    recv,
+                    frame
+                );
+                #[cfg(feature = "defmt-log")]
+                defmt::trace!(
+                    "Got next packet from device, {} bytes: {=[u8]:x}",
+                    recv,
+                    frame
+                );
+                Ok(recv)
+            }
+            Err(err) => {
+                warn!("Receive failed: {:?}", Debug2Format(&err));
+                if self.workarounds.reset_rx_after_error {
+                    if let Err(err) = self.reset_rx() {
+                        warn!("Receive logic reset failed: {:?}", Debug2Format(&err));
+                    }
+                }
+                Err(err)
+            }
+        }
+    }
+
+    #[inline]
+    fn transmit(&mut self, buffer: &[u8]) -> Result<(), ::enc28j60::Error<E>> {
+        trace!("Sending {} bytes to device", buffer.len());
+        match self.chip.transmit(buffer) {
+            Ok(()) => {
+                #[cfg(not(feature = "defmt-log"))]
+                log::trace!("Sent {} bytes: \n{:02x?}", buffer.len(), buffer);
+                #[cfg(feature = "defmt-log")]
+                defmt::trace!("Sent {} bytes: {=[u8]:x}", buffer.len(), buffer);
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Failed to send {} bytes to device", buffer.len());
+                // The retry that follows only gets through once the transmit
+                // logic is reset.
+                if self.workarounds.reset_tx_after_error {
+                    if let Err(err) = self.reset_tx() {
+                        warn!("Transmit logic reset failed: {:?}", Debug2Format(&err));
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    #[inline]
+    fn set_frame_filter(&mut self, filter: &FrameFilter) -> Result<(), E> {
+        debug!(
+            "Setting frame filter: ERXFCON {:08b}, hash table {:#018x}",
+            filter.erxfcon(),
+            u64::from_be_bytes(filter.hash_table())
+        );
+        self.bus.registers(|regs| {
+            for (i, &byte) in filter.hash_table().iter().enumerate() {
+                regs.write(EHT0.offset(i as u8), byte)?;
+            }
+            regs.write(ERXFCON, filter.erxfcon())
+        })
+    }
+
+    #[inline]
+    fn is_link_up(&mut self) -> Result<bool, E> {
+        match self.bus.registers(|regs| regs.read_phy(PHSTAT2))? {
+            Some(phstat2) => Ok(phstat2 & PHSTAT2_LSTAT != 0),
+            None => {
+                warn!("Timed out reading the link status");
+                Ok(false)
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Silicon revisions of the ENC28J60, and the errata workarounds the driver
+//! applies for each of them.
+
+use core::fmt::{self, Display};
+
+/// A silicon revision, as reported by the EREVID register.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Revision {
+    B1,
+    B4,
+    B5,
+    B7,
+    /// A revision we don't know about. Reads of 0x00 or 0xFF usually mean
+    /// nothing is answering on the SPI bus.
+    Unknown(u8),
+}
+
+impl Revision {
+    pub fn from_erevid(erevid: u8) -> Self {
+        match erevid {
+            0x02 => Revision::B1,
+            0x04 => Revision::B4,
+            0x05 => Revision::B5,
+            0x06 => Revision::B7,
+            other => Revision::Unknown(other),
+        }
+    }
+
+    /// Whether EREVID read back as all zeroes or all ones, which is what a
+    /// floating or shorted MISO line looks like.
+    pub fn is_absent(self) -> bool {
+        matches!(self, Revision::Unknown(0x00) | Revision::Unknown(0xFF))
+    }
+}
+
+impl Display for Revision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Revision::B1 => f.write_str("B1"),
+            Revision::B4 => f.write_str("B4"),
+            Revision::B5 => f.write_str("B5"),
+            Revision::B7 => f.write_str("B7"),
+            Revision::Unknown(erevid) => write!(f, "unknown ({:#04x})", erevid),
+        }
+    }
+}
+
+/// The errata workarounds enabled for a revision.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Workarounds {
+    /// Reset the receive logic after a failed receive. On B5 and B7 the
+    /// receive logic can get stuck after an error, so that no more frames
+    /// arrive until it is reset.
+    pub reset_rx_after_error: bool,
+    /// Reset the transmit logic after a failed transmission, such as a late
+    /// collision. Every revision can stall its transmit logic there.
+    pub reset_tx_after_error: bool,
+}
+
+impl Workarounds {
+    pub fn for_revision(revision: Revision) -> Self {
+        Self {
+            reset_rx_after_error: matches!(revision, Revision::B5 | Revision::B7),
+            reset_tx_after_error: true,
+        }
+    }
+
+    /// Short names of the enabled workarounds, for logs and diagnostics.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        IntoIterator::into_iter([
+            (self.reset_rx_after_error, "rx_reset"),
+            (self.reset_tx_after_error, "tx_reset"),
+        ])
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{format, vec::Vec};
+
+    use super::*;
+
+    #[test]
+    fn known_revisions_are_decoded() {
+        assert_eq!(Revision::from_erevid(0x02), Revision::B1);
+        assert_eq!(Revision::from_erevid(0x06), Revision::B7);
+        assert_eq!(Revision::from_erevid(0x03), Revision::Unknown(0x03));
+        assert_eq!(format!("{}", Revision::B5), "B5");
+        assert_eq!(format!("{}", Revision::Unknown(0x03)), "unknown (0x03)");
+    }
+
+    #[test]
+    fn missing_chip_is_detected() {
+        assert!(Revision::from_erevid(0x00).is_absent());
+        assert!(Revision::from_erevid(0xFF).is_absent());
+        assert!(!Revision::from_erevid(0x06).is_absent());
+        assert!(!Revision::from_erevid(0x03).is_absent());
+    }
+
+    #[test]
+    fn workarounds_depend_on_revision() {
+        let b4 = Workarounds::for_revision(Revision::B4);
+        assert_eq!(b4.names().collect::<Vec<_>>(), ["tx_reset"]);
+        let b7 = Workarounds::for_revision(Revision::B7);
+        assert_eq!(b7.names().collect::<Vec<_>>(), ["rx_reset", "tx_reset"]);
+        assert_eq!(Workarounds::default().names().count(), 0);
+    }
+}
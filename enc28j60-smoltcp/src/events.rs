@@ -0,0 +1,27 @@
+use smoltcp::{socket::SocketHandle, wire::Ipv4Address};
+
+/// Observer for state changes in the network stack.
+///
+/// All callbacks are invoked from `NetworkStack::poll`, and default to doing
+/// nothing, so implementors only need to override the events they care about.
+pub trait NetworkEvents {
+    fn link_up(&mut self) {}
+
+    fn link_down(&mut self) {}
+
+    fn address_acquired(&mut self, _addr: Ipv4Address) {}
+
+    fn address_lost(&mut self, _addr: Ipv4Address) {}
+
+    fn client_connected(&mut self, _handle: SocketHandle) {}
+
+    fn client_disconnected(&mut self, _handle: SocketHandle) {}
+
+    /// The driver failed this many times in a row, even after retrying. Called
+    /// on every poll in which the count goes up, so a controller that stopped
+    /// responding can be reset.
+    fn driver_failing(&mut self, _consecutive_failures: u32) {}
+}
+
+/// Ignores all events.
+impl NetworkEvents for () {}
@@ -0,0 +1,150 @@
+use smoltcp::wire::{EthernetAddress, Ipv4Address};
+
+// ERXFCON bits, see section 8.0 of the ENC28J60 datasheet.
+const UCEN: u8 = 1 << 7;
+const CRCEN: u8 = 1 << 5;
+const HTEN: u8 = 1 << 2;
+const MCEN: u8 = 1 << 1;
+const BCEN: u8 = 1 << 0;
+
+/// Which Ethernet frames the ENC28J60 should pass on to us.
+///
+/// Frames that don't match the filter are dropped by the chip itself, so they
+/// never cost us an SPI transfer or a trip through smoltcp.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameFilter {
+    /// Accept frames addressed to our own MAC address.
+    pub unicast: bool,
+    /// Accept frames addressed to FF:FF:FF:FF:FF:FF. Required for ARP and DHCP.
+    pub broadcast: bool,
+    /// Accept all multicast frames, regardless of the subscribed groups.
+    pub all_multicast: bool,
+    /// Drop frames with an invalid CRC.
+    pub check_crc: bool,
+    hash_table: [u8; 8],
+}
+
+impl Default for FrameFilter {
+    fn default() -> Self {
+        Self {
+            unicast: true,
+            broadcast: true,
+            all_multicast: false,
+            check_crc: true,
+            hash_table: [0; 8],
+        }
+    }
+}
+
+impl FrameFilter {
+    /// Accept frames sent to the given multicast address.
+    ///
+    /// The ENC28J60 only supports imperfect multicast filtering through a
+    /// 64-bit hash table, so other groups hashing to the same bit will be
+    /// let through as well. smoltcp discards those for us.
+    pub fn subscribe(&mut self, addr: EthernetAddress) {
+        let bit = hash_bit(addr);
+        self.hash_table[bit / 8] |= 1 << (bit % 8);
+    }
+
+    /// Stop accepting frames sent to the given multicast address.
+    ///
+    /// Because the hash table is shared, this may also unsubscribe other
+    /// groups mapping to the same bit. Resubscribe those afterwards.
+    pub fn unsubscribe(&mut self, addr: EthernetAddress) {
+        let bit = hash_bit(addr);
+        self.hash_table[bit / 8] &= !(1 << (bit % 8));
+    }
+
+    pub fn subscribe_ipv4(&mut self, addr: Ipv4Address) {
+        self.subscribe(multicast_mac(addr));
+    }
+
+    pub fn unsubscribe_ipv4(&mut self, addr: Ipv4Address) {
+        self.unsubscribe(multicast_mac(addr));
+    }
+
+    /// The value of the ERXFCON register for this filter.
+    pub fn erxfcon(&self) -> u8 {
+        let mut reg = 0;
+        if self.unicast {
+            reg |= UCEN;
+        }
+        if self.broadcast {
+            reg |= BCEN;
+        }
+        if self.all_multicast {
+            reg |= MCEN;
+        }
+        if self.hash_table != [0; 8] {
+            reg |= HTEN;
+        }
+        if self.check_crc {
+            reg |= CRCEN;
+        }
+        reg
+    }
+
+    /// The value of the EHT0..EHT7 registers for this filter.
+    pub fn hash_table(&self) -> [u8; 8] {
+        self.hash_table
+    }
+}
+
+/// Maps an IPv4 multicast group to its Ethernet address (RFC 1112, section 6.4).
+fn multicast_mac(addr: Ipv4Address) -> EthernetAddress {
+    let ip = addr.0;
+    EthernetAddress([0x01, 0x00, 0x5E, ip[1] & 0x7F, ip[2], ip[3]])
+}
+
+/// The ENC28J60 indexes its hash table using bits 28:23 of the
+/// Ethernet CRC of the destination address.
+fn hash_bit(addr: EthernetAddress) -> usize {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in addr.0.iter() {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            if (crc ^ byte as u32) & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB8_8320;
+            } else {
+                crc >>= 1;
+            }
+            byte >>= 1;
+        }
+    }
+    ((!crc >> 23) & 0x3F) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_filter_accepts_unicast_and_broadcast() {
+        assert_eq!(UCEN | CRCEN | BCEN, FrameFilter::default().erxfcon());
+    }
+
+    #[test]
+    fn subscribing_enables_hash_table() {
+        let mut filter = FrameFilter::default();
+        filter.subscribe_ipv4(Ipv4Address::new(224, 0, 0, 251));
+        assert_eq!(UCEN | CRCEN | HTEN | BCEN, filter.erxfcon());
+        let bits: u32 = filter.hash_table().iter().map(|b| b.count_ones()).sum();
+        assert_eq!(1, bits);
+
+        filter.unsubscribe_ipv4(Ipv4Address::new(224, 0, 0, 251));
+        assert_eq!(FrameFilter::default(), filter);
+    }
+
+    #[test]
+    fn multicast_mac_drops_high_bit() {
+        assert_eq!(
+            EthernetAddress([0x01, 0x00, 0x5E, 0x00, 0x00, 0xFB]),
+            multicast_mac(Ipv4Address::new(224, 0, 0, 251))
+        );
+        assert_eq!(
+            EthernetAddress([0x01, 0x00, 0x5E, 0x7F, 0x80, 0x01]),
+            multicast_mac(Ipv4Address::new(239, 255, 128, 1))
+        );
+    }
+}
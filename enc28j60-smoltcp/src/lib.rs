@@ -0,0 +1,48 @@
+//! Glue between smoltcp and a network controller, written for the ENC28J60
+//! but usable with anything that can send and receive whole Ethernet frames.
+//!
+//! A controller is wrapped by implementing `Driver` for it, after which
+//! `NetworkStack` takes care of the link, DHCP and the sockets. Protocols are
+//! implemented as a `TcpClient` or `UdpClient`, each of which gets a socket
+//! backed by its own `TcpClientStore` or `UdpClientStore`, so all memory is
+//! allocated up front.
+#![no_std]
+
+// Declared first, so its macros are available in every other module.
+#[macro_use]
+mod logging;
+#[macro_use]
+pub mod log_throttle;
+
+pub mod client;
+pub mod dhcp;
+pub mod driver;
+#[cfg(feature = "enc28j60")]
+mod enc28j60;
+pub mod errata;
+pub mod events;
+pub mod filter;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod neighbours;
+pub mod random;
+#[cfg(feature = "enc28j60")]
+pub mod registers;
+pub mod stack;
+
+#[cfg(feature = "enc28j60")]
+pub use crate::enc28j60::{read_revision, Chip, Enc28j60Driver};
+pub use stack::{BackingStore, NetStatus, NetworkStack};
+
+#[cfg(test)]
+extern crate std;
+
+/// The time, as seen by the stack. Must not go backwards.
+pub trait Clock {
+    /// Milliseconds since an arbitrary point, like boot.
+    fn millis(&self) -> i64;
+
+    fn instant(&self) -> smoltcp::time::Instant {
+        smoltcp::time::Instant::from_millis(self.millis())
+    }
+}
@@ -0,0 +1,112 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// Messages that can be logged in a row before throttling kicks in.
+const BURST: u32 = 3;
+// After that, one message is let through this often.
+const REFILL_SECS: u32 = 30;
+
+// The time of the last poll of the stack, in seconds, which is as precise as
+// the throttling needs to be.
+static NOW_SECS: AtomicU32 = AtomicU32::new(0);
+
+/// Token bucket for a single log statement, so an error that repeats on every
+/// poll doesn't drown out everything else on the console.
+///
+/// The updates aren't atomic as a whole, so an interrupt can make it let
+/// through a message too many or too few, which is fine for logging.
+pub struct Throttle {
+    tokens: AtomicU32,
+    refilled_at: AtomicU32,
+    suppressed: AtomicU32,
+}
+
+impl Throttle {
+    pub const fn new() -> Self {
+        Self {
+            tokens: AtomicU32::new(BURST),
+            refilled_at: AtomicU32::new(0),
+            suppressed: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns the number of messages suppressed since the last one that was
+    /// let through, or `None` if this one should be suppressed as well.
+    pub fn acquire(&self, now_ms: i64) -> Option<u32> {
+        let now = (now_ms / 1000) as u32;
+        let refilled_at = self.refilled_at.load(Ordering::Relaxed);
+        let refills = now.wrapping_sub(refilled_at) / REFILL_SECS;
+        let mut tokens = self.tokens.load(Ordering::Relaxed);
+        if refills > 0 {
+            tokens = tokens.saturating_add(refills).min(BURST);
+            self.refilled_at.store(
+                refilled_at.wrapping_add(refills * REFILL_SECS),
+                Ordering::Relaxed,
+            );
+        }
+        if tokens == 0 {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.tokens.store(tokens - 1, Ordering::Relaxed);
+        Some(self.suppressed.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// Records the time, for the throttling done by `warn_throttled!` within this
+/// crate. The stack calls this whenever it is polled.
+pub(crate) fn set_time(now_ms: i64) {
+    NOW_SECS.store((now_ms / 1000) as u32, Ordering::Relaxed);
+}
+
+/// The time of the last poll of the stack, to pass to `warn_throttled!`
+/// within this crate.
+pub(crate) fn now_ms() -> i64 {
+    NOW_SECS.load(Ordering::Relaxed) as i64 * 1000
+}
+
+/// Like `warn!`, but throttled per call site, given the time in milliseconds.
+/// Once messages have been suppressed, the next one that gets through says
+/// how many.
+///
+/// Logs through defmt if the calling crate has a `defmt-log` feature enabled,
+/// like this one, and through `log` otherwise.
+#[macro_export]
+macro_rules! warn_throttled {
+    ($now_ms:expr, $($arg:tt)+) => {{
+        static THROTTLE: $crate::log_throttle::Throttle = $crate::log_throttle::Throttle::new();
+        match THROTTLE.acquire($now_ms) {
+            #[cfg(not(feature = "defmt-log"))]
+            Some(0) => log::warn!($($arg)+),
+            #[cfg(not(feature = "defmt-log"))]
+            Some(suppressed) => {
+                log::warn!("{} (repeated {}×)", format_args!($($arg)+), suppressed)
+            }
+            #[cfg(feature = "defmt-log")]
+            Some(0) => defmt::warn!($($arg)+),
+            // defmt can't nest format strings, so this takes two messages.
+            #[cfg(feature = "defmt-log")]
+            Some(suppressed) => {
+                defmt::warn!($($arg)+);
+                defmt::warn!("(repeated {=u32}×)", suppressed);
+            }
+            None => {}
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_lets_burst_through_then_refills() {
+        let throttle = Throttle::new();
+        for _ in 0..BURST {
+            assert_eq!(Some(0), throttle.acquire(0));
+        }
+        assert_eq!(None, throttle.acquire(1000));
+        assert_eq!(None, throttle.acquire(2000));
+        assert_eq!(Some(2), throttle.acquire(REFILL_SECS as i64 * 1000));
+        assert_eq!(None, throttle.acquire(REFILL_SECS as i64 * 1000));
+    }
+}
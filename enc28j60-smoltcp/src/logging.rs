@@ -0,0 +1,72 @@
+//! Logging macros, which log through defmt with the `defmt-log` feature, and
+//! through `log` otherwise. The application using defmt provides the
+//! timestamp.
+//!
+//! Format strings must work for both: stick to `{}` and `{:?}` with integer
+//! hints like `{:#06x}`, and wrap arguments other than integers, booleans and
+//! strings in `Display2Format` or `Debug2Format`.
+
+#[cfg(feature = "defmt-log")]
+pub use defmt::{Debug2Format, Display2Format};
+
+#[cfg(not(feature = "defmt-log"))]
+mod wrappers {
+    use core::fmt;
+
+    /// Logs a value through its `Display` implementation.
+    pub struct Display2Format<'a, T: ?Sized>(pub &'a T);
+
+    impl<T: fmt::Display + ?Sized> fmt::Display for Display2Format<'_, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    /// Logs a value through its `Debug` implementation.
+    pub struct Debug2Format<'a, T: ?Sized>(pub &'a T);
+
+    impl<T: fmt::Debug + ?Sized> fmt::Debug for Debug2Format<'_, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+}
+
+#[cfg(not(feature = "defmt-log"))]
+pub use wrappers::{Debug2Format, Display2Format};
+
+macro_rules! trace {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "defmt-log")]
+        defmt::trace!($($arg)+);
+        #[cfg(not(feature = "defmt-log"))]
+        log::trace!($($arg)+);
+    }};
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "defmt-log")]
+        defmt::debug!($($arg)+);
+        #[cfg(not(feature = "defmt-log"))]
+        log::debug!($($arg)+);
+    }};
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "defmt-log")]
+        defmt::info!($($arg)+);
+        #[cfg(not(feature = "defmt-log"))]
+        log::info!($($arg)+);
+    }};
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "defmt-log")]
+        defmt::warn!($($arg)+);
+        #[cfg(not(feature = "defmt-log"))]
+        log::warn!($($arg)+);
+    }};
+}
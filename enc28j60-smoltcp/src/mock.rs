@@ -0,0 +1,142 @@
+//! A `Driver` without hardware, for testing code that uses the stack on the
+//! host. Enable the `mock` feature to use it outside this crate.
+//!
+//! Frames queued with `inject()` are received in order, and frames
+//! transmitted are kept to be inspected. A `Responder` can answer transmitted
+//! frames, standing in for the rest of the network: a DHCP server, a router
+//! answering ARP requests, or a broker accepting a connection.
+
+use arrayvec::ArrayVec;
+
+use crate::{
+    driver::{Driver, MAX_FRAME_LENGTH},
+    filter::FrameFilter,
+};
+
+/// Frames kept in either direction.
+pub const MAX_FRAMES: usize = 8;
+
+pub type Frame = ArrayVec<u8, MAX_FRAME_LENGTH>;
+
+/// Answers a transmitted frame with a frame to receive, if it wants to.
+pub type Responder = fn(sent: &[u8]) -> Option<Frame>;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MockError;
+
+pub struct MockDriver {
+    pending: ArrayVec<Frame, MAX_FRAMES>,
+    transmitted: ArrayVec<Frame, MAX_FRAMES>,
+    responder: Option<Responder>,
+    filter: Option<FrameFilter>,
+    link_up: bool,
+    failures: u32,
+}
+
+impl MockDriver {
+    /// A driver with the link up, and nothing to receive.
+    pub fn new() -> Self {
+        Self {
+            pending: ArrayVec::new(),
+            transmitted: ArrayVec::new(),
+            responder: None,
+            filter: None,
+            link_up: true,
+            failures: 0,
+        }
+    }
+
+    /// Queues a frame to be received.
+    ///
+    /// Panics if `MAX_FRAMES` are waiting to be received already.
+    pub fn inject(&mut self, frame: &[u8]) {
+        let mut pending = Frame::new();
+        pending.try_extend_from_slice(frame).unwrap();
+        self.pending.push(pending);
+    }
+
+    /// Answers every frame transmitted from now on with `responder`.
+    pub fn respond_with(&mut self, responder: Responder) {
+        self.responder = Some(responder);
+    }
+
+    /// The last `MAX_FRAMES` frames transmitted, oldest first.
+    pub fn transmitted(&self) -> &[Frame] {
+        &self.transmitted
+    }
+
+    /// Takes the oldest frame transmitted.
+    pub fn take_transmitted(&mut self) -> Option<Frame> {
+        self.transmitted.pop_at(0)
+    }
+
+    /// The frame filter last set by the stack.
+    pub fn filter(&self) -> Option<FrameFilter> {
+        self.filter
+    }
+
+    pub fn set_link_up(&mut self, up: bool) {
+        self.link_up = up;
+    }
+
+    /// Makes the next `count` operations fail, including their retries.
+    pub fn fail_next(&mut self, count: u32) {
+        self.failures = count;
+    }
+
+    fn fail(&mut self) -> Result<(), MockError> {
+        if self.failures > 0 {
+            self.failures -= 1;
+            return Err(MockError);
+        }
+        Ok(())
+    }
+}
+
+impl Default for MockDriver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Driver for MockDriver {
+    type Error = MockError;
+    type TransmitError = MockError;
+
+    fn pending_packets(&mut self) -> Result<u8, MockError> {
+        self.fail()?;
+        Ok(self.pending.len() as u8)
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<u16, MockError> {
+        self.fail()?;
+        let frame = self.pending.pop_at(0).ok_or(MockError)?;
+        buffer[..frame.len()].copy_from_slice(&frame);
+        Ok(frame.len() as u16)
+    }
+
+    fn transmit(&mut self, buffer: &[u8]) -> Result<(), MockError> {
+        self.fail()?;
+        let mut frame = Frame::new();
+        frame.try_extend_from_slice(buffer).map_err(|_| MockError)?;
+        if self.transmitted.is_full() {
+            self.transmitted.remove(0);
+        }
+        self.transmitted.push(frame);
+        if let Some(reply) = self.responder.and_then(|respond| respond(buffer)) {
+            self.inject(&reply);
+        }
+        Ok(())
+    }
+
+    fn set_frame_filter(&mut self, filter: &FrameFilter) -> Result<(), MockError> {
+        self.fail()?;
+        self.filter = Some(*filter);
+        Ok(())
+    }
+
+    fn is_link_up(&mut self) -> Result<bool, MockError> {
+        self.fail()?;
+        Ok(self.link_up)
+    }
+}
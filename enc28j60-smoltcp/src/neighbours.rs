@@ -0,0 +1,135 @@
+use smoltcp::wire::{
+    ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol, Ipv4Address,
+};
+
+// smoltcp doesn't let us look into its neighbour cache, which is filled from
+// ARP traffic. To see which hosts are around, that traffic is picked out of
+// the received frames as well, like the DHCP options are.
+
+pub const MAX_NEIGHBOURS: usize = 8;
+
+/// A host that announced its hardware address through ARP.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Neighbour {
+    pub addr: Ipv4Address,
+    pub hardware_addr: EthernetAddress,
+}
+
+/// The hosts heard from most recently, the most recent one first.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Neighbours([Option<Neighbour>; MAX_NEIGHBOURS]);
+
+impl Neighbours {
+    /// Moves the neighbour to the front, replacing the one heard from the
+    /// longest ago if there is no room for it.
+    pub fn heard_from(&mut self, neighbour: Neighbour) {
+        let pos = self
+            .0
+            .iter()
+            .position(|known| known.map_or(true, |known| known.addr == neighbour.addr))
+            .unwrap_or(MAX_NEIGHBOURS - 1);
+        self.0[..=pos].rotate_right(1);
+        self.0[0] = Some(neighbour);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Neighbour> {
+        self.0.iter().flatten()
+    }
+}
+
+/// Returns the sender of the ARP packet in the frame, if it holds one.
+pub fn sender(frame: &[u8]) -> Option<Neighbour> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    if frame.ethertype() != EthernetProtocol::Arp {
+        return None;
+    }
+    let packet = ArpPacket::new_checked(frame.payload()).ok()?;
+    match ArpRepr::parse(&packet).ok()? {
+        // Probes for address conflicts are sent from the unspecified address.
+        ArpRepr::EthernetIpv4 {
+            source_hardware_addr,
+            source_protocol_addr,
+            ..
+        } if !source_protocol_addr.is_unspecified() => Some(Neighbour {
+            addr: source_protocol_addr,
+            hardware_addr: source_hardware_addr,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrayvec::ArrayVec;
+
+    use super::*;
+
+    type Frame = ArrayVec<u8, 64>;
+
+    /// An ARP request from `sender`, broadcast.
+    fn request(sender: [u8; 4]) -> Frame {
+        let mut frame = Frame::new();
+        let mut push = |bytes: &[u8]| frame.try_extend_from_slice(bytes).unwrap();
+        // Ethernet
+        push(&[0xFF; 6]);
+        push(&[0x02, 0, 0, 0, 0, sender[3]]);
+        push(&[0x08, 0x06]);
+        // ARP: Ethernet and IPv4, a request.
+        push(&[0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+        push(&[0x02, 0, 0, 0, 0, sender[3]]);
+        push(&sender);
+        push(&[0; 6]);
+        push(&[10, 0, 0, 2]);
+        frame
+    }
+
+    fn neighbour(last_octet: u8) -> Neighbour {
+        Neighbour {
+            addr: Ipv4Address::new(10, 0, 0, last_octet),
+            hardware_addr: EthernetAddress([0x02, 0, 0, 0, 0, last_octet]),
+        }
+    }
+
+    #[test]
+    fn arp_sender_is_read() {
+        assert_eq!(Some(neighbour(1)), sender(&request([10, 0, 0, 1])));
+    }
+
+    #[test]
+    fn probes_are_ignored() {
+        assert_eq!(None, sender(&request([0, 0, 0, 0])));
+    }
+
+    #[test]
+    fn other_frames_are_ignored() {
+        let mut frame = request([10, 0, 0, 1]);
+        // IPv4 instead of ARP.
+        frame[13] = 0x00;
+        assert_eq!(None, sender(&frame));
+        assert_eq!(None, sender(&request([10, 0, 0, 1])[..20]));
+    }
+
+    #[test]
+    fn most_recent_neighbour_comes_first() {
+        let mut neighbours = Neighbours::default();
+        neighbours.heard_from(neighbour(1));
+        neighbours.heard_from(neighbour(2));
+        neighbours.heard_from(neighbour(1));
+        let order: ArrayVec<_, MAX_NEIGHBOURS> = neighbours.iter().map(|n| n.addr.0[3]).collect();
+        assert_eq!(&[1, 2], &order[..]);
+    }
+
+    #[test]
+    fn oldest_neighbour_is_replaced_when_full() {
+        let mut neighbours = Neighbours::default();
+        for i in 0..=MAX_NEIGHBOURS as u8 {
+            neighbours.heard_from(neighbour(i));
+        }
+        assert_eq!(MAX_NEIGHBOURS, neighbours.iter().count());
+        assert_eq!(
+            Some(&neighbour(MAX_NEIGHBOURS as u8)),
+            neighbours.iter().next()
+        );
+        assert!(neighbours.iter().all(|n| *n != neighbour(0)));
+    }
+}
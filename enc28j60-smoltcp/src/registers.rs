@@ -0,0 +1,371 @@
+#![allow(deprecated)] // Required because enc28j60 depends on v1.
+
+//! Direct access to the ENC28J60's control registers, for what the `enc28j60`
+//! crate has no methods for.
+//!
+//! That driver owns the SPI bus and chip select it's given, so to get at the
+//! registers as well, both go into a `Bus`, and the driver gets handles to it.
+
+use core::cell::UnsafeCell;
+
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v1::OutputPin,
+};
+
+/// A control register, and the bank it's in.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Register {
+    bank: Bank,
+    addr: u8,
+    // MAC and MII registers shift out a dummy byte before their value.
+    dummy_byte: bool,
+}
+
+impl Register {
+    /// The register `offset` places further on, like `EHT1` for `EHT0`.
+    pub fn offset(self, offset: u8) -> Self {
+        Self {
+            addr: self.addr + offset,
+            ..self
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Bank {
+    /// The registers from 0x1B up are in every bank.
+    Common,
+    Banked(u8),
+}
+
+const fn eth(bank: u8, addr: u8) -> Register {
+    Register {
+        bank: Bank::Banked(bank),
+        addr,
+        dummy_byte: false,
+    }
+}
+
+const fn mii(bank: u8, addr: u8) -> Register {
+    Register {
+        bank: Bank::Banked(bank),
+        addr,
+        dummy_byte: true,
+    }
+}
+
+const fn common(addr: u8) -> Register {
+    Register {
+        bank: Bank::Common,
+        addr,
+        dummy_byte: false,
+    }
+}
+
+pub const EIR: Register = common(0x1C);
+pub const ECON1: Register = common(0x1F);
+pub const EHT0: Register = eth(1, 0x00);
+pub const ERXFCON: Register = eth(1, 0x18);
+pub const MICMD: Register = mii(2, 0x12);
+pub const MIREGADR: Register = mii(2, 0x14);
+pub const MIRDL: Register = mii(2, 0x18);
+pub const MIRDH: Register = mii(2, 0x19);
+pub const MISTAT: Register = mii(3, 0x0A);
+pub const EREVID: Register = eth(3, 0x12);
+
+/// PHY registers, read through the MII registers.
+pub const PHSTAT2: u8 = 0x11;
+
+pub const ECON1_BSEL: u8 = 0b11;
+pub const ECON1_RXEN: u8 = 1 << 2;
+pub const ECON1_RXRST: u8 = 1 << 6;
+pub const ECON1_TXRST: u8 = 1 << 7;
+pub const EIR_TXERIF: u8 = 1 << 1;
+pub const MICMD_MIIRD: u8 = 1 << 0;
+pub const MISTAT_BUSY: u8 = 1 << 0;
+pub const PHSTAT2_LSTAT: u16 = 1 << 10;
+
+// A PHY read takes 10.24 µs, which is a few register reads at most.
+const MAX_MII_POLLS: usize = 100;
+
+const OP_READ_CONTROL: u8 = 0x00;
+const OP_WRITE_CONTROL: u8 = 0x40;
+const OP_BIT_FIELD_SET: u8 = 0x80;
+const OP_BIT_FIELD_CLEAR: u8 = 0xA0;
+
+/// The SPI bus and chip select of an ENC28J60, shared between the `enc28j60`
+/// driver, which gets `spi()` and `ncs()`, and our own register access.
+pub struct Bus<SPI, NCS> {
+    spi: UnsafeCell<SPI>,
+    ncs: UnsafeCell<NCS>,
+}
+
+// Everything holding a reference to the bus belongs to a single
+// `Enc28j60Driver`, which uses one of them at a time, from its `&mut self`
+// methods. None of them hold on to the SPI bus or pin across calls.
+unsafe impl<SPI: Send, NCS: Send> Sync for Bus<SPI, NCS> {}
+
+impl<SPI, NCS> Bus<SPI, NCS> {
+    pub fn new(spi: SPI, ncs: NCS) -> Self {
+        Self {
+            spi: UnsafeCell::new(spi),
+            ncs: UnsafeCell::new(ncs),
+        }
+    }
+
+    /// The SPI bus, for `Enc28j60::new`.
+    pub fn spi(&self) -> BusSpi<'_, SPI, NCS> {
+        BusSpi(self)
+    }
+
+    /// The chip select, for `Enc28j60::new`.
+    pub fn ncs(&self) -> BusNcs<'_, SPI, NCS> {
+        BusNcs(self)
+    }
+
+    /// Gives `f` the registers. The bank selected before is selected again
+    /// afterwards, since the `enc28j60` driver keeps track of it.
+    pub(crate) fn registers<T, E>(
+        &self,
+        f: impl FnOnce(&mut Registers<SPI, NCS>) -> Result<T, E>,
+    ) -> Result<T, E>
+    where
+        SPI: Transfer<u8, Error = E>,
+        NCS: OutputPin,
+    {
+        // Safety: see the `Sync` implementation.
+        let mut registers = Registers {
+            spi: unsafe { &mut *self.spi.get() },
+            ncs: unsafe { &mut *self.ncs.get() },
+            bank: None,
+        };
+        let bank = registers.read(ECON1)? & ECON1_BSEL;
+        let result = f(&mut registers);
+        let restored = registers.select_bank(bank);
+        result.and_then(|value| restored.map(|()| value))
+    }
+}
+
+pub struct BusSpi<'a, SPI, NCS>(&'a Bus<SPI, NCS>);
+
+impl<SPI: Transfer<u8>, NCS> Transfer<u8> for BusSpi<'_, SPI, NCS> {
+    type Error = SPI::Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        // Safety: see the `Sync` implementation of `Bus`.
+        unsafe { &mut *self.0.spi.get() }.transfer(words)
+    }
+}
+
+impl<SPI: Write<u8>, NCS> Write<u8> for BusSpi<'_, SPI, NCS> {
+    type Error = SPI::Error;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        // Safety: see the `Sync` implementation of `Bus`.
+        unsafe { &mut *self.0.spi.get() }.write(words)
+    }
+}
+
+pub struct BusNcs<'a, SPI, NCS>(&'a Bus<SPI, NCS>);
+
+impl<SPI, NCS: OutputPin> OutputPin for BusNcs<'_, SPI, NCS> {
+    fn set_low(&mut self) {
+        // Safety: see the `Sync` implementation of `Bus`.
+        unsafe { &mut *self.0.ncs.get() }.set_low()
+    }
+
+    fn set_high(&mut self) {
+        // Safety: see the `Sync` implementation of `Bus`.
+        unsafe { &mut *self.0.ncs.get() }.set_high()
+    }
+}
+
+pub struct Registers<'a, SPI, NCS> {
+    spi: &'a mut SPI,
+    ncs: &'a mut NCS,
+    // The bank we selected last, if any.
+    bank: Option<u8>,
+}
+
+impl<SPI, E, NCS> Registers<'_, SPI, NCS>
+where
+    SPI: Transfer<u8, Error = E>,
+    NCS: OutputPin,
+{
+    pub fn read(&mut self, reg: Register) -> Result<u8, E> {
+        self.select(reg)?;
+        if reg.dummy_byte {
+            self.command(&mut [OP_READ_CONTROL | reg.addr, 0, 0])
+        } else {
+            self.command(&mut [OP_READ_CONTROL | reg.addr, 0])
+        }
+    }
+
+    /// Reads a PHY register, or returns `None` if the MII stays busy.
+    pub fn read_phy(&mut self, addr: u8) -> Result<Option<u16>, E> {
+        self.write(MIREGADR, addr)?;
+        self.write(MICMD, MICMD_MIIRD)?;
+        let mut done = false;
+        for _ in 0..MAX_MII_POLLS {
+            if self.read(MISTAT)? & MISTAT_BUSY == 0 {
+                done = true;
+                break;
+            }
+        }
+        self.write(MICMD, 0)?;
+        if !done {
+            return Ok(None);
+        }
+        let low = self.read(MIRDL)?;
+        let high = self.read(MIRDH)?;
+        Ok(Some(u16::from_le_bytes([low, high])))
+    }
+
+    pub fn write(&mut self, reg: Register, value: u8) -> Result<(), E> {
+        self.select(reg)?;
+        self.command(&mut [OP_WRITE_CONTROL | reg.addr, value])
+            .map(drop)
+    }
+
+    /// Only works on ETH registers, like `ECON1` and `EIR`.
+    pub fn set_bits(&mut self, reg: Register, mask: u8) -> Result<(), E> {
+        self.select(reg)?;
+        self.command(&mut [OP_BIT_FIELD_SET | reg.addr, mask])
+            .map(drop)
+    }
+
+    /// Only works on ETH registers, like `ECON1` and `EIR`.
+    pub fn clear_bits(&mut self, reg: Register, mask: u8) -> Result<(), E> {
+        self.select(reg)?;
+        self.command(&mut [OP_BIT_FIELD_CLEAR | reg.addr, mask])
+            .map(drop)
+    }
+
+    fn select(&mut self, reg: Register) -> Result<(), E> {
+        match reg.bank {
+            Bank::Banked(bank) if self.bank != Some(bank) => self.select_bank(bank),
+            _ => Ok(()),
+        }
+    }
+
+    fn select_bank(&mut self, bank: u8) -> Result<(), E> {
+        self.command(&mut [OP_BIT_FIELD_CLEAR | ECON1.addr, ECON1_BSEL])?;
+        if bank != 0 {
+            self.command(&mut [OP_BIT_FIELD_SET | ECON1.addr, bank])?;
+        }
+        self.bank = Some(bank);
+        Ok(())
+    }
+
+    /// Sends a command, and returns the last byte shifted in.
+    fn command(&mut self, bytes: &mut [u8]) -> Result<u8, E> {
+        self.ncs.set_low();
+        let result = self.spi.transfer(bytes).map(|read| read[read.len() - 1]);
+        self.ncs.set_high();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, vec, vec::Vec};
+
+    use super::*;
+
+    /// Records the commands sent while selected. Keeps track of ECON1, and
+    /// reads 0x42 from every other register.
+    #[derive(Default)]
+    struct Chip {
+        selected: bool,
+        commands: Vec<Vec<u8>>,
+        econ1: u8,
+    }
+
+    struct Spi<'a>(&'a RefCell<Chip>);
+    struct Ncs<'a>(&'a RefCell<Chip>);
+
+    impl Transfer<u8> for Spi<'_> {
+        type Error = ();
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], ()> {
+            let mut chip = self.0.borrow_mut();
+            assert!(chip.selected);
+            chip.commands.push(words.to_vec());
+            let (op, addr) = (words[0] & 0xE0, words[0] & 0x1F);
+            if addr == ECON1.addr {
+                match op {
+                    OP_BIT_FIELD_SET => chip.econ1 |= words[1],
+                    OP_BIT_FIELD_CLEAR => chip.econ1 &= !words[1],
+                    _ => {}
+                }
+            }
+            let last = words.len() - 1;
+            words[last] = if addr == ECON1.addr { chip.econ1 } else { 0x42 };
+            Ok(words)
+        }
+    }
+
+    impl OutputPin for Ncs<'_> {
+        fn set_low(&mut self) {
+            self.0.borrow_mut().selected = true;
+        }
+        fn set_high(&mut self) {
+            self.0.borrow_mut().selected = false;
+        }
+    }
+
+    fn run<T>(econ1: u8, f: impl FnOnce(&mut Registers<Spi, Ncs>) -> Result<T, ()>) -> (T, Chip) {
+        let chip = RefCell::new(Chip {
+            econ1,
+            ..Chip::default()
+        });
+        let bus = Bus::new(Spi(&chip), Ncs(&chip));
+        let result = bus.registers(f).unwrap();
+        (result, chip.into_inner())
+    }
+
+    #[test]
+    fn bank_is_selected_once() {
+        let (_, chip) = run(0, |regs| {
+            regs.write(EHT0, 0xAA)?;
+            regs.write(ERXFCON, 0xBB)
+        });
+        assert_eq!(
+            vec![
+                vec![0x1F, 0],
+                vec![0xBF, 0b11],
+                vec![0x9F, 1],
+                vec![0x40, 0xAA],
+                vec![0x58, 0xBB],
+                vec![0xBF, 0b11],
+            ],
+            chip.commands
+        );
+        assert_eq!(0, chip.econ1 & ECON1_BSEL);
+    }
+
+    #[test]
+    fn previous_bank_is_selected_again() {
+        let (erevid, chip) = run(2, |regs| regs.read(EREVID));
+        assert_eq!(0x42, erevid);
+        assert_eq!(2, chip.econ1 & ECON1_BSEL);
+    }
+
+    #[test]
+    fn phy_registers_are_read_through_mii() {
+        let (phstat2, chip) = run(0, |regs| regs.read_phy(PHSTAT2));
+        assert_eq!(Some(0x4242), phstat2);
+        assert!(chip.commands.contains(&vec![0x54, PHSTAT2]));
+        // MIRDL and MIRDH, each with a dummy byte.
+        assert!(chip.commands.contains(&vec![0x18, 0, 0]));
+        assert!(chip.commands.contains(&vec![0x19, 0, 0]));
+    }
+
+    #[test]
+    fn common_registers_need_no_bank() {
+        let (_, chip) = run(1, |regs| regs.set_bits(ECON1, 0b100));
+        assert_eq!(vec![0x9F, 0b100], chip.commands[1]);
+        assert_eq!(0b101, chip.econ1);
+    }
+}
@@ -0,0 +1,940 @@
+use core::fmt::{self, Display};
+
+use arrayvec::ArrayVec;
+use smoltcp::{
+    dhcp::{Dhcpv4Client, Dhcpv4Config},
+    iface::{EthernetInterface, EthernetInterfaceBuilder, Neighbor, NeighborCache, Route, Routes},
+    socket::{
+        RawPacketMetadata, RawSocketBuffer, SocketHandle, SocketSet, SocketSetItem, TcpSocket,
+        TcpSocketBuffer, UdpSocket, UdpSocketBuffer,
+    },
+    wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv4Cidr},
+};
+
+use crate::{
+    client::{TcpAction, TcpClient, TcpClientStore, UdpClient, UdpClientStore},
+    driver::{Driver, Enc28j60Phy},
+    events::NetworkEvents,
+    filter::FrameFilter,
+    log_throttle,
+    logging::{Debug2Format, Display2Format},
+    neighbours::Neighbours,
+    random::Random,
+    Clock,
+};
+
+const EPHEMERAL_PORT_START: u16 = 49152;
+const EPHEMERAL_PORT_COUNT: u16 = 16383;
+
+// Reading the link status costs an SPI transaction, so don't do it every poll.
+const LINK_CHECK_INTERVAL_MS: i64 = 500;
+
+const MAX_CLIENTS: usize = 6;
+
+const DHCP_RX_MET_SZ: usize = 4;
+const DHCP_TX_MET_SZ: usize = 4;
+
+pub const DEFAULT_DHCP_BUF_SZ: usize = 1024;
+pub const DEFAULT_NEIGH_CACHE_SZ: usize = 64;
+/// Room for the DHCP socket and ten clients.
+pub const DEFAULT_SOCKET_STORE_SZ: usize = 11;
+
+/// What the stack is able to do at the moment.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum NetStatus {
+    /// There is no Ethernet link.
+    NoLink,
+    /// The link is up, but there is no address yet.
+    Discovering,
+    AddressAcquired {
+        addr: Ipv4Address,
+    },
+    /// An address is configured, but the link is down, so connections stall
+    /// until it comes back.
+    Degraded {
+        addr: Ipv4Address,
+    },
+}
+
+impl NetStatus {
+    /// The configured address, if any.
+    pub fn address(&self) -> Option<Ipv4Address> {
+        match self {
+            NetStatus::NoLink | NetStatus::Discovering => None,
+            NetStatus::AddressAcquired { addr } | NetStatus::Degraded { addr } => Some(*addr),
+        }
+    }
+}
+
+impl Default for NetStatus {
+    fn default() -> Self {
+        NetStatus::NoLink
+    }
+}
+
+impl Display for NetStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetStatus::NoLink => write!(f, "no_link"),
+            NetStatus::Discovering => write!(f, "discovering"),
+            NetStatus::AddressAcquired { .. } => write!(f, "address_acquired"),
+            NetStatus::Degraded { .. } => write!(f, "degraded"),
+        }
+    }
+}
+
+/// Backing memory for the interface and socket set.
+///
+/// `SOCKET_STORE_SZ` must include the DHCP socket, so it should be one more
+/// than the number of TCP and UDP clients added to the stack.
+pub struct BackingStore<
+    'store,
+    const DHCP_BUF_SZ: usize = DEFAULT_DHCP_BUF_SZ,
+    const NEIGH_CACHE_SZ: usize = DEFAULT_NEIGH_CACHE_SZ,
+    const SOCKET_STORE_SZ: usize = DEFAULT_SOCKET_STORE_SZ,
+> {
+    dhcp_rx_buffer: [u8; DHCP_BUF_SZ],
+    dhcp_tx_buffer: [u8; DHCP_BUF_SZ],
+    dhcp_rx_metadata: [RawPacketMetadata; DHCP_RX_MET_SZ],
+    dhcp_tx_metadata: [RawPacketMetadata; DHCP_TX_MET_SZ],
+    neigh_cache: [Option<(IpAddress, Neighbor)>; NEIGH_CACHE_SZ],
+    address_store: [IpCidr; 1],
+    route_store: [Option<(IpCidr, Route)>; 1],
+    socket_store: [Option<SocketSetItem<'store>>; SOCKET_STORE_SZ],
+}
+
+impl<
+        'store,
+        const DHCP_BUF_SZ: usize,
+        const NEIGH_CACHE_SZ: usize,
+        const SOCKET_STORE_SZ: usize,
+    > BackingStore<'store, DHCP_BUF_SZ, NEIGH_CACHE_SZ, SOCKET_STORE_SZ>
+{
+    pub fn new() -> Self {
+        const NO_SOCKET: Option<SocketSetItem> = None;
+        BackingStore {
+            dhcp_rx_buffer: [0; DHCP_BUF_SZ],
+            dhcp_tx_buffer: [0; DHCP_BUF_SZ],
+            dhcp_rx_metadata: [RawPacketMetadata::EMPTY; DHCP_RX_MET_SZ],
+            dhcp_tx_metadata: [RawPacketMetadata::EMPTY; DHCP_TX_MET_SZ],
+            neigh_cache: [None; NEIGH_CACHE_SZ],
+            address_store: [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)],
+            route_store: [None; 1],
+            socket_store: [NO_SOCKET; SOCKET_STORE_SZ],
+        }
+    }
+}
+
+pub struct NetworkStack<'store, D: Driver> {
+    interface: EthernetInterface<'store, Enc28j60Phy<D>>,
+    dhcp_client: Dhcpv4Client,
+    sockets: SocketSet<'store>,
+    filter: FrameFilter,
+    // Set if the address is configured statically, in which case DHCP is not used.
+    static_address: bool,
+    link_up: bool,
+    next_link_check: i64,
+    address: Option<Ipv4Address>,
+    // The address and gateway last applied to the interface.
+    cidr: Option<Ipv4Cidr>,
+    gateway: Option<Ipv4Address>,
+    clients: ArrayVec<ClientState, MAX_CLIENTS>,
+    // Driver failures in a row, as of the last poll.
+    driver_failures: u32,
+}
+
+struct ClientState {
+    handle: SocketHandle,
+    connected: bool,
+}
+
+impl<'store, D: Driver> NetworkStack<'store, D> {
+    pub fn new<
+        const DHCP_BUF_SZ: usize,
+        const NEIGH_CACHE_SZ: usize,
+        const SOCKET_STORE_SZ: usize,
+    >(
+        driver: D,
+        clock: &mut impl Clock,
+        store: &'store mut BackingStore<'store, DHCP_BUF_SZ, NEIGH_CACHE_SZ, SOCKET_STORE_SZ>,
+        addr: [u8; 6],
+        filter: FrameFilter,
+    ) -> NetworkStack<'store, D> {
+        info!("Starting network setup");
+        let mut device = Enc28j60Phy::new(driver);
+        if let Err(err) = device.driver_mut().set_frame_filter(&filter) {
+            warn!("Failed to set frame filter: {:?}", Debug2Format(&err));
+        }
+        let eth_addr = EthernetAddress(addr);
+        let neigh_cache = NeighborCache::new(&mut store.neigh_cache[..]);
+        let routes = Routes::new(&mut store.route_store[..]);
+
+        let interface = EthernetInterfaceBuilder::new(device)
+            .ethernet_addr(eth_addr)
+            .neighbor_cache(neigh_cache)
+            .ip_addrs(&mut store.address_store[..])
+            .routes(routes)
+            .finalize();
+
+        let dhcp_rx_buffer = RawSocketBuffer::new(
+            &mut store.dhcp_tx_metadata[..],
+            &mut store.dhcp_rx_buffer[..],
+        );
+        let dhcp_tx_buffer = RawSocketBuffer::new(
+            &mut store.dhcp_rx_metadata[..],
+            &mut store.dhcp_tx_buffer[..],
+        );
+        let mut sockets = SocketSet::new(&mut store.socket_store[..]);
+
+        let dhcp_client = Dhcpv4Client::new(
+            &mut sockets,
+            dhcp_rx_buffer,
+            dhcp_tx_buffer,
+            clock.instant(),
+        );
+
+        Self {
+            interface,
+            dhcp_client,
+            sockets,
+            filter,
+            static_address: false,
+            link_up: false,
+            next_link_check: 0,
+            address: None,
+            cidr: None,
+            gateway: None,
+            clients: ArrayVec::new(),
+            driver_failures: 0,
+        }
+    }
+
+    /// Use a fixed address instead of requesting one through DHCP.
+    pub fn set_static_address(&mut self, cidr: Ipv4Cidr, gateway: Option<Ipv4Address>) {
+        info!("Using static address {}", Display2Format(&cidr));
+        self.static_address = true;
+        self.apply_address(cidr, gateway);
+    }
+
+    /// The state of the link and address, as of the last `poll()`.
+    pub fn status(&self) -> NetStatus {
+        match (self.link_up, self.address) {
+            (false, None) => NetStatus::NoLink,
+            (true, None) => NetStatus::Discovering,
+            (true, Some(addr)) => NetStatus::AddressAcquired { addr },
+            (false, Some(addr)) => NetStatus::Degraded { addr },
+        }
+    }
+
+    /// NTP servers advertised through DHCP, if any.
+    pub fn ntp_servers(&self) -> &[Ipv4Address] {
+        self.interface.device().ntp_servers()
+    }
+
+    /// The TFTP server advertised through DHCP, if any.
+    pub fn tftp_server(&self) -> Option<Ipv4Address> {
+        self.interface.device().tftp_server()
+    }
+
+    /// The address and prefix length of the interface, once configured.
+    pub fn cidr(&self) -> Option<Ipv4Cidr> {
+        self.interface
+            .ip_addrs()
+            .iter()
+            .find_map(|cidr| match cidr {
+                IpCidr::Ipv4(cidr) if !cidr.address().is_unspecified() => Some(*cidr),
+                _ => None,
+            })
+    }
+
+    /// The router the default route goes through, if there is one.
+    pub fn gateway(&self) -> Option<Ipv4Address> {
+        self.gateway
+    }
+
+    /// Hosts on the local network that sent ARP traffic, the most recent one
+    /// first.
+    pub fn neighbours(&self) -> &Neighbours {
+        self.interface.device().neighbours()
+    }
+
+    /// The driver, e.g. to look at the frames a `MockDriver` was handed.
+    pub fn driver_mut(&mut self) -> &mut D {
+        self.interface.device_mut().driver_mut()
+    }
+
+    /// Start receiving frames sent to the given IPv4 multicast group.
+    pub fn join_multicast_group(&mut self, group: Ipv4Address) {
+        info!("Joining multicast group {}", Display2Format(&group));
+        self.filter.subscribe_ipv4(group);
+        self.apply_filter();
+    }
+
+    /// Stop receiving frames sent to the given IPv4 multicast group.
+    pub fn leave_multicast_group(&mut self, group: Ipv4Address) {
+        info!("Leaving multicast group {}", Display2Format(&group));
+        self.filter.unsubscribe_ipv4(group);
+        self.apply_filter();
+    }
+
+    fn apply_filter(&mut self) {
+        let filter = self.filter;
+        if let Err(err) = self
+            .interface
+            .device_mut()
+            .driver_mut()
+            .set_frame_filter(&filter)
+        {
+            warn!("Failed to update frame filter: {:?}", Debug2Format(&err));
+        }
+    }
+
+    pub fn add_client<C: TcpClient, const RX_BUF_SZ: usize, const TX_BUF_SZ: usize>(
+        &mut self,
+        client: &mut C,
+        store: &'store mut TcpClientStore<RX_BUF_SZ, TX_BUF_SZ>,
+    ) {
+        let socket = TcpSocket::new(
+            TcpSocketBuffer::new(&mut store.rx_buffer[..]),
+            TcpSocketBuffer::new(&mut store.tx_buffer[..]),
+        );
+        let handle = self.sockets.add(socket);
+        client.set_socket_handle(handle);
+        if self
+            .clients
+            .try_push(ClientState {
+                handle,
+                connected: false,
+            })
+            .is_err()
+        {
+            warn!("Too many clients, connection events will not be reported");
+        }
+    }
+
+    pub fn add_udp_client<C: UdpClient, const RX_BUF_SZ: usize, const TX_BUF_SZ: usize>(
+        &mut self,
+        client: &mut C,
+        store: &'store mut UdpClientStore<RX_BUF_SZ, TX_BUF_SZ>,
+    ) {
+        let socket = UdpSocket::new(
+            UdpSocketBuffer::new(&mut store.rx_metadata[..], &mut store.rx_buffer[..]),
+            UdpSocketBuffer::new(&mut store.tx_metadata[..], &mut store.tx_buffer[..]),
+        );
+        client.set_socket_handle(self.sockets.add(socket));
+    }
+
+    pub fn poll<E: NetworkEvents>(
+        &mut self,
+        clock: &mut impl Clock,
+        events: &mut E,
+    ) -> Option<i64> {
+        log_throttle::set_time(clock.millis());
+        self.poll_link(clock, events);
+
+        match self.interface.poll(&mut self.sockets, clock.instant()) {
+            Ok(processed) if processed => {
+                trace!("Processed/emitted new packets during polling");
+            }
+            Err(e) => {
+                warn_throttled!(
+                    log_throttle::now_ms(),
+                    "Error during polling: {:?}",
+                    Debug2Format(&e)
+                );
+            }
+            _ => {}
+        }
+        let failures = self.interface.device().consecutive_failures();
+        if failures > self.driver_failures {
+            events.driver_failing(failures);
+        }
+        self.driver_failures = failures;
+        if !self.static_address {
+            self.poll_dhcp(clock);
+        }
+        self.poll_address(events);
+        self.poll_client_states(events);
+
+        self.poll_at(clock)
+    }
+
+    /// Returns the time (in `Clock` milliseconds) at which the stack should be
+    /// polled again, or `None` if there is nothing scheduled.
+    pub fn poll_at(&mut self, clock: &mut impl Clock) -> Option<i64> {
+        self.interface
+            .poll_at(&self.sockets, clock.instant())
+            .map(|t| t.total_millis())
+    }
+
+    pub fn poll_client<C: TcpClient>(
+        &mut self,
+        clock: &mut impl Clock,
+        random: &mut Random,
+        client: &mut C,
+    ) {
+        // Only handle TCP/IP if we have a valid address
+        if self.status().address().is_some() {
+            let handle = client.get_socket_handle();
+            let mut socket = self.sockets.get::<TcpSocket>(handle);
+            match client.poll(&mut *socket, clock.instant(), random) {
+                TcpAction::Nothing => {}
+                TcpAction::Listen(port) => {
+                    if let Err(err) = socket.listen(port) {
+                        warn!(
+                            "Failed to listen on port {}: {}",
+                            port,
+                            Display2Format(&err)
+                        );
+                    }
+                }
+                TcpAction::Connect {
+                    remote,
+                    timeout,
+                    keep_alive,
+                } => {
+                    socket.set_timeout(timeout);
+                    socket.set_keep_alive(keep_alive);
+                    let local = generate_local_port(random);
+                    if let Err(err) = socket.connect(remote, local) {
+                        warn_throttled!(
+                            log_throttle::now_ms(),
+                            "Failed to connect to {}: {}",
+                            Display2Format(&remote),
+                            Display2Format(&err)
+                        );
+                    }
+                }
+                TcpAction::Close => socket.close(),
+                TcpAction::Abort => socket.abort(),
+            }
+        }
+    }
+
+    pub fn poll_udp_client<C: UdpClient>(
+        &mut self,
+        clock: &mut impl Clock,
+        random: &mut Random,
+        client: &mut C,
+    ) {
+        if self.status().address().is_some() {
+            let socket = self.sockets.get(client.get_socket_handle());
+            client.poll(socket, clock.instant(), random);
+        }
+    }
+
+    /// Whether the network controller answers a register read.
+    pub fn driver_responds(&mut self) -> bool {
+        self.interface
+            .device_mut()
+            .driver_mut()
+            .is_link_up()
+            .is_ok()
+    }
+
+    fn poll_link<E: NetworkEvents>(&mut self, clock: &mut impl Clock, events: &mut E) {
+        let now = clock.millis();
+        if now < self.next_link_check {
+            return;
+        }
+        self.next_link_check = now + LINK_CHECK_INTERVAL_MS;
+
+        match self.interface.device_mut().driver_mut().is_link_up() {
+            Ok(up) if up != self.link_up => {
+                self.link_up = up;
+                if up {
+                    info!("Link up");
+                    events.link_up();
+                } else {
+                    info!("Link down");
+                    events.link_down();
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                warn_throttled!(
+                    log_throttle::now_ms(),
+                    "Failed to read link status: {:?}",
+                    Debug2Format(&err)
+                )
+            }
+        }
+    }
+
+    fn poll_address<E: NetworkEvents>(&mut self, events: &mut E) {
+        let address = self
+            .interface
+            .ipv4_addr()
+            .filter(|addr| !addr.is_unspecified());
+        if address == self.address {
+            return;
+        }
+        if let Some(old) = self.address {
+            events.address_lost(old);
+        }
+        if let Some(new) = address {
+            events.address_acquired(new);
+        }
+        self.address = address;
+    }
+
+    /// Aborts the connections of all TCP clients. They see their sockets
+    /// close, and reconnect on their own.
+    fn abort_clients(&mut self) {
+        for client in self.clients.iter() {
+            self.sockets.get::<TcpSocket>(client.handle).abort();
+        }
+    }
+
+    fn poll_client_states<E: NetworkEvents>(&mut self, events: &mut E) {
+        for client in self.clients.iter_mut() {
+            let socket = self.sockets.get::<TcpSocket>(client.handle);
+            // Same logic as the clients use: a connection is established once
+            // we can send, and closed once no more packets are exchanged.
+            if socket.may_send() && !client.connected {
+                client.connected = true;
+                events.client_connected(client.handle);
+            } else if !socket.is_active() && client.connected {
+                client.connected = false;
+                events.client_disconnected(client.handle);
+            }
+        }
+    }
+
+    fn poll_dhcp(&mut self, clock: &mut impl Clock) {
+        match self
+            .dhcp_client
+            .poll(&mut self.interface, &mut self.sockets, clock.instant())
+        {
+            Ok(Some(config)) => self.handle_dhcp(config),
+            Err(err) if err == smoltcp::Error::Malformed => {
+                // This will happen from time to time on most networks,
+                // so we shouldn't let it pollute our logs.
+                trace!("Malformed DHCP packet");
+            }
+            Err(err) if err == smoltcp::Error::Unrecognized => {
+                // Same as with Malformed.
+                trace!("Unrecognised DHCP packet");
+            }
+            Err(err) => warn_throttled!(
+                log_throttle::now_ms(),
+                "DHCP error: {}",
+                Display2Format(&err)
+            ),
+            _ => {}
+        }
+    }
+
+    fn handle_dhcp(&mut self, cfg: Dhcpv4Config) {
+        info!(
+            "Received DHCP configuration: {:?} via {:?}, DNS {:?}",
+            Debug2Format(&cfg.address),
+            Debug2Format(&cfg.router),
+            Debug2Format(&cfg.dns_servers)
+        );
+
+        match cfg {
+            Dhcpv4Config {
+                address: Some(cidr),
+                router: Some(router),
+                ..
+            } => {
+                info!("Received CIDR: {}", Display2Format(&cidr));
+                self.apply_address(cidr, Some(router));
+            }
+            cfg => {
+                warn!(
+                    "DHCP configuration did not contain address or DNS: {:?}",
+                    Debug2Format(&cfg)
+                );
+            }
+        }
+    }
+
+    fn apply_address(&mut self, cidr: Ipv4Cidr, router: Option<Ipv4Address>) {
+        let changed = self.cidr.map_or(false, |old| old != cidr)
+            || (self.gateway.is_some() && self.gateway != router);
+        self.cidr = Some(cidr);
+        self.gateway = router;
+        if changed {
+            // Most likely the router was replaced. Connections made through
+            // the old one will never complete, so make the clients start over.
+            warn!("Network configuration changed, resetting connections");
+            self.abort_clients();
+        }
+
+        // This also flushes the neighbor cache, which may still hold the
+        // hardware address of the old router.
+        self.interface.update_ip_addrs(|addrs| {
+            let addr = addrs.iter_mut().next().unwrap();
+            *addr = IpCidr::Ipv4(cidr);
+        });
+        let router = match router {
+            Some(router) => router,
+            None => {
+                if let Some(prev_route) = self.interface.routes_mut().remove_default_ipv4_route() {
+                    info!(
+                        "Removed previous route {}",
+                        Display2Format(&prev_route.via_router)
+                    );
+                }
+                return;
+            }
+        };
+        if let Some(prev_route) = self
+            .interface
+            .routes_mut()
+            .add_default_ipv4_route(router)
+            .unwrap()
+        {
+            info!(
+                "Replaced previous route {} with {}",
+                Display2Format(&prev_route.via_router),
+                Display2Format(&router)
+            );
+        } else {
+            info!("Added new default route via {}", Display2Format(&router));
+        }
+    }
+}
+
+#[inline]
+pub fn generate_local_port(random: &mut Random) -> u16 {
+    EPHEMERAL_PORT_START + random.next(EPHEMERAL_PORT_COUNT as u32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::{
+        socket::SocketHandle,
+        time::Instant,
+        wire::{IpEndpoint, Ipv4Packet, UdpPacket},
+    };
+    use std::boxed::Box;
+
+    use super::*;
+    use crate::{
+        client::TcpConnection,
+        mock::{Frame, MockDriver},
+    };
+
+    const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x10];
+    const SERVER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+    // The DHCP server, router and broker, all in one.
+    const SERVER: [u8; 4] = [10, 0, 0, 1];
+    const ADDRESS: [u8; 4] = [10, 0, 0, 10];
+    const BROKER_PORT: u16 = 1883;
+
+    const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+    const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+    // Ethernet, and IPv4 without options.
+    const IP_PAYLOAD: usize = 14 + 20;
+    // DHCP message types.
+    const DISCOVER: u8 = 1;
+    const OFFER: u8 = 2;
+    const REQUEST: u8 = 3;
+    const ACK: u8 = 5;
+
+    struct TestClock(i64);
+
+    impl Clock for TestClock {
+        fn millis(&self) -> i64 {
+            self.0
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        link_up: bool,
+        address: Option<Ipv4Address>,
+    }
+
+    impl NetworkEvents for Recorder {
+        fn link_up(&mut self) {
+            self.link_up = true;
+        }
+
+        fn address_acquired(&mut self, addr: Ipv4Address) {
+            self.address = Some(addr);
+        }
+    }
+
+    /// Connects to the broker once it's polled.
+    struct Connector(Option<SocketHandle>);
+
+    impl TcpClient for Connector {
+        fn set_socket_handle(&mut self, handle: SocketHandle) {
+            self.0 = Some(handle);
+        }
+
+        fn get_socket_handle(&mut self) -> SocketHandle {
+            self.0.unwrap()
+        }
+
+        fn poll(
+            &mut self,
+            socket: &mut dyn TcpConnection,
+            _timestamp: Instant,
+            _random: &mut Random,
+        ) -> TcpAction {
+            if socket.is_open() {
+                return TcpAction::Nothing;
+            }
+            TcpAction::Connect {
+                remote: IpEndpoint::new(Ipv4Address(SERVER).into(), BROKER_PORT),
+                timeout: None,
+                keep_alive: None,
+            }
+        }
+    }
+
+    fn push(frame: &mut Frame, bytes: &[u8]) {
+        frame.try_extend_from_slice(bytes).unwrap();
+    }
+
+    /// A UDP datagram from the server, with its checksums filled in.
+    fn udp_from_server(dst: [u8; 4], src_port: u16, dst_port: u16, payload: &[u8]) -> Frame {
+        let udp_len = 8 + payload.len() as u16;
+        let mut frame = Frame::new();
+        push(&mut frame, &[0xFF; 6]);
+        push(&mut frame, &SERVER_MAC);
+        push(&mut frame, &ETHERTYPE_IPV4);
+        push(&mut frame, &[0x45, 0]);
+        push(&mut frame, &(20 + udp_len).to_be_bytes());
+        push(&mut frame, &[0, 0, 0, 0, 64, 17, 0, 0]);
+        push(&mut frame, &SERVER);
+        push(&mut frame, &dst);
+        push(&mut frame, &src_port.to_be_bytes());
+        push(&mut frame, &dst_port.to_be_bytes());
+        push(&mut frame, &udp_len.to_be_bytes());
+        push(&mut frame, &[0, 0]);
+        push(&mut frame, payload);
+        Ipv4Packet::new_unchecked(&mut frame[14..]).fill_checksum();
+        UdpPacket::new_unchecked(&mut frame[IP_PAYLOAD..]).fill_checksum(
+            &IpAddress::Ipv4(Ipv4Address(SERVER)),
+            &IpAddress::Ipv4(Ipv4Address(dst)),
+        );
+        frame
+    }
+
+    /// An ARP request or reply from the server.
+    fn arp_from_server(operation: u8, target_mac: [u8; 6], target: [u8; 4]) -> Frame {
+        let mut frame = Frame::new();
+        push(&mut frame, &[0xFF; 6]);
+        push(&mut frame, &SERVER_MAC);
+        push(&mut frame, &ETHERTYPE_ARP);
+        push(&mut frame, &[0, 1, 0x08, 0x00, 6, 4, 0, operation]);
+        push(&mut frame, &SERVER_MAC);
+        push(&mut frame, &SERVER);
+        push(&mut frame, &target_mac);
+        push(&mut frame, &target);
+        frame
+    }
+
+    fn dhcp_message_type(mut options: &[u8]) -> Option<u8> {
+        while let [kind, rest @ ..] = options {
+            match kind {
+                0 => {
+                    options = rest;
+                    continue;
+                }
+                255 => return None,
+                _ => {}
+            }
+            let (&len, rest) = rest.split_first()?;
+            if *kind == 53 {
+                return rest.first().copied();
+            }
+            options = rest.get(len as usize..)?;
+        }
+        None
+    }
+
+    /// Offers `ADDRESS` in reply to a discover, and acknowledges a request
+    /// for it.
+    fn dhcp_server(sent: &[u8]) -> Option<Frame> {
+        let dhcp = sent.get(IP_PAYLOAD + 8..)?;
+        if sent[12..14] != ETHERTYPE_IPV4 || sent[23] != 17 || dhcp.len() < 240 {
+            return None;
+        }
+        let reply_type = match dhcp_message_type(&dhcp[240..])? {
+            DISCOVER => OFFER,
+            REQUEST => ACK,
+            _ => return None,
+        };
+        let mut reply = ArrayVec::<u8, 320>::new();
+        let mut push = |bytes: &[u8]| reply.try_extend_from_slice(bytes).unwrap();
+        // BOOTREPLY over Ethernet, for the same transaction.
+        push(&[2, 1, 6, 0]);
+        push(&dhcp[4..8]);
+        push(&[0; 8]);
+        push(&ADDRESS);
+        push(&[0; 8]);
+        // The client's hardware address.
+        push(&dhcp[28..44]);
+        push(&[0; 192]);
+        push(&[99, 130, 83, 99]);
+        push(&[53, 1, reply_type]);
+        // Server identifier, subnet mask, router, lease time and NTP server.
+        push(&[54, 4, 10, 0, 0, 1]);
+        push(&[1, 4, 255, 255, 255, 0]);
+        push(&[3, 4, 10, 0, 0, 1]);
+        push(&[51, 4, 0, 0, 0x0E, 0x10]);
+        push(&[42, 4, 10, 0, 0, 1]);
+        push(&[255]);
+        Some(udp_from_server([255; 4], 67, 68, &reply))
+    }
+
+    /// Answers ARP requests for the server.
+    fn arp_responder(sent: &[u8]) -> Option<Frame> {
+        let arp = sent.get(14..42)?;
+        if sent[12..14] != ETHERTYPE_ARP || arp[6..8] != [0, 1] || arp[24..28] != SERVER {
+            return None;
+        }
+        let mut reply = arp_from_server(2, MAC, ADDRESS);
+        reply[..6].copy_from_slice(&MAC);
+        Some(reply)
+    }
+
+    fn server(sent: &[u8]) -> Option<Frame> {
+        dhcp_server(sent).or_else(|| arp_responder(sent))
+    }
+
+    fn stack() -> (NetworkStack<'static, MockDriver>, TestClock) {
+        let mut driver = MockDriver::new();
+        driver.respond_with(server);
+        let store: &'static mut BackingStore<'static> = Box::leak(Box::new(BackingStore::new()));
+        let mut clock = TestClock(0);
+        let stack = NetworkStack::new(driver, &mut clock, store, MAC, FrameFilter::default());
+        (stack, clock)
+    }
+
+    fn driver<'a>(stack: &'a mut NetworkStack<'static, MockDriver>) -> &'a mut MockDriver {
+        stack.driver_mut()
+    }
+
+    fn with_static_address(stack: &mut NetworkStack<MockDriver>, clock: &mut TestClock) {
+        let cidr = Ipv4Cidr::new(Ipv4Address(ADDRESS), 24);
+        stack.set_static_address(cidr, Some(Ipv4Address(SERVER)));
+        stack.poll(clock, &mut ());
+    }
+
+    #[test]
+    fn dhcp_assigns_address() {
+        let (mut stack, mut clock) = stack();
+        let mut events = Recorder::default();
+        for _ in 0..30 {
+            stack.poll(&mut clock, &mut events);
+            if stack.status().address().is_some() {
+                break;
+            }
+            clock.0 += 1000;
+        }
+        let addr = Ipv4Address(ADDRESS);
+        assert_eq!(NetStatus::AddressAcquired { addr }, stack.status());
+        assert!(events.link_up);
+        assert_eq!(Some(addr), events.address);
+        // Picked out of the acknowledgement by the driver glue.
+        assert_eq!(&[Ipv4Address(SERVER)], stack.ntp_servers());
+    }
+
+    #[test]
+    fn link_down_degrades_status() {
+        let (mut stack, mut clock) = stack();
+        with_static_address(&mut stack, &mut clock);
+        let addr = Ipv4Address(ADDRESS);
+        assert_eq!(NetStatus::AddressAcquired { addr }, stack.status());
+
+        driver(&mut stack).set_link_up(false);
+        clock.0 += LINK_CHECK_INTERVAL_MS;
+        stack.poll(&mut clock, &mut ());
+        assert_eq!(NetStatus::Degraded { addr }, stack.status());
+    }
+
+    #[test]
+    fn arp_requests_for_our_address_are_answered() {
+        let (mut stack, mut clock) = stack();
+        with_static_address(&mut stack, &mut clock);
+        while driver(&mut stack).take_transmitted().is_some() {}
+
+        driver(&mut stack).inject(&arp_from_server(1, [0; 6], [10, 0, 0, 99]));
+        driver(&mut stack).inject(&arp_from_server(1, [0; 6], ADDRESS));
+        stack.poll(&mut clock, &mut ());
+
+        let reply = driver(&mut stack).take_transmitted().unwrap();
+        assert_eq!(ETHERTYPE_ARP, reply[12..14]);
+        // A reply, from us, to the server.
+        assert_eq!([0, 2], reply[20..22]);
+        assert_eq!(MAC, reply[22..28]);
+        assert_eq!(ADDRESS, reply[28..32]);
+        assert_eq!(SERVER_MAC, reply[32..38]);
+        assert!(driver(&mut stack).take_transmitted().is_none());
+    }
+
+    #[test]
+    fn connecting_resolves_peer_then_sends_syn() {
+        let (mut stack, mut clock) = stack();
+        with_static_address(&mut stack, &mut clock);
+        let mut client = Connector(None);
+        let client_store: &'static mut TcpClientStore<256, 256> =
+            Box::leak(Box::new(TcpClientStore::new()));
+        stack.add_client(&mut client, client_store);
+        let mut random = Random::new(1);
+        while driver(&mut stack).take_transmitted().is_some() {}
+
+        stack.poll_client(&mut clock, &mut random, &mut client);
+        for _ in 0..3 {
+            clock.0 += 10;
+            stack.poll(&mut clock, &mut ());
+        }
+
+        let request = driver(&mut stack).take_transmitted().unwrap();
+        assert_eq!(ETHERTYPE_ARP, request[12..14]);
+        assert_eq!([0, 1], request[20..22]);
+        assert_eq!(SERVER, request[38..42]);
+
+        let syn = driver(&mut stack).take_transmitted().unwrap();
+        assert_eq!(SERVER_MAC, syn[..6]);
+        assert_eq!(ETHERTYPE_IPV4, syn[12..14]);
+        // TCP, to the broker, with only SYN set.
+        assert_eq!(6, syn[23]);
+        assert_eq!(
+            BROKER_PORT.to_be_bytes(),
+            syn[IP_PAYLOAD + 2..IP_PAYLOAD + 4]
+        );
+        assert_eq!(0x02, syn[IP_PAYLOAD + 13]);
+    }
+
+    #[test]
+    fn multicast_groups_update_filter() {
+        let (mut stack, _) = stack();
+        assert_eq!(Some(FrameFilter::default()), driver(&mut stack).filter());
+
+        let group = Ipv4Address::new(224, 0, 0, 251);
+        stack.join_multicast_group(group);
+        let mut expected = FrameFilter::default();
+        expected.subscribe_ipv4(group);
+        assert_eq!(Some(expected), driver(&mut stack).filter());
+
+        stack.leave_multicast_group(group);
+        assert_eq!(Some(FrameFilter::default()), driver(&mut stack).filter());
+    }
+
+    #[test]
+    fn driver_failures_are_reported() {
+        #[derive(Default)]
+        struct Failures(u32);
+
+        impl NetworkEvents for Failures {
+            fn driver_failing(&mut self, consecutive_failures: u32) {
+                self.0 = consecutive_failures;
+            }
+        }
+
+        let (mut stack, mut clock) = stack();
+        let mut failures = Failures::default();
+        driver(&mut stack).fail_next(u32::MAX);
+        stack.poll(&mut clock, &mut failures);
+        assert!(failures.0 > 0);
+    }
+}
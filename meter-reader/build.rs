@@ -0,0 +1,178 @@
+use std::{env, fmt::Write, fs, net::Ipv4Addr, path::PathBuf};
+
+use serde::Deserialize;
+
+fn main() {
+    // defmt needs its linker script, which only exists if defmt is linked.
+    if env::var_os("CARGO_FEATURE_DEFMT_LOG").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+    generate_defaults();
+}
+
+/// The contents of `config.toml`. Every setting must be present, and unknown
+/// ones are refused, so typos don't go unnoticed.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    network: Network,
+    mqtt: Mqtt,
+    uart: Uart,
+    ota: Ota,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Network {
+    eth_addr: [u8; 6],
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Mqtt {
+    broker: Ipv4Addr,
+    port: u16,
+    client_id: String,
+    usage_topic: String,
+    status_topic: String,
+    diagnostics_topic: String,
+    costs_topic: String,
+    totals_topic: String,
+    peak_topic: String,
+    gas_topic: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Uart {
+    baud: u32,
+    data_bits: u8,
+    parity: Parity,
+    inverted: bool,
+}
+
+/// Named like `uart::Parity`, so its `Debug` output can be used in the
+/// generated code.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Ota {
+    public_key: String,
+}
+
+/// Turns `config.toml`, or the file `METER_READER_CONFIG` points to, into
+/// the constants of `config::defaults`, so the settings that differ per site
+/// can be set for a build without changing the source.
+fn generate_defaults() {
+    println!("cargo:rerun-if-env-changed=METER_READER_CONFIG");
+    let path = env::var_os("METER_READER_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("config.toml"));
+    println!("cargo:rerun-if-changed={}", path.display());
+    let source = fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err));
+    let settings: Settings = toml::from_str(&source)
+        .unwrap_or_else(|err| panic!("Failed to parse {}: {}", path.display(), err));
+
+    let mut out = String::new();
+    let eth_addr = settings.network.eth_addr;
+    if eth_addr[0] & 1 != 0 {
+        panic!("network.eth_addr must be a unicast address");
+    }
+    writeln!(out, "pub const ETH_ADDR: [u8; 6] = {:?};", eth_addr).unwrap();
+
+    let mqtt = settings.mqtt;
+    writeln!(
+        out,
+        "pub const MQTT_BROKER: Ipv4Address = Ipv4Address({:?});",
+        mqtt.broker.octets()
+    )
+    .unwrap();
+    if mqtt.port == 0 {
+        panic!("mqtt.port must be a number from 1 to {}", u16::MAX);
+    }
+    writeln!(out, "pub const MQTT_PORT: u16 = {};", mqtt.port).unwrap();
+    check_len(&mqtt.client_id, 32, "mqtt.client_id");
+    writeln!(
+        out,
+        "pub const MQTT_CLIENT_ID: &str = {:?};",
+        mqtt.client_id
+    )
+    .unwrap();
+    for (name, topic) in [
+        ("usage", &mqtt.usage_topic),
+        ("status", &mqtt.status_topic),
+        ("diagnostics", &mqtt.diagnostics_topic),
+        ("costs", &mqtt.costs_topic),
+        ("totals", &mqtt.totals_topic),
+        ("peak", &mqtt.peak_topic),
+        ("gas", &mqtt.gas_topic),
+    ] {
+        check_len(topic, 64, &format!("mqtt.{}_topic", name));
+        writeln!(
+            out,
+            "pub const {}_TOPIC: &str = {:?};",
+            name.to_uppercase(),
+            topic
+        )
+        .unwrap();
+    }
+
+    let uart = settings.uart;
+    if uart.baud == 0 {
+        panic!("uart.baud must be a number from 1 to {}", u32::MAX);
+    }
+    let data_bits = match uart.data_bits {
+        7 => "Seven",
+        8 => "Eight",
+        _ => panic!("uart.data_bits must be 7 or 8"),
+    };
+    writeln!(
+        out,
+        "pub const UART: UartConfig = UartConfig {{ baud: {}, data_bits: DataBits::{}, \
+        parity: Parity::{:?}, inverted: {} }};",
+        uart.baud, data_bits, uart.parity, uart.inverted
+    )
+    .unwrap();
+
+    let public_key = match settings.ota.public_key.as_str() {
+        "" => "None".to_owned(),
+        hex => format!("Some({:?})", hex_bytes(hex, 32, "ota.public_key")),
+    };
+    writeln!(
+        out,
+        "pub const OTA_PUBLIC_KEY: Option<[u8; 32]> = {};",
+        public_key
+    )
+    .unwrap();
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out_dir.join("defaults.rs"), out).unwrap();
+}
+
+fn check_len(value: &str, max_len: usize, key: &str) {
+    if value.len() > max_len {
+        panic!("{} must be at most {} bytes long", key, max_len);
+    }
+}
+
+fn hex_bytes(hex: &str, len: usize, key: &str) -> Vec<u8> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect::<Option<Vec<_>>>();
+    match bytes {
+        Some(bytes) if bytes.len() == len => bytes,
+        _ => panic!("{} must be {} hex digits", key, len * 2),
+    }
+}
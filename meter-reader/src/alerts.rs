@@ -0,0 +1,381 @@
+use core::{
+    fmt::{self, Display, Write},
+    str::FromStr,
+};
+
+use arrayvec::{ArrayString, ArrayVec};
+use dsmr42::{Line, Phase, Telegram};
+use enc28j60_smoltcp::warn_throttled;
+
+use crate::{clock, counters, derived::Derived, wall_clock::LocalTime};
+
+pub const MAX_RULES: usize = 4;
+// Alerts raised or cleared by a single telegram, and those from earlier
+// telegrams that haven't been handed to the MQTT client yet.
+const MAX_PENDING: usize = 2 * MAX_RULES;
+const PHASES: [Phase; 3] = [Phase::L1, Phase::L2, Phase::L3];
+// Codes of the quantities, as stored in the configuration.
+const QUANTITY_CODES: core::ops::RangeInclusive<u8> = 1..=19;
+
+/// A value in a telegram that a rule can watch, named as in the JSON
+/// telegrams published to MQTT.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Quantity {
+    TotalConsuming,
+    TotalProducing,
+    NetPower,
+    Current(Phase),
+    Consuming(Phase),
+    Producing(Phase),
+    Voltage(Phase),
+    PowerFailures,
+    LongPowerFailures,
+    VoltageSags,
+    VoltageSwells,
+}
+
+impl Quantity {
+    pub fn from_u8(code: u8) -> Option<Self> {
+        let phase = |offset: u8| PHASES.get((code - offset) as usize).copied();
+        Some(match code {
+            1 => Quantity::TotalConsuming,
+            2 => Quantity::TotalProducing,
+            3 => Quantity::NetPower,
+            4..=6 => Quantity::Current(phase(4)?),
+            7..=9 => Quantity::Consuming(phase(7)?),
+            10..=12 => Quantity::Producing(phase(10)?),
+            13..=15 => Quantity::Voltage(phase(13)?),
+            16 => Quantity::PowerFailures,
+            17 => Quantity::LongPowerFailures,
+            18 => Quantity::VoltageSags,
+            19 => Quantity::VoltageSwells,
+            _ => return None,
+        })
+    }
+
+    pub fn to_u8(self) -> u8 {
+        let phase = |phase: Phase| PHASES.iter().position(|p| *p == phase).unwrap_or(0) as u8;
+        match self {
+            Quantity::TotalConsuming => 1,
+            Quantity::TotalProducing => 2,
+            Quantity::NetPower => 3,
+            Quantity::Current(p) => 4 + phase(p),
+            Quantity::Consuming(p) => 7 + phase(p),
+            Quantity::Producing(p) => 10 + phase(p),
+            Quantity::Voltage(p) => 13 + phase(p),
+            Quantity::PowerFailures => 16,
+            Quantity::LongPowerFailures => 17,
+            Quantity::VoltageSags => 18,
+            Quantity::VoltageSwells => 19,
+        }
+    }
+
+    /// The value in `telegram`, in the units of the parser: W, A, 0.1 V or a
+    /// count.
+    fn value(self, telegram: &Telegram) -> Option<i64> {
+        if self == Quantity::NetPower {
+            return Derived::from_telegram(telegram).net_power;
+        }
+        telegram.lines.iter().find_map(|line| {
+            let value = match (self, line) {
+                (Quantity::TotalConsuming, Line::TotalConsuming(value))
+                | (Quantity::TotalProducing, Line::TotalProducing(value))
+                | (Quantity::PowerFailures, Line::PowerFailures(value))
+                | (Quantity::LongPowerFailures, Line::LongPowerFailures(value))
+                | (Quantity::VoltageSags, Line::VoltageSags(value))
+                | (Quantity::VoltageSwells, Line::VoltageSwells(value)) => value,
+                (Quantity::Current(p), Line::Current(phase, value))
+                | (Quantity::Consuming(p), Line::Consuming(phase, value))
+                | (Quantity::Producing(p), Line::Producing(phase, value))
+                | (Quantity::Voltage(p), Line::Voltage(phase, value))
+                    if p == *phase =>
+                {
+                    value
+                }
+                _ => return None,
+            };
+            Some(*value as i64)
+        })
+    }
+
+    fn is_counter(self) -> bool {
+        matches!(
+            self,
+            Quantity::PowerFailures
+                | Quantity::LongPowerFailures
+                | Quantity::VoltageSags
+                | Quantity::VoltageSwells
+        )
+    }
+
+    /// Writes a value in the units of the JSON telegrams, which for voltages
+    /// are volts rather than tenths.
+    fn write_value<W: Write>(self, writer: &mut W, value: i64) -> fmt::Result {
+        match self {
+            Quantity::Voltage(_) => write!(writer, "{}.{}", value / 10, value % 10),
+            _ => write!(writer, "{}", value),
+        }
+    }
+
+    /// Parses a threshold in the units `write_value()` uses.
+    fn parse_threshold(self, value: Option<&str>) -> Result<i32, ()> {
+        let value = value.ok_or(())?;
+        match self {
+            Quantity::Voltage(_) => {
+                let mut parts = value.splitn(2, '.');
+                let volts: u16 = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+                let tenths: u8 = match parts.next() {
+                    Some(digit) if digit.len() == 1 => digit.parse().map_err(|_| ())?,
+                    Some(_) => return Err(()),
+                    None => 0,
+                };
+                Ok(volts as i32 * 10 + tenths as i32)
+            }
+            _ => value.parse().map_err(|_| ()),
+        }
+    }
+}
+
+impl Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Quantity::TotalConsuming => write!(f, "total_consuming"),
+            Quantity::TotalProducing => write!(f, "total_producing"),
+            Quantity::NetPower => write!(f, "net_power"),
+            Quantity::Current(phase) => write!(f, "{}_current", phase),
+            Quantity::Consuming(phase) => write!(f, "{}_consuming", phase),
+            Quantity::Producing(phase) => write!(f, "{}_producing", phase),
+            Quantity::Voltage(phase) => write!(f, "{}_voltage", phase),
+            Quantity::PowerFailures => write!(f, "power_failures"),
+            Quantity::LongPowerFailures => write!(f, "long_power_failures"),
+            Quantity::VoltageSags => write!(f, "voltage_sags"),
+            Quantity::VoltageSwells => write!(f, "voltage_swells"),
+        }
+    }
+}
+
+impl FromStr for Quantity {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        QUANTITY_CODES
+            .filter_map(Quantity::from_u8)
+            .find(|quantity| {
+                let mut name = ArrayString::<24>::new();
+                write!(name, "{}", quantity).is_ok() && name.as_str() == s
+            })
+            .ok_or(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Condition {
+    Above(i32),
+    Below(i32),
+    /// A counter, like the number of voltage swells, went up.
+    Increased,
+}
+
+/// Raises an alert once `condition` has held for `for_secs`, like
+/// `total_producing > 3000 for 600`, `l1_voltage < 207.0` or
+/// `voltage_swells increased`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AlertRule {
+    pub quantity: Quantity,
+    pub condition: Condition,
+    /// For `Increased`, how long the counter must stay the same before the
+    /// alert clears.
+    pub for_secs: u32,
+}
+
+impl Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.quantity)?;
+        match self.condition {
+            Condition::Above(threshold) => {
+                write!(f, " > ")?;
+                self.quantity.write_value(f, threshold as i64)?;
+            }
+            Condition::Below(threshold) => {
+                write!(f, " < ")?;
+                self.quantity.write_value(f, threshold as i64)?;
+            }
+            Condition::Increased => write!(f, " increased")?,
+        }
+        if self.for_secs > 0 {
+            write!(f, " for {}", self.for_secs)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for AlertRule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut words = s.split_whitespace();
+        let quantity: Quantity = words.next().ok_or(())?.parse()?;
+        let condition = match words.next() {
+            Some(">") => Condition::Above(quantity.parse_threshold(words.next())?),
+            Some("<") => Condition::Below(quantity.parse_threshold(words.next())?),
+            Some("increased") if quantity.is_counter() => Condition::Increased,
+            _ => return Err(()),
+        };
+        let for_secs = match words.next() {
+            Some("for") => words.next().ok_or(())?.parse().map_err(|_| ())?,
+            Some(_) => return Err(()),
+            None => 0,
+        };
+        match words.next() {
+            Some(_) => Err(()),
+            None => Ok(Self {
+                quantity,
+                condition,
+                for_secs,
+            }),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AlertConfig {
+    pub rules: [Option<AlertRule>; MAX_RULES],
+    /// How far past the threshold, as a percentage of it, a value must be
+    /// back before an alert clears, so one hovering around the threshold
+    /// doesn't raise it over and over.
+    pub hysteresis_pct: u8,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            rules: [None; MAX_RULES],
+            hysteresis_pct: 5,
+        }
+    }
+}
+
+/// An alert that was raised or cleared.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AlertEvent {
+    /// The number of the rule, as in the `alerts.<n>` setting.
+    pub number: u8,
+    pub rule: AlertRule,
+    pub raised: bool,
+    /// The value in the telegram that raised or cleared the alert.
+    pub value: i64,
+    /// The time of that telegram.
+    pub at: Option<LocalTime>,
+}
+
+impl AlertEvent {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> fmt::Result {
+        // Poor man's JSON, same as the telegrams
+        write!(
+            writer,
+            "{{\"alert\": {}, \"rule\": \"{}\", \"state\": \"{}\", \"value\": ",
+            self.number,
+            self.rule,
+            if self.raised { "raised" } else { "cleared" }
+        )?;
+        self.rule.quantity.write_value(writer, self.value)?;
+        if let Some(at) = self.at {
+            write!(writer, ", \"at\": \"{}\"", at)?;
+        }
+        write!(writer, "}}")
+    }
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+struct RuleState {
+    active: bool,
+    // `Clock` time since which the condition has held, or for counters, at
+    // which the counter last went up.
+    since: Option<i64>,
+    last_count: Option<i64>,
+}
+
+/// Evaluates the alert rules against every telegram.
+pub struct Alerts {
+    config: AlertConfig,
+    states: [RuleState; MAX_RULES],
+    pending: ArrayVec<AlertEvent, MAX_PENDING>,
+}
+
+impl Alerts {
+    pub fn new(config: AlertConfig) -> Self {
+        Self {
+            config,
+            states: [RuleState::default(); MAX_RULES],
+            pending: ArrayVec::new(),
+        }
+    }
+
+    /// Evaluates the rules against a telegram received at `Clock` time `now`.
+    pub fn observe(&mut self, telegram: &Telegram, now: i64) {
+        for (i, rule) in self.config.rules.iter().enumerate() {
+            let rule = match rule {
+                Some(rule) => rule,
+                None => continue,
+            };
+            let value = match rule.quantity.value(telegram) {
+                Some(value) => value,
+                None => continue,
+            };
+            let state = &mut self.states[i];
+            let for_ms = rule.for_secs as i64 * 1000;
+            let raised = match rule.condition {
+                Condition::Above(threshold) | Condition::Below(threshold) => {
+                    let threshold = threshold as i64;
+                    let margin = threshold.abs() * self.config.hysteresis_pct as i64 / 100;
+                    let (holds, cleared) = match rule.condition {
+                        Condition::Above(_) => (value > threshold, value <= threshold - margin),
+                        _ => (value < threshold, value >= threshold + margin),
+                    };
+                    if holds {
+                        let since = *state.since.get_or_insert(now);
+                        state.active || now - since >= for_ms
+                    } else {
+                        state.since = None;
+                        state.active && !cleared
+                    }
+                }
+                Condition::Increased => {
+                    if state.last_count.is_some_and(|count| value > count) {
+                        state.since = Some(now);
+                    }
+                    state.last_count = Some(value);
+                    match state.since {
+                        // The telegram that raised it doesn't also clear it.
+                        Some(since) => since == now || now - since < for_ms,
+                        None => false,
+                    }
+                }
+            };
+            if raised != state.active {
+                state.active = raised;
+                let event = AlertEvent {
+                    number: i as u8 + 1,
+                    rule: *rule,
+                    raised,
+                    value,
+                    at: counters::telegram_time(telegram),
+                };
+                log::info!(
+                    "Alert {} {}: {}",
+                    event.number,
+                    if raised { "raised" } else { "cleared" },
+                    event.rule
+                );
+                if self.pending.try_push(event).is_err() {
+                    warn_throttled!(clock::millis(), "Too many alerts pending, dropping one");
+                }
+            }
+        }
+    }
+
+    /// Takes the oldest alert that hasn't been published yet.
+    pub fn take(&mut self) -> Option<AlertEvent> {
+        self.pending.pop_at(0)
+    }
+}
@@ -0,0 +1,30 @@
+use crate::{
+    flash::{self, SECTOR_SZ},
+    page_log::{PageLog, PAYLOAD_SZ},
+};
+
+// The two sectors after the snapshots, see `totals/store.rs`.
+const BANK_OFFSETS: [u32; 2] = [
+    flash::FIRMWARE_MAX_SZ + 4 * SECTOR_SZ,
+    flash::FIRMWARE_MAX_SZ + 5 * SECTOR_SZ,
+];
+const MAGIC: [u8; 4] = *b"MRBC";
+
+/// Counts this boot in flash, and returns its number, starting at 1. Call
+/// this once, during startup.
+///
+/// Each boot takes a page, so a sector is erased every 16 boots, which is
+/// far within the flash's endurance.
+pub fn increment() -> u32 {
+    let (mut counter, payload) = PageLog::load(BANK_OFFSETS, MAGIC);
+    let count = payload
+        .map_or(0, |p| u32::from_le_bytes([p[0], p[1], p[2], p[3]]))
+        .wrapping_add(1);
+    let mut payload = [0xFF; PAYLOAD_SZ];
+    payload[..4].copy_from_slice(&count.to_le_bytes());
+    if let Err(err) = counter.save(&payload) {
+        log::warn!("Failed to save boot count: {:?}", err);
+    }
+    log::info!("Boot number {}", count);
+    count
+}
@@ -0,0 +1,128 @@
+use crate::wall_clock::TimeSource;
+
+// Long enough for the jitter in when a time is received to be a fraction of
+// a ppm, and for the one second resolution of telegram timestamps, which are
+// sent right as the second starts, not to matter.
+const WINDOW_MS: i64 = 6 * 3600 * 1000;
+// Crystals are accurate to within tens of ppm, so a larger difference means
+// the time source was adjusted, rather than that the clock drifted.
+const MAX_DRIFT_PPM: i64 = 500;
+
+#[derive(Copy, Clone, Debug)]
+struct Reference {
+    unix_ms: i64,
+    clock_ms: i64,
+    source: TimeSource,
+}
+
+/// Measures how fast the `Clock` runs compared to an external time source,
+/// and corrects its rate to match. Each measurement compares the time that
+/// passed on both over a window of several hours.
+///
+/// SNTP is more precise, so telegram timestamps are only used while it
+/// hasn't been heard from for two windows.
+pub struct Calibration {
+    reference: Option<Reference>,
+    drift_ppm: Option<i32>,
+}
+
+impl Calibration {
+    pub fn new() -> Self {
+        Self {
+            reference: None,
+            drift_ppm: None,
+        }
+    }
+
+    /// How many ppm the crystal runs fast, or slow if negative, once
+    /// measured.
+    pub fn drift_ppm(&self) -> Option<i32> {
+        self.drift_ppm
+    }
+
+    /// Records that it was `unix_ms` at `Clock` time `clock_ms`. Returns the
+    /// drift when it's measured again, which the clock should be corrected
+    /// for.
+    pub fn observe(&mut self, unix_ms: i64, clock_ms: i64, source: TimeSource) -> Option<i32> {
+        let observed = Reference {
+            unix_ms,
+            clock_ms,
+            source,
+        };
+        let reference = match self.reference {
+            Some(reference) if reference.source == source => reference,
+            Some(reference)
+                if reference.source == TimeSource::Sntp
+                    && clock_ms - reference.clock_ms < 2 * WINDOW_MS =>
+            {
+                return None;
+            }
+            _ => {
+                self.reference = Some(observed);
+                return None;
+            }
+        };
+        let clock_elapsed = clock_ms - reference.clock_ms;
+        if clock_elapsed < WINDOW_MS {
+            return None;
+        }
+        self.reference = Some(observed);
+
+        // The clock has been corrected for the drift measured before, so
+        // this is what's left of it.
+        let unix_elapsed = unix_ms - reference.unix_ms;
+        if unix_elapsed <= 0 {
+            log::warn!(
+                "{:?} time went back {} ms, measuring from here",
+                source,
+                -unix_elapsed
+            );
+            return None;
+        }
+        let residual_ppm = (clock_elapsed - unix_elapsed) * 1_000_000 / unix_elapsed;
+        let drift_ppm = self.drift_ppm.unwrap_or(0) as i64 + residual_ppm;
+        if residual_ppm.abs() > MAX_DRIFT_PPM || drift_ppm.abs() > MAX_DRIFT_PPM {
+            log::warn!(
+                "Clock is {} ms off from {:?} after {} s, assuming it was adjusted",
+                clock_elapsed - unix_elapsed,
+                source,
+                clock_elapsed / 1000
+            );
+            return None;
+        }
+        log::info!("Clock drifts {} ppm compared to {:?}", drift_ppm, source);
+        self.drift_ppm = Some(drift_ppm as i32);
+        self.drift_ppm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNIX_MS: i64 = 1_600_000_000_000;
+
+    #[test]
+    fn drift_is_measured_over_a_window() {
+        let mut calibration = Calibration::new();
+        assert_eq!(None, calibration.observe(UNIX_MS, 0, TimeSource::Sntp));
+        // 216 ms fast over six hours.
+        let drift = calibration.observe(UNIX_MS + WINDOW_MS, WINDOW_MS + 216, TimeSource::Sntp);
+        assert_eq!(Some(10), drift);
+    }
+
+    #[test]
+    fn time_going_back_restarts_the_measurement() {
+        let mut calibration = Calibration::new();
+        calibration.observe(UNIX_MS, 0, TimeSource::Telegram);
+        for unix_ms in [UNIX_MS, UNIX_MS - 1000] {
+            let clock_ms = calibration.reference.unwrap().clock_ms + WINDOW_MS;
+            assert_eq!(
+                None,
+                calibration.observe(unix_ms, clock_ms, TimeSource::Telegram)
+            );
+            assert_eq!(clock_ms, calibration.reference.unwrap().clock_ms);
+        }
+        assert_eq!(None, calibration.drift_ppm());
+    }
+}
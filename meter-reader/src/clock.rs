@@ -1,12 +1,59 @@
+use core::{
+    cell::Cell,
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use cortex_m::interrupt::{self, Mutex};
 use smoltcp::time::Instant;
 use teensy4_bsp::hal::{
     ccm::{self, perclk, IPGFrequency},
-    gpt::{self, Mode, GPT},
+    gpt::{self, Mode, OutputCompareRegister, GPT},
 };
 
+// The nominal rate, which the crystal is only accurate to within some ppm.
+const TICKS_PER_SEC: i64 = 7_500_000;
+
+const GPT2: usize = 0x401F_0000;
+const GPT_SR: usize = GPT2 + 0x08;
+const GPT_IR: usize = GPT2 + 0x0C;
+const GPT_CNT: usize = GPT2 + 0x24;
+const SR_OF1: u32 = 1 << 0;
+const SR_ROV: u32 = 1 << 5;
+const IR_OF1IE: u32 = 1 << 0;
+
+// Upper 32 bits of the tick count, incremented by `on_interrupt()`.
+static ROLLOVERS: AtomicU32 = AtomicU32::new(0);
+
+static RATE: Mutex<Cell<Rate>> = Mutex::new(Cell::new(Rate {
+    base_ticks: 0,
+    base_ms: 0,
+    ticks_per_sec: TICKS_PER_SEC,
+}));
+
+/// How ticks are turned into milliseconds. Changing the rate starts counting
+/// from the current time, so `millis()` doesn't jump.
+#[derive(Copy, Clone)]
+struct Rate {
+    base_ticks: u64,
+    base_ms: i64,
+    ticks_per_sec: i64,
+}
+
+impl Rate {
+    fn millis(&self, ticks: u64) -> i64 {
+        self.base_ms + (ticks - self.base_ticks) as i64 * 1000 / self.ticks_per_sec
+    }
+
+    /// The first tick at which `millis()` reaches `ms`.
+    fn ticks(&self, ms: i64) -> u64 {
+        let since_base = (ms - self.base_ms) * self.ticks_per_sec;
+        (self.base_ticks as i64 + (since_base + 999) / 1000) as u64
+    }
+}
+
 pub struct Clock {
     gpt: GPT,
-    rollover_count: u32,
 }
 
 impl Clock {
@@ -21,35 +68,115 @@ impl Clock {
 
         let mut gpt = gpt.clock(&mut clk_cfg);
         gpt.set_mode(Mode::FreeRunning);
+        gpt.set_rollover_interrupt_enable(true);
         gpt.set_enable(true);
         log::debug!(
             "GPT rolls over in {} seconds",
             (gpt.clock_period() * u32::max_value()).as_secs()
         );
-        Self {
-            gpt,
-            rollover_count: 0,
-        }
+        Self { gpt }
     }
 
     pub fn ticks(&self) -> u32 {
         self.gpt.count()
     }
 
-    pub fn millis(&mut self) -> i64 {
-        // Quirk: this only works if millis() is called often enough, otherwise
-        // we may skip a rollover. Since we call it multiple times per main
-        // loop iteration, this is not an issue.
-        if self.gpt.rollover() {
-            self.gpt.clear_rollover();
-            self.rollover_count += 1;
-            log::debug!("Clock rolled over to {}", self.rollover_count);
-        }
-        let total_ticks = (self.rollover_count as i64) << 32 | self.gpt.count() as i64;
-        total_ticks / 7500
+    pub fn millis(&self) -> i64 {
+        millis()
     }
 
-    pub fn instant(&mut self) -> Instant {
+    pub fn instant(&self) -> Instant {
         Instant::from_millis(self.millis())
     }
+
+    /// Raise the GPT compare interrupt once `millis()` reaches `at`, which
+    /// makes `on_interrupt()` return `true`.
+    pub fn set_alarm(&mut self, at: i64) {
+        // Only the lower 32 bits are compared, which is fine as long as the
+        // alarm is never set further ahead than a single rollover period.
+        // The interrupt handler modifies the same registers.
+        interrupt::free(|cs| {
+            let ticks = RATE.borrow(cs).get().ticks(at) as u32;
+            self.gpt
+                .set_output_compare_count(OutputCompareRegister::One, ticks);
+            self.gpt
+                .set_output_interrupt_on_compare(OutputCompareRegister::One, true);
+        });
+    }
+}
+
+impl enc28j60_smoltcp::Clock for Clock {
+    fn millis(&self) -> i64 {
+        millis()
+    }
+}
+
+/// Total number of GPT ticks since the clock was initialised.
+///
+/// Safe to call from interrupt handlers, including ones that preempt the
+/// GPT2 interrupt.
+pub fn ticks() -> u64 {
+    loop {
+        let high = ROLLOVERS.load(Ordering::Acquire);
+        let low = unsafe { ptr::read_volatile(GPT_CNT as *const u32) };
+        let pending = unsafe { ptr::read_volatile(GPT_SR as *const u32) } & SR_ROV != 0;
+        if ROLLOVERS.load(Ordering::Acquire) != high {
+            // The interrupt ran in between, so try again.
+            continue;
+        }
+        // If we preempted the GPT2 interrupt, the counter may have rolled
+        // over without the high word being updated yet.
+        let high = if pending && low < u32::max_value() / 2 {
+            high + 1
+        } else {
+            high
+        };
+        return (high as u64) << 32 | low as u64;
+    }
+}
+
+/// Milliseconds since the clock was initialised. Safe to call from interrupt
+/// handlers.
+pub fn millis() -> i64 {
+    interrupt::free(|cs| RATE.borrow(cs).get().millis(ticks()))
+}
+
+/// Microseconds since the clock was initialised, for timing short
+/// operations. Unlike `millis()`, this isn't corrected for drift.
+pub fn micros() -> i64 {
+    // 7.5 ticks per microsecond.
+    (ticks() * 2 / 15) as i64
+}
+
+/// Corrects the rate of the clock for a crystal that runs `drift_ppm` parts
+/// per million fast, or slow if negative, as measured by `Calibration`.
+pub fn set_drift_ppm(drift_ppm: i32) {
+    interrupt::free(|cs| {
+        let rate = RATE.borrow(cs);
+        let ticks = ticks();
+        rate.set(Rate {
+            base_ticks: ticks,
+            base_ms: rate.get().millis(ticks),
+            ticks_per_sec: TICKS_PER_SEC + TICKS_PER_SEC * drift_ppm as i64 / 1_000_000,
+        });
+    });
+}
+
+/// Handles the GPT2 interrupt. Returns `true` if the alarm went off.
+pub fn on_interrupt() -> bool {
+    unsafe {
+        let sr = ptr::read_volatile(GPT_SR as *const u32);
+        if sr & SR_ROV != 0 {
+            // Status flags are write-1-to-clear.
+            ptr::write_volatile(GPT_SR as *mut u32, SR_ROV);
+            ROLLOVERS.fetch_add(1, Ordering::AcqRel);
+        }
+        if sr & SR_OF1 != 0 {
+            // The alarm only goes off once.
+            let ir = ptr::read_volatile(GPT_IR as *const u32);
+            ptr::write_volatile(GPT_IR as *mut u32, ir & !IR_OF1IE);
+            ptr::write_volatile(GPT_SR as *mut u32, SR_OF1);
+        }
+        sr & SR_OF1 != 0
+    }
 }
@@ -0,0 +1,1047 @@
+pub mod defaults;
+mod store;
+
+use core::{fmt::Write, str::FromStr};
+
+use arrayvec::ArrayString;
+use dsmr42::{Field, SerializeOptions};
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+
+use crate::{
+    alerts::{AlertConfig, AlertRule, Condition, Quantity},
+    costs::{self, CostConfig},
+    crc::crc32,
+    graphite::GraphiteConfig,
+    influx::InfluxConfig,
+    multicast::{MulticastConfig, MulticastFormat},
+    network::proxy::{ProxyConfig, ProxyKind},
+    provisioning::ProvisioningConfig,
+    s0::S0Config,
+    sd_log::SdLogFormat,
+    sntp::SntpConfig,
+    statsd::StatsdConfig,
+    topic,
+    uart::{DataBits, DataRequestMode, Parity, UartConfig},
+};
+
+pub use store::ConfigStore;
+
+// Encoded size of a configuration. Must be a multiple of the flash page size.
+pub const RECORD_SZ: usize = 2048;
+// Records were these sizes before the multicast and the InfluxDB settings
+// were added, respectively. The layout is otherwise the same.
+const LEGACY_RECORD_SZS: [usize; 2] = [1024, 512];
+// Encoded configurations start with this, followed by the layout version.
+const MAGIC: [u8; 4] = *b"MRCF";
+const VERSION: u8 = 1;
+// Magic, version, sequence number and payload length.
+const HEADER_SZ: usize = 11;
+const CRC_SZ: usize = 4;
+
+pub type Topic = ArrayString<64>;
+
+const DEFAULT_RECONNECT_JITTER_PCT: u8 = 25;
+const DEFAULT_REJECT_QUIET_SECS: u32 = 60;
+const DEFAULT_TEMPERATURE_INTERVAL_MS: i64 = 60_000;
+const DEFAULT_UART_WATCHDOG_MINS: u16 = 10;
+
+/// Names of all settings, as used by `Config::get()` and `Config::set()`.
+pub const KEYS: [&str; 60] = [
+    "mqtt.host",
+    "mqtt.port",
+    "mqtt.client_id",
+    "mqtt.usage_topic",
+    "mqtt.status_topic",
+    "mqtt.diagnostics_topic",
+    "mqtt.costs_topic",
+    "mqtt.totals_topic",
+    "mqtt.peak_topic",
+    "mqtt.gas_topic",
+    "mqtt.fields",
+    "mqtt.proxy",
+    "mqtt.hmac_key",
+    "mqtt.reconnect_jitter",
+    "mqtt.reject_quiet_secs",
+    "network.address",
+    "network.gateway",
+    "uart.baud",
+    "uart.data_bits",
+    "uart.parity",
+    "uart.inverted",
+    "uart.autodetect",
+    "uart.data_request",
+    "uart.retransmit",
+    "uart.watchdog_mins",
+    "telegram_interval_ms",
+    "diagnostics_interval_ms",
+    "low_power",
+    "costs.tariff1_consumed",
+    "costs.tariff2_consumed",
+    "costs.tariff1_produced",
+    "costs.tariff2_produced",
+    "influx.host",
+    "influx.port",
+    "influx.org",
+    "influx.bucket",
+    "influx.token",
+    "graphite.host",
+    "graphite.port",
+    "graphite.prefix",
+    "statsd.host",
+    "statsd.port",
+    "statsd.prefix",
+    "multicast.group",
+    "multicast.port",
+    "multicast.format",
+    "multicast.interval_ms",
+    "sntp.server",
+    "provisioning.file",
+    "sd_log",
+    "aggregate",
+    "alerts.1",
+    "alerts.2",
+    "alerts.3",
+    "alerts.4",
+    "alerts.hysteresis",
+    "s0.enabled",
+    "s0.name",
+    "s0.pulses_per_unit",
+    "temperature_interval_ms",
+];
+
+/// Whether the value of `key` must be kept out of logs, which are streamed
+/// over the network as well.
+pub fn is_secret(key: &str) -> bool {
+    matches!(key, "mqtt.hmac_key" | "influx.token")
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SetError {
+    UnknownKey,
+    InvalidValue,
+}
+
+/// Settings that can be changed without rebuilding the firmware.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    pub mqtt: MqttConfig,
+    pub network: NetworkConfig,
+    pub uart: UartConfig,
+    /// Try other common configurations if `uart` doesn't work.
+    pub uart_autodetect: bool,
+    pub data_request: DataRequestMode,
+    /// Send valid telegrams on to another P1 consumer, on the UART's TX pin.
+    pub uart_retransmit: bool,
+    /// Minutes without a valid telegram after which the UART is
+    /// reinitialised, and after as many again the reader is reset. 0 to
+    /// never do either.
+    pub uart_watchdog_mins: u16,
+    /// Minimum time between published telegrams. Set this to e.g. 10 seconds
+    /// to reduce the load on the broker with DSMR 5 meters, which send one
+    /// every second.
+    pub min_telegram_interval_ms: i64,
+    /// Fields whose instantaneous values are summarised over the telegrams
+    /// between two published ones, instead of only publishing the last.
+    pub aggregate: SerializeOptions,
+    pub diagnostics_interval_ms: i64,
+    /// Let the CPU sleep between telegrams, once the meter's interval is known.
+    pub low_power: bool,
+    /// Energy prices. Running costs are only published once one is set.
+    pub costs: CostConfig,
+    /// Where to write telegrams to, besides MQTT.
+    pub influx: InfluxConfig,
+    /// Where to send metrics to, besides MQTT.
+    pub graphite: GraphiteConfig,
+    /// Where to send gauges to, besides MQTT.
+    pub statsd: StatsdConfig,
+    /// Where to send telegrams to on the local network, besides MQTT.
+    pub multicast: MulticastConfig,
+    pub sntp: SntpConfig,
+    pub provisioning: ProvisioningConfig,
+    /// How to write telegrams to the SD card, on boards that have a slot.
+    pub sd_log: SdLogFormat,
+    /// Rules raising alerts, which are published to MQTT.
+    pub alerts: AlertConfig,
+    /// The S0 pulse input, for a water meter or the like.
+    pub s0: S0Config,
+    /// How often to read the temperature sensors, 0 to not read them.
+    pub temperature_interval_ms: i64,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MqttConfig {
+    pub broker: Ipv4Address,
+    pub port: u16,
+    pub client_id: ArrayString<32>,
+    pub usage_topic: Topic,
+    pub status_topic: Topic,
+    pub diagnostics_topic: Topic,
+    pub costs_topic: Topic,
+    pub totals_topic: Topic,
+    pub peak_topic: Topic,
+    /// Gas readings are published here, only when the gas meter reports.
+    pub gas_topic: Topic,
+    /// Telegram fields to publish.
+    pub fields: SerializeOptions,
+    /// Proxy to reach the broker through, if it can't be reached directly.
+    pub proxy: ProxyConfig,
+    /// Key to sign usage data with, or empty to not sign it.
+    pub hmac_key: ArrayString<64>,
+    /// Percentage by which the reconnect backoff is randomly lengthened or
+    /// shortened, so readers don't all reconnect at once after the broker
+    /// restarts.
+    pub reconnect_jitter_pct: u8,
+    /// Minimum time to wait before reconnecting after the broker rejected
+    /// the connection, which a quick retry is unlikely to fix.
+    pub reject_quiet_secs: u32,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct NetworkConfig {
+    /// Use this address instead of DHCP.
+    pub static_address: Option<Ipv4Cidr>,
+    pub gateway: Option<Ipv4Address>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mqtt: MqttConfig {
+                broker: defaults::MQTT_BROKER,
+                port: defaults::MQTT_PORT,
+                client_id: str_or_empty(defaults::MQTT_CLIENT_ID),
+                usage_topic: str_or_empty(defaults::USAGE_TOPIC),
+                status_topic: str_or_empty(defaults::STATUS_TOPIC),
+                diagnostics_topic: str_or_empty(defaults::DIAGNOSTICS_TOPIC),
+                costs_topic: str_or_empty(defaults::COSTS_TOPIC),
+                totals_topic: str_or_empty(defaults::TOTALS_TOPIC),
+                peak_topic: str_or_empty(defaults::PEAK_TOPIC),
+                gas_topic: str_or_empty(defaults::GAS_TOPIC),
+                fields: SerializeOptions::ALL,
+                proxy: ProxyConfig::NONE,
+                hmac_key: ArrayString::new(),
+                reconnect_jitter_pct: DEFAULT_RECONNECT_JITTER_PCT,
+                reject_quiet_secs: DEFAULT_REJECT_QUIET_SECS,
+            },
+            network: NetworkConfig {
+                static_address: None,
+                gateway: None,
+            },
+            uart: defaults::UART,
+            uart_autodetect: true,
+            data_request: DataRequestMode::Continuous,
+            uart_retransmit: false,
+            uart_watchdog_mins: DEFAULT_UART_WATCHDOG_MINS,
+            min_telegram_interval_ms: 0,
+            aggregate: SerializeOptions::NONE,
+            diagnostics_interval_ms: 60_000,
+            low_power: false,
+            costs: CostConfig::default(),
+            influx: InfluxConfig::default(),
+            graphite: GraphiteConfig::default(),
+            statsd: StatsdConfig::default(),
+            multicast: MulticastConfig::default(),
+            sntp: SntpConfig::default(),
+            provisioning: ProvisioningConfig::default(),
+            sd_log: SdLogFormat::Csv,
+            alerts: AlertConfig::default(),
+            s0: S0Config::default(),
+            temperature_interval_ms: DEFAULT_TEMPERATURE_INTERVAL_MS,
+        }
+    }
+}
+
+fn str_or_empty<const CAP: usize>(s: &str) -> ArrayString<CAP> {
+    ArrayString::from(s).unwrap_or_default()
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, SetError> {
+    value.parse().map_err(|_| SetError::InvalidValue)
+}
+
+fn parse_positive(value: &str) -> Result<i64, SetError> {
+    match parse(value)? {
+        ms if ms > 0 => Ok(ms),
+        _ => Err(SetError::InvalidValue),
+    }
+}
+
+fn parse_price(value: &str) -> Result<u32, SetError> {
+    costs::parse_price(value).ok_or(SetError::InvalidValue)
+}
+
+/// Parses a comma-separated list of fields to include, like
+/// `consumed,produced,power`. Starting with `all` includes everything, after
+/// which fields prefixed with `-` are left out, like `all,-voltage`.
+fn parse_fields(value: &str) -> Result<SerializeOptions, SetError> {
+    let mut fields = SerializeOptions::NONE;
+    if value == "none" {
+        return Ok(fields);
+    }
+    for (index, name) in value.split(',').enumerate() {
+        fields = match name {
+            "all" if index == 0 => SerializeOptions::ALL,
+            name if name.starts_with('-') => {
+                fields.without(Field::from_name(&name[1..]).ok_or(SetError::InvalidValue)?)
+            }
+            name => fields.with(Field::from_name(name).ok_or(SetError::InvalidValue)?),
+        };
+    }
+    Ok(fields)
+}
+
+/// Formats fields in the shortest form `parse_fields()` accepts.
+fn format_fields<W: Write>(writer: &mut W, fields: &SerializeOptions) -> core::fmt::Result {
+    let included = Field::ALL.iter().filter(|f| fields.includes(**f)).count();
+    let mut separator = "";
+    if included == 0 {
+        write!(writer, "none")?;
+    } else if included > Field::ALL.len() / 2 {
+        write!(writer, "all")?;
+        for field in Field::ALL.iter().filter(|f| !fields.includes(**f)) {
+            write!(writer, ",-{}", field.name())?;
+        }
+    } else {
+        for field in Field::ALL.iter().filter(|f| fields.includes(**f)) {
+            write!(writer, "{}{}", separator, field.name())?;
+            separator = ",";
+        }
+    }
+    Ok(())
+}
+
+fn parse_alert(value: &str) -> Result<Option<AlertRule>, SetError> {
+    match value {
+        "none" => Ok(None),
+        rule => Ok(Some(parse(rule)?)),
+    }
+}
+
+fn format_alert<W: Write>(writer: &mut W, rule: &Option<AlertRule>) -> core::fmt::Result {
+    match rule {
+        Some(rule) => write!(writer, "{}", rule),
+        None => write!(writer, "none"),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, SetError> {
+    match value {
+        "true" | "on" | "1" => Ok(true),
+        "false" | "off" | "0" => Ok(false),
+        _ => Err(SetError::InvalidValue),
+    }
+}
+
+fn parse_str<const CAP: usize>(value: &str) -> Result<ArrayString<CAP>, SetError> {
+    ArrayString::from(value).map_err(|_| SetError::InvalidValue)
+}
+
+fn parse_topic(value: &str) -> Result<Topic, SetError> {
+    match topic::is_valid_template(value) {
+        true => parse_str(value),
+        false => Err(SetError::InvalidValue),
+    }
+}
+
+/// Parses a name that goes into the JSON telegrams as is.
+fn parse_name<const CAP: usize>(value: &str) -> Result<ArrayString<CAP>, SetError> {
+    let valid = value
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'_');
+    match valid && !value.is_empty() {
+        true => parse_str(value),
+        false => Err(SetError::InvalidValue),
+    }
+}
+
+impl Config {
+    /// Formats a single setting, in the same format `set()` accepts.
+    pub fn get(&self, key: &str) -> Option<ArrayString<64>> {
+        let mut value = ArrayString::new();
+        // Nothing we write here is longer than a topic.
+        let _ = match key {
+            "mqtt.host" => write!(value, "{}", self.mqtt.broker),
+            "mqtt.port" => write!(value, "{}", self.mqtt.port),
+            "mqtt.client_id" => write!(value, "{}", self.mqtt.client_id),
+            "mqtt.usage_topic" => write!(value, "{}", self.mqtt.usage_topic),
+            "mqtt.status_topic" => write!(value, "{}", self.mqtt.status_topic),
+            "mqtt.diagnostics_topic" => write!(value, "{}", self.mqtt.diagnostics_topic),
+            "mqtt.costs_topic" => write!(value, "{}", self.mqtt.costs_topic),
+            "mqtt.totals_topic" => write!(value, "{}", self.mqtt.totals_topic),
+            "mqtt.peak_topic" => write!(value, "{}", self.mqtt.peak_topic),
+            "mqtt.gas_topic" => write!(value, "{}", self.mqtt.gas_topic),
+            "mqtt.fields" => format_fields(&mut value, &self.mqtt.fields),
+            "mqtt.proxy" => write!(value, "{}", self.mqtt.proxy),
+            "mqtt.hmac_key" => match self.mqtt.hmac_key.is_empty() {
+                true => write!(value, "none"),
+                false => write!(value, "(hidden)"),
+            },
+            "mqtt.reconnect_jitter" => write!(value, "{}", self.mqtt.reconnect_jitter_pct),
+            "mqtt.reject_quiet_secs" => write!(value, "{}", self.mqtt.reject_quiet_secs),
+            "network.address" => match self.network.static_address {
+                Some(cidr) => write!(value, "{}", cidr),
+                None => write!(value, "dhcp"),
+            },
+            "network.gateway" => match self.network.gateway {
+                Some(gateway) => write!(value, "{}", gateway),
+                None => write!(value, "none"),
+            },
+            "uart.baud" => write!(value, "{}", self.uart.baud),
+            "uart.data_bits" => match self.uart.data_bits {
+                DataBits::Seven => write!(value, "7"),
+                DataBits::Eight => write!(value, "8"),
+            },
+            "uart.parity" => match self.uart.parity {
+                Parity::None => write!(value, "none"),
+                Parity::Even => write!(value, "even"),
+                Parity::Odd => write!(value, "odd"),
+            },
+            "uart.inverted" => write!(value, "{}", self.uart.inverted),
+            "uart.autodetect" => write!(value, "{}", self.uart_autodetect),
+            "uart.data_request" => match self.data_request {
+                DataRequestMode::Continuous => write!(value, "continuous"),
+                DataRequestMode::Interval { interval_ms } => write!(value, "{}", interval_ms),
+            },
+            "uart.retransmit" => write!(value, "{}", self.uart_retransmit),
+            "uart.watchdog_mins" => write!(value, "{}", self.uart_watchdog_mins),
+            "telegram_interval_ms" => write!(value, "{}", self.min_telegram_interval_ms),
+            "diagnostics_interval_ms" => write!(value, "{}", self.diagnostics_interval_ms),
+            "low_power" => write!(value, "{}", self.low_power),
+            "costs.tariff1_consumed" => costs::format_price(&mut value, self.costs.consumed[0]),
+            "costs.tariff2_consumed" => costs::format_price(&mut value, self.costs.consumed[1]),
+            "costs.tariff1_produced" => costs::format_price(&mut value, self.costs.produced[0]),
+            "costs.tariff2_produced" => costs::format_price(&mut value, self.costs.produced[1]),
+            "influx.host" => write!(value, "{}", self.influx.host),
+            "influx.port" => write!(value, "{}", self.influx.port),
+            "influx.org" => write!(value, "{}", self.influx.org),
+            "influx.bucket" => write!(value, "{}", self.influx.bucket),
+            // Too long to fit, and best not shown anyway.
+            "influx.token" => match self.influx.token.is_empty() {
+                true => write!(value, "none"),
+                false => write!(value, "(hidden)"),
+            },
+            "graphite.host" => write!(value, "{}", self.graphite.host),
+            "graphite.port" => write!(value, "{}", self.graphite.port),
+            "graphite.prefix" => write!(value, "{}", self.graphite.prefix),
+            "statsd.host" => write!(value, "{}", self.statsd.host),
+            "statsd.port" => write!(value, "{}", self.statsd.port),
+            "statsd.prefix" => write!(value, "{}", self.statsd.prefix),
+            "multicast.group" => write!(value, "{}", self.multicast.group),
+            "multicast.port" => write!(value, "{}", self.multicast.port),
+            "multicast.format" => write!(value, "{}", self.multicast.format.name()),
+            "multicast.interval_ms" => write!(value, "{}", self.multicast.interval_ms),
+            "sntp.server" => write!(value, "{}", self.sntp.server),
+            "provisioning.file" => match self.provisioning.file.is_empty() {
+                true => write!(value, "none"),
+                false => write!(value, "{}", self.provisioning.file),
+            },
+            "sd_log" => write!(value, "{}", self.sd_log.name()),
+            "aggregate" => format_fields(&mut value, &self.aggregate),
+            "alerts.1" => format_alert(&mut value, &self.alerts.rules[0]),
+            "alerts.2" => format_alert(&mut value, &self.alerts.rules[1]),
+            "alerts.3" => format_alert(&mut value, &self.alerts.rules[2]),
+            "alerts.4" => format_alert(&mut value, &self.alerts.rules[3]),
+            "alerts.hysteresis" => write!(value, "{}", self.alerts.hysteresis_pct),
+            "s0.enabled" => write!(value, "{}", self.s0.enabled),
+            "s0.name" => write!(value, "{}", self.s0.name),
+            "s0.pulses_per_unit" => write!(value, "{}", self.s0.pulses_per_unit),
+            "temperature_interval_ms" => write!(value, "{}", self.temperature_interval_ms),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Changes a single setting.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), SetError> {
+        match key {
+            "mqtt.host" => self.mqtt.broker = parse(value)?,
+            "mqtt.port" => self.mqtt.port = parse(value)?,
+            "mqtt.client_id" => self.mqtt.client_id = parse_str(value)?,
+            "mqtt.usage_topic" => self.mqtt.usage_topic = parse_topic(value)?,
+            "mqtt.status_topic" => self.mqtt.status_topic = parse_topic(value)?,
+            "mqtt.diagnostics_topic" => self.mqtt.diagnostics_topic = parse_topic(value)?,
+            "mqtt.costs_topic" => self.mqtt.costs_topic = parse_topic(value)?,
+            "mqtt.totals_topic" => self.mqtt.totals_topic = parse_topic(value)?,
+            "mqtt.peak_topic" => self.mqtt.peak_topic = parse_topic(value)?,
+            "mqtt.gas_topic" => self.mqtt.gas_topic = parse_topic(value)?,
+            "mqtt.fields" => self.mqtt.fields = parse_fields(value)?,
+            "mqtt.proxy" => self.mqtt.proxy = parse(value)?,
+            "mqtt.hmac_key" => {
+                self.mqtt.hmac_key = match value {
+                    "none" => ArrayString::new(),
+                    key => parse_str(key)?,
+                }
+            }
+            "mqtt.reconnect_jitter" => {
+                self.mqtt.reconnect_jitter_pct = match parse(value)? {
+                    pct @ 0..=100 => pct,
+                    _ => return Err(SetError::InvalidValue),
+                }
+            }
+            "mqtt.reject_quiet_secs" => self.mqtt.reject_quiet_secs = parse(value)?,
+            "network.address" => {
+                self.network.static_address = match value {
+                    "dhcp" => None,
+                    cidr => Some(parse(cidr)?),
+                }
+            }
+            "network.gateway" => {
+                self.network.gateway = match value {
+                    "none" => None,
+                    gateway => Some(parse(gateway)?),
+                }
+            }
+            "uart.baud" => self.uart.baud = parse(value)?,
+            "uart.data_bits" => {
+                self.uart.data_bits = match value {
+                    "7" => DataBits::Seven,
+                    "8" => DataBits::Eight,
+                    _ => return Err(SetError::InvalidValue),
+                }
+            }
+            "uart.parity" => {
+                self.uart.parity = match value {
+                    "none" => Parity::None,
+                    "even" => Parity::Even,
+                    "odd" => Parity::Odd,
+                    _ => return Err(SetError::InvalidValue),
+                }
+            }
+            "uart.inverted" => self.uart.inverted = parse_bool(value)?,
+            "uart.autodetect" => self.uart_autodetect = parse_bool(value)?,
+            "uart.data_request" => {
+                self.data_request = match value {
+                    "continuous" => DataRequestMode::Continuous,
+                    interval => DataRequestMode::Interval {
+                        interval_ms: parse_positive(interval)?,
+                    },
+                }
+            }
+            "uart.retransmit" => self.uart_retransmit = parse_bool(value)?,
+            "uart.watchdog_mins" => self.uart_watchdog_mins = parse(value)?,
+            "telegram_interval_ms" => self.min_telegram_interval_ms = parse(value)?,
+            "diagnostics_interval_ms" => self.diagnostics_interval_ms = parse_positive(value)?,
+            "low_power" => self.low_power = parse_bool(value)?,
+            "costs.tariff1_consumed" => self.costs.consumed[0] = parse_price(value)?,
+            "costs.tariff2_consumed" => self.costs.consumed[1] = parse_price(value)?,
+            "costs.tariff1_produced" => self.costs.produced[0] = parse_price(value)?,
+            "costs.tariff2_produced" => self.costs.produced[1] = parse_price(value)?,
+            "influx.host" => self.influx.host = parse(value)?,
+            "influx.port" => self.influx.port = parse(value)?,
+            "influx.org" => self.influx.org = parse_str(value)?,
+            "influx.bucket" => self.influx.bucket = parse_str(value)?,
+            "influx.token" => {
+                self.influx.token = match value {
+                    "none" => ArrayString::new(),
+                    token => parse_str(token)?,
+                }
+            }
+            "graphite.host" => self.graphite.host = parse(value)?,
+            "graphite.port" => self.graphite.port = parse(value)?,
+            "graphite.prefix" => self.graphite.prefix = parse_str(value)?,
+            "statsd.host" => self.statsd.host = parse(value)?,
+            "statsd.port" => self.statsd.port = parse(value)?,
+            "statsd.prefix" => self.statsd.prefix = parse_str(value)?,
+            "multicast.group" => {
+                self.multicast.group = match parse(value)? {
+                    group if group.is_unspecified() || group.is_multicast() => group,
+                    _ => return Err(SetError::InvalidValue),
+                }
+            }
+            "multicast.port" => self.multicast.port = parse(value)?,
+            "multicast.format" => {
+                self.multicast.format = match value {
+                    "json" => MulticastFormat::Json,
+                    "binary" => MulticastFormat::Binary,
+                    _ => return Err(SetError::InvalidValue),
+                }
+            }
+            "multicast.interval_ms" => {
+                self.multicast.interval_ms = match parse(value)? {
+                    ms if ms >= 0 => ms,
+                    _ => return Err(SetError::InvalidValue),
+                }
+            }
+            "sntp.server" => self.sntp.server = parse(value)?,
+            "provisioning.file" => {
+                self.provisioning.file = match value {
+                    "none" => ArrayString::new(),
+                    file => parse_str(file)?,
+                }
+            }
+            "sd_log" => {
+                self.sd_log = match value {
+                    "off" => SdLogFormat::Off,
+                    "csv" => SdLogFormat::Csv,
+                    "ndjson" => SdLogFormat::Ndjson,
+                    _ => return Err(SetError::InvalidValue),
+                }
+            }
+            "aggregate" => self.aggregate = parse_fields(value)?,
+            "alerts.1" => self.alerts.rules[0] = parse_alert(value)?,
+            "alerts.2" => self.alerts.rules[1] = parse_alert(value)?,
+            "alerts.3" => self.alerts.rules[2] = parse_alert(value)?,
+            "alerts.4" => self.alerts.rules[3] = parse_alert(value)?,
+            "alerts.hysteresis" => {
+                self.alerts.hysteresis_pct = match parse(value)? {
+                    pct @ 0..=100 => pct,
+                    _ => return Err(SetError::InvalidValue),
+                }
+            }
+            "s0.enabled" => self.s0.enabled = parse_bool(value)?,
+            "s0.name" => self.s0.name = parse_name(value)?,
+            "s0.pulses_per_unit" => {
+                self.s0.pulses_per_unit = match parse(value)? {
+                    0 => return Err(SetError::InvalidValue),
+                    pulses => pulses,
+                }
+            }
+            "temperature_interval_ms" => {
+                self.temperature_interval_ms = match parse(value)? {
+                    ms if ms >= 0 => ms,
+                    _ => return Err(SetError::InvalidValue),
+                }
+            }
+            _ => return Err(SetError::UnknownKey),
+        }
+        Ok(())
+    }
+
+    /// Encodes the configuration into a record that can be written to flash.
+    pub fn encode(&self, sequence: u32) -> [u8; RECORD_SZ] {
+        let mut record = [0xFF; RECORD_SZ];
+        let mut w = Writer {
+            buf: &mut record[HEADER_SZ..RECORD_SZ - CRC_SZ],
+            pos: 0,
+        };
+
+        w.bytes(&self.mqtt.broker.0);
+        w.u16(self.mqtt.port);
+        w.str(&self.mqtt.client_id);
+        w.str(&self.mqtt.usage_topic);
+        w.str(&self.mqtt.status_topic);
+        w.str(&self.mqtt.diagnostics_topic);
+
+        match self.network.static_address {
+            Some(cidr) => {
+                w.u8(1);
+                w.bytes(&cidr.address().0);
+                w.u8(cidr.prefix_len());
+            }
+            None => w.u8(0),
+        }
+        match self.network.gateway {
+            Some(gateway) => {
+                w.u8(1);
+                w.bytes(&gateway.0);
+            }
+            None => w.u8(0),
+        }
+
+        w.u32(self.uart.baud);
+        w.u8(match self.uart.data_bits {
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        });
+        w.u8(match self.uart.parity {
+            Parity::None => 0,
+            Parity::Even => 1,
+            Parity::Odd => 2,
+        });
+        w.u8(self.uart.inverted as u8);
+        w.u8(self.uart_autodetect as u8);
+        match self.data_request {
+            DataRequestMode::Continuous => w.u8(0),
+            DataRequestMode::Interval { interval_ms } => {
+                w.u8(1);
+                w.u32(interval_ms as u32);
+            }
+        }
+        w.u32(self.min_telegram_interval_ms as u32);
+        w.u32(self.diagnostics_interval_ms as u32);
+        w.str(&self.mqtt.costs_topic);
+        for rate in self.costs.consumed.iter().chain(self.costs.produced.iter()) {
+            w.u32(*rate);
+        }
+        w.str(&self.mqtt.totals_topic);
+        w.str(&self.mqtt.peak_topic);
+        w.bytes(&self.influx.host.0);
+        w.u16(self.influx.port);
+        w.str(&self.influx.org);
+        w.str(&self.influx.bucket);
+        w.str(&self.influx.token);
+        w.bytes(&self.graphite.host.0);
+        w.u16(self.graphite.port);
+        w.str(&self.graphite.prefix);
+        w.bytes(&self.statsd.host.0);
+        w.u16(self.statsd.port);
+        w.str(&self.statsd.prefix);
+        w.u16(self.mqtt.fields.bits());
+        w.bytes(&self.sntp.server.0);
+        w.u8(match self.mqtt.proxy.kind {
+            ProxyKind::None => 0,
+            ProxyKind::Http => 1,
+            ProxyKind::Socks5 => 2,
+        });
+        w.bytes(&self.mqtt.proxy.host.0);
+        w.u16(self.mqtt.proxy.port);
+        w.u8(self.low_power as u8);
+        w.str(&self.mqtt.hmac_key);
+        w.u8(self.mqtt.reconnect_jitter_pct);
+        w.u32(self.mqtt.reject_quiet_secs);
+        w.str(&self.mqtt.gas_topic);
+        w.str(&self.provisioning.file);
+        w.u8(match self.sd_log {
+            SdLogFormat::Off => 0,
+            SdLogFormat::Csv => 1,
+            SdLogFormat::Ndjson => 2,
+        });
+        w.u16(self.aggregate.bits());
+        for rule in self.alerts.rules.iter() {
+            match rule {
+                Some(rule) => {
+                    w.u8(rule.quantity.to_u8());
+                    let (condition, threshold) = match rule.condition {
+                        Condition::Above(threshold) => (0, threshold),
+                        Condition::Below(threshold) => (1, threshold),
+                        Condition::Increased => (2, 0),
+                    };
+                    w.u8(condition);
+                    w.u32(threshold as u32);
+                    w.u32(rule.for_secs);
+                }
+                None => w.u8(0),
+            }
+        }
+        w.u8(self.alerts.hysteresis_pct);
+        w.u8(self.s0.enabled as u8);
+        w.str(&self.s0.name);
+        w.u32(self.s0.pulses_per_unit);
+        w.u32(self.temperature_interval_ms as u32);
+        w.u8(self.uart_retransmit as u8);
+        w.bytes(&self.multicast.group.0);
+        w.u16(self.multicast.port);
+        w.u8(match self.multicast.format {
+            MulticastFormat::Json => 0,
+            MulticastFormat::Binary => 1,
+        });
+        w.u32(self.multicast.interval_ms as u32);
+        w.u16(self.uart_watchdog_mins);
+
+        let len = w.pos as u16;
+        record[..4].copy_from_slice(&MAGIC);
+        record[4] = VERSION;
+        record[5..9].copy_from_slice(&sequence.to_le_bytes());
+        record[9..11].copy_from_slice(&len.to_le_bytes());
+        let crc = crc32(&record[..RECORD_SZ - CRC_SZ]);
+        record[RECORD_SZ - CRC_SZ..].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    /// Decodes a record, returning its sequence number and the configuration
+    /// if it is valid.
+    pub fn decode<const SZ: usize>(record: &[u8; SZ]) -> Option<(u32, Config)> {
+        if record[..4] != MAGIC || record[4] != VERSION {
+            return None;
+        }
+        let mut crc = [0; CRC_SZ];
+        crc.copy_from_slice(&record[SZ - CRC_SZ..]);
+        if crc32(&record[..SZ - CRC_SZ]) != u32::from_le_bytes(crc) {
+            return None;
+        }
+        let sequence = u32::from_le_bytes([record[5], record[6], record[7], record[8]]);
+        let len = u16::from_le_bytes([record[9], record[10]]) as usize;
+        let mut r = Reader {
+            buf: record[HEADER_SZ..SZ - CRC_SZ].get(..len)?,
+            pos: 0,
+        };
+
+        let mqtt = MqttConfig {
+            broker: Ipv4Address(r.array()?),
+            port: r.u16()?,
+            client_id: r.str()?,
+            usage_topic: r.str()?,
+            status_topic: r.str()?,
+            diagnostics_topic: r.str()?,
+            costs_topic: str_or_empty(defaults::COSTS_TOPIC),
+            totals_topic: str_or_empty(defaults::TOTALS_TOPIC),
+            peak_topic: str_or_empty(defaults::PEAK_TOPIC),
+            gas_topic: str_or_empty(defaults::GAS_TOPIC),
+            fields: SerializeOptions::ALL,
+            proxy: ProxyConfig::NONE,
+            hmac_key: ArrayString::new(),
+            reconnect_jitter_pct: DEFAULT_RECONNECT_JITTER_PCT,
+            reject_quiet_secs: DEFAULT_REJECT_QUIET_SECS,
+        };
+        let static_address = match r.u8()? {
+            0 => None,
+            _ => {
+                let address = Ipv4Address(r.array()?);
+                match r.u8()? {
+                    prefix_len @ 0..=32 => Some(Ipv4Cidr::new(address, prefix_len)),
+                    _ => return None,
+                }
+            }
+        };
+        let gateway = match r.u8()? {
+            0 => None,
+            _ => Some(Ipv4Address(r.array()?)),
+        };
+        let uart = UartConfig {
+            baud: r.u32()?,
+            data_bits: match r.u8()? {
+                7 => DataBits::Seven,
+                8 => DataBits::Eight,
+                _ => return None,
+            },
+            parity: match r.u8()? {
+                0 => Parity::None,
+                1 => Parity::Even,
+                2 => Parity::Odd,
+                _ => return None,
+            },
+            inverted: r.u8()? != 0,
+        };
+        let uart_autodetect = r.u8()? != 0;
+        let data_request = match r.u8()? {
+            0 => DataRequestMode::Continuous,
+            _ => DataRequestMode::Interval {
+                interval_ms: r.u32()? as i64,
+            },
+        };
+
+        let mut config = Config {
+            mqtt,
+            network: NetworkConfig {
+                static_address,
+                gateway,
+            },
+            uart,
+            uart_autodetect,
+            data_request,
+            uart_retransmit: false,
+            uart_watchdog_mins: DEFAULT_UART_WATCHDOG_MINS,
+            min_telegram_interval_ms: r.u32()? as i64,
+            aggregate: SerializeOptions::NONE,
+            diagnostics_interval_ms: r.u32()? as i64,
+            low_power: false,
+            costs: CostConfig::default(),
+            influx: InfluxConfig::default(),
+            graphite: GraphiteConfig::default(),
+            statsd: StatsdConfig::default(),
+            multicast: MulticastConfig::default(),
+            sntp: SntpConfig::default(),
+            provisioning: ProvisioningConfig::default(),
+            sd_log: SdLogFormat::Csv,
+            alerts: AlertConfig::default(),
+            s0: S0Config::default(),
+            temperature_interval_ms: DEFAULT_TEMPERATURE_INTERVAL_MS,
+        };
+        // Added later, so records saved by older firmware end here.
+        if let Some(topic) = r.str() {
+            config.mqtt.costs_topic = topic;
+            for rate in config
+                .costs
+                .consumed
+                .iter_mut()
+                .chain(config.costs.produced.iter_mut())
+            {
+                *rate = r.u32()?;
+            }
+        }
+        if let Some(topic) = r.str() {
+            config.mqtt.totals_topic = topic;
+        }
+        if let Some(topic) = r.str() {
+            config.mqtt.peak_topic = topic;
+        }
+        if let Some(host) = r.array() {
+            config.influx = InfluxConfig {
+                host: Ipv4Address(host),
+                port: r.u16()?,
+                org: r.str()?,
+                bucket: r.str()?,
+                token: r.str()?,
+            };
+        }
+        if let Some(host) = r.array() {
+            config.graphite = GraphiteConfig {
+                host: Ipv4Address(host),
+                port: r.u16()?,
+                prefix: r.str()?,
+            };
+        }
+        if let Some(host) = r.array() {
+            config.statsd = StatsdConfig {
+                host: Ipv4Address(host),
+                port: r.u16()?,
+                prefix: r.str()?,
+            };
+        }
+        if let Some(fields) = r.u16() {
+            config.mqtt.fields = SerializeOptions::from_bits(fields);
+        }
+        if let Some(server) = r.array() {
+            config.sntp.server = Ipv4Address(server);
+        }
+        if let Some(kind) = r.u8() {
+            config.mqtt.proxy = ProxyConfig {
+                kind: match kind {
+                    1 => ProxyKind::Http,
+                    2 => ProxyKind::Socks5,
+                    _ => ProxyKind::None,
+                },
+                host: Ipv4Address(r.array()?),
+                port: r.u16()?,
+            };
+        }
+        if let Some(low_power) = r.u8() {
+            config.low_power = low_power != 0;
+        }
+        if let Some(key) = r.str() {
+            config.mqtt.hmac_key = key;
+        }
+        if let Some(pct) = r.u8() {
+            config.mqtt.reconnect_jitter_pct = pct.min(100);
+            config.mqtt.reject_quiet_secs = r.u32()?;
+        }
+        if let Some(topic) = r.str() {
+            config.mqtt.gas_topic = topic;
+        }
+        if let Some(file) = r.str() {
+            config.provisioning.file = file;
+        }
+        if let Some(format) = r.u8() {
+            config.sd_log = match format {
+                0 => SdLogFormat::Off,
+                2 => SdLogFormat::Ndjson,
+                _ => SdLogFormat::Csv,
+            };
+        }
+        if let Some(fields) = r.u16() {
+            config.aggregate = SerializeOptions::from_bits(fields);
+        }
+        if !r.at_end() {
+            for rule in config.alerts.rules.iter_mut() {
+                *rule = match r.u8()? {
+                    0 => None,
+                    quantity => {
+                        let quantity = Quantity::from_u8(quantity)?;
+                        let condition = r.u8()?;
+                        let threshold = r.u32()? as i32;
+                        Some(AlertRule {
+                            quantity,
+                            condition: match condition {
+                                0 => Condition::Above(threshold),
+                                1 => Condition::Below(threshold),
+                                _ => Condition::Increased,
+                            },
+                            for_secs: r.u32()?,
+                        })
+                    }
+                };
+            }
+            config.alerts.hysteresis_pct = r.u8()?.min(100);
+        }
+        if let Some(enabled) = r.u8() {
+            config.s0 = S0Config {
+                enabled: enabled != 0,
+                name: r.str()?,
+                pulses_per_unit: r.u32()?.max(1),
+            };
+        }
+        if let Some(ms) = r.u32() {
+            config.temperature_interval_ms = ms as i64;
+        }
+        if let Some(retransmit) = r.u8() {
+            config.uart_retransmit = retransmit != 0;
+        }
+        if let Some(group) = r.array() {
+            config.multicast = MulticastConfig {
+                group: Ipv4Address(group),
+                port: r.u16()?,
+                format: match r.u8()? {
+                    1 => MulticastFormat::Binary,
+                    _ => MulticastFormat::Json,
+                },
+                interval_ms: r.u32()? as i64,
+            };
+        }
+        if let Some(mins) = r.u16() {
+            config.uart_watchdog_mins = mins;
+        }
+        Some((sequence, config))
+    }
+}
+
+/// Little-endian encoder. Everything in a `Config` is bounded in size, so
+/// the record always has enough room.
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    fn u8(&mut self, val: u8) {
+        self.bytes(&[val]);
+    }
+
+    fn u16(&mut self, val: u16) {
+        self.bytes(&val.to_le_bytes());
+    }
+
+    fn u32(&mut self, val: u32) {
+        self.bytes(&val.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u8(s.len() as u8);
+        self.bytes(s.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn at_end(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Some(array)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.array()?))
+    }
+
+    fn str<const CAP: usize>(&mut self) -> Option<ArrayString<CAP>> {
+        let len = self.u8()? as usize;
+        let s = core::str::from_utf8(self.bytes(len)?).ok()?;
+        ArrayString::from(s).ok()
+    }
+}
@@ -0,0 +1,8 @@
+//! The settings a reader starts out with, generated by `build.rs` from
+//! `config.toml`, or the file `METER_READER_CONFIG` points to.
+
+use smoltcp::wire::Ipv4Address;
+
+use crate::uart::{DataBits, Parity, UartConfig};
+
+include!(concat!(env!("OUT_DIR"), "/defaults.rs"));
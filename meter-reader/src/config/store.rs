@@ -0,0 +1,135 @@
+use super::{Config, LEGACY_RECORD_SZS, RECORD_SZ};
+use crate::flash::{self, FlashError, PAGE_SZ, SECTOR_SZ};
+
+// The Teensy linker scripts leave the end of flash to the EEPROM emulation,
+// which we don't use. Its first two sectors hold the configuration.
+const BANK_OFFSETS: [u32; 2] = [flash::FIRMWARE_MAX_SZ, flash::FIRMWARE_MAX_SZ + SECTOR_SZ];
+const SLOTS_PER_BANK: usize = SECTOR_SZ as usize / RECORD_SZ;
+
+/// Persists the configuration in two flash sectors.
+///
+/// Each save is appended to the first free slot of the sector holding the
+/// newest configuration, so a sector is only erased once every
+/// `SLOTS_PER_BANK` saves. When it is full, the other sector is erased and
+/// written instead, so a valid configuration survives a power loss at any
+/// point. Records carry a sequence number and a CRC, and the newest valid
+/// one wins.
+pub struct ConfigStore {
+    sequence: u32,
+    // Bank and slot of the newest valid record.
+    latest: Option<(usize, usize)>,
+}
+
+impl ConfigStore {
+    /// Loads the newest valid configuration from flash, if there is one.
+    pub fn load() -> (Self, Option<Config>) {
+        let mut store = Self {
+            sequence: 0,
+            latest: None,
+        };
+        let mut config = None;
+        for bank in 0..BANK_OFFSETS.len() {
+            for slot in 0..SLOTS_PER_BANK {
+                if let Some((sequence, c)) = Config::decode(&read_record(bank, slot)) {
+                    if store.latest.is_none() || sequence > store.sequence {
+                        store.sequence = sequence;
+                        store.latest = Some((bank, slot));
+                        config = Some(c);
+                    }
+                }
+            }
+        }
+        if store.latest.is_none() {
+            // Left for the next save to erase, which also takes care of
+            // slots that wouldn't line up with the new record size.
+            let legacy = [
+                load_legacy::<{ LEGACY_RECORD_SZS[0] }>(),
+                load_legacy::<{ LEGACY_RECORD_SZS[1] }>(),
+            ];
+            let newest = legacy.iter().flatten().copied().max_by_key(|(s, _)| *s);
+            if let Some((sequence, c)) = newest {
+                log::info!("Loaded configuration {} saved by older firmware", sequence);
+                store.sequence = sequence;
+                return (store, Some(c));
+            }
+        }
+        match store.latest {
+            Some((bank, slot)) => log::info!(
+                "Loaded configuration {} from bank {}, slot {}",
+                store.sequence,
+                bank,
+                slot
+            ),
+            None => log::info!("No stored configuration found, using defaults"),
+        }
+        (store, config)
+    }
+
+    pub fn save(&mut self, config: &Config) -> Result<(), FlashError> {
+        let sequence = self.sequence.wrapping_add(1);
+        let (bank, slot) = match self.latest {
+            Some((bank, slot)) if slot + 1 < SLOTS_PER_BANK && is_erased(bank, slot + 1) => {
+                (bank, slot + 1)
+            }
+            Some((bank, _)) => (1 - bank, 0),
+            None => (0, 0),
+        };
+        if slot == 0 {
+            flash::erase(BANK_OFFSETS[bank], SECTOR_SZ)?;
+        }
+        let record = config.encode(sequence);
+        program_record(bank, slot, &record)?;
+        if Config::decode(&read_record(bank, slot)).map(|(s, _)| s) != Some(sequence) {
+            return Err(FlashError::Verify);
+        }
+        log::info!(
+            "Saved configuration {} to bank {}, slot {}",
+            sequence,
+            bank,
+            slot
+        );
+        self.sequence = sequence;
+        self.latest = Some((bank, slot));
+        Ok(())
+    }
+}
+
+/// Finds the newest configuration written with an old record size.
+fn load_legacy<const SZ: usize>() -> Option<(u32, Config)> {
+    let mut newest: Option<(u32, Config)> = None;
+    for bank in 0..BANK_OFFSETS.len() {
+        for slot in 0..SECTOR_SZ as usize / SZ {
+            let mut record = [0; SZ];
+            flash::read(BANK_OFFSETS[bank] + (slot * SZ) as u32, &mut record);
+            if let Some((sequence, config)) = Config::decode(&record) {
+                if newest.map_or(true, |(newest, _)| sequence > newest) {
+                    newest = Some((sequence, config));
+                }
+            }
+        }
+    }
+    newest
+}
+
+fn record_offset(bank: usize, slot: usize) -> u32 {
+    BANK_OFFSETS[bank] + (slot * RECORD_SZ) as u32
+}
+
+fn read_record(bank: usize, slot: usize) -> [u8; RECORD_SZ] {
+    let mut record = [0; RECORD_SZ];
+    flash::read(record_offset(bank, slot), &mut record);
+    record
+}
+
+fn is_erased(bank: usize, slot: usize) -> bool {
+    read_record(bank, slot).iter().all(|b| *b == 0xFF)
+}
+
+fn program_record(bank: usize, slot: usize, record: &[u8; RECORD_SZ]) -> Result<(), FlashError> {
+    let mut page = [0; PAGE_SZ];
+    for (index, chunk) in record.chunks(PAGE_SZ).enumerate() {
+        page.copy_from_slice(chunk);
+        flash::program_page(record_offset(bank, slot) + (index * PAGE_SZ) as u32, &page)?;
+    }
+    Ok(())
+}
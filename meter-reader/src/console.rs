@@ -0,0 +1,214 @@
+use arrayvec::ArrayVec;
+#[cfg(not(feature = "sim"))]
+use teensy4_bsp::usb::Reader;
+
+#[cfg(feature = "sim")]
+use crate::sim::console::Reader;
+use crate::{
+    config::{self, Config, ConfigStore, SetError},
+    diagnostics::{self, Diagnostics},
+    events::Event,
+    logging,
+};
+
+const MAX_LINE_LENGTH: usize = 128;
+
+/// A command line on the USB serial port, for provisioning devices without
+/// rebuilding the firmware. Output goes to the log.
+///
+/// Supported commands:
+/// - `show config`: print all settings
+/// - `show status`: print the current diagnostics
+/// - `show errors`: print the most recent telegrams that failed to parse
+/// - `show net`: print the address, gateway and neighbours on the network
+/// - `set <key> <value>`: change a setting
+/// - `save`: write the settings to flash
+/// - `reboot`: restart, applying saved settings
+/// - `log <level>`: change the log level until the next reboot
+/// - `selftest`: check the hardware and the connection to the broker
+/// - `dump log`: print the records in the SPI flash log
+pub struct Console {
+    // Absent if USB failed to initialise.
+    reader: Option<Reader>,
+    line: ArrayVec<u8, MAX_LINE_LENGTH>,
+    // Dropping input until the end of a line that was too long.
+    discarding: bool,
+    config: Config,
+    store: ConfigStore,
+}
+
+impl Console {
+    /// `config` is the configuration currently in use.
+    pub fn new(reader: Option<Reader>, config: Config, store: ConfigStore) -> Self {
+        Self {
+            reader,
+            line: ArrayVec::new(),
+            discarding: false,
+            config,
+            store,
+        }
+    }
+
+    /// Runs any commands that came in. Returns an event for the commands that
+    /// other subsystems act on, like `ConfigChanged` once the configuration has
+    /// been saved.
+    pub fn poll(&mut self, status: &Diagnostics) -> Option<Event> {
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => return None,
+        };
+        let mut event = None;
+        let mut buf = [0; 64];
+        let read = reader.read(&mut buf);
+        for b in buf[..read].iter() {
+            match b {
+                b'\r' | b'\n' => {
+                    if !self.discarding {
+                        event = self.run_line(status).or(event);
+                    }
+                    self.line.clear();
+                    self.discarding = false;
+                }
+                b => {
+                    if self.line.try_push(*b).is_err() && !self.discarding {
+                        log::warn!("Command too long, ignoring it");
+                        self.discarding = true;
+                    }
+                }
+            }
+        }
+        event
+    }
+
+    /// Saves a configuration fetched at boot, and asks for a reboot to apply
+    /// it.
+    pub fn provision(&mut self, config: Config) -> Option<Event> {
+        // The console may have been used to make the same changes meanwhile.
+        if config == self.config {
+            log::info!("Provisioned configuration matches the current one");
+            return None;
+        }
+        self.config = config;
+        match self.store.save(&self.config) {
+            Ok(()) => {
+                log::info!("Saved provisioned configuration, rebooting");
+                return Some(Event::ResetRequested);
+            }
+            Err(err) => log::error!("Failed to save provisioned configuration: {:?}", err),
+        }
+        None
+    }
+
+    fn run_line(&mut self, status: &Diagnostics) -> Option<Event> {
+        let line = match core::str::from_utf8(&self.line) {
+            Ok(line) => line.trim(),
+            Err(_) => {
+                log::warn!("Command is not valid UTF-8");
+                return None;
+            }
+        };
+        if line.is_empty() {
+            return None;
+        }
+        let mut words = line.splitn(3, ' ');
+        let command = (words.next(), words.next(), words.next());
+        match command {
+            (Some("set"), Some(key), Some(_)) if config::is_secret(key) => {
+                log::info!("> set {} <redacted>", key)
+            }
+            _ => log::info!("> {}", line),
+        }
+
+        match command {
+            (Some("show"), Some("config"), None) => {
+                for key in config::KEYS.iter() {
+                    if let Some(value) = self.config.get(key) {
+                        log::info!("{} = {}", key, value);
+                    }
+                }
+            }
+            (Some("show"), Some("status"), None) => {
+                let mut json = arrayvec::ArrayString::<{ diagnostics::MAX_SERIALIZED_LEN }>::new();
+                match status.serialize(&mut json) {
+                    Ok(()) => log::info!("{}", json),
+                    Err(_) => log::warn!("Status does not fit in {} bytes", json.capacity()),
+                }
+            }
+            (Some("show"), Some("errors"), None) => {
+                if status.parse_errors.last().is_none() {
+                    log::info!("No parse errors since boot");
+                }
+                for failure in status.parse_errors.iter() {
+                    let mut hex = arrayvec::ArrayString::<64>::new();
+                    // Fits: 16 bytes of 3 characters each.
+                    let _ = failure.write_hex(&mut hex, " ");
+                    log::info!(
+                        "At {} s: {}, {} bytes buffered, at offset {:?}; from {}: {}",
+                        failure.at / 1000,
+                        failure.kind,
+                        failure.buffer_len,
+                        failure.offset,
+                        failure.dump_start(),
+                        hex
+                    );
+                }
+            }
+            (Some("show"), Some("net"), None) => {
+                log::info!("Network: {}", status.network);
+                match status.cidr {
+                    Some(cidr) => log::info!("Address: {}", cidr),
+                    None => log::info!("Address: none"),
+                }
+                match status.gateway {
+                    Some(gateway) => log::info!("Gateway: {}", gateway),
+                    None => log::info!("Gateway: none"),
+                }
+                if status.neighbours.iter().next().is_none() {
+                    log::info!("No neighbours heard from");
+                }
+                for neighbour in status.neighbours.iter() {
+                    log::info!(
+                        "Neighbour: {} at {}",
+                        neighbour.addr,
+                        neighbour.hardware_addr
+                    );
+                }
+            }
+            (Some("set"), Some(key), Some(value)) => {
+                let value = value.trim();
+                let shown = match config::is_secret(key) {
+                    true => "<redacted>",
+                    false => value,
+                };
+                match self.config.set(key, value) {
+                    Ok(()) => log::info!("{} = {} (save and reboot to apply)", key, shown),
+                    Err(SetError::UnknownKey) => log::warn!("Unknown setting: {}", key),
+                    Err(SetError::InvalidValue) => {
+                        log::warn!("Invalid value for {}: {}", key, shown)
+                    }
+                }
+            }
+            (Some("save"), None, None) => {
+                match self.store.save(&self.config) {
+                    Ok(()) => return Some(Event::ConfigChanged),
+                    Err(err) => log::error!("Failed to save configuration: {:?}", err),
+                }
+            }
+            (Some("reboot"), None, None) => {
+                log::info!("Rebooting");
+                return Some(Event::ResetRequested);
+            }
+            (Some("selftest"), None, None) => return Some(Event::SelfTestRequested),
+            // The network task owns the SPI bus, so it does the reading.
+            (Some("dump"), Some("log"), None) => return Some(Event::LogDumpRequested),
+            (Some("log"), Some(level), None) => match level.parse() {
+                Ok(level) => logging::set_level(level),
+                Err(_) => log::warn!("Unknown log level: {}", level),
+            },
+            _ => log::warn!(
+                "Unknown command. Commands: show config, show status, show errors, show net, set <key> <value>, save, reboot, log <level>, selftest, dump log"
+            ),
+        }
+        None
+    }
+}
@@ -0,0 +1,179 @@
+use core::fmt::{self, Display, Write};
+
+use dsmr42::Telegram;
+
+use crate::counters::{self, Counters, TARIFFS};
+
+const MICROS_PER_EURO: i64 = 1_000_000;
+
+/// Energy prices, in millionths of a euro per kWh, indexed by tariff.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct CostConfig {
+    pub consumed: [u32; TARIFFS],
+    /// Paid back for energy returned to the grid.
+    pub produced: [u32; TARIFFS],
+}
+
+impl CostConfig {
+    /// Costs are only tracked once a price has been set.
+    pub fn is_enabled(&self) -> bool {
+        self.consumed
+            .iter()
+            .chain(self.produced.iter())
+            .any(|rate| *rate != 0)
+    }
+}
+
+// Year, month and day in local time.
+type Date = (i64, u8, u8);
+
+/// Cost of the energy used between `baseline` and `latest`, in millionths of
+/// a euro.
+fn cost_since(latest: &Counters, baseline: &Counters, rates: &CostConfig) -> i64 {
+    let mut cost = 0;
+    for t in 0..TARIFFS {
+        let consumed = latest.consumed[t].saturating_sub(baseline.consumed[t]) as i64;
+        let produced = latest.produced[t].saturating_sub(baseline.produced[t]) as i64;
+        cost += consumed * rates.consumed[t] as i64 / 1000;
+        cost -= produced * rates.produced[t] as i64 / 1000;
+    }
+    cost
+}
+
+/// Running cost estimates, in millionths of a euro.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CostReport {
+    date: Date,
+    pub day: i64,
+    pub month: i64,
+}
+
+impl CostReport {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> fmt::Result {
+        // Poor man's JSON, same as the telegrams
+        let (year, month, day) = self.date;
+        write!(
+            writer,
+            "{{\"date\": \"{:04}-{:02}-{:02}\", \"day_eur\": {}, \"month_eur\": {}}}",
+            year,
+            month,
+            day,
+            Euros(self.day),
+            Euros(self.month)
+        )
+    }
+}
+
+/// Formats millionths of a euro as euros, with four decimals.
+struct Euros(i64);
+
+impl Display for Euros {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let micros = self.0.abs();
+        write!(
+            f,
+            "{}{}.{:04}",
+            sign,
+            micros / MICROS_PER_EURO,
+            micros % MICROS_PER_EURO / 100
+        )
+    }
+}
+
+/// Tracks what the energy used today and this month has cost, based on the
+/// cumulative counters in the telegrams.
+///
+/// Days and months start at midnight according to the timestamps in the
+/// telegrams, so the meter's clock decides. The totals start over at boot,
+/// so after a reset they only cover the time since.
+pub struct CostTracker {
+    rates: CostConfig,
+    // Readings at the start of the current day and month.
+    day: Option<(Date, Counters)>,
+    month: Option<(Date, Counters)>,
+    latest: Option<(Date, Counters)>,
+}
+
+impl CostTracker {
+    pub fn new(rates: CostConfig) -> Self {
+        Self {
+            rates,
+            day: None,
+            month: None,
+            latest: None,
+        }
+    }
+
+    pub fn update(&mut self, telegram: &Telegram) {
+        if !self.rates.is_enabled() {
+            return;
+        }
+        let date = match counters::telegram_time(telegram) {
+            Some(time) => (time.year, time.month, time.day),
+            None => return,
+        };
+        let counters = match Counters::from_telegram(telegram) {
+            Some(counters) => counters,
+            None => return,
+        };
+
+        // A new period starts from the last reading of the previous one, so
+        // nothing used in between is lost.
+        let baseline = self.latest.map_or(counters, |(_, latest)| latest);
+        if self.day.map_or(true, |(start, _)| start != date) {
+            self.day = Some((date, baseline));
+        }
+        if self
+            .month
+            .map_or(true, |(start, _)| (start.0, start.1) != (date.0, date.1))
+        {
+            self.month = Some((date, baseline));
+        }
+        self.latest = Some((date, counters));
+    }
+
+    /// The current estimates, once a telegram has been seen.
+    pub fn report(&self) -> Option<CostReport> {
+        let (date, latest) = self.latest?;
+        let (_, day) = self.day?;
+        let (_, month) = self.month?;
+        Some(CostReport {
+            date,
+            day: cost_since(&latest, &day, &self.rates),
+            month: cost_since(&latest, &month, &self.rates),
+        })
+    }
+}
+
+/// Parses a price in euros, like `0.2231`, into millionths of a euro.
+pub fn parse_price(value: &str) -> Option<u32> {
+    let (whole, fraction) = match value.find('.') {
+        Some(dot) => (&value[..dot], &value[dot + 1..]),
+        None => (value, ""),
+    };
+    if fraction.len() > 6 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let whole: u32 = whole.parse().ok()?;
+    let mut micros: u32 = 0;
+    for i in 0..6 {
+        let digit = fraction.as_bytes().get(i).map_or(0, |b| b - b'0');
+        micros = micros * 10 + digit as u32;
+    }
+    whole
+        .checked_mul(MICROS_PER_EURO as u32)?
+        .checked_add(micros)
+}
+
+/// Formats a price in millionths of a euro, in the format `parse_price()`
+/// accepts.
+pub fn format_price<W: Write>(writer: &mut W, micros: u32) -> fmt::Result {
+    let micros_per_euro = MICROS_PER_EURO as u32;
+    write!(
+        writer,
+        "{}.{:06}",
+        micros / micros_per_euro,
+        micros % micros_per_euro
+    )
+}
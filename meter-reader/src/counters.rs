@@ -0,0 +1,65 @@
+use dsmr42::{Line, Telegram};
+
+use crate::wall_clock::LocalTime;
+
+// Tariff 1 (low) and 2 (normal).
+pub const TARIFFS: usize = 2;
+
+/// Cumulative meter readings in Wh, indexed by tariff.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Counters {
+    pub consumed: [u32; TARIFFS],
+    pub produced: [u32; TARIFFS],
+}
+
+impl Counters {
+    /// The readings in a telegram, if it has any.
+    pub fn from_telegram(telegram: &Telegram) -> Option<Self> {
+        let mut counters = Counters::default();
+        let mut found = false;
+        for line in telegram.lines.iter() {
+            match line {
+                Line::Consumed(tariff @ 1..=2, wh) => {
+                    counters.consumed[*tariff as usize - 1] = *wh;
+                    found = true;
+                }
+                Line::Produced(tariff @ 1..=2, wh) => {
+                    counters.produced[*tariff as usize - 1] = *wh;
+                }
+                _ => {}
+            }
+        }
+        if found {
+            Some(counters)
+        } else {
+            None
+        }
+    }
+
+    /// Energy consumed and produced since `baseline`, in Wh, over all tariffs.
+    pub fn since(&self, baseline: &Counters) -> (u32, u32) {
+        let delta = |now: &[u32; TARIFFS], then: &[u32; TARIFFS]| {
+            now.iter()
+                .zip(then.iter())
+                .map(|(now, then)| now.saturating_sub(*then))
+                .sum()
+        };
+        (
+            delta(&self.consumed, &baseline.consumed),
+            delta(&self.produced, &baseline.produced),
+        )
+    }
+}
+
+/// The timestamp of a telegram, as a Unix time in seconds.
+pub fn telegram_unix_time(telegram: &Telegram) -> Option<i64> {
+    telegram.lines.iter().find_map(|line| match line {
+        Line::Timestamp(ts) => Some(ts.unix_time()),
+        _ => None,
+    })
+}
+
+/// The timestamp of a telegram, in local time.
+pub fn telegram_time(telegram: &Telegram) -> Option<LocalTime> {
+    telegram_unix_time(telegram).map(LocalTime::from_unix)
+}
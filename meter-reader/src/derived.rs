@@ -0,0 +1,124 @@
+use core::fmt::{self, Write};
+
+use dsmr42::{Line, Phase, Telegram};
+
+const PHASES: [Phase; 3] = [Phase::L1, Phase::L2, Phase::L3];
+
+/// Values worked out from the ones in a telegram, for alerting on what the
+/// meter doesn't report itself.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Derived {
+    /// Power consumed minus power produced, in W; negative while more is
+    /// produced than consumed.
+    pub net_power: Option<i64>,
+    /// Per phase, voltage times current, in VA.
+    pub apparent_power: [Option<u32>; 3],
+    /// Per phase, the real power over the apparent power, in hundredths.
+    /// The meter rounds the current to whole amperes, so this is only a
+    /// rough figure at low loads, and it's capped at 1.
+    pub power_factor: [Option<u32>; 3],
+    /// How far the current of the phase furthest from the average is off,
+    /// as a percentage of the average. Only for three-phase meters.
+    pub imbalance: Option<u32>,
+}
+
+impl Derived {
+    pub fn from_telegram(telegram: &Telegram) -> Self {
+        let mut voltage = [None; 3];
+        let mut current = [None; 3];
+        let mut power = [None; 3];
+        let mut consuming = None;
+        let mut producing = None;
+        for line in telegram.lines.iter() {
+            match line {
+                Line::TotalConsuming(watts) => consuming = Some(*watts as i64),
+                Line::TotalProducing(watts) => producing = Some(*watts as i64),
+                Line::Voltage(phase, decivolts) => voltage[index(phase)] = Some(*decivolts),
+                Line::Current(phase, amperes) => current[index(phase)] = Some(*amperes),
+                Line::Consuming(phase, watts) => {
+                    let power = &mut power[index(phase)];
+                    *power = Some(power.unwrap_or(0) + *watts as i64);
+                }
+                Line::Producing(phase, watts) => {
+                    let power = &mut power[index(phase)];
+                    *power = Some(power.unwrap_or(0) - *watts as i64);
+                }
+                _ => {}
+            }
+        }
+
+        let mut derived = Derived {
+            net_power: consuming.zip(producing).map(|(c, p)| c - p),
+            imbalance: imbalance(&current),
+            ..Derived::default()
+        };
+        for i in 0..PHASES.len() {
+            let apparent = voltage[i]
+                .zip(current[i])
+                .map(|(decivolts, amperes)| (decivolts * amperes + 5) / 10);
+            derived.apparent_power[i] = apparent;
+            derived.power_factor[i] = match (power[i], apparent) {
+                (Some(watts), Some(va)) if va > 0 => {
+                    Some(((watts.unsigned_abs() * 100 + va as u64 / 2) / va as u64).min(100) as u32)
+                }
+                _ => None,
+            };
+        }
+        derived
+    }
+
+    /// Writes the values that could be worked out as JSON object members,
+    /// like `"net_power": -1200`. `separator` goes before the first one.
+    pub fn serialize<W: Write>(&self, writer: &mut W, mut separator: &str) -> fmt::Result {
+        if let Some(watts) = self.net_power {
+            write!(writer, "{}\"net_power\": {}", separator, watts)?;
+            separator = ",";
+        }
+        for (i, phase) in PHASES.iter().enumerate() {
+            if let Some(va) = self.apparent_power[i] {
+                write!(writer, "{}\"{}_apparent_power\": {}", separator, phase, va)?;
+                separator = ",";
+            }
+            if let Some(pf) = self.power_factor[i] {
+                write!(
+                    writer,
+                    "{}\"{}_power_factor\": {}.{:02}",
+                    separator,
+                    phase,
+                    pf / 100,
+                    pf % 100
+                )?;
+                separator = ",";
+            }
+        }
+        if let Some(percent) = self.imbalance {
+            write!(writer, "{}\"phase_imbalance\": {}", separator, percent)?;
+        }
+        Ok(())
+    }
+}
+
+fn index(phase: &Phase) -> usize {
+    match phase {
+        Phase::L1 => 0,
+        Phase::L2 => 1,
+        Phase::L3 => 2,
+    }
+}
+
+/// The largest deviation from the average current, as a rounded percentage
+/// of it. Nothing flows with no current at all, so there is no imbalance.
+fn imbalance(current: &[Option<u32>; 3]) -> Option<u32> {
+    let (a, b, c) = (current[0]?, current[1]?, current[2]?);
+    let sum = a + b + c;
+    if sum == 0 {
+        return Some(0);
+    }
+    // Worked out in thirds of an ampere, to stay in integers.
+    let deviation = [a, b, c]
+        .iter()
+        .map(|i| (i * 3).abs_diff(sum))
+        .max()
+        .unwrap_or(0);
+    Some((deviation * 100 + sum / 2) / sum)
+}
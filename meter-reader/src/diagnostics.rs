@@ -0,0 +1,171 @@
+use core::fmt::{self, Write};
+
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+
+use crate::{
+    events::{Event, EventConsumer},
+    memstats::MemStats,
+    network::{neighbours::Neighbours, NetStatus},
+    parse_errors::ParseErrorLog,
+    system_info::{BootReason, EthernetChip},
+    temperature::{self, Temperatures},
+    uart::UartStats,
+    wall_clock::LocalTime,
+};
+
+/// Room needed for the serialized diagnostics.
+pub const MAX_SERIALIZED_LEN: usize = 1760;
+
+/// Health information about the reader itself, published periodically
+/// alongside the telegrams.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Diagnostics {
+    pub uart: UartStats,
+    /// Milliseconds between receiving a telegram and publishing it.
+    pub publish_latency: Option<i64>,
+    pub time: Option<LocalTime>,
+    /// How many ppm the clock's crystal runs fast, once calibrated.
+    pub clock_drift_ppm: Option<i32>,
+    pub boot_reason: BootReason,
+    pub ethernet_chip: Option<EthernetChip>,
+    pub uptime_secs: i64,
+    pub memory: MemStats,
+    /// Seconds between telegrams from the meter, once known.
+    pub meter_interval_secs: Option<u32>,
+    pub network: NetStatus,
+    /// The address and prefix length of the interface, once configured.
+    pub cidr: Option<Ipv4Cidr>,
+    pub gateway: Option<Ipv4Address>,
+    pub neighbours: Neighbours,
+    pub events: EventStats,
+    pub parse_errors: ParseErrorLog,
+    /// The last reading of each 1-Wire temperature sensor.
+    pub temperatures: Temperatures,
+}
+
+/// Counts events that point at problems, since boot.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct EventStats {
+    pub crc_failures: u32,
+    pub parse_failures: u32,
+    pub link_drops: u32,
+    pub mqtt_disconnects: u32,
+}
+
+impl EventConsumer for EventStats {
+    fn on_event(&mut self, event: Event, _now: i64) {
+        let counter = match event {
+            Event::CrcFailed => &mut self.crc_failures,
+            Event::ParseFailed => &mut self.parse_failures,
+            Event::LinkDown => &mut self.link_drops,
+            Event::MqttDisconnected => &mut self.mqtt_disconnects,
+            _ => return,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+impl Diagnostics {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> fmt::Result {
+        // Poor man's JSON, same as the telegrams
+        write!(writer, "{{")?;
+        if let Some(time) = self.time {
+            write!(writer, "\"time\": \"{}\", ", time)?;
+        }
+        if let Some(drift) = self.clock_drift_ppm {
+            write!(writer, "\"clock_drift_ppm\": {}, ", drift)?;
+        }
+        write!(
+            writer,
+            "\"boot_reason\": \"{}\", \"uptime_s\": {}, ",
+            self.boot_reason, self.uptime_secs
+        )?;
+        if let Some(chip) = self.ethernet_chip {
+            write!(writer, "\"eth_revision\": \"{}\", ", chip.revision)?;
+            write!(writer, "\"eth_workarounds\": [")?;
+            for (i, name) in chip.workarounds.names().enumerate() {
+                let sep = if i == 0 { "" } else { ", " };
+                write!(writer, "{}\"{}\"", sep, name)?;
+            }
+            write!(writer, "], ")?;
+        }
+        write!(writer, "\"network\": \"{}\", ", self.network)?;
+        if let Some(addr) = self.network.address() {
+            write!(writer, "\"address\": \"{}\", ", addr)?;
+        }
+        if let Some(cidr) = self.cidr {
+            write!(writer, "\"prefix_len\": {}, ", cidr.prefix_len())?;
+        }
+        if let Some(gateway) = self.gateway {
+            write!(writer, "\"gateway\": \"{}\", ", gateway)?;
+        }
+        write!(writer, "\"neighbours\": [")?;
+        for (i, neighbour) in self.neighbours.iter().enumerate() {
+            let sep = if i == 0 { "" } else { ", " };
+            write!(
+                writer,
+                "{}{{\"address\": \"{}\", \"mac\": \"{}\"}}",
+                sep, neighbour.addr, neighbour.hardware_addr
+            )?;
+        }
+        write!(writer, "], ")?;
+        if let Some(interval) = self.meter_interval_secs {
+            write!(writer, "\"meter_interval_s\": {}, ", interval)?;
+        }
+        if self.temperatures.iter().any(Option::is_some) {
+            write!(writer, "\"temperatures\": ")?;
+            temperature::serialize(writer, &self.temperatures)?;
+            write!(writer, ", ")?;
+        }
+        if let Some(latency) = self.publish_latency {
+            write!(writer, "\"publish_latency_ms\": {}, ", latency)?;
+        }
+        if let Some(stack) = self.memory.stack {
+            write!(
+                writer,
+                "\"stack_used\": {}, \"stack_size\": {}, ",
+                stack.used, stack.size
+            )?;
+        }
+        write!(
+            writer,
+            "\"uart_buffer_peak\": {}, \"mqtt_queue_depth\": {}, ",
+            self.memory.uart_buffer_peak, self.memory.mqtt_queue_depth
+        )?;
+        write!(
+            writer,
+            "\"crc_failures\": {}, \"parse_failures\": {}, \"link_drops\": {}, \
+            \"mqtt_disconnects\": {}, ",
+            self.events.crc_failures,
+            self.events.parse_failures,
+            self.events.link_drops,
+            self.events.mqtt_disconnects,
+        )?;
+        if let Some(failure) = self.parse_errors.last() {
+            write!(
+                writer,
+                "\"last_parse_error\": {{\"kind\": \"{}\", \"uptime_s\": {}, ",
+                failure.kind.name(),
+                failure.at / 1000
+            )?;
+            if let Some(offset) = failure.offset {
+                write!(writer, "\"offset\": {}, ", offset)?;
+            }
+            write!(writer, "\"bytes\": \"")?;
+            failure.write_hex(writer, "")?;
+            write!(writer, "\"}}, ")?;
+        }
+        write!(
+            writer,
+            "\"uart_overruns\": {}, \"uart_framing_errors\": {}, \
+            \"uart_parity_errors\": {}, \"uart_noise_errors\": {}, \
+            \"uart_buffer_overflows\": {}, \"meter_stalls\": {}}}",
+            self.uart.overruns,
+            self.uart.framing_errors,
+            self.uart.parity_errors,
+            self.uart.noise_errors,
+            self.uart.buffer_overflows,
+            self.uart.meter_stalls,
+        )
+    }
+}
@@ -0,0 +1,123 @@
+use arrayvec::ArrayVec;
+use enc28j60_smoltcp::warn_throttled;
+use smoltcp::{socket::SocketHandle, wire::Ipv4Address};
+
+use crate::{
+    clock,
+    fault::{FaultPolicy, Subsystem},
+    logging::Debug2Format,
+    network::events::NetworkEvents,
+};
+
+// Events are dispatched on every network poll, so only a burst of them
+// arriving within a few milliseconds needs to fit.
+const EVENT_QUEUE_LEN: usize = 16;
+
+/// Something that happened in one subsystem, which others may want to act on.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Event {
+    TelegramParsed,
+    CrcFailed,
+    /// A telegram could not be parsed for another reason than its CRC.
+    ParseFailed,
+    LinkUp,
+    LinkDown,
+    AddressAcquired(Ipv4Address),
+    AddressLost,
+    MqttConnected,
+    MqttDisconnected,
+    /// The configuration was saved, and will be applied after a reboot.
+    ConfigChanged,
+    SelfTestRequested,
+    /// The records in the SPI flash log should be written to the log.
+    LogDumpRequested,
+    /// The firmware should reset, once the MQTT client has told the broker
+    /// it's going offline.
+    ResetRequested,
+}
+
+/// Receives events from an `EventQueue`.
+pub trait EventConsumer {
+    fn on_event(&mut self, event: Event, now: i64);
+}
+
+/// Collects events until they are dispatched to the consumers, so subsystems
+/// don't need to know about each other.
+///
+/// Network events are collected by passing the queue to `NetworkStack::poll`.
+pub struct EventQueue {
+    events: ArrayVec<Event, EVENT_QUEUE_LEN>,
+    mqtt_handle: Option<SocketHandle>,
+    ethernet: FaultPolicy,
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        Self {
+            events: ArrayVec::new(),
+            mqtt_handle: None,
+            ethernet: FaultPolicy::new(Subsystem::Ethernet),
+        }
+    }
+
+    /// Sets the socket of the MQTT client, so its connection state can be
+    /// told apart from that of other clients.
+    pub fn watch_mqtt(&mut self, handle: SocketHandle) {
+        self.mqtt_handle = Some(handle);
+    }
+
+    pub fn push(&mut self, event: Event) {
+        if self.events.try_push(event).is_err() {
+            warn_throttled!(
+                clock::millis(),
+                "Event queue full, dropping {:?}",
+                Debug2Format(&event)
+            );
+        }
+    }
+
+    /// Hands every queued event to each of the consumers, in order.
+    pub fn dispatch(&mut self, now: i64, consumers: &mut [&mut dyn EventConsumer]) {
+        for event in self.events.drain(..) {
+            log::trace!("Dispatching {:?}", event);
+            for consumer in consumers.iter_mut() {
+                consumer.on_event(event, now);
+            }
+        }
+    }
+}
+
+impl NetworkEvents for EventQueue {
+    fn link_up(&mut self) {
+        self.push(Event::LinkUp);
+    }
+
+    fn link_down(&mut self) {
+        self.push(Event::LinkDown);
+    }
+
+    fn address_acquired(&mut self, addr: Ipv4Address) {
+        self.push(Event::AddressAcquired(addr));
+    }
+
+    fn address_lost(&mut self, _addr: Ipv4Address) {
+        self.push(Event::AddressLost);
+    }
+
+    fn client_connected(&mut self, handle: SocketHandle) {
+        if Some(handle) == self.mqtt_handle {
+            self.push(Event::MqttConnected);
+        }
+    }
+
+    fn client_disconnected(&mut self, handle: SocketHandle) {
+        if Some(handle) == self.mqtt_handle {
+            self.push(Event::MqttDisconnected);
+        }
+    }
+
+    fn driver_failing(&mut self, consecutive_failures: u32) {
+        // Resets the firmware once the controller has failed too often.
+        self.ethernet.failed_in_a_row(consecutive_failures);
+    }
+}
@@ -0,0 +1,107 @@
+use core::fmt::Debug;
+
+use crate::system_info;
+
+/// The parts of the firmware that can fail independently.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Subsystem {
+    Usb,
+    Uart,
+    Ethernet,
+}
+
+impl Subsystem {
+    /// Consecutive recoverable failures after which the subsystem is
+    /// reinitialised, or the firmware is reset, if at all.
+    fn limits(self) -> (Option<u32>, Option<u32>) {
+        match self {
+            // Line errors are usually caused by the meter or the cable, so
+            // resetting won't help, but reconfiguring might.
+            Subsystem::Uart => (Some(10), None),
+            // The ENC28J60 can't be reinitialised without giving up the SPI
+            // bus, so if it keeps failing, start over.
+            Subsystem::Ethernet => (None, Some(50)),
+            Subsystem::Usb => (None, None),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Severity {
+    /// The operation may succeed if it is tried again.
+    Recoverable,
+    /// The subsystem is unavailable, but everything else can carry on.
+    Degraded,
+}
+
+/// What the caller should do about a failure.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Action {
+    Retry,
+    Reinit,
+    Continue,
+}
+
+/// Decides how to respond to failures in a subsystem, escalating when they
+/// keep happening. Each subsystem keeps its own.
+#[derive(Debug)]
+pub struct FaultPolicy {
+    subsystem: Subsystem,
+    // Consecutive recoverable failures.
+    failures: u32,
+}
+
+impl FaultPolicy {
+    pub const fn new(subsystem: Subsystem) -> Self {
+        Self {
+            subsystem,
+            failures: 0,
+        }
+    }
+
+    /// Reports a failure. Resets the firmware instead of returning if the
+    /// subsystem has failed too often.
+    pub fn failed<E: Debug>(&mut self, severity: Severity, err: &E) -> Action {
+        if severity == Severity::Degraded {
+            log::warn!("{:?} unavailable: {:?}", self.subsystem, err);
+            return Action::Continue;
+        }
+        self.failures += 1;
+        log::debug!("{:?} failure {}: {:?}", self.subsystem, self.failures, err);
+        let (reinit_after, reset_after) = self.subsystem.limits();
+        if reset_after.map_or(false, |limit| self.failures >= limit) {
+            fatal(self.subsystem, err);
+        }
+        if reinit_after.map_or(false, |limit| self.failures >= limit) {
+            log::warn!(
+                "{:?} failed {} times in a row, reinitialising",
+                self.subsystem,
+                self.failures
+            );
+            self.failures = 0;
+            return Action::Reinit;
+        }
+        Action::Retry
+    }
+
+    /// Reports failures that were counted elsewhere, like the network stack
+    /// does for its driver. Escalates the same way as `failed`.
+    pub fn failed_in_a_row(&mut self, failures: u32) -> Action {
+        self.failures = failures.saturating_sub(1);
+        self.failed(Severity::Recoverable, &"driver errors")
+    }
+
+    /// Reports that the subsystem is working again.
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// Handles a failure the firmware can't continue after, by resetting.
+///
+/// Most of these happen during startup, where a reset gives peripherals
+/// that weren't ready yet another chance.
+pub fn fatal<E: Debug>(subsystem: Subsystem, err: &E) -> ! {
+    log::error!("{:?} failed, resetting: {:?}", subsystem, err);
+    system_info::reset()
+}
@@ -0,0 +1,175 @@
+use core::{ops::Range, ptr};
+
+pub const SECTOR_SZ: u32 = 4096;
+pub const PAGE_SZ: usize = 256;
+// The Teensy 4.0 has 2MB of flash, of which the last 64K are not used for
+// the firmware image. The Teensy 4.1 has 8MB, and reserves the last 256K.
+#[cfg(feature = "teensy40")]
+pub const FLASH_SZ: u32 = 0x20_0000;
+#[cfg(feature = "teensy40")]
+pub const FIRMWARE_MAX_SZ: u32 = 0x1F_0000;
+#[cfg(feature = "teensy41")]
+pub const FLASH_SZ: u32 = 0x80_0000;
+#[cfg(feature = "teensy41")]
+pub const FIRMWARE_MAX_SZ: u32 = 0x7C_0000;
+
+const FLASH_BASE: usize = 0x6000_0000;
+// teensy4-rt copies the code to ITCM at startup, and runs it from there.
+const ITCM: Range<usize> = 0x0000_0000..0x0008_0000;
+// Location of the boot ROM API tree, see the i.MX RT1060 reference manual.
+const ROM_API_TREE: usize = 0x0020_001C;
+const FLEXSPI_INSTANCE: u32 = 0;
+// The FlexSPI NOR configuration block the boot ROM booted from.
+const FCB_SZ: usize = 512;
+const STATUS_SUCCESS: i32 = 0;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FlashError {
+    Erase(i32),
+    Program(i32),
+    /// The written data could not be read back.
+    Verify,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct RomApiTree {
+    version: u32,
+    copyright: *const u8,
+    run_bootloader: unsafe extern "C" fn(*mut u8),
+    reserved: *const u32,
+    flexspi_nor_driver: *const FlexSpiNorDriver,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct FlexSpiNorDriver {
+    version: u32,
+    init: unsafe extern "C" fn(u32, *mut u32) -> i32,
+    program: unsafe extern "C" fn(u32, *mut u32, u32, *const u32) -> i32,
+    erase_all: unsafe extern "C" fn(u32, *mut u32) -> i32,
+    erase: unsafe extern "C" fn(u32, *mut u32, u32, u32) -> i32,
+    read: unsafe extern "C" fn(u32, *mut u32, *mut u32, u32, u32) -> i32,
+    clear_cache: unsafe extern "C" fn(u32),
+}
+
+/// Reads from flash, starting `offset` bytes from its start.
+#[inline(always)]
+pub fn read(offset: u32, buf: &mut [u8]) {
+    let addr = FLASH_BASE + offset as usize;
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = unsafe { ptr::read_volatile((addr + i) as *const u8) };
+    }
+}
+
+/// Erases the sectors covering `len` bytes from `offset`, which must be
+/// sector-aligned.
+pub fn erase(offset: u32, len: u32) -> Result<(), FlashError> {
+    erase_with(&mut read_fcb(), offset, len)
+}
+
+/// Programs a single page at `offset`, which must be page-aligned and erased.
+pub fn program_page(offset: u32, page: &[u8; PAGE_SZ]) -> Result<(), FlashError> {
+    program_page_with(&mut read_fcb(), offset, page)
+}
+
+/// Whether `replace_firmware` can run, which needs the copy loop to be in
+/// ITCM rather than in the flash it rewrites.
+pub fn can_replace_firmware() -> bool {
+    ITCM.contains(&(copy_image as usize))
+}
+
+/// Copies `len` bytes from `src` to the start of flash, replacing the running
+/// firmware, and resets. Only call this if `can_replace_firmware()` holds.
+///
+/// Interrupts stay disabled until the reset, since their handlers would run
+/// the old firmware against a half-written image.
+pub fn replace_firmware(src: u32, len: u32) -> ! {
+    cortex_m::interrupt::disable();
+    copy_image(src, len)
+}
+
+// Nothing in here may be fetched from flash once the first sector is erased.
+// The helpers below are inlined into it, so checking where this function
+// ended up covers them too, and the flash driver itself is in ROM.
+#[inline(never)]
+fn copy_image(src: u32, len: u32) -> ! {
+    // The configuration block is at the start of flash, so it is overwritten
+    // as well. Keep using the one we booted with.
+    let mut fcb = read_fcb();
+    let mut page = [0; PAGE_SZ];
+    let mut offset = 0;
+    while offset < len {
+        if offset % SECTOR_SZ == 0 {
+            // Nothing we can do about failures at this point, other than hoping
+            // the next attempt after a power cycle goes better.
+            let _ = erase_with(&mut fcb, offset, SECTOR_SZ);
+        }
+        read(src + offset, &mut page);
+        let _ = program_page_with(&mut fcb, offset, &page);
+        offset += PAGE_SZ as u32;
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+#[inline(always)]
+fn read_fcb() -> [u32; FCB_SZ / 4] {
+    let mut fcb = [0u32; FCB_SZ / 4];
+    for (i, word) in fcb.iter_mut().enumerate() {
+        *word = unsafe { ptr::read_volatile((FLASH_BASE as *const u32).add(i)) };
+    }
+    fcb
+}
+
+#[inline(always)]
+fn erase_with(fcb: &mut [u32; FCB_SZ / 4], offset: u32, len: u32) -> Result<(), FlashError> {
+    let len = (len + SECTOR_SZ - 1) / SECTOR_SZ * SECTOR_SZ;
+    let status = with_driver(offset, len, |driver| unsafe {
+        (driver.erase)(FLEXSPI_INSTANCE, fcb.as_mut_ptr(), offset, len)
+    });
+    match status {
+        STATUS_SUCCESS => Ok(()),
+        status => Err(FlashError::Erase(status)),
+    }
+}
+
+#[inline(always)]
+fn program_page_with(
+    fcb: &mut [u32; FCB_SZ / 4],
+    offset: u32,
+    page: &[u8; PAGE_SZ],
+) -> Result<(), FlashError> {
+    // The ROM expects word-aligned data.
+    let mut words = [0u32; PAGE_SZ / 4];
+    for (word, bytes) in words.iter_mut().zip(page.chunks(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    let status = with_driver(offset, PAGE_SZ as u32, |driver| unsafe {
+        (driver.program)(FLEXSPI_INSTANCE, fcb.as_mut_ptr(), offset, words.as_ptr())
+    });
+    match status {
+        STATUS_SUCCESS => Ok(()),
+        status => Err(FlashError::Program(status)),
+    }
+}
+
+/// Runs a ROM flash operation. Flash can't be read while the ROM is busy, and
+/// interrupt handlers may read constants or the vector table from it, so
+/// interrupts are disabled meanwhile. The caches are cleared afterwards so we
+/// read back what was written.
+#[inline(always)]
+fn with_driver<F>(offset: u32, len: u32, op: F) -> i32
+where
+    F: FnOnce(&FlexSpiNorDriver) -> i32,
+{
+    cortex_m::interrupt::free(|_| unsafe {
+        let tree = &*(ptr::read_volatile(ROM_API_TREE as *const *const RomApiTree));
+        let driver = &*tree.flexspi_nor_driver;
+        let status = op(driver);
+        (driver.clear_cache)(FLEXSPI_INSTANCE);
+        let mut core = cortex_m::Peripherals::steal();
+        core.SCB
+            .invalidate_dcache_by_address(FLASH_BASE + offset as usize, len as usize);
+        status
+    })
+}
@@ -0,0 +1,140 @@
+use arrayvec::ArrayString;
+use core::{convert::TryFrom, fmt::Write};
+
+use crate::{
+    config::{self, Config, ConfigStore},
+    events::{Event, EventConsumer},
+    log_store::{LogStore, RecordKind, Storage},
+    outage::{OutageBuffer, Sample, SAMPLE_LEN},
+    panic::PanicReport,
+};
+
+// Room for the message and the backtrace of a panic report.
+const CRASH_RECORD_SZ: usize = 384;
+
+/// Keeps crash reports, configuration changes and the outage buffer in the
+/// log on the SPI flash, so they survive a reboot. Does nothing if there is
+/// no flash.
+pub struct FlashLog<S> {
+    store: Option<LogStore<S>>,
+}
+
+impl<S: Storage> FlashLog<S> {
+    pub fn new(store: Option<LogStore<S>>) -> Self {
+        Self { store }
+    }
+
+    pub fn record_crash(&mut self, report: &PanicReport) {
+        let mut text = ArrayString::<CRASH_RECORD_SZ>::new();
+        // A report that doesn't fit is cut short, which still beats nothing.
+        let _ = write!(text, "{}", report);
+        self.append(RecordKind::Crash, text.as_bytes());
+    }
+
+    /// Puts back the samples that weren't published before the reboot.
+    pub fn restore_outage(&mut self, outage: &mut OutageBuffer) {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return,
+        };
+        let result = store.for_each(|kind, payload| match kind {
+            RecordKind::OutageSample => {
+                if let Ok(record) = <&[u8; SAMPLE_LEN]>::try_from(payload) {
+                    outage.restore(Sample::decode(record));
+                }
+            }
+            RecordKind::OutageDrained => outage.clear(),
+            _ => {}
+        });
+        match result {
+            Ok(()) if outage.pending() > 0 => {
+                log::info!("Restored {} outage samples from flash", outage.pending())
+            }
+            Ok(()) => {}
+            Err(err) => log::warn!("Failed to read the flash log: {:?}", err),
+        }
+    }
+
+    /// Journals what happened to the outage buffer since the last call.
+    pub fn sync_outage(&mut self, outage: &mut OutageBuffer) {
+        if let Some(sample) = outage.take_added() {
+            self.append(RecordKind::OutageSample, &sample.encode());
+        }
+        if outage.take_drained() {
+            self.append(RecordKind::OutageDrained, &[]);
+        }
+    }
+
+    fn append(&mut self, kind: RecordKind, payload: &[u8]) {
+        if let Some(store) = &mut self.store {
+            if let Err(err) = store.append(kind, payload) {
+                log::warn!(
+                    "Failed to append {} to the flash log: {:?}",
+                    kind.name(),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Writes every record to the log, oldest first.
+    fn dump(&mut self) {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => {
+                log::info!("No SPI flash, so there is no log to dump");
+                return;
+            }
+        };
+        log::info!(
+            "Flash log of {} sectors, erased at most {} times",
+            store.sectors(),
+            store.max_erase_count()
+        );
+        let mut count = 0;
+        let result = store.for_each(|kind, payload| {
+            count += 1;
+            match kind {
+                RecordKind::OutageSample => {
+                    let mut json = ArrayString::<256>::new();
+                    if let Ok(record) = <&[u8; SAMPLE_LEN]>::try_from(payload) {
+                        let _ = Sample::decode(record).serialize(&mut json);
+                    }
+                    log::info!("{}: {}", kind.name(), json);
+                }
+                RecordKind::Config => {
+                    let valid = <&[u8; config::RECORD_SZ]>::try_from(payload)
+                        .ok()
+                        .and_then(Config::decode)
+                        .is_some();
+                    log::info!("{}: {} bytes, valid: {}", kind.name(), payload.len(), valid);
+                }
+                RecordKind::Crash => {
+                    let text = core::str::from_utf8(payload).unwrap_or("<invalid UTF-8>");
+                    log::info!("{}: {}", kind.name(), text);
+                }
+                RecordKind::OutageDrained => log::info!("{}", kind.name()),
+            }
+        });
+        match result {
+            Ok(()) => log::info!("{} records", count),
+            Err(err) => log::warn!("Failed to read the flash log: {:?}", err),
+        }
+    }
+}
+
+impl<S: Storage> EventConsumer for FlashLog<S> {
+    fn on_event(&mut self, event: Event, _now: i64) {
+        match event {
+            // Keep a copy of every saved configuration, to see what changed
+            // when, or to recover one.
+            Event::ConfigChanged => {
+                if let (_, Some(config)) = ConfigStore::load() {
+                    self.append(RecordKind::Config, &config.encode(0));
+                }
+            }
+            Event::LogDumpRequested => self.dump(),
+            _ => {}
+        }
+    }
+}
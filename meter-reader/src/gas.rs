@@ -0,0 +1,68 @@
+use core::fmt::{self, Write};
+
+use dsmr42::{Line, Telegram};
+
+/// A reading of the gas meter, which the electricity meter relays in its
+/// telegrams.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GasReading {
+    /// The M-Bus channel the gas meter is on.
+    pub channel: u8,
+    /// When the gas meter took the reading.
+    pub unix_time: i64,
+    /// Cumulative volume delivered, in dm³.
+    pub delivered: u32,
+}
+
+impl GasReading {
+    /// The first gas reading in a telegram, if it has one.
+    pub fn from_telegram(telegram: &Telegram) -> Option<Self> {
+        telegram.lines.iter().find_map(|line| match line {
+            Line::GasDelivered(channel, ts, delivered) => Some(GasReading {
+                channel: *channel,
+                unix_time: ts.unix_time(),
+                delivered: *delivered,
+            }),
+            _ => None,
+        })
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> fmt::Result {
+        // Poor man's JSON, same as the telegrams
+        write!(
+            writer,
+            "{{\"channel\": {}, \"unix_time\": {}, \"gas_delivered\": {}.{:03}}}",
+            self.channel,
+            self.unix_time,
+            self.delivered / 1000,
+            self.delivered % 1000,
+        )
+    }
+}
+
+/// Picks the gas readings out of the telegrams that are new. The gas meter
+/// only reports every five minutes, and the electricity meter repeats its last
+/// reading in the telegrams in between.
+pub struct GasTracker {
+    last_reading: Option<i64>,
+}
+
+impl GasTracker {
+    pub fn new() -> Self {
+        Self { last_reading: None }
+    }
+
+    /// The gas reading in the telegram, if it was taken after the last one
+    /// that was returned.
+    pub fn observe(&mut self, telegram: &Telegram) -> Option<GasReading> {
+        let reading = GasReading::from_telegram(telegram)?;
+        if self
+            .last_reading
+            .map_or(false, |last| reading.unix_time <= last)
+        {
+            return None;
+        }
+        self.last_reading = Some(reading.unix_time);
+        Some(reading)
+    }
+}
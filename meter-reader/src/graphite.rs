@@ -0,0 +1,171 @@
+use arrayvec::ArrayString;
+use core::fmt::Write;
+use smoltcp::{
+    socket::SocketHandle,
+    time::{Duration, Instant},
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{
+    counters,
+    metrics::metrics,
+    network::client::{TcpAction, TcpClient, TcpConnection},
+    random::Random,
+    telemetry::{TelemetryRecord, TelemetrySink},
+};
+
+const QUEUE_SZ: usize = 1024;
+const BACKOFF_CAP_MS: u64 = 300_000;
+const INITIAL_BACKOFF_MS: u64 = 1000;
+
+/// Where to send metrics to. Nothing is sent until a host has been set.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GraphiteConfig {
+    pub host: Ipv4Address,
+    pub port: u16,
+    /// Prepended to every metric name, followed by a dot.
+    pub prefix: ArrayString<32>,
+}
+
+impl Default for GraphiteConfig {
+    fn default() -> Self {
+        Self {
+            host: Ipv4Address::UNSPECIFIED,
+            port: 2003,
+            prefix: ArrayString::from("meter").unwrap_or_default(),
+        }
+    }
+}
+
+impl GraphiteConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.host.is_unspecified()
+    }
+}
+
+/// Sends the values in each telegram to Carbon, using its plaintext protocol,
+/// for those with an existing Graphite setup.
+///
+/// The connection is kept open. Telegrams that arrive while it is down are
+/// dropped, like with MQTT.
+pub struct GraphiteClient {
+    config: GraphiteConfig,
+    handle: Option<SocketHandle>,
+    connected: bool,
+    queued: ArrayString<QUEUE_SZ>,
+    next_backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl TcpClient for GraphiteClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll(
+        &mut self,
+        socket: &mut dyn TcpConnection,
+        timestamp: Instant,
+        _random: &mut Random,
+    ) -> TcpAction {
+        if !self.config.is_enabled() {
+            return TcpAction::Nothing;
+        }
+
+        if socket.may_send() && !self.connected {
+            self.connected = true;
+            self.next_backoff = Duration::from_millis(INITIAL_BACKOFF_MS);
+            log::info!("Connected to Carbon at {}", socket.remote_endpoint());
+        } else if !socket.is_active() && self.connected {
+            self.connected = false;
+            log::info!("Disconnected from Carbon");
+        }
+
+        if !socket.is_active() {
+            self.queued.clear();
+            return self.try_connect(timestamp);
+        }
+
+        // Carbon never sends anything, but don't let it fill up the buffer.
+        if socket.can_recv() {
+            if let Err(err) = socket.consume(usize::MAX) {
+                log::warn!("Failed to discard received data: {}", err);
+            }
+        }
+
+        if socket.can_send() && !self.queued.is_empty() {
+            let free = socket.send_free();
+            if free < self.queued.len() {
+                log::warn!(
+                    "Carbon is too slow, dropping metrics ({} bytes, {} free)",
+                    self.queued.len(),
+                    free
+                );
+            } else if let Err(err) = socket.send_slice(self.queued.as_bytes()) {
+                log::warn!("Failed to send metrics to Carbon: {}", err);
+            }
+            self.queued.clear();
+        }
+        TcpAction::Nothing
+    }
+}
+
+impl GraphiteClient {
+    pub fn new(config: GraphiteConfig) -> Self {
+        Self {
+            config,
+            handle: None,
+            connected: false,
+            queued: ArrayString::new(),
+            next_backoff: Duration::from_millis(INITIAL_BACKOFF_MS),
+            next_attempt: Instant::from_millis(0),
+        }
+    }
+
+    fn try_connect(&mut self, timestamp: Instant) -> TcpAction {
+        if timestamp < self.next_attempt {
+            return TcpAction::Nothing;
+        }
+        self.next_attempt = timestamp + self.next_backoff;
+        self.next_backoff =
+            Duration::from_millis((self.next_backoff.total_millis() * 2).min(BACKOFF_CAP_MS));
+
+        let remote = IpEndpoint::new(IpAddress::Ipv4(self.config.host), self.config.port);
+        log::debug!("Connecting to Carbon at {}", remote);
+        TcpAction::Connect {
+            remote,
+            timeout: None,
+            keep_alive: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl TelemetrySink for GraphiteClient {
+    /// Queues the values in a telegram, replacing any that haven't been sent
+    /// yet. Carbon needs a timestamp, so telegrams without one are skipped.
+    fn accept(&mut self, record: &TelemetryRecord) {
+        if !self.config.is_enabled() || !self.connected {
+            return;
+        }
+        let timestamp = match counters::telegram_unix_time(record.telegram) {
+            Some(timestamp) => timestamp,
+            None => return,
+        };
+        self.queued.clear();
+        for (name, value) in metrics(record.telegram) {
+            if writeln!(
+                self.queued,
+                "{}.{} {} {}",
+                self.config.prefix, name, value, timestamp
+            )
+            .is_err()
+            {
+                log::warn!("Metrics do not fit in {} bytes", self.queued.capacity());
+                self.queued.clear();
+                return;
+            }
+        }
+    }
+}
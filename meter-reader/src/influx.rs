@@ -0,0 +1,351 @@
+use arrayvec::ArrayString;
+use core::{
+    fmt::{self, Write},
+    mem,
+};
+use dsmr42::Telegram;
+use smoltcp::{
+    socket::SocketHandle,
+    time::{Duration, Instant},
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{
+    counters,
+    metrics::metrics,
+    network::client::{TcpAction, TcpClient, TcpConnection},
+    random::Random,
+    telemetry::{TelemetryRecord, TelemetrySink},
+};
+
+const MEASUREMENT: &str = "electricity";
+// Telegrams are written in batches of this many, or once the oldest one has
+// been waiting this long, whichever comes first.
+const FLUSH_LINES: usize = 10;
+const FLUSH_INTERVAL_MS: u64 = 60_000;
+const BATCH_SZ: usize = 2048;
+const MAX_LINE_SZ: usize = 512;
+const BACKOFF_CAP_MS: u64 = 300_000;
+const INITIAL_BACKOFF_MS: u64 = 1000;
+// How long to wait for the response once the request is sent. InfluxDB
+// answers well within this, unless it hangs.
+const RESPONSE_TIMEOUT_MS: u64 = 30_000;
+// Room for the request line and headers, with the org and bucket encoded.
+const HEADER_SZ: usize = 640;
+
+pub type Token = ArrayString<128>;
+
+/// Where to write telegrams to. Nothing is written until both a host and a
+/// token have been set.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct InfluxConfig {
+    pub host: Ipv4Address,
+    pub port: u16,
+    pub org: ArrayString<32>,
+    pub bucket: ArrayString<32>,
+    pub token: Token,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        Self {
+            host: Ipv4Address::UNSPECIFIED,
+            port: 8086,
+            org: ArrayString::new(),
+            bucket: ArrayString::from("smart_meter").unwrap_or_default(),
+            token: ArrayString::new(),
+        }
+    }
+}
+
+impl InfluxConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.host.is_unspecified() && !self.token.is_empty()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    Idle,
+    Connecting,
+    AwaitingResponse { deadline: Instant },
+}
+
+/// Writes telegrams to InfluxDB through its v2 HTTP API, as an alternative to
+/// MQTT for those who don't run a broker.
+///
+/// Telegrams are converted to line protocol and collected into a batch, which
+/// is written over a new connection each time. While a batch is being
+/// written, the next one is collected. If both are full, telegrams are
+/// dropped.
+pub struct InfluxClient {
+    config: InfluxConfig,
+    handle: Option<SocketHandle>,
+    state: State,
+    // Lines waiting to be written.
+    batch: ArrayString<BATCH_SZ>,
+    batch_lines: usize,
+    batch_started: Option<Instant>,
+    // Lines being written.
+    sending: ArrayString<BATCH_SZ>,
+    dropped: u32,
+    next_backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl TcpClient for InfluxClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll(
+        &mut self,
+        socket: &mut dyn TcpConnection,
+        timestamp: Instant,
+        _random: &mut Random,
+    ) -> TcpAction {
+        if !self.config.is_enabled() {
+            return TcpAction::Nothing;
+        }
+
+        if !self.batch.is_empty() && self.batch_started.is_none() {
+            self.batch_started = Some(timestamp);
+        }
+        let batch_due = self.batch_lines >= FLUSH_LINES
+            || self.batch_started.map_or(false, |started| {
+                timestamp - started >= Duration::from_millis(FLUSH_INTERVAL_MS)
+            });
+        if self.sending.is_empty() && batch_due {
+            mem::swap(&mut self.sending, &mut self.batch);
+            self.batch_lines = 0;
+            self.batch_started = None;
+        }
+
+        match self.state {
+            State::Idle => {
+                if !self.sending.is_empty() && !socket.is_open() && timestamp >= self.next_attempt {
+                    return self.connect();
+                }
+            }
+            State::Connecting => {
+                if socket.may_send() {
+                    match self.send_batch(socket) {
+                        Ok(()) => {
+                            self.state = State::AwaitingResponse {
+                                deadline: timestamp + Duration::from_millis(RESPONSE_TIMEOUT_MS),
+                            }
+                        }
+                        Err(free) => {
+                            log::warn!(
+                                "InfluxDB request does not fit in socket buffer ({} bytes free)",
+                                free
+                            );
+                            self.retry_later(timestamp);
+                            return TcpAction::Abort;
+                        }
+                    }
+                } else if !socket.is_active() {
+                    log::warn!("Failed to connect to InfluxDB at {}", self.config.host);
+                    self.retry_later(timestamp);
+                }
+            }
+            State::AwaitingResponse { deadline } => {
+                if socket.can_recv() {
+                    // Only the status line matters, so wait until it's in,
+                    // then discard everything.
+                    let res = socket.peek().map(|buf| match buf.len() {
+                        len if len < 12 => (0, None),
+                        len => (len, Some(parse_status(buf))),
+                    });
+                    match res.and_then(|(len, status)| socket.consume(len).map(|_| status)) {
+                        Ok(Some(status)) => {
+                            self.handle_response(status, timestamp);
+                            return TcpAction::Close;
+                        }
+                        Ok(None) => {}
+                        Err(err) => log::warn!("Failed to receive InfluxDB response: {}", err),
+                    }
+                } else if !socket.is_active() {
+                    log::warn!("InfluxDB closed the connection without responding");
+                    self.retry_later(timestamp);
+                } else if timestamp >= deadline {
+                    log::warn!("InfluxDB did not respond in time, retrying");
+                    self.retry_later(timestamp);
+                    return TcpAction::Abort;
+                }
+            }
+        }
+        TcpAction::Nothing
+    }
+}
+
+impl InfluxClient {
+    pub fn new(config: InfluxConfig) -> Self {
+        Self {
+            config,
+            handle: None,
+            state: State::Idle,
+            batch: ArrayString::new(),
+            batch_lines: 0,
+            batch_started: None,
+            sending: ArrayString::new(),
+            dropped: 0,
+            next_backoff: Duration::from_millis(INITIAL_BACKOFF_MS),
+            next_attempt: Instant::from_millis(0),
+        }
+    }
+
+    /// Connects to InfluxDB. If that fails, the socket stays closed, and the
+    /// batch is retried once the `Connecting` state sees that.
+    fn connect(&mut self) -> TcpAction {
+        let remote = IpEndpoint::new(IpAddress::Ipv4(self.config.host), self.config.port);
+        log::debug!("Connecting to InfluxDB at {}", remote);
+        self.state = State::Connecting;
+        TcpAction::Connect {
+            remote,
+            timeout: Some(Duration::from_secs(30)),
+            keep_alive: None,
+        }
+    }
+
+    /// Sends the request for the current batch, or returns the free space in
+    /// the socket buffer if it doesn't fit.
+    fn send_batch(&mut self, socket: &mut dyn TcpConnection) -> Result<(), usize> {
+        let mut header = ArrayString::<HEADER_SZ>::new();
+        let free = socket.send_free();
+        if write!(
+            header,
+            "POST /api/v2/write?org={}&bucket={}&precision=s HTTP/1.1\r\n\
+            Host: {}:{}\r\n\
+            Authorization: Token {}\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Length: {}\r\n\
+            Connection: close\r\n\r\n",
+            QueryValue(&self.config.org),
+            QueryValue(&self.config.bucket),
+            self.config.host,
+            self.config.port,
+            self.config.token,
+            self.sending.len(),
+        )
+        .is_err()
+            || header.len() + self.sending.len() > free
+        {
+            return Err(free);
+        }
+        for part in [header.as_bytes(), self.sending.as_bytes()].iter() {
+            if let Err(err) = socket.send_slice(part) {
+                log::warn!("Failed to send InfluxDB request: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_response(&mut self, status: Option<u16>, timestamp: Instant) {
+        match status {
+            Some(200..=299) => {
+                log::debug!("Wrote {} bytes to InfluxDB", self.sending.len());
+                if self.dropped > 0 {
+                    log::warn!("Dropped {} telegrams while InfluxDB was busy", self.dropped);
+                    self.dropped = 0;
+                }
+                self.sending.clear();
+                self.next_backoff = Duration::from_millis(INITIAL_BACKOFF_MS);
+                self.state = State::Idle;
+            }
+            // Overloaded or unavailable, so try again later.
+            Some(429) | Some(500..=599) => {
+                log::warn!("InfluxDB write failed with status {:?}, retrying", status);
+                self.retry_later(timestamp);
+            }
+            // Retrying won't fix a bad token or a missing bucket.
+            _ => {
+                log::warn!(
+                    "InfluxDB rejected write with status {:?}, dropping batch",
+                    status
+                );
+                self.sending.clear();
+                self.state = State::Idle;
+            }
+        }
+    }
+
+    fn retry_later(&mut self, timestamp: Instant) {
+        self.state = State::Idle;
+        self.next_attempt = timestamp + self.next_backoff;
+        self.next_backoff =
+            Duration::from_millis((self.next_backoff.total_millis() * 2).min(BACKOFF_CAP_MS));
+    }
+}
+
+impl TelemetrySink for InfluxClient {
+    /// Adds a telegram to the next batch.
+    fn accept(&mut self, record: &TelemetryRecord) {
+        if !self.config.is_enabled() {
+            return;
+        }
+        let mut line = ArrayString::<MAX_LINE_SZ>::new();
+        if write_line(&mut line, record.telegram).is_err() {
+            log::warn!("Failed to convert telegram to line protocol");
+            return;
+        }
+        if self.batch.try_push_str(&line).is_err() {
+            if self.dropped == 0 {
+                log::warn!("InfluxDB batch is full, dropping telegrams");
+            }
+            self.dropped += 1;
+            return;
+        }
+        self.batch_lines += 1;
+    }
+}
+
+/// Percent-encodes a value for the query string, leaving only the unreserved
+/// characters of RFC 3986 as they are.
+struct QueryValue<'a>(&'a str);
+
+impl fmt::Display for QueryValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    f.write_char(byte as char)?
+                }
+                _ => write!(f, "%{:02X}", byte)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the status code from an HTTP status line, like `HTTP/1.1 204`.
+fn parse_status(response: &[u8]) -> Option<u16> {
+    if !response.starts_with(b"HTTP/1.") {
+        return None;
+    }
+    core::str::from_utf8(response.get(9..12)?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Writes a telegram as a single line of line protocol, timestamped with the
+/// meter's clock if it has one.
+fn write_line<W: Write>(writer: &mut W, telegram: &Telegram) -> fmt::Result {
+    write!(writer, "{}", MEASUREMENT)?;
+    let mut separator = " ";
+    for (name, value) in metrics(telegram) {
+        write!(writer, "{}{}={}i", separator, name, value)?;
+        separator = ",";
+    }
+    // A line without fields is invalid.
+    if separator == " " {
+        return Err(fmt::Error);
+    }
+    if let Some(timestamp) = counters::telegram_unix_time(telegram) {
+        write!(writer, " {}", timestamp)?;
+    }
+    writeln!(writer)
+}
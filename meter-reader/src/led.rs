@@ -0,0 +1,156 @@
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::events::{Event, EventConsumer};
+
+// How long the LED goes dark when a telegram arrives.
+const TELEGRAM_BLINK_MS: i64 = 50;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Colour {
+    Off,
+    Red,
+    Yellow,
+    Green,
+    Blue,
+}
+
+/// Something that can show a `Colour`.
+pub trait Indicator {
+    fn set(&mut self, colour: Colour);
+}
+
+/// A single-colour LED, which is on for any colour other than `Off`.
+pub struct SingleLed<P>(pub P);
+
+impl<P: OutputPin> Indicator for SingleLed<P> {
+    fn set(&mut self, colour: Colour) {
+        set_pin(&mut self.0, colour != Colour::Off);
+    }
+}
+
+/// An RGB LED with a common cathode, driven by one pin per channel.
+pub struct RgbLed<R, G, B> {
+    pub red: R,
+    pub green: G,
+    pub blue: B,
+}
+
+impl<R: OutputPin, G: OutputPin, B: OutputPin> Indicator for RgbLed<R, G, B> {
+    fn set(&mut self, colour: Colour) {
+        let (r, g, b) = match colour {
+            Colour::Off => (false, false, false),
+            Colour::Red => (true, false, false),
+            Colour::Yellow => (true, true, false),
+            Colour::Green => (false, true, false),
+            Colour::Blue => (false, false, true),
+        };
+        set_pin(&mut self.red, r);
+        set_pin(&mut self.green, g);
+        set_pin(&mut self.blue, b);
+    }
+}
+
+fn set_pin<P: OutputPin>(pin: &mut P, on: bool) {
+    // Errors can't be handled in any meaningful way, and don't occur on GPIO.
+    let _ = if on { pin.set_high() } else { pin.set_low() };
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Pattern {
+    /// Link down, or the last telegram could not be parsed: fast red blink.
+    Error,
+    /// Waiting for DHCP: slow yellow blink.
+    AwaitingAddress,
+    /// Waiting for the MQTT connection: short blue blink.
+    AwaitingBroker,
+    /// Connected to the broker: solid green, going dark for each telegram.
+    Connected,
+}
+
+impl Pattern {
+    fn colour(self, now: i64) -> Colour {
+        let (colour, on_ms, period_ms) = match self {
+            Pattern::Error => (Colour::Red, 100, 200),
+            Pattern::AwaitingAddress => (Colour::Yellow, 500, 1000),
+            Pattern::AwaitingBroker => (Colour::Blue, 100, 1000),
+            Pattern::Connected => return Colour::Green,
+        };
+        if now.rem_euclid(period_ms) < on_ms {
+            colour
+        } else {
+            Colour::Off
+        }
+    }
+}
+
+/// Shows the state of the reader on an LED.
+///
+/// Everything it shows is learned from `Event`s.
+pub struct StatusLed<I> {
+    indicator: I,
+    link_up: bool,
+    has_address: bool,
+    mqtt_connected: bool,
+    telegram_error: bool,
+    blink_until: i64,
+    shown: Option<Colour>,
+}
+
+impl<I: Indicator> StatusLed<I> {
+    pub fn new(indicator: I) -> Self {
+        Self {
+            indicator,
+            link_up: false,
+            has_address: false,
+            mqtt_connected: false,
+            telegram_error: false,
+            blink_until: 0,
+            shown: None,
+        }
+    }
+
+    /// Updates the LED. Call this at least every few milliseconds, or blinks
+    /// will be irregular.
+    pub fn update(&mut self, now: i64) {
+        let pattern = if !self.link_up || self.telegram_error {
+            Pattern::Error
+        } else if !self.has_address {
+            Pattern::AwaitingAddress
+        } else if !self.mqtt_connected {
+            Pattern::AwaitingBroker
+        } else {
+            Pattern::Connected
+        };
+        let colour = if now < self.blink_until {
+            Colour::Off
+        } else {
+            pattern.colour(now)
+        };
+        if self.shown != Some(colour) {
+            self.indicator.set(colour);
+            self.shown = Some(colour);
+        }
+    }
+}
+
+impl<I> EventConsumer for StatusLed<I> {
+    fn on_event(&mut self, event: Event, now: i64) {
+        match event {
+            Event::TelegramParsed => {
+                self.telegram_error = false;
+                self.blink_until = now + TELEGRAM_BLINK_MS;
+            }
+            Event::CrcFailed | Event::ParseFailed => self.telegram_error = true,
+            Event::LinkUp => self.link_up = true,
+            Event::LinkDown => self.link_up = false,
+            Event::AddressAcquired(_) => self.has_address = true,
+            Event::AddressLost => self.has_address = false,
+            Event::MqttConnected => self.mqtt_connected = true,
+            Event::MqttDisconnected => self.mqtt_connected = false,
+            Event::ConfigChanged
+            | Event::SelfTestRequested
+            | Event::LogDumpRequested
+            | Event::ResetRequested => {}
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use core::fmt::Debug;
+
+use crate::crc::crc32;
+
+pub const SECTOR_SZ: u32 = 4096;
+/// Largest payload of a record, enough for a configuration record.
+pub const MAX_RECORD_SZ: usize = crate::config::RECORD_SZ;
+
+const SECTOR_MAGIC: [u8; 4] = *b"MRLG";
+// Magic, sequence number, erase count and CRC.
+const SECTOR_HEADER_SZ: u32 = 16;
+// Length, kind, a reserved byte and the CRC of the payload.
+const RECORD_HEADER_SZ: u32 = 8;
+const ERASED_LEN: u16 = 0xFFFF;
+
+/// Flash the log is kept in, erased in sectors of `SECTOR_SZ`.
+pub trait Storage {
+    type Error: Debug;
+
+    /// Size in bytes, a multiple of `SECTOR_SZ`.
+    fn capacity(&self) -> u32;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Programs erased flash. `data` may span several pages.
+    fn program(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Self::Error>;
+}
+
+/// Storage for boards without a flash chip for the log, which can't be
+/// constructed.
+pub enum Unavailable {}
+
+impl Storage for Unavailable {
+    type Error = core::convert::Infallible;
+
+    fn capacity(&self) -> u32 {
+        match *self {}
+    }
+
+    fn read(&mut self, _offset: u32, _buf: &mut [u8]) -> Result<(), Self::Error> {
+        match *self {}
+    }
+
+    fn program(&mut self, _offset: u32, _data: &[u8]) -> Result<(), Self::Error> {
+        match *self {}
+    }
+
+    fn erase_sector(&mut self, _offset: u32) -> Result<(), Self::Error> {
+        match *self {}
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RecordKind {
+    /// A sample put in the outage buffer.
+    OutageSample,
+    /// The outage buffer was emptied, so the samples before this are gone.
+    OutageDrained,
+    /// A configuration record, as saved to internal flash.
+    Config,
+    /// The panic report of the previous boot, as text.
+    Crash,
+}
+
+impl RecordKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(RecordKind::OutageSample),
+            2 => Some(RecordKind::OutageDrained),
+            3 => Some(RecordKind::Config),
+            4 => Some(RecordKind::Crash),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            RecordKind::OutageSample => 1,
+            RecordKind::OutageDrained => 2,
+            RecordKind::Config => 3,
+            RecordKind::Crash => 4,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RecordKind::OutageSample => "outage_sample",
+            RecordKind::OutageDrained => "outage_drained",
+            RecordKind::Config => "config",
+            RecordKind::Crash => "crash",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct SectorHeader {
+    sequence: u32,
+    erase_count: u32,
+}
+
+impl SectorHeader {
+    fn encode(&self) -> [u8; SECTOR_HEADER_SZ as usize] {
+        let mut header = [0; SECTOR_HEADER_SZ as usize];
+        header[..4].copy_from_slice(&SECTOR_MAGIC);
+        header[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        header[8..12].copy_from_slice(&self.erase_count.to_le_bytes());
+        let crc = crc32(&header[..12]);
+        header[12..].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    fn decode(header: &[u8; SECTOR_HEADER_SZ as usize]) -> Option<Self> {
+        let word =
+            |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        if header[..4] != SECTOR_MAGIC || crc32(&header[..12]) != word(12) {
+            return None;
+        }
+        Some(Self {
+            sequence: word(4),
+            erase_count: word(8),
+        })
+    }
+}
+
+/// The sector being appended to.
+#[derive(Copy, Clone, Debug)]
+struct Head {
+    sector: u32,
+    header: SectorHeader,
+    // Offset of the next record within the sector.
+    end: u32,
+}
+
+/// An append-only log of records in external flash.
+///
+/// Sectors are written in turn, and once the last one is full, the oldest one
+/// is erased to make room, so every sector is erased equally often. Each
+/// sector starts with a header holding a sequence number, which tells the
+/// newest sector apart after a reboot, and the number of times it was erased.
+/// Records carry a CRC, so one that was cut short by a power loss is skipped.
+pub struct LogStore<S> {
+    storage: S,
+    sectors: u32,
+    head: Option<Head>,
+    max_erase_count: u32,
+}
+
+impl<S: Storage> LogStore<S> {
+    /// Finds where the log left off, by reading the header of every sector.
+    pub fn mount(mut storage: S) -> Result<Self, S::Error> {
+        let sectors = storage.capacity() / SECTOR_SZ;
+        let mut head: Option<Head> = None;
+        let mut max_erase_count = 0;
+        for sector in 0..sectors {
+            if let Some(header) = read_header(&mut storage, sector)? {
+                max_erase_count = max_erase_count.max(header.erase_count);
+                if head.is_none_or(|h| header.sequence > h.header.sequence) {
+                    head = Some(Head {
+                        sector,
+                        header,
+                        end: SECTOR_HEADER_SZ,
+                    });
+                }
+            }
+        }
+        let mut store = Self {
+            storage,
+            sectors,
+            head,
+            max_erase_count,
+        };
+        if let Some(mut head) = store.head {
+            head.end = store.for_each_in_sector(head.sector, |_, _| {})?;
+            store.head = Some(head);
+        }
+        Ok(store)
+    }
+
+    pub fn sectors(&self) -> u32 {
+        self.sectors
+    }
+
+    /// The most times any sector has been erased.
+    pub fn max_erase_count(&self) -> u32 {
+        self.max_erase_count
+    }
+
+    pub fn append(&mut self, kind: RecordKind, payload: &[u8]) -> Result<(), S::Error> {
+        debug_assert!(payload.len() <= MAX_RECORD_SZ);
+        let len = RECORD_HEADER_SZ + payload.len() as u32;
+        let mut head = match self.head {
+            Some(head) if head.end + len <= SECTOR_SZ => head,
+            head => self.start_sector(head)?,
+        };
+        let mut header = [0xFF; RECORD_HEADER_SZ as usize];
+        header[..2].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        header[2] = kind.to_u8();
+        header[4..].copy_from_slice(&crc32(payload).to_le_bytes());
+        // The header goes first: a record without one would be overwritten
+        // by the next, and flash can't be programmed twice.
+        let offset = head.sector * SECTOR_SZ + head.end;
+        self.storage.program(offset, &header)?;
+        self.storage.program(offset + RECORD_HEADER_SZ, payload)?;
+        head.end += len;
+        self.head = Some(head);
+        Ok(())
+    }
+
+    /// Calls `f` with every intact record, oldest first.
+    pub fn for_each(&mut self, mut f: impl FnMut(RecordKind, &[u8])) -> Result<(), S::Error> {
+        let head = match self.head {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+        for i in 1..=self.sectors {
+            let sector = (head.sector + i) % self.sectors;
+            if read_header(&mut self.storage, sector)?.is_some() {
+                self.for_each_in_sector(sector, &mut f)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Erases the sector after `head` and makes it the new head.
+    fn start_sector(&mut self, head: Option<Head>) -> Result<Head, S::Error> {
+        let (sector, sequence) = match head {
+            Some(head) => ((head.sector + 1) % self.sectors, head.header.sequence + 1),
+            None => (0, 1),
+        };
+        // A sector without a valid header may never have been erased by us,
+        // or its header may have been lost halfway through; assume the worst.
+        let erase_count = match read_header(&mut self.storage, sector)? {
+            Some(header) => header.erase_count + 1,
+            None => self.max_erase_count + 1,
+        };
+        self.storage.erase_sector(sector * SECTOR_SZ)?;
+        let header = SectorHeader {
+            sequence,
+            erase_count,
+        };
+        self.storage.program(sector * SECTOR_SZ, &header.encode())?;
+        self.max_erase_count = self.max_erase_count.max(erase_count);
+        let head = Head {
+            sector,
+            header,
+            end: SECTOR_HEADER_SZ,
+        };
+        self.head = Some(head);
+        Ok(head)
+    }
+
+    /// Calls `f` with the intact records in `sector`, and returns the offset
+    /// after the last record.
+    fn for_each_in_sector(
+        &mut self,
+        sector: u32,
+        mut f: impl FnMut(RecordKind, &[u8]),
+    ) -> Result<u32, S::Error> {
+        let base = sector * SECTOR_SZ;
+        let mut end = SECTOR_HEADER_SZ;
+        let mut payload = [0; MAX_RECORD_SZ];
+        while end + RECORD_HEADER_SZ <= SECTOR_SZ {
+            let mut header = [0; RECORD_HEADER_SZ as usize];
+            self.storage.read(base + end, &mut header)?;
+            let len = u16::from_le_bytes([header[0], header[1]]);
+            if len == ERASED_LEN {
+                break;
+            }
+            if len as usize > MAX_RECORD_SZ || end + RECORD_HEADER_SZ + len as u32 > SECTOR_SZ {
+                // Nothing after this can be trusted, so treat the sector as
+                // full.
+                end = SECTOR_SZ;
+                break;
+            }
+            let payload = &mut payload[..len as usize];
+            self.storage.read(base + end + RECORD_HEADER_SZ, payload)?;
+            let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            if crc32(payload) == crc {
+                if let Some(kind) = RecordKind::from_u8(header[2]) {
+                    f(kind, payload);
+                }
+            }
+            end += RECORD_HEADER_SZ + len as u32;
+        }
+        Ok(end)
+    }
+}
+
+fn read_header<S: Storage>(storage: &mut S, sector: u32) -> Result<Option<SectorHeader>, S::Error> {
+    let mut header = [0; SECTOR_HEADER_SZ as usize];
+    storage.read(sector * SECTOR_SZ, &mut header)?;
+    Ok(SectorHeader::decode(&header))
+}
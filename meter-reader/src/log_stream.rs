@@ -0,0 +1,241 @@
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::{Level, LevelFilter};
+use smoltcp::{socket::SocketHandle, time::Instant};
+
+use crate::{
+    clock,
+    network::client::{TcpAction, TcpClient, TcpConnection},
+    random::Random,
+};
+
+// Next to the telegram server and the OTA receiver.
+const LISTEN_PORT: u16 = 2003;
+// Enough to hold a burst of a few dozen lines between two polls.
+const RING_SZ: usize = 4096;
+// Long enough for any level name, with a line ending.
+const MAX_COMMAND_LEN: usize = 8;
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+static RING: Ring = Ring::new();
+
+/// Queues a log record for the connected consumer, if there is one and the
+/// record passes its level filter. Called by the logger for every record.
+pub fn capture(level: Level, target: &str, args: fmt::Arguments) {
+    if level > log::max_level() {
+        return;
+    }
+    RING.with(|ring| {
+        if level <= ring.level {
+            let _ = writeln!(
+                ring,
+                "[{:>8}ms {:<5} {}] {}",
+                clock::millis(),
+                level,
+                target,
+                args
+            );
+        }
+    });
+}
+
+/// Streams log output to whoever is connected to `LISTEN_PORT`, so it can be
+/// followed with e.g. `nc <address> 2003` instead of over USB.
+///
+/// Records are queued in a small ring until the socket has room for them, and
+/// the oldest lines are discarded when it fills up. Only records at `info`
+/// level or more severe are sent, unless the consumer sends the name of
+/// another level, like `debug`. Like the telegram server, it only takes a
+/// single consumer at a time.
+pub struct LogStreamClient {
+    handle: Option<SocketHandle>,
+    connected: bool,
+}
+
+impl TcpClient for LogStreamClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll(
+        &mut self,
+        socket: &mut dyn TcpConnection,
+        _timestamp: Instant,
+        _random: &mut Random,
+    ) -> TcpAction {
+        if !socket.is_open() {
+            log::debug!("Listening for log consumers on port {}", LISTEN_PORT);
+            return TcpAction::Listen(LISTEN_PORT);
+        }
+
+        if socket.may_send() && !self.connected {
+            self.connected = true;
+            set_level(DEFAULT_LEVEL);
+            log::info!("Log consumer connected: {}", socket.remote_endpoint());
+        } else if !socket.is_active() && self.connected {
+            self.connected = false;
+            // Stop queueing records, and discard the ones nobody will read.
+            set_level(LevelFilter::Off);
+            log::info!("Log consumer disconnected");
+        }
+
+        if socket.can_recv() {
+            self.receive_level(socket);
+        }
+
+        // The consumer closed its side, so close ours to make the socket
+        // available for the next one.
+        if socket.may_send() && !socket.may_recv() {
+            return TcpAction::Close;
+        }
+
+        if socket.can_send() {
+            let sent = RING.with(|ring| socket.send_with(&mut |buf| ring.drain(buf)));
+            if let Some(Err(err)) = sent {
+                log::warn!("Failed to send log output to consumer: {}", err);
+            }
+        }
+        TcpAction::Nothing
+    }
+}
+
+impl LogStreamClient {
+    pub fn new() -> Self {
+        Self {
+            handle: None,
+            connected: false,
+        }
+    }
+
+    fn receive_level(&mut self, socket: &mut dyn TcpConnection) {
+        let mut command = [0; MAX_COMMAND_LEN];
+        let len = match socket.recv_slice(&mut command) {
+            Ok(len) => len,
+            Err(err) => {
+                log::warn!("Failed to receive from log consumer: {}", err);
+                return;
+            }
+        };
+        // Anything that didn't fit can't be a level name.
+        if let Err(err) = socket.consume(usize::MAX) {
+            log::warn!("Failed to discard received data: {}", err);
+        }
+        match core::str::from_utf8(&command[..len])
+            .ok()
+            .and_then(|command| command.trim().parse().ok())
+        {
+            Some(level) => {
+                set_level(level);
+                log::info!("Log stream level set to {}", level);
+            }
+            None => log::warn!("Log consumer sent an invalid level"),
+        }
+    }
+}
+
+fn set_level(level: LevelFilter) {
+    RING.with(|ring| {
+        ring.level = level;
+        if level == LevelFilter::Off {
+            ring.len = 0;
+        }
+    });
+}
+
+/// Log output waiting to be sent, shared between everything that logs and the
+/// client that sends it on.
+///
+/// Interrupt handlers log too, so instead of waiting for the ring, a record
+/// that comes in while it's in use is dropped.
+struct Ring {
+    busy: AtomicBool,
+    inner: UnsafeCell<RingInner>,
+}
+
+// `inner` is only accessed while holding `busy`.
+unsafe impl Sync for Ring {}
+
+struct RingInner {
+    buf: [u8; RING_SZ],
+    // Index of the oldest queued byte, and the number of queued bytes.
+    start: usize,
+    len: usize,
+    // `Off` while nobody is connected.
+    level: LevelFilter,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            busy: AtomicBool::new(false),
+            inner: UnsafeCell::new(RingInner {
+                buf: [0; RING_SZ],
+                start: 0,
+                len: 0,
+                level: LevelFilter::Off,
+            }),
+        }
+    }
+
+    /// Runs `f` on the ring, unless it's already in use.
+    fn with<T>(&self, f: impl FnOnce(&mut RingInner) -> T) -> Option<T> {
+        if self
+            .busy
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+        // Safety: holding `busy` gives us exclusive access.
+        let result = f(unsafe { &mut *self.inner.get() });
+        self.busy.store(false, Ordering::Release);
+        Some(result)
+    }
+}
+
+impl RingInner {
+    fn push(&mut self, byte: u8) {
+        if self.len == RING_SZ {
+            self.discard_oldest_line();
+        }
+        self.buf[(self.start + self.len) % RING_SZ] = byte;
+        self.len += 1;
+    }
+
+    /// Makes room by discarding the oldest line as a whole, so the consumer
+    /// doesn't get half of one.
+    fn discard_oldest_line(&mut self) {
+        while self.len > 0 {
+            let byte = self.buf[self.start];
+            self.start = (self.start + 1) % RING_SZ;
+            self.len -= 1;
+            if byte == b'\n' {
+                break;
+            }
+        }
+    }
+
+    /// Moves as much queued output into `buf` as fits, returning its length.
+    fn drain(&mut self, buf: &mut [u8]) -> usize {
+        let len = self.len.min(buf.len());
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.buf[(self.start + i) % RING_SZ];
+        }
+        self.start = (self.start + len) % RING_SZ;
+        self.len -= len;
+        len
+    }
+}
+
+impl Write for RingInner {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|byte| self.push(byte));
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+//! Logging macros for the network and MQTT code, which is chatty enough at
+//! debug level that formatting its output on the Teensy dominates the time
+//! spent polling. With the `defmt-log` feature, these log through defmt over
+//! RTT, which defers formatting to the host. Otherwise they go to `log` like
+//! everything else.
+//!
+//! Format strings must work for both: stick to `{}` and `{:?}` with integer
+//! hints like `{:#06x}`, and wrap arguments other than integers, booleans and
+//! strings in `Display2Format` or `Debug2Format`.
+
+use core::fmt::{self, Write};
+
+#[cfg(feature = "defmt-log")]
+pub use defmt::{Debug2Format, Display2Format};
+
+use crate::{clock, log_stream};
+
+static LOGGER: Logger = Logger;
+
+/// Installs the `log` backend, which writes every record to the console
+/// output (USB on the Teensy, stderr in the simulator) and passes it on to
+/// the log stream.
+pub fn init() -> Result<(), log::SetLoggerError> {
+    log::set_logger(&LOGGER)
+}
+
+/// Changes the level of `log` output from here on, e.g. to temporarily trace
+/// a device in the field. The defmt level is fixed at build time.
+pub fn set_level(level: log::LevelFilter) {
+    log::set_max_level(level);
+    log::info!("Log level set to {}", level);
+}
+
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(
+                Output,
+                "[{:>8}ms {:<5} {}] {}",
+                clock::millis(),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            log_stream::capture(record.level(), record.target(), *record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Where log output is shown locally.
+struct Output;
+
+#[cfg(not(feature = "sim"))]
+extern "C" {
+    // The USB serial driver from the Teensy core, which teensy4-bsp links in
+    // and starts in `usb::init`.
+    fn usb_serial_write(buffer: *const u8, size: u32) -> i32;
+}
+
+impl Write for Output {
+    #[cfg(not(feature = "sim"))]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Like everywhere else log output goes, failures are ignored.
+        unsafe { usb_serial_write(s.as_ptr(), s.len() as u32) };
+        Ok(())
+    }
+
+    #[cfg(feature = "sim")]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        use std::io::Write;
+        std::io::stderr()
+            .write_all(s.as_bytes())
+            .map_err(|_| fmt::Error)
+    }
+}
+
+#[cfg(not(feature = "defmt-log"))]
+mod wrappers {
+    use core::fmt;
+
+    /// Logs a value through its `Display` implementation.
+    pub struct Display2Format<'a, T: ?Sized>(pub &'a T);
+
+    impl<T: fmt::Display + ?Sized> fmt::Display for Display2Format<'_, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    /// Logs a value through its `Debug` implementation.
+    pub struct Debug2Format<'a, T: ?Sized>(pub &'a T);
+
+    impl<T: fmt::Debug + ?Sized> fmt::Debug for Debug2Format<'_, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+}
+
+#[cfg(not(feature = "defmt-log"))]
+pub use wrappers::{Debug2Format, Display2Format};
+
+#[cfg(feature = "defmt-log")]
+defmt::timestamp!("{=i64}", crate::clock::millis());
+
+macro_rules! trace {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "defmt-log")]
+        defmt::trace!($($arg)+);
+        #[cfg(not(feature = "defmt-log"))]
+        log::trace!($($arg)+);
+    }};
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "defmt-log")]
+        defmt::debug!($($arg)+);
+        #[cfg(not(feature = "defmt-log"))]
+        log::debug!($($arg)+);
+    }};
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "defmt-log")]
+        defmt::info!($($arg)+);
+        #[cfg(not(feature = "defmt-log"))]
+        log::info!($($arg)+);
+    }};
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "defmt-log")]
+        defmt::warn!($($arg)+);
+        #[cfg(not(feature = "defmt-log"))]
+        log::warn!($($arg)+);
+    }};
+}
@@ -1,163 +1,1004 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(feature = "sim"), no_std)]
+#![cfg_attr(not(feature = "sim"), no_main)]
 
+#[cfg(all(feature = "teensy40", feature = "teensy41"))]
+compile_error!("Enable only one of the `teensy40` and `teensy41` features");
+
+// Declared first, so its macros are available in every other module.
+#[macro_use]
+mod logging;
+
+mod alerts;
+mod boot_count;
+mod calibration;
+// The simulator brings its own clock and flash, see `sim.rs`.
+#[cfg_attr(feature = "sim", path = "sim/clock.rs")]
 mod clock;
+mod config;
+mod console;
+mod costs;
+mod counters;
+mod crc;
+mod derived;
+mod diagnostics;
+mod events;
+mod fault;
+#[cfg_attr(feature = "sim", path = "sim/flash.rs")]
+mod flash;
+#[cfg(not(feature = "sim"))]
+mod flash_log;
+mod gas;
+mod graphite;
+mod influx;
+mod led;
+#[cfg(not(feature = "sim"))]
+mod log_store;
+mod log_stream;
+mod memstats;
+mod metrics;
 mod mqtt;
+mod multicast;
 mod network;
+#[cfg(not(feature = "sim"))]
+mod onewire;
+mod ota;
+mod outage;
+mod page_log;
 mod panic;
-mod random;
+mod parse_errors;
+mod peak;
+#[cfg(not(feature = "sim"))]
+mod power;
+mod provisioning;
+// The simulator has no S0 input, so pulses are never counted.
+#[cfg_attr(feature = "sim", allow(dead_code))]
+mod s0;
+// The simulator has no SD card, and only uses the format setting.
+#[cfg_attr(feature = "sim", allow(dead_code))]
+mod sd_log;
+mod selftest;
+#[cfg(feature = "sim")]
+mod sim;
+mod sntp;
+// Only the Teensy 4.0 has devices on SPI, the Teensy 4.1 uses its own Ethernet.
+#[cfg(feature = "teensy40")]
+mod spi_bus;
+mod statsd;
+mod system_info;
+mod tasks;
+mod telegram_server;
+mod telemetry;
+// The simulator has no 1-Wire bus, so there are no temperatures.
+#[cfg_attr(feature = "sim", allow(dead_code))]
+mod temperature;
+mod topic;
+mod totals;
 mod uart;
+#[cfg(feature = "teensy41")]
+mod usdhc;
+#[cfg(feature = "teensy40")]
+mod w25q;
+mod wall_clock;
+
+// The generator the network stack picks local ports with, also used for
+// jitter and the like.
+use enc28j60_smoltcp::random;
 
+#[cfg(feature = "defmt-log")]
+use defmt_rtt as _;
+#[cfg(not(feature = "sim"))]
+use dsmr42::Telegram;
+#[cfg(feature = "teensy40")]
 use embedded_hal::digital::v1_compat::OldOutputPin;
-use hal::ccm::{spi, PLL1};
+#[cfg(feature = "teensy40")]
+use enc28j60_smoltcp::{registers::Bus, Enc28j60Driver};
+#[cfg(feature = "teensy40")]
+use hal::ccm::spi;
+#[cfg(not(feature = "sim"))]
+use hal::ccm::PLL1;
+#[cfg(not(feature = "sim"))]
 use mqtt::MqttClient;
+#[cfg(not(feature = "sim"))]
+use rtic::Mutex;
+#[cfg(feature = "teensy40")]
+use teensy4_bsp::hal::iomuxc::gpio::Pin;
+#[cfg(feature = "teensy40")]
+use teensy4_bsp::t40 as board;
+#[cfg(feature = "teensy41")]
+use teensy4_bsp::t41 as board;
+#[cfg(not(feature = "sim"))]
 use teensy4_bsp::{
-    hal::{self, ccm, gpio::GPIO, iomuxc::gpio::Pin},
-    t40, usb,
+    hal::{self, ccm, gpio::GPIO},
+    usb,
     usb::LoggingConfig,
     SysTick,
 };
 
+#[cfg(feature = "teensy40")]
+use crate::network::enc28j60::create_enc28j60;
+#[cfg(feature = "teensy41")]
+use crate::network::enet::{create_enet, Enet};
+#[cfg(feature = "teensy41")]
+use crate::usdhc::Usdhc;
+#[cfg(not(feature = "sim"))]
 use crate::{
     clock::Clock,
-    hal::gpio::Output,
+    config::{defaults, Config, ConfigStore},
+    console::Console,
+    costs::CostTracker,
+    diagnostics::Diagnostics,
+    events::EventQueue,
+    fault::{Severity, Subsystem},
+    flash_log::FlashLog,
+    graphite::GraphiteClient,
+    hal::gpio::{Input, Output},
+    influx::InfluxClient,
+    led::StatusLed,
+    log_stream::LogStreamClient,
+    memstats::{self, MemStats},
+    multicast::MulticastClient,
     network::{
-        client::TcpClientStore,
-        driver::{create_enc28j60, Enc28j60Phy},
+        client::{TcpClient, TcpClientStore, UdpClientStore},
+        filter::FrameFilter,
         stack::NetworkStack,
     },
+    onewire::OneWirePin,
+    ota::OtaReceiver,
+    peak::PeakTracker,
+    provisioning::Provisioner,
     random::Random,
-    uart::DsmrUart,
+    s0::PulseCounter,
+    sd_log::SdLog,
+    selftest::SelfTest,
+    sntp::SntpClient,
+    statsd::StatsdClient,
+    system_info::SystemInfo,
+    tasks::{Clients, Trackers},
+    telegram_server::TelegramServer,
+    telemetry::Pipeline,
+    temperature::TemperatureSensors,
+    totals::DailyTotals,
+    uart::{DataRequest, DsmrUart},
+    wall_clock::WallClock,
 };
+#[cfg(feature = "teensy40")]
+use crate::{
+    log_store::LogStore,
+    spi_bus::{ChipSelect, SharedBus, SpiDevice},
+    w25q::W25q,
+};
+
+#[cfg(feature = "sim")]
+fn main() {
+    sim::run()
+}
 
+// Initial log level, which can be changed at runtime with `log <level>`.
 const LOG_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
+#[cfg(feature = "teensy40")]
 const SPI_CLOCK_HZ: u32 = 16_000_000;
-const DSMR_42_BAUD: u32 = 115200;
-const DSMR_INVERTED: bool = false;
-const ETH_ADDR: [u8; 6] = [0xEE, 0x00, 0x00, 0x0E, 0x4C, 0xA2];
-
-#[cortex_m_rt::entry]
-fn main() -> ! {
-    let stack_bot = 0u8;
-    // Take control of the peripherals.
-    let mut per = teensy4_bsp::Peripherals::take().unwrap();
-    let core_per = cortex_m::Peripherals::take().unwrap();
-    let mut systick = SysTick::new(core_per.SYST);
-
-    // Enable serial USB logging.
-    let usb = hal::ral::usb::USB1::take().unwrap();
-    let _ = usb::init(
-        usb,
-        LoggingConfig {
-            max_level: LOG_LEVEL,
-            filters: &[],
-        },
-    )
-    .unwrap();
-
-    // Wait a bit for the host to catch up.
-    systick.delay(5000);
-    log::info!("USB logging initialised");
-
-    // Set the default clock speed (600MHz).
-    let (_, ipg) = per
-        .ccm
-        .pll1
-        .set_arm_clock(PLL1::ARM_HZ, &mut per.ccm.handle, &mut per.dcdc);
-    let mut clock = Clock::init(per.ccm.perclk, ipg, &mut per.ccm.handle, per.gpt2);
-
-    // Configure the SPI clock. All SPI builders must be extracted at once,
-    // so we discard the ones we don't need.
-    let (_, _, _, spi4_builder) = per.spi.clock(
-        &mut per.ccm.handle,
-        spi::ClockSelect::Pll2,
-        spi::PrescalarSelect::LPSPI_PODF_5,
-    );
-
-    // Configure UART.
-    let uarts = per.uart.clock(
-        &mut per.ccm.handle,
-        ccm::uart::ClockSelect::OSC,
-        ccm::uart::PrescalarSelect::DIVIDE_1,
-    );
-
-    let pins = t40::into_pins(per.iomuxc);
-
-    // Set SPI pin assignments.
-    let mut spi4 = spi4_builder.build(pins.p11, pins.p12, pins.p13);
-    // SET UART pin assignments.
-    let mut uart = uarts
-        .uart2
-        .init(pins.p14, pins.p15, DSMR_42_BAUD)
-        .unwrap_or_else(|err| {
-            log::error!("Failed to configure UART: {:?}", err);
-            panic!();
-        });
-    uart.set_rx_inversion(DSMR_INVERTED);
+// The ENC28J60 interrupt pin isn't connected, so incoming frames are only
+// noticed when we poll for them. Poll at least this often, to make sure its
+// receive buffer doesn't fill up.
+const MAX_POLL_INTERVAL_MS: i64 = 5;
+// MQTT only receives a handful of small control packets, but needs to be able
+// to hold a few serialised telegrams in case the broker is slow to ACK.
+const MQTT_RX_BUF_SZ: usize = 512;
+const MQTT_TX_BUF_SZ: usize = 2048;
+// The telegram server never receives anything meaningful, and only needs to
+// hold a single raw telegram.
+const TELEGRAM_SERVER_RX_BUF_SZ: usize = 256;
+const TELEGRAM_SERVER_TX_BUF_SZ: usize = 2048;
+// Firmware images are written to flash a page at a time, so the receive buffer
+// only needs to cover a few pages. Only a short status line is sent back.
+const OTA_RX_BUF_SZ: usize = 4096;
+const OTA_TX_BUF_SZ: usize = 64;
+// Only a level name is ever received. Log output is queued in a ring of its
+// own, so this only needs to hold what's sent in a single poll.
+const LOG_STREAM_RX_BUF_SZ: usize = 64;
+const LOG_STREAM_TX_BUF_SZ: usize = 2048;
+// InfluxDB writes only get a short response, but the whole request has to fit
+// in the transmit buffer.
+const INFLUX_RX_BUF_SZ: usize = 256;
+const INFLUX_TX_BUF_SZ: usize = 3072;
+// Carbon never sends anything, and metrics for a single telegram are sent at
+// a time.
+const GRAPHITE_RX_BUF_SZ: usize = 64;
+const GRAPHITE_TX_BUF_SZ: usize = 2048;
+// Statsd never sends anything back.
+const STATSD_RX_BUF_SZ: usize = 64;
+const STATSD_TX_BUF_SZ: usize = 1024;
+// Nothing is received, and a datagram holds a single telegram.
+const MULTICAST_RX_BUF_SZ: usize = 64;
+const MULTICAST_TX_BUF_SZ: usize = 1024;
+const SNTP_RX_BUF_SZ: usize = 256;
+const SNTP_TX_BUF_SZ: usize = 256;
+// Room for a full TFTP block. Only requests and acknowledgements are sent.
+const PROVISIONING_RX_BUF_SZ: usize = 1024;
+const PROVISIONING_TX_BUF_SZ: usize = 64;
+
+#[cfg(feature = "teensy40")]
+type SpiBus = hal::spi::SPI<hal::iomuxc::consts::U4>;
+#[cfg(feature = "teensy40")]
+type EthSpi = SpiDevice<'static, SpiBus>;
+#[cfg(feature = "teensy40")]
+type EthNcs = OldOutputPin<ChipSelect<'static, SpiBus, GPIO<board::P10, Output>>>;
+#[cfg(feature = "teensy40")]
+type EthDriver =
+    Enc28j60Driver<EthSpi, EthNcs, enc28j60::Unconnected, OldOutputPin<GPIO<board::P9, Output>>>;
+#[cfg(feature = "teensy41")]
+type EthDriver = Enet;
+// The SPI flash for the flash log, on pin 8. The Teensy 4.1 doesn't have one.
+#[cfg(feature = "teensy40")]
+type LogStorage =
+    W25q<SpiDevice<'static, SpiBus>, ChipSelect<'static, SpiBus, GPIO<board::P8, Output>>>;
+#[cfg(feature = "teensy41")]
+type LogStorage = log_store::Unavailable;
+// The SD card slot of the Teensy 4.1. The Teensy 4.0 doesn't have one.
+#[cfg(feature = "teensy40")]
+type SdCard = sd_log::Unavailable;
+#[cfg(feature = "teensy41")]
+type SdCard = Usdhc;
+#[cfg(not(feature = "sim"))]
+type DataRequestPin = GPIO<board::P16, Output>;
+// GPIO1_IO24 on both boards.
+#[cfg(not(feature = "sim"))]
+type S0Pin = GPIO<board::P22, Input>;
+#[cfg(all(feature = "teensy40", not(feature = "rgb-led")))]
+type Indicator = led::SingleLed<GPIO<board::P2, Output>>;
+#[cfg(all(feature = "teensy41", not(feature = "rgb-led")))]
+type Indicator = led::SingleLed<GPIO<board::P13, Output>>;
+#[cfg(all(not(feature = "sim"), feature = "rgb-led"))]
+type Indicator =
+    led::RgbLed<GPIO<board::P2, Output>, GPIO<board::P3, Output>, GPIO<board::P4, Output>>;
+
+#[cfg(not(feature = "sim"))]
+#[rtic::app(device = teensy4_bsp, peripherals = true)]
+const APP: () = {
+    struct Resources {
+        clock: Clock,
+        random: Random,
+        pipeline: Pipeline<DataRequestPin>,
+        network: NetworkStack<'static, EthDriver>,
+        client: MqttClient,
+        telegram_server: TelegramServer,
+        influx: InfluxClient,
+        graphite: GraphiteClient,
+        statsd: StatsdClient,
+        multicast: MulticastClient,
+        sntp: SntpClient,
+        provisioner: Provisioner,
+        // Handed from the network task to the console, which saves it.
+        provisioned_config: Option<Config>,
+        ota: OtaReceiver,
+        log_stream: LogStreamClient,
+        led: StatusLed<Indicator>,
+        events: EventQueue,
+        wall_clock: WallClock,
+        costs: CostTracker,
+        totals: DailyTotals,
+        peak: PeakTracker,
+        selftest: SelfTest,
+        console: Console,
+        flash_log: FlashLog<LogStorage>,
+        sd_log: SdLog<SdCard>,
+        pulses: PulseCounter,
+        // Only set up if the S0 input is enabled, since a floating pin
+        // would count noise.
+        s0_pin: Option<S0Pin>,
+        temperatures: TemperatureSensors<OneWirePin>,
+        system_info: SystemInfo,
+        diagnostics: Diagnostics,
+        diagnostics_interval_ms: i64,
+        next_diagnostics: i64,
+        low_power: bool,
+    }
+
+    #[init(spawn = [poll_network])]
+    fn init(cx: init::Context) -> init::LateResources {
+        // The network stack borrows these for the rest of the program. They
+        // can't be constructed in a `const` context, so start out empty.
+        static mut STORE: Option<network::BackingStore<'static>> = None;
+        static mut CLIENT_STORE: Option<TcpClientStore<MQTT_RX_BUF_SZ, MQTT_TX_BUF_SZ>> = None;
+        static mut SERVER_STORE: Option<
+            TcpClientStore<TELEGRAM_SERVER_RX_BUF_SZ, TELEGRAM_SERVER_TX_BUF_SZ>,
+        > = None;
+        static mut OTA_STORE: Option<TcpClientStore<OTA_RX_BUF_SZ, OTA_TX_BUF_SZ>> = None;
+        static mut LOG_STREAM_STORE: Option<
+            TcpClientStore<LOG_STREAM_RX_BUF_SZ, LOG_STREAM_TX_BUF_SZ>,
+        > = None;
+        static mut INFLUX_STORE: Option<TcpClientStore<INFLUX_RX_BUF_SZ, INFLUX_TX_BUF_SZ>> = None;
+        static mut GRAPHITE_STORE: Option<TcpClientStore<GRAPHITE_RX_BUF_SZ, GRAPHITE_TX_BUF_SZ>> =
+            None;
+        static mut STATSD_STORE: Option<UdpClientStore<STATSD_RX_BUF_SZ, STATSD_TX_BUF_SZ>> = None;
+        static mut MULTICAST_STORE: Option<
+            UdpClientStore<MULTICAST_RX_BUF_SZ, MULTICAST_TX_BUF_SZ>,
+        > = None;
+        static mut SNTP_STORE: Option<UdpClientStore<SNTP_RX_BUF_SZ, SNTP_TX_BUF_SZ>> = None;
+        static mut PROVISIONING_STORE: Option<
+            UdpClientStore<PROVISIONING_RX_BUF_SZ, PROVISIONING_TX_BUF_SZ>,
+        > = None;
+        // Shared by the devices on it, which borrow it for the rest of the
+        // program as well.
+        #[cfg(feature = "teensy40")]
+        static mut SPI_BUS: Option<SharedBus<SpiBus>> = None;
+        // Shared by the ENC28J60 driver and our own access to its registers.
+        #[cfg(feature = "teensy40")]
+        static mut ETH_BUS: Option<Bus<EthSpi, EthNcs>> = None;
 
-    // Set SPI clock speed.
-    match spi4.set_clock_speed(hal::spi::ClockSpeed(SPI_CLOCK_HZ)) {
-        Ok(()) => {
-            log::info!("Set SPI clock speed to {} Hz", SPI_CLOCK_HZ);
+        memstats::paint_stack();
+
+        let mut per = cx.device;
+        let mut systick = SysTick::new(cx.core.SYST);
+
+        // Our logger goes in before the USB stack is started, so the log
+        // stream gets every record as well. The USB stack then only supplies
+        // the output, and the reader for the console. Interrupts stay
+        // disabled until `init` returns, so the host can't enumerate the
+        // device before then, and log output is buffered.
+        logging::init().unwrap();
+        let usb = hal::ral::usb::USB1::take().unwrap();
+        let usb_reader = usb::init(
+            usb,
+            LoggingConfig {
+                // Not used, since our logger is already installed.
+                max_level: log::LevelFilter::Off,
+                filters: &[],
+            },
+        )
+        .map_err(|err| {
+            fault::FaultPolicy::new(Subsystem::Usb).failed(Severity::Degraded, &err);
+        })
+        .ok();
+
+        log::set_max_level(LOG_LEVEL);
+        log::info!("USB logging initialised");
+        #[allow(unused_mut)]
+        let mut system_info = SystemInfo::read();
+        let last_panic = panic::take_last();
+        if let Some(report) = &last_panic {
+            log::warn!("Reset after panic: {}", report);
         }
-        Err(err) => {
-            log::warn!("Unable to set SPI clock speed: {:?}", err);
+        let (config_store, stored_config) = ConfigStore::load();
+        let config = stored_config.unwrap_or_default();
+        let console = Console::new(usb_reader, config, config_store);
+
+        // Set the default clock speed (600MHz).
+        let (_, ipg) = per
+            .ccm
+            .pll1
+            .set_arm_clock(PLL1::ARM_HZ, &mut per.ccm.handle, &mut per.dcdc);
+        let mut clock = Clock::init(per.ccm.perclk, ipg, &mut per.ccm.handle, per.gpt2);
+
+        // Configure UART.
+        let uarts = per.uart.clock(
+            &mut per.ccm.handle,
+            ccm::uart::ClockSelect::OSC,
+            ccm::uart::PrescalarSelect::DIVIDE_1,
+        );
+
+        let pins = board::into_pins(per.iomuxc);
+
+        // SET UART pin assignments.
+        let uart = uarts
+            .uart2
+            .init(pins.p14, pins.p15, config.uart.baud)
+            .unwrap_or_else(|err| fault::fatal(Subsystem::Uart, &err));
+
+        // The DMA channels are driven directly, so we only need the clock.
+        #[cfg(feature = "dma-uart")]
+        let _ = per.dma.clock(&mut per.ccm.handle);
+        let data_request = DataRequest::new(GPIO::new(pins.p16).output(), config.data_request);
+        let mut dsmr_uart = DsmrUart::new(uart, config.uart, data_request);
+        dsmr_uart.set_retransmit(config.uart_retransmit);
+        dsmr_uart.set_watchdog(config.uart_watchdog_mins);
+        if config.uart_autodetect {
+            dsmr_uart.start_probe(clock.millis());
         }
-    }
+        dsmr_uart.set_interrupt_enable(true);
+        let pipeline = Pipeline::new(
+            dsmr_uart,
+            config.min_telegram_interval_ms,
+            config.aggregate,
+            config.alerts,
+        );
+
+        // On the Teensy 4.0, the onboard LED shares pin 13 with the SPI clock,
+        // so the status LED has to be connected externally.
+        #[cfg(all(feature = "teensy40", not(feature = "rgb-led")))]
+        let indicator = led::SingleLed(GPIO::new(pins.p2).output());
+        #[cfg(all(feature = "teensy41", not(feature = "rgb-led")))]
+        let indicator = led::SingleLed(GPIO::new(pins.p13).output());
+        #[cfg(feature = "rgb-led")]
+        let indicator = led::RgbLed {
+            red: GPIO::new(pins.p2).output(),
+            green: GPIO::new(pins.p3).output(),
+            blue: GPIO::new(pins.p4).output(),
+        };
+        let led = StatusLed::new(indicator);
+
+        let pulses = PulseCounter::load(config.s0);
+        // S0 outputs are open collector, pulling the input low for the
+        // length of a pulse. Both edges are needed for debouncing.
+        let s0_pin = if config.s0.enabled {
+            let mut pin = pins.p22;
+            hal::iomuxc::configure(
+                &mut pin,
+                hal::iomuxc::Config::zero()
+                    .set_hysteresis(hal::iomuxc::Hysteresis::Enabled)
+                    .set_pull_keeper(Some(hal::iomuxc::PullKeeper::Pullup100k)),
+            );
+            let mut s0_pin = GPIO::new(pin);
+            s0_pin.set_interrupt_configuration(hal::gpio::InterruptConfiguration::EitherEdge);
+            s0_pin.set_interrupt_enable(true);
+            Some(s0_pin)
+        } else {
+            None
+        };
 
-    let mut dsmr_uart = DsmrUart::new(uart);
-
-    let ncs = make_output_pin(pins.p10);
-    let rst = make_output_pin(pins.p9);
-    let driver = create_enc28j60(&mut systick, spi4, ncs, rst, ETH_ADDR);
-    let mut random = Random::new(clock.ticks());
-    let mut store = network::BackingStore::new();
-
-    let mut network = NetworkStack::new(driver, &mut clock, &mut store, ETH_ADDR);
-
-    let mut client_store = TcpClientStore::new();
-    let mut client = MqttClient::new();
-
-    network.add_client(&mut client, &mut client_store);
-
-    let stack_top = 0u8;
-    log::info!("STACK_BOT: {:p}", &stack_bot);
-    log::info!("STACK_TOP: {:p}", &stack_top);
-    let stack_bot_addr = (&stack_bot as *const u8) as usize;
-    let stack_top_addr = (&stack_top as *const u8) as usize;
-    log::info!("STACK_SZE: {}K", (stack_top_addr - stack_bot_addr) / 1024);
-
-    log::info!("Entering main loop");
-    loop {
-        dsmr_uart.poll();
-        network.poll(&mut clock);
-        network.poll_client(&mut random, &mut client);
-        let (read, res) = dsmr42::parse(dsmr_uart.get_buffer());
-        match res {
-            Ok(telegram) => {
-                log::info!("Got new telegram: {}", telegram.device_id);
-                client.queue_telegram(telegram);
+        // The sensors need an external 4.7k pull-up, the internal one is too
+        // weak to raise the line in time for a slot. It only keeps the line
+        // high if nothing is connected.
+        let bus = if config.temperature_interval_ms > 0 {
+            let mut pin = pins.p23;
+            hal::iomuxc::configure(
+                &mut pin,
+                hal::iomuxc::Config::zero()
+                    .set_hysteresis(hal::iomuxc::Hysteresis::Enabled)
+                    .set_pull_keeper(Some(hal::iomuxc::PullKeeper::Pullup100k)),
+            );
+            let (mut dcb, mut dwt) = (cx.core.DCB, cx.core.DWT);
+            Some(OneWirePin::new(GPIO::new(pin), &mut dcb, &mut dwt))
+        } else {
+            None
+        };
+        let temperatures = TemperatureSensors::new(bus, config.temperature_interval_ms);
+
+        #[cfg(feature = "teensy40")]
+        let (driver, log_store) = {
+            // Configure the SPI clock. All SPI builders must be extracted at
+            // once, so we discard the ones we don't need.
+            let (_, _, _, spi4_builder) = per.spi.clock(
+                &mut per.ccm.handle,
+                spi::ClockSelect::Pll2,
+                spi::PrescalarSelect::LPSPI_PODF_5,
+            );
+            let mut spi4 = spi4_builder.build(pins.p11, pins.p12, pins.p13);
+            match spi4.set_clock_speed(hal::spi::ClockSpeed(SPI_CLOCK_HZ)) {
+                Ok(()) => {
+                    log::info!("Set SPI clock speed to {} Hz", SPI_CLOCK_HZ);
+                }
+                Err(err) => {
+                    log::warn!("Unable to set SPI clock speed: {:?}", err);
+                }
             }
-            Err(dsmr42::TelegramParseError::Incomplete) => {}
+            let bus: &'static SharedBus<SpiBus> = SPI_BUS.get_or_insert(SharedBus::new(spi4));
+            let mut ncs = GPIO::new(pins.p10).output();
+            ncs.set_fast(true);
+            let (spi, ncs) = bus
+                .device(ncs)
+                .unwrap_or_else(|err| fault::fatal(Subsystem::Ethernet, &err));
+            let eth_bus: &'static Bus<EthSpi, EthNcs> =
+                ETH_BUS.get_or_insert(Bus::new(spi, OldOutputPin::new(ncs)));
+            let rst = make_output_pin(pins.p9);
+            let log_store = mount_flash_log(bus, GPIO::new(pins.p8).output());
+            (
+                create_enc28j60(&mut systick, eth_bus, rst, defaults::ETH_ADDR),
+                log_store,
+            )
+        };
+        #[cfg(feature = "teensy41")]
+        let (driver, log_store) = (create_enet(&mut systick, defaults::ETH_ADDR), None);
+        #[allow(unused_mut)]
+        let mut driver = driver.unwrap_or_else(|err| fault::fatal(Subsystem::Ethernet, &err));
+        #[cfg(feature = "teensy40")]
+        {
+            system_info.ethernet_chip = Some(system_info::EthernetChip {
+                revision: driver.revision(),
+                workarounds: driver.workarounds(),
+            });
+        }
+        // Without it, received frames would wait for the next timed poll,
+        // which may be seconds away in low-power mode.
+        #[cfg(feature = "teensy41")]
+        if config.low_power {
+            driver.enable_rx_interrupt();
+        }
+        let random = Random::new(clock.ticks());
+        let store = STORE.get_or_insert_with(network::BackingStore::new);
+
+        let mut network = NetworkStack::new(
+            driver,
+            &mut clock,
+            store,
+            defaults::ETH_ADDR,
+            FrameFilter::default(),
+        );
+        if let Some(cidr) = config.network.static_address {
+            network.set_static_address(cidr, config.network.gateway);
+        }
+
+        let mut flash_log = FlashLog::new(log_store);
+        let mut client = MqttClient::new(config.mqtt, boot_count::increment());
+        flash_log.restore_outage(client.outage_mut());
+        if let Some(report) = last_panic {
+            flash_log.record_crash(&report);
+            client.queue_last_panic(report);
+        }
+
+        network.add_client(
+            &mut client,
+            CLIENT_STORE.get_or_insert_with(TcpClientStore::new),
+        );
+        let mut events = EventQueue::new();
+        events.watch_mqtt(client.get_socket_handle());
+
+        let mut telegram_server = TelegramServer::new();
+
+        network.add_client(
+            &mut telegram_server,
+            SERVER_STORE.get_or_insert_with(TcpClientStore::new),
+        );
+
+        let mut influx = InfluxClient::new(config.influx);
+
+        network.add_client(
+            &mut influx,
+            INFLUX_STORE.get_or_insert_with(TcpClientStore::new),
+        );
+
+        let mut graphite = GraphiteClient::new(config.graphite);
+
+        network.add_client(
+            &mut graphite,
+            GRAPHITE_STORE.get_or_insert_with(TcpClientStore::new),
+        );
+
+        let mut statsd = StatsdClient::new(config.statsd);
+
+        network.add_udp_client(
+            &mut statsd,
+            STATSD_STORE.get_or_insert_with(UdpClientStore::new),
+        );
+
+        let mut multicast = MulticastClient::new(config.multicast);
+
+        network.add_udp_client(
+            &mut multicast,
+            MULTICAST_STORE.get_or_insert_with(UdpClientStore::new),
+        );
+
+        let mut sntp = SntpClient::new(config.sntp);
+
+        network.add_udp_client(
+            &mut sntp,
+            SNTP_STORE.get_or_insert_with(UdpClientStore::new),
+        );
+
+        let mut provisioner = Provisioner::new(config.provisioning, config);
+
+        network.add_udp_client(
+            &mut provisioner,
+            PROVISIONING_STORE.get_or_insert_with(UdpClientStore::new),
+        );
+
+        #[cfg(feature = "teensy40")]
+        let card = None;
+        #[cfg(feature = "teensy41")]
+        let card = match Usdhc::init() {
+            Ok(card) => Some(card),
             Err(err) => {
-                let buffer = dsmr_uart.get_buffer();
-                log::warn!(
-                    "Failed to parse telegram ({} bytes): {:?}, buffer: {:?}",
-                    buffer.len(),
-                    err,
-                    core::str::from_utf8(buffer)
-                );
-                dsmr_uart.clear();
+                log::info!("No SD card found ({:?}), not logging telegrams to it", err);
+                None
+            }
+        };
+        let sd_log = SdLog::new(card, config.sd_log);
+
+        let mut ota = OtaReceiver::new(defaults::OTA_PUBLIC_KEY);
+
+        network.add_client(&mut ota, OTA_STORE.get_or_insert_with(TcpClientStore::new));
+
+        let mut log_stream = LogStreamClient::new();
+
+        network.add_client(
+            &mut log_stream,
+            LOG_STREAM_STORE.get_or_insert_with(TcpClientStore::new),
+        );
+
+        let next_diagnostics = clock.millis() + config.diagnostics_interval_ms;
+        // From here on, the network task keeps itself running through the
+        // clock alarm.
+        let _ = cx.spawn.poll_network();
+
+        let mut selftest = SelfTest::new();
+        selftest.start(clock.millis());
+
+        log::info!("Entering main loop");
+        init::LateResources {
+            clock,
+            random,
+            pipeline,
+            network,
+            client,
+            telegram_server,
+            influx,
+            graphite,
+            statsd,
+            multicast,
+            sntp,
+            provisioner,
+            provisioned_config: None,
+            ota,
+            log_stream,
+            led,
+            events,
+            wall_clock: WallClock::new(),
+            costs: CostTracker::new(config.costs),
+            totals: DailyTotals::load(),
+            peak: PeakTracker::new(),
+            selftest,
+            console,
+            flash_log,
+            sd_log,
+            pulses,
+            s0_pin,
+            temperatures,
+            system_info,
+            diagnostics: Diagnostics::default(),
+            diagnostics_interval_ms: config.diagnostics_interval_ms,
+            next_diagnostics,
+            low_power: config.low_power,
+        }
+    }
+
+    #[idle(resources = [console, diagnostics, events, provisioned_config])]
+    fn idle(mut cx: idle::Context) -> ! {
+        loop {
+            let diagnostics = cx.resources.diagnostics.lock(|diagnostics| *diagnostics);
+            if let Some(event) = cx.resources.console.poll(&diagnostics) {
+                cx.resources.events.lock(|events| events.push(event));
+            }
+            if let Some(config) = cx.resources.provisioned_config.lock(|config| config.take()) {
+                if let Some(event) = cx.resources.console.provision(config) {
+                    cx.resources.events.lock(|events| events.push(event));
+                }
+            }
+            // The USB interrupt wakes us up when there is console input.
+            cortex_m::asm::wfi();
+        }
+    }
+
+    /// Keeps the clock running, and runs the network task when its alarm
+    /// goes off.
+    #[task(binds = GPT2, priority = 3, spawn = [poll_network])]
+    fn on_gpt2(cx: on_gpt2::Context) {
+        if clock::on_interrupt() {
+            // Fails if the task is already pending, which is fine.
+            let _ = cx.spawn.poll_network();
+        }
+    }
+
+    /// Polls the network when a frame arrives. Only enabled in low-power mode,
+    /// and only on the Teensy 4.1; the ENC28J60's interrupt pin isn't connected.
+    #[task(binds = ENET, priority = 3, spawn = [poll_network])]
+    fn on_enet(cx: on_enet::Context) {
+        #[cfg(feature = "teensy41")]
+        network::enet::clear_rx_interrupt();
+        let _ = cx.spawn.poll_network();
+    }
+
+    /// Counts pulses on the S0 input.
+    #[task(binds = GPIO1_Combined_16_31, priority = 3, resources = [s0_pin])]
+    fn on_s0(cx: on_s0::Context) {
+        if let Some(pin) = cx.resources.s0_pin {
+            if pin.is_interrupt_status() {
+                pin.clear_interrupt_status();
+                s0::on_edge(!pin.is_set(), clock::millis());
+            }
+        }
+    }
+
+    /// Reads from the meter whenever data arrives, or when the network task
+    /// asks for it.
+    #[task(
+        binds = LPUART2,
+        priority = 2,
+        resources = [pipeline, events],
+        spawn = [handle_telegram],
+    )]
+    fn on_uart(cx: on_uart::Context) {
+        let pipeline = cx.resources.pipeline;
+        if let Some(telegram) = pipeline.poll(clock::millis(), cx.resources.events) {
+            if cx
+                .spawn
+                .handle_telegram(telegram, pipeline.received_at())
+                .is_err()
+            {
+                log::warn!("Telegram queue full, dropping telegram");
             }
         }
-        if read > 0 {
-            dsmr_uart.consume(read);
+    }
+
+    #[task(
+        priority = 1,
+        capacity = 2,
+        spawn = [poll_network],
+        resources = [
+            pipeline,
+            client,
+            telegram_server,
+            influx,
+            graphite,
+            statsd,
+            multicast,
+            wall_clock,
+            costs,
+            totals,
+            peak,
+            sd_log,
+            pulses,
+        ],
+    )]
+    fn handle_telegram(cx: handle_telegram::Context, telegram: Telegram, received_at: Option<i64>) {
+        let handle_telegram::Resources {
+            mut pipeline,
+            client,
+            telegram_server,
+            influx,
+            graphite,
+            statsd,
+            multicast,
+            wall_clock,
+            costs,
+            totals,
+            peak,
+            sd_log,
+            pulses,
+        } = cx.resources;
+        pipeline.lock(|pipeline| {
+            tasks::handle_telegram(
+                &telegram,
+                received_at,
+                pipeline,
+                pulses.reading(),
+                Trackers {
+                    wall_clock,
+                    costs,
+                    totals,
+                    peak,
+                },
+                &mut [
+                    &mut *client,
+                    &mut *telegram_server,
+                    &mut *influx,
+                    &mut *graphite,
+                    &mut *statsd,
+                    &mut *multicast,
+                    &mut *sd_log,
+                ],
+            )
+        });
+        // Publish it right away, the network task may be asleep.
+        let _ = cx.spawn.poll_network();
+    }
+
+    #[task(
+        priority = 1,
+        spawn = [poll_network],
+        resources = [
+            clock,
+            random,
+            pipeline,
+            network,
+            client,
+            telegram_server,
+            influx,
+            graphite,
+            statsd,
+            multicast,
+            sntp,
+            provisioner,
+            provisioned_config,
+            ota,
+            log_stream,
+            led,
+            events,
+            wall_clock,
+            costs,
+            totals,
+            peak,
+            pulses,
+            temperatures,
+            selftest,
+            flash_log,
+            system_info,
+            diagnostics,
+            diagnostics_interval_ms,
+            next_diagnostics,
+            low_power,
+        ],
+    )]
+    fn poll_network(cx: poll_network::Context) {
+        let poll_network::Resources {
+            clock,
+            random,
+            mut pipeline,
+            network,
+            client,
+            telegram_server,
+            influx,
+            graphite,
+            statsd,
+            multicast,
+            sntp,
+            provisioner,
+            provisioned_config,
+            ota,
+            log_stream,
+            led,
+            mut events,
+            wall_clock,
+            costs,
+            totals,
+            peak,
+            pulses,
+            temperatures,
+            selftest,
+            flash_log,
+            system_info,
+            diagnostics,
+            diagnostics_interval_ms,
+            next_diagnostics,
+            low_power,
+        } = cx.resources;
+
+        events.lock(|events| network.poll(clock, events));
+        let mut clients = Clients {
+            mqtt: client,
+            telegram_server,
+            influx,
+            graphite,
+            statsd,
+            multicast,
+            sntp,
+            provisioner,
+            ota,
+            log_stream,
+        };
+        if let Some(config) =
+            tasks::poll_clients(network, clock, random, &mut clients, wall_clock, selftest)
+        {
+            *provisioned_config = Some(config);
+        }
+        let client = clients.mqtt;
+
+        let now = clock.millis();
+        let (uart, uart_buffer_peak, meter_interval_secs, last_telegram, parse_errors) = pipeline
+            .lock(|pipeline| {
+                let uart = pipeline.uart();
+                (
+                    uart.stats(),
+                    uart.buffer_peak(),
+                    pipeline.meter_interval_secs(),
+                    pipeline.received_at(),
+                    *pipeline.parse_errors(),
+                )
+            });
+        while let Some(alert) = pipeline.lock(|pipeline| pipeline.take_alert()) {
+            client.queue_alert(alert);
+        }
+        events.lock(|events| {
+            events.dispatch(
+                now,
+                &mut [
+                    &mut *led,
+                    &mut diagnostics.events,
+                    &mut *client,
+                    &mut *selftest,
+                    &mut *flash_log,
+                ],
+            )
+        });
+        flash_log.sync_outage(client.outage_mut());
+        led.update(now);
+        if let Some(report) =
+            selftest.poll(now, || network.driver_responds(), client.is_connected())
+        {
+            client.queue_selftest(report);
+        }
+
+        let publish_diagnostics = now >= *next_diagnostics;
+        // Scanning the stack takes a while, so memory usage is only measured
+        // when diagnostics are published.
+        let memory = if publish_diagnostics {
+            MemStats::measure(uart_buffer_peak, client.queue_depth())
+        } else {
+            diagnostics.memory
+        };
+        *diagnostics = Diagnostics {
+            uart,
+            publish_latency: client.publish_latency(),
+            time: wall_clock.local_time(now),
+            clock_drift_ppm: wall_clock.drift_ppm(),
+            boot_reason: system_info.boot_reason,
+            ethernet_chip: system_info.ethernet_chip,
+            uptime_secs: system_info.uptime_secs(now),
+            memory,
+            meter_interval_secs,
+            network: network.status(),
+            cidr: network.cidr(),
+            gateway: network.gateway(),
+            neighbours: *network.neighbours(),
+            events: diagnostics.events,
+            parse_errors,
+            temperatures: temperatures.readings(),
+        };
+        totals.tick(diagnostics.time);
+        pulses.poll(now);
+        temperatures.poll(now);
+        if publish_diagnostics {
+            *next_diagnostics += *diagnostics_interval_ms;
+            tasks::queue_reports(client, *diagnostics, costs, totals, peak);
+        }
+
+        // Run the UART task as well, for its timers, and to pick up DMA
+        // buffers that filled up without the line going idle.
+        rtic::pend(hal::ral::interrupt::LPUART2);
+
+        // Clients may have queued new data, so the deadline must be determined
+        // after polling them.
+        let max_deadline = power::sleep_until(now, last_telegram, meter_interval_secs)
+            .filter(|_| *low_power && !client.is_shutting_down())
+            .map_or(now + MAX_POLL_INTERVAL_MS, |wake| {
+                wake.min(*next_diagnostics)
+            });
+        let deadline = network
+            .poll_at(clock)
+            .map_or(max_deadline, |d| d.min(max_deadline));
+        clock.set_alarm(deadline);
+        // If the deadline passed while setting the alarm, it won't go off
+        // until the counter wraps around, so run again straight away.
+        if clock.millis() >= deadline {
+            let _ = cx.spawn.poll_network();
         }
     }
 
-    fn make_output_pin<P: Pin>(pin: P) -> OldOutputPin<GPIO<P, Output>> {
-        let mut gpio = GPIO::new(pin).output();
-        gpio.set_fast(true);
-        OldOutputPin::new(gpio)
+    // Interrupts of unused peripherals, used to dispatch software tasks.
+    extern "C" {
+        fn LPUART8();
+    }
+};
+
+#[cfg(feature = "teensy40")]
+fn make_output_pin<P: Pin>(pin: P) -> OldOutputPin<GPIO<P, Output>> {
+    let mut gpio = GPIO::new(pin).output();
+    gpio.set_fast(true);
+    OldOutputPin::new(gpio)
+}
+
+/// Looks for a W25Q on pin 8, and finds where the log on it left off. Runs
+/// without a flash log if there is none.
+#[cfg(feature = "teensy40")]
+fn mount_flash_log(
+    bus: &'static SharedBus<SpiBus>,
+    cs: GPIO<board::P8, Output>,
+) -> Option<LogStore<LogStorage>> {
+    let (spi, cs) = bus.device(cs).ok()?;
+    let flash = match W25q::probe(spi, cs) {
+        Ok(flash) => flash,
+        Err(err) => {
+            log::info!(
+                "No SPI flash found ({:?}), running without the flash log",
+                err
+            );
+            return None;
+        }
+    };
+    match LogStore::mount(flash) {
+        Ok(store) => Some(store),
+        Err(err) => {
+            log::warn!("Failed to mount the flash log: {:?}", err);
+            None
+        }
     }
 }
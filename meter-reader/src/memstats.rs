@@ -0,0 +1,91 @@
+#[cfg(not(feature = "sim"))]
+use core::ptr;
+
+// Written over the unused part of the stack at boot. Anything that no longer
+// holds this has been used since.
+#[cfg(not(feature = "sim"))]
+const STACK_PAINT: u32 = 0x5AC4_5AC4;
+// The frames below `paint_stack()` itself are left alone.
+#[cfg(not(feature = "sim"))]
+const PAINT_MARGIN: usize = 256;
+
+#[cfg(not(feature = "sim"))]
+extern "C" {
+    static _stack_start: u32;
+    // End of the statically allocated RAM, which the stack grows towards.
+    static __sheap: u32;
+}
+
+/// How much of the stack has been used since boot, in bytes.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct StackUsage {
+    pub used: usize,
+    pub size: usize,
+}
+
+/// Worst-case memory usage, for diagnostics.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct MemStats {
+    /// Unknown when running in the simulator.
+    pub stack: Option<StackUsage>,
+    /// Most bytes ever held in the UART read buffer.
+    pub uart_buffer_peak: usize,
+    /// Messages waiting to be published to MQTT.
+    pub mqtt_queue_depth: usize,
+}
+
+impl MemStats {
+    /// Scans the stack, which takes a while, so don't call this on every poll.
+    pub fn measure(uart_buffer_peak: usize, mqtt_queue_depth: usize) -> Self {
+        Self {
+            stack: stack_usage(),
+            uart_buffer_peak,
+            mqtt_queue_depth,
+        }
+    }
+}
+
+/// Fills the unused part of the stack with a pattern, so its high-water mark
+/// can be found later. Call this first thing at boot.
+#[cfg(not(feature = "sim"))]
+#[inline(never)]
+pub fn paint_stack() {
+    let sp = cortex_m::register::msp::read() as usize;
+    let (bottom, _) = stack_bounds();
+    let mut addr = bottom;
+    while addr + PAINT_MARGIN < sp {
+        unsafe { ptr::write_volatile(addr as *mut u32, STACK_PAINT) };
+        addr += 4;
+    }
+}
+
+#[cfg(not(feature = "sim"))]
+fn stack_usage() -> Option<StackUsage> {
+    let (bottom, top) = stack_bounds();
+    // The stack grows down, so the paint that is left sits at the bottom.
+    let untouched = (bottom..top)
+        .step_by(4)
+        .take_while(|addr| unsafe { ptr::read_volatile(*addr as *const u32) } == STACK_PAINT)
+        .count()
+        * 4;
+    let size = top - bottom;
+    Some(StackUsage {
+        used: size - untouched,
+        size,
+    })
+}
+
+#[cfg(feature = "sim")]
+fn stack_usage() -> Option<StackUsage> {
+    None
+}
+
+#[cfg(not(feature = "sim"))]
+fn stack_bounds() -> (usize, usize) {
+    unsafe {
+        (
+            &__sheap as *const u32 as usize,
+            &_stack_start as *const u32 as usize,
+        )
+    }
+}
@@ -0,0 +1,64 @@
+use core::fmt::{self, Display};
+
+use dsmr42::{Line, Telegram};
+
+/// The name of a numeric value in a telegram, the same as in the JSON
+/// telegrams published to MQTT.
+#[derive(Copy, Clone, Debug)]
+pub struct Name<'a>(&'a Line);
+
+impl Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Line::Consumed(tariff, _) => write!(f, "tariff_{}_consumed", tariff),
+            Line::Produced(tariff, _) => write!(f, "tariff_{}_produced", tariff),
+            Line::ActiveTariff(_) => write!(f, "active_tariff"),
+            Line::TotalConsuming(_) => write!(f, "total_consuming"),
+            Line::TotalProducing(_) => write!(f, "total_producing"),
+            Line::PowerFailures(_) => write!(f, "power_failures"),
+            Line::LongPowerFailures(_) => write!(f, "long_power_failures"),
+            Line::VoltageSags(_) => write!(f, "voltage_sags"),
+            Line::VoltageSwells(_) => write!(f, "voltage_swells"),
+            Line::Current(phase, _) => write!(f, "{}_current", phase),
+            Line::Consuming(phase, _) => write!(f, "{}_consuming", phase),
+            Line::Producing(phase, _) => write!(f, "{}_producing", phase),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Name<'_> {
+    /// Whether the value is a momentary reading, rather than a counter.
+    pub fn is_instantaneous(&self) -> bool {
+        matches!(
+            self.0,
+            Line::TotalConsuming(_)
+                | Line::TotalProducing(_)
+                | Line::Current(..)
+                | Line::Consuming(..)
+                | Line::Producing(..)
+        )
+    }
+}
+
+/// The numeric values in a telegram, for exporters that don't take JSON.
+pub fn metrics(telegram: &Telegram) -> impl Iterator<Item = (Name<'_>, u32)> {
+    telegram.lines.iter().filter_map(|line| {
+        let value = match line {
+            Line::ActiveTariff(tariff) => *tariff as u32,
+            Line::Consumed(_, value)
+            | Line::Produced(_, value)
+            | Line::TotalConsuming(value)
+            | Line::TotalProducing(value)
+            | Line::PowerFailures(value)
+            | Line::LongPowerFailures(value)
+            | Line::VoltageSags(value)
+            | Line::VoltageSwells(value)
+            | Line::Current(_, value)
+            | Line::Consuming(_, value)
+            | Line::Producing(_, value) => *value,
+            _ => return None,
+        };
+        Some((Name(line), value))
+    })
+}
@@ -1,12 +1,13 @@
-use arrayvec::ArrayString;
-use core::fmt::{Debug, Display};
-use dsmr42::Telegram;
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::{self, Debug, Display, Write};
+use dsmr42::{aggregate::Summaries, SerializeOptions, Telegram, MAX_EQUIPMENT_ID_LEN};
 use embedded_mqtt::{
     codec::{Decodable, Encodable},
     fixed_header::PacketType,
     fixed_header::PublishFlags,
     packet::Packet,
     payload,
+    qos::QoS,
     status::Status,
     variable_header::connect::Flags,
     variable_header::VariableHeader,
@@ -15,30 +16,91 @@ use embedded_mqtt::{
         connect::{Level, Protocol},
     },
 };
+use enc28j60_smoltcp::warn_throttled;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use smoltcp::{
-    iface::EthernetInterface,
-    phy,
-    socket::{SocketHandle, SocketRef, TcpSocket},
-    time::Duration,
+    socket::SocketHandle,
+    time::{Duration, Instant},
     wire::IpAddress,
     wire::IpEndpoint,
-    wire::Ipv4Address,
 };
 
-use crate::{network::client::TcpClient, network::stack, random::Random};
-
-const REMOTE_HOST: [u8; 4] = [10, 190, 30, 14];
-const REMOTE_PORT: u16 = 1883;
+use crate::{
+    alerts::AlertEvent,
+    clock,
+    config::{self, MqttConfig},
+    costs::CostReport,
+    derived::Derived,
+    diagnostics::{self, Diagnostics},
+    events::{Event, EventConsumer},
+    gas::GasReading,
+    logging::{Debug2Format, Display2Format},
+    network::client::{TcpAction, TcpClient, TcpConnection},
+    network::proxy::{HandshakeStatus, ProxyHandshake},
+    outage::{OutageBuffer, Sample},
+    panic::PanicReport,
+    peak::PeakReport,
+    random::Random,
+    s0::PulseReading,
+    selftest::SelfTestReport,
+    telemetry::{TelemetryRecord, TelemetrySink},
+    topic::{self, ExpandedTopic},
+    totals::TotalsReport,
+};
 
-const BACKOFF_CAP: u32 = 400000;
-const INITIAL_BACKOFF: u32 = 1000;
+const BACKOFF_CAP_MS: u64 = 300_000;
+const INITIAL_BACKOFF_MS: u64 = 1000;
 
 const KEEPALIVE: u16 = 30;
 
-const CLIENT_ID: &str = "smart-meter-reader";
+// Commands are published to this topic, and must not be retained.
+const COMMAND_TOPIC: &str = "smart_meter/command";
+const SUBSCRIBE_PACKET_ID: u16 = 1;
+// Details of the panic that caused the last reset, published once after boot.
+const LAST_PANIC_TOPIC: &str = "smart_meter/last_panic";
+const SELFTEST_TOPIC: &str = "smart_meter/selftest";
+// Alerts raised or cleared by the rules in the configuration.
+const ALERTS_TOPIC: &str = "smart_meter/alerts";
+// Alerts are events rather than the latest state, so a few are kept while the
+// broker can't be reached, instead of only the last one.
+const MAX_QUEUED_ALERTS: usize = 8;
+// Samples of telegrams received while the broker couldn't be reached, published
+// oldest first once it can.
+const BACKFILL_TOPIC: &str = "smart_meter/backfill";
+// Room needed for `,"hmac": "<64 hex digits>"`.
+const HMAC_FIELD_LEN: usize = 75;
+// How long to try telling the broker we're going offline before a reset.
+const SHUTDOWN_TIMEOUT_MS: i64 = 2000;
+// A DISCONNECT packet has no variable header or payload.
+const DISCONNECT_PACKET: [u8; 2] = [0xE0, 0x00];
+
+/// A telegram waiting to be published, with what's published along with it.
+struct QueuedTelegram {
+    telegram: Telegram,
+    sequence: u32,
+    /// The `Clock` time at which it was received.
+    received_at: Option<i64>,
+    /// The same, as Unix time in milliseconds.
+    received_unix_ms: Option<i64>,
+    parse_us: Option<u32>,
+    /// The summaries of the aggregated fields.
+    summaries: Summaries,
+    pulses: Option<PulseReading>,
+}
 
-const STATUS_TOPIC: &str = "smart_meter/status";
-const USAGE_TOPIC: &str = "smart_meter/usage";
+/// Commands received on `COMMAND_TOPIC`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Command {
+    /// Start accepting a firmware image (payload `ota`).
+    EnableOta,
+    /// Restart the reader (payload `reboot`).
+    Reboot,
+    /// Change the log level (payload `log <level>`, e.g. `log trace`).
+    SetLogLevel(log::LevelFilter),
+    /// Run the self-test (payload `selftest`).
+    SelfTest,
+}
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 enum MqttState {
@@ -46,9 +108,24 @@ enum MqttState {
     Connecting,
     Connected,
     Ready,
+    /// The broker sent something we didn't expect. The connection is
+    /// aborted on the next poll.
     Invalid,
 }
 
+/// Progress of saying goodbye to the broker before a reset.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Shutdown {
+    Running,
+    /// Publishing `offline` and disconnecting, until `deadline`.
+    Pending {
+        deadline: i64,
+        sent: bool,
+    },
+    /// The broker knows, or we weren't connected to begin with.
+    Done,
+}
+
 impl Display for MqttState {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         Debug::fmt(self, f)
@@ -56,12 +133,37 @@ impl Display for MqttState {
 }
 
 pub struct MqttClient {
+    config: MqttConfig,
     handle: Option<SocketHandle>,
     connected: bool,
-    next_backoff: u32,
-    current_backoff: u32,
+    next_backoff: Duration,
+    next_attempt: Instant,
     mqtt_state: MqttState,
-    queued_telegram: Option<Telegram>,
+    proxy: ProxyHandshake,
+    queued_telegram: Option<QueuedTelegram>,
+    queued_gas: Option<GasReading>,
+    // Number of telegrams queued since boot.
+    telegram_sequence: u32,
+    boot_count: u32,
+    queued_diagnostics: Option<Diagnostics>,
+    queued_costs: Option<CostReport>,
+    queued_totals: Option<TotalsReport>,
+    queued_peak: Option<PeakReport>,
+    queued_selftest: Option<SelfTestReport>,
+    queued_alerts: ArrayVec<AlertEvent, MAX_QUEUED_ALERTS>,
+    outage: OutageBuffer,
+    last_panic: Option<PanicReport>,
+    // Time between receiving the first byte of the last published telegram,
+    // and handing it to the socket.
+    publish_latency: Option<i64>,
+    command: Option<Command>,
+    // The meter's equipment ID, which `{device_id}` in topics expands to once
+    // it is known.
+    device_id: Option<ArrayString<MAX_EQUIPMENT_ID_LEN>>,
+    // Set when the status topic changed, since the broker only learns the
+    // topic of our will when we connect.
+    reconnect: bool,
+    shutdown: Shutdown,
 }
 
 impl TcpClient for MqttClient {
@@ -71,59 +173,89 @@ impl TcpClient for MqttClient {
     fn get_socket_handle(&mut self) -> SocketHandle {
         self.handle.unwrap()
     }
-    fn poll<DeviceT>(
+    fn poll(
         &mut self,
-        _interface: &mut EthernetInterface<DeviceT>,
-        mut socket: SocketRef<TcpSocket>,
+        socket: &mut dyn TcpConnection,
+        timestamp: Instant,
         random: &mut Random,
-    ) where
-        DeviceT: for<'d> phy::Device<'d>,
-    {
+    ) -> TcpAction {
         // A connection is considered established if we can send data.
         // However, it is only considered closed once we are no longer exchanging packets.
         // Because of this we track both states here.
         if socket.may_send() && !self.connected {
             self.connected = true;
-            self.next_backoff = INITIAL_BACKOFF;
-            self.current_backoff = 0;
-            log::debug!(
-                "Connected {} -> {}, keepalive {:?}, timeout {:?}",
-                socket.local_endpoint(),
-                socket.remote_endpoint(),
-                socket.keep_alive(),
-                socket.timeout(),
+            self.next_backoff = Duration::from_millis(INITIAL_BACKOFF_MS);
+            debug!(
+                "Connected {} -> {}",
+                Display2Format(&socket.local_endpoint()),
+                Display2Format(&socket.remote_endpoint()),
             );
         } else if !socket.is_active() && self.connected {
             self.connected = false;
             self.mqtt_state = MqttState::Unconnected;
-            log::debug!(
+            debug!(
                 "Disconnected {} -> {}",
-                socket.local_endpoint(),
-                socket.remote_endpoint()
+                Display2Format(&socket.local_endpoint()),
+                Display2Format(&socket.remote_endpoint())
             );
         }
 
+        if self.shutdown != Shutdown::Running {
+            return self.poll_shutdown(socket);
+        }
+
         if !socket.is_active() {
-            self.try_connect(socket, random);
-            return;
+            return self.try_connect(timestamp, random);
+        }
+
+        if socket.may_send() {
+            match self.proxy.poll(socket, self.broker()) {
+                HandshakeStatus::Ready => {}
+                HandshakeStatus::Pending => return TcpAction::Nothing,
+                HandshakeStatus::Failed => return TcpAction::Abort,
+            }
         }
 
         if socket.can_recv() {
-            let recv_res = socket.recv(|buf| match Packet::decode(buf) {
-                Ok(Status::Complete((len, pkt))) => (len, Some(pkt)),
+            // The packet borrows the receive buffer, so it is only consumed
+            // once the packet has been handled.
+            let recv_res = socket.peek().map(|buf| match Packet::decode(buf) {
+                Ok(Status::Complete((len, pkt))) => {
+                    self.handle_packet(pkt, timestamp);
+                    len
+                }
                 Ok(Status::Partial(_)) => {
-                    log::info!("Got partial MQTT packet, retrying later.");
-                    (0, None)
+                    info!("Got partial MQTT packet, retrying later.");
+                    0
                 }
                 Err(err) => {
-                    log::warn!("Decode error: {}", err);
-                    (buf.len(), None)
+                    warn!("Decode error: {}", Display2Format(&err));
+                    buf.len()
                 }
             });
-            match recv_res {
-                Ok(Some(pkt)) => self.handle_packet(pkt),
-                Err(err) => log::warn!("Failed to receive MQTT packet: {}", err),
-                _ => {}
+            if let Err(err) = recv_res.and_then(|len| socket.consume(len)) {
+                warn_throttled!(
+                    clock::millis(),
+                    "Failed to receive MQTT packet: {}",
+                    Display2Format(&err)
+                );
+            }
+        }
+
+        // Whatever else the broker sent can't be trusted either, so start
+        // over with a new connection rather than leave it to fill the buffers.
+        if self.mqtt_state == MqttState::Invalid {
+            warn!("Aborting MQTT connection after protocol violation");
+            self.mqtt_state = MqttState::Unconnected;
+            return TcpAction::Abort;
+        }
+
+        if self.reconnect {
+            self.reconnect = false;
+            if self.mqtt_state != MqttState::Unconnected {
+                info!("Reconnecting to update the status topic");
+                self.mqtt_state = MqttState::Unconnected;
+                return TcpAction::Close;
             }
         }
 
@@ -132,30 +264,133 @@ impl TcpClient for MqttClient {
                 MqttState::Unconnected => self.connect_mqtt(socket),
                 MqttState::Connected => self.send_status(socket),
                 MqttState::Ready => {
-                    if let Some(telegram) = self.queued_telegram.take() {
-                        self.send_telegram(socket, telegram);
+                    if let Some(report) = self.last_panic.take() {
+                        self.send_last_panic(socket, report);
+                    } else if let Some(alert) = self.queued_alerts.pop_at(0) {
+                        self.send_alert(socket, alert);
+                    } else if let Some(queued) = self.queued_telegram.take() {
+                        if self.send_telegram(socket, &queued) {
+                            self.publish_latency =
+                                queued.received_at.map(|t| timestamp.total_millis() - t);
+                        } else {
+                            self.queued_telegram = Some(queued);
+                        }
+                    } else if let Some(gas) = self.queued_gas.take() {
+                        self.send_gas(socket, gas);
+                    } else if let Some(diagnostics) = self.queued_diagnostics.take() {
+                        self.send_diagnostics(socket, diagnostics);
+                    } else if let Some(costs) = self.queued_costs.take() {
+                        self.send_costs(socket, costs);
+                    } else if let Some(totals) = self.queued_totals.take() {
+                        self.send_totals(socket, totals);
+                    } else if let Some(peak) = self.queued_peak.take() {
+                        self.send_peak(socket, peak);
+                    } else if let Some(report) = self.queued_selftest.take() {
+                        self.send_selftest(socket, report);
+                    } else if let Some(sample) = self.outage.oldest() {
+                        if self.send_backfill(socket, sample) {
+                            self.outage.remove_oldest();
+                        }
                     }
                 }
                 _ => {}
             }
         }
+        TcpAction::Nothing
+    }
+}
+
+impl TelemetrySink for MqttClient {
+    /// Queues a telegram for publishing, replacing any that hasn't been
+    /// published yet. If the broker can't be reached, the telegram is also
+    /// handed to the outage buffer, to be published to `BACKFILL_TOPIC` later.
+    /// A new gas reading is queued for the gas topic.
+    fn accept(&mut self, record: &TelemetryRecord) {
+        if self.device_id.is_none() {
+            self.device_id = dsmr42::equipment_id(record.raw).filter(|id| topic::is_topic_safe(id));
+            if let Some(id) = &self.device_id {
+                info!("Meter equipment ID is {}", id.as_str());
+                self.reconnect = self.config.status_topic.contains("{device_id}");
+            }
+        }
+        if !self.is_connected() {
+            self.outage.accept(record);
+        }
+        if let Some(gas) = record.gas {
+            self.queued_gas = Some(gas);
+        }
+        self.telegram_sequence = self.telegram_sequence.wrapping_add(1);
+        self.queued_telegram = Some(QueuedTelegram {
+            telegram: record.telegram.clone(),
+            sequence: self.telegram_sequence,
+            received_at: record.received_at,
+            received_unix_ms: record.received_unix_ms,
+            parse_us: record.parse_us,
+            summaries: record.summaries.clone(),
+            pulses: record.pulses,
+        });
+    }
+}
+
+impl EventConsumer for MqttClient {
+    fn on_event(&mut self, event: Event, now: i64) {
+        match event {
+            // Attempts made while we had no address say nothing about the
+            // broker, so don't make it wait out their backoff.
+            Event::AddressAcquired(_) => {
+                self.next_backoff = Duration::from_millis(INITIAL_BACKOFF_MS);
+                self.next_attempt = Instant::from_millis(0);
+            }
+            Event::ResetRequested => self.shut_down(now),
+            _ => {}
+        }
     }
 }
 
 impl MqttClient {
-    pub fn new() -> Self {
+    /// `boot_count` is included with each telegram, so consumers can tell
+    /// when the sequence numbers start over.
+    pub fn new(config: MqttConfig, boot_count: u32) -> Self {
         Self {
+            config,
             handle: None,
             connected: false,
-            next_backoff: INITIAL_BACKOFF,
-            current_backoff: 0,
+            next_backoff: Duration::from_millis(INITIAL_BACKOFF_MS),
+            next_attempt: Instant::from_millis(0),
             mqtt_state: MqttState::Unconnected,
+            proxy: ProxyHandshake::new(config.proxy),
             queued_telegram: None,
+            queued_gas: None,
+            telegram_sequence: 0,
+            boot_count,
+            queued_diagnostics: None,
+            queued_costs: None,
+            queued_totals: None,
+            queued_peak: None,
+            queued_selftest: None,
+            queued_alerts: ArrayVec::new(),
+            outage: OutageBuffer::new(),
+            last_panic: None,
+            publish_latency: None,
+            command: None,
+            device_id: None,
+            reconnect: false,
+            shutdown: Shutdown::Running,
         }
     }
 
-    fn connect_mqtt(&mut self, socket: SocketRef<TcpSocket>) {
-        log::debug!("Creating MQTT connect request");
+    /// Expands a topic template. `{device_id}` becomes the meter's equipment
+    /// ID, or the client ID until a telegram has shown what it is.
+    fn topic(&self, template: &config::Topic) -> ExpandedTopic {
+        let device_id = match &self.device_id {
+            Some(id) => id.as_str(),
+            None => self.config.client_id.as_str(),
+        };
+        topic::expand(template, device_id)
+    }
+
+    fn connect_mqtt(&mut self, socket: &mut dyn TcpConnection) {
+        debug!("Creating MQTT connect request");
         self.mqtt_state = MqttState::Connecting;
         let mut flags = Flags::default();
         flags.set_clean_session(true);
@@ -167,89 +402,560 @@ impl MqttClient {
             flags,
             KEEPALIVE,
         );
-        let will = payload::connect::Will::new(STATUS_TOPIC, b"offline");
-        let payload = payload::connect::Connect::new(CLIENT_ID, Some(will), None, None);
+        // Copied, because the packet borrows them while `self` is borrowed mutably.
+        let status_topic = self.topic(&self.config.status_topic);
+        let client_id = self.config.client_id;
+        let will = payload::connect::Will::new(&status_topic, b"offline");
+        let payload = payload::connect::Connect::new(&client_id, Some(will), None, None);
         match Packet::connect(header, payload) {
             Ok(packet) => match self.send_packet(socket, packet) {
-                Ok(_) => log::debug!("Sent MQTT connect request"),
-                Err(err) => log::warn!("Failed to send connect packet: {}", err),
+                Ok(_) => debug!("Sent MQTT connect request"),
+                Err(err) => warn_throttled!(
+                    clock::millis(),
+                    "Failed to send connect packet: {}",
+                    Display2Format(&err)
+                ),
             },
-            Err(err) => log::warn!("Failed to create connect packet: {}", err),
+            Err(err) => warn!("Failed to create connect packet: {}", Display2Format(&err)),
         }
     }
 
-    pub fn send_status(&mut self, socket: SocketRef<TcpSocket>) {
-        self.send_pub(socket, STATUS_TOPIC, b"online");
-        log::debug!("MQTT State: Connected -> Ready");
+    pub fn send_status(&mut self, socket: &mut dyn TcpConnection) {
+        let topic = self.topic(&self.config.status_topic);
+        self.send_pub(socket, &topic, b"online", true);
+        self.subscribe_commands(socket);
+        debug!("MQTT State: Connected -> Ready");
         self.mqtt_state = MqttState::Ready;
     }
 
-    pub fn queue_telegram(&mut self, telegram: Telegram) {
-        self.queued_telegram = Some(telegram);
+    fn poll_shutdown(&mut self, socket: &mut dyn TcpConnection) -> TcpAction {
+        let deadline = match self.shutdown {
+            Shutdown::Pending { deadline, sent } if !sent => deadline,
+            Shutdown::Pending { .. } => {
+                // Everything sent has been acknowledged.
+                if socket.send_free() < socket.send_capacity() {
+                    return TcpAction::Nothing;
+                }
+                debug!("Disconnected from the broker");
+                self.shutdown = Shutdown::Done;
+                return TcpAction::Close;
+            }
+            _ => return TcpAction::Nothing,
+        };
+        match self.mqtt_state {
+            MqttState::Connected | MqttState::Ready => {}
+            _ => {
+                self.shutdown = Shutdown::Done;
+                return if socket.is_active() {
+                    TcpAction::Abort
+                } else {
+                    TcpAction::Nothing
+                };
+            }
+        }
+        if !socket.can_send() {
+            return TcpAction::Nothing;
+        }
+        let topic = self.topic(&self.config.status_topic);
+        self.send_pub(socket, &topic, b"offline", true);
+        if let Err(err) = socket.send_slice(&DISCONNECT_PACKET) {
+            warn!("Failed to send disconnect packet: {}", Display2Format(&err));
+        }
+        self.mqtt_state = MqttState::Unconnected;
+        self.shutdown = Shutdown::Pending {
+            deadline,
+            sent: true,
+        };
+        TcpAction::Nothing
+    }
+
+    fn subscribe_commands(&mut self, socket: &mut dyn TcpConnection) {
+        let topics = [(COMMAND_TOPIC, QoS::AtMostOnce)];
+        let header = variable_header::packet_identifier::PacketIdentifier::new(SUBSCRIBE_PACKET_ID);
+        let payload = payload::subscribe::Subscribe::new(&topics);
+        match Packet::subscribe(header, payload).map(|p| self.send_packet(socket, p)) {
+            Err(err) => warn!(
+                "Failed to encode subscribe packet: {}",
+                Display2Format(&err)
+            ),
+            Ok(Err(err)) => warn!("Failed to send subscribe packet: {}", Display2Format(&err)),
+            Ok(Ok(())) => debug!("Subscribed to {}", COMMAND_TOPIC),
+        }
+    }
+
+    /// Returns the last command received, if it hasn't been taken yet.
+    pub fn take_command(&mut self) -> Option<Command> {
+        self.command.take()
+    }
+
+    /// The samples waiting to be backfilled, for journalling them in flash.
+    #[cfg_attr(feature = "sim", allow(dead_code))]
+    pub fn outage_mut(&mut self) -> &mut OutageBuffer {
+        &mut self.outage
+    }
+
+    /// Number of messages waiting to be published.
+    pub fn queue_depth(&self) -> usize {
+        self.last_panic.is_some() as usize
+            + self.queued_telegram.is_some() as usize
+            + self.queued_gas.is_some() as usize
+            + self.queued_diagnostics.is_some() as usize
+            + self.queued_costs.is_some() as usize
+            + self.queued_totals.is_some() as usize
+            + self.queued_peak.is_some() as usize
+            + self.queued_selftest.is_some() as usize
+            + self.queued_alerts.len()
+            + self.outage.pending()
+    }
+
+    /// Publishes `offline` to the status topic and disconnects, before a
+    /// deliberate reset, so the broker doesn't wait for the connection to
+    /// time out before publishing our will. Nothing else is published from
+    /// then on.
+    pub fn shut_down(&mut self, now: i64) {
+        if self.shutdown == Shutdown::Running {
+            info!("Disconnecting from the broker");
+            self.shutdown = Shutdown::Pending {
+                deadline: now + SHUTDOWN_TIMEOUT_MS,
+                sent: false,
+            };
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown != Shutdown::Running
+    }
+
+    /// Whether it's time to reset after `shut_down()`: once the broker has
+    /// received the disconnect, or it's taken too long.
+    pub fn is_shut_down(&self, now: i64) -> bool {
+        match self.shutdown {
+            Shutdown::Running => false,
+            Shutdown::Pending { deadline, .. } => now >= deadline,
+            Shutdown::Done => true,
+        }
+    }
+
+    /// Whether we're connected to the broker, and ready to publish.
+    pub fn is_connected(&self) -> bool {
+        self.mqtt_state == MqttState::Ready
+    }
+
+    /// Latency in milliseconds of the last published telegram.
+    pub fn publish_latency(&self) -> Option<i64> {
+        self.publish_latency
+    }
+
+    /// Writes the publish packet straight into the socket's send buffer, as
+    /// telegrams can be too large to keep another copy of. Returns `false`
+    /// if the telegram should be published again later, once the broker has
+    /// acknowledged enough of what was sent before to make room for it.
+    fn send_telegram(&mut self, socket: &mut dyn TcpConnection, queued: &QueuedTelegram) -> bool {
+        let topic = self.topic(&self.config.usage_topic);
+        // The packet is written twice: once to find out how long it is, and
+        // again into the buffer.
+        let mut payload = PacketWriter::new(0, &mut []);
+        let _ = self.write_telegram(&mut payload, queued);
+        let payload_len = payload.len();
+        let mut packet = PacketWriter::new(0, &mut []);
+        write_publish_header(&mut packet, &topic, payload_len);
+        let packet_len = packet.len() + payload_len;
+
+        if packet_len > socket.send_capacity() {
+            warn_throttled!(
+                clock::millis(),
+                "Telegram of {} bytes does not fit in the {} byte send buffer",
+                packet_len,
+                socket.send_capacity()
+            );
+            return true;
+        }
+        if packet_len > socket.send_free() {
+            debug!("Waiting for room for a telegram of {} bytes", packet_len);
+            return false;
+        }
+
+        info!("Publishing {} bytes to {}", payload_len, topic);
+        // The free part of the buffer can wrap around its end, in which case
+        // it takes another pass to write the rest of the packet.
+        let mut sent = 0;
+        while sent < packet_len {
+            let res = socket.send_with(&mut |buf| {
+                let mut packet = PacketWriter::new(sent, buf);
+                write_publish_header(&mut packet, &topic, payload_len);
+                let _ = self.write_telegram(&mut packet, queued);
+                packet.written()
+            });
+            match res {
+                Ok(len) if len > 0 => sent += len,
+                res => {
+                    if let Err(err) = res {
+                        warn!("Failed to send publish packet: {}", Display2Format(&err));
+                    }
+                    // The broker can't make sense of the rest of the stream
+                    // after part of a packet.
+                    if sent > 0 {
+                        self.mqtt_state = MqttState::Invalid;
+                    }
+                    return true;
+                }
+            }
+        }
+        true
+    }
+
+    /// Writes a telegram as a JSON object, with the summaries, the derived
+    /// values, the pulse count and the sequence number added to it, and
+    /// signed like `sign()` does.
+    fn write_telegram(&self, writer: &mut PacketWriter, queued: &QueuedTelegram) -> fmt::Result {
+        if !self.config.hmac_key.is_empty() {
+            writer.mac = Some(
+                Hmac::<Sha256>::new_from_slice(self.config.hmac_key.as_bytes())
+                    .expect("HMAC accepts keys of any length"),
+            );
+        }
+        // Aggregated fields are published as their summaries instead.
+        let fields = self.config.fields.bits() & !queued.summaries.fields().bits();
+        queued.telegram.serialize(
+            &mut Unclosed::new(writer),
+            &SerializeOptions::from_bits(fields),
+        );
+
+        let separator = writer.separator();
+        queued.summaries.serialize(writer, separator)?;
+        let separator = writer.separator();
+        Derived::from_telegram(&queued.telegram).serialize(writer, separator)?;
+        if let Some(pulses) = queued.pulses {
+            let separator = writer.separator();
+            pulses.serialize(writer, separator)?;
+        }
+        // Gaps in the sequence number show telegrams that were replaced
+        // before they could be published.
+        let separator = writer.separator();
+        write!(
+            writer,
+            "{}\"sequence\": {},\"boot_count\": {}",
+            separator, queued.sequence, self.boot_count
+        )?;
+        // To tell stalls on our side from gaps at the broker.
+        if let Some(received_at) = queued.received_unix_ms {
+            write!(writer, ",\"received_at\": {}", received_at)?;
+        }
+        if let Some(parse_us) = queued.parse_us {
+            write!(writer, ",\"parse_us\": {}", parse_us)?;
+        }
+
+        let mut mac = match writer.mac.take() {
+            Some(mac) => mac,
+            None => return writer.write_str("}"),
+        };
+        mac.update(b"}");
+        write!(writer, ",\"hmac\": \"")?;
+        for byte in mac.finalize().into_bytes() {
+            write!(writer, "{:02x}", byte)?;
+        }
+        write!(writer, "\"}}")
+    }
+
+    fn send_gas(&mut self, socket: &mut dyn TcpConnection, gas: GasReading) {
+        let mut content = ArrayString::<{ 96 + HMAC_FIELD_LEN }>::new();
+
+        if gas
+            .serialize(&mut content)
+            .and_then(|_| self.sign(&mut content))
+            .is_err()
+        {
+            warn!("Gas reading does not fit in {} bytes", content.capacity());
+            return;
+        }
+
+        let topic = self.topic(&self.config.gas_topic);
+        self.send_pub(socket, &topic, content.as_bytes(), true);
+    }
+
+    pub fn queue_diagnostics(&mut self, diagnostics: Diagnostics) {
+        self.queued_diagnostics = Some(diagnostics);
+    }
+
+    fn send_diagnostics(&mut self, socket: &mut dyn TcpConnection, diagnostics: Diagnostics) {
+        let mut content = ArrayString::<{ diagnostics::MAX_SERIALIZED_LEN }>::new();
+
+        if diagnostics.serialize(&mut content).is_err() {
+            warn!("Diagnostics do not fit in {} bytes", content.capacity());
+            return;
+        }
+
+        let topic = self.topic(&self.config.diagnostics_topic);
+        self.send_pub(socket, &topic, content.as_bytes(), true);
+    }
+
+    pub fn queue_costs(&mut self, costs: CostReport) {
+        self.queued_costs = Some(costs);
+    }
+
+    fn send_costs(&mut self, socket: &mut dyn TcpConnection, costs: CostReport) {
+        let mut content = ArrayString::<{ 128 + HMAC_FIELD_LEN }>::new();
+
+        if costs
+            .serialize(&mut content)
+            .and_then(|_| self.sign(&mut content))
+            .is_err()
+        {
+            warn!("Costs do not fit in {} bytes", content.capacity());
+            return;
+        }
+
+        let topic = self.topic(&self.config.costs_topic);
+        self.send_pub(socket, &topic, content.as_bytes(), true);
+    }
+
+    pub fn queue_totals(&mut self, totals: TotalsReport) {
+        self.queued_totals = Some(totals);
     }
 
-    fn send_telegram(&mut self, socket: SocketRef<TcpSocket>, telegram: Telegram) {
+    fn send_totals(&mut self, socket: &mut dyn TcpConnection, totals: TotalsReport) {
+        let mut content = ArrayString::<{ 192 + HMAC_FIELD_LEN }>::new();
+
+        if totals
+            .serialize(&mut content)
+            .and_then(|_| self.sign(&mut content))
+            .is_err()
+        {
+            warn!("Totals do not fit in {} bytes", content.capacity());
+            return;
+        }
+
+        let topic = self.topic(&self.config.totals_topic);
+        self.send_pub(socket, &topic, content.as_bytes(), true);
+    }
+
+    pub fn queue_peak(&mut self, peak: PeakReport) {
+        self.queued_peak = Some(peak);
+    }
+
+    fn send_peak(&mut self, socket: &mut dyn TcpConnection, peak: PeakReport) {
+        let mut content = ArrayString::<{ 128 + HMAC_FIELD_LEN }>::new();
+
+        if peak
+            .serialize(&mut content)
+            .and_then(|_| self.sign(&mut content))
+            .is_err()
+        {
+            warn!("Peak does not fit in {} bytes", content.capacity());
+            return;
+        }
+
+        let topic = self.topic(&self.config.peak_topic);
+        self.send_pub(socket, &topic, content.as_bytes(), true);
+    }
+
+    /// Queues the report of a panic from before the last reset for publishing.
+    pub fn queue_last_panic(&mut self, report: PanicReport) {
+        self.last_panic = Some(report);
+    }
+
+    fn send_last_panic(&mut self, socket: &mut dyn TcpConnection, report: PanicReport) {
         let mut content = ArrayString::<512>::new();
 
-        telegram.serialize(&mut content);
+        if write!(content, "{}", report).is_err() {
+            warn!("Panic report does not fit in {} bytes", content.capacity());
+            return;
+        }
+
+        self.send_pub(socket, LAST_PANIC_TOPIC, content.as_bytes(), true);
+    }
+
+    pub fn queue_selftest(&mut self, report: SelfTestReport) {
+        self.queued_selftest = Some(report);
+    }
+
+    fn send_selftest(&mut self, socket: &mut dyn TcpConnection, report: SelfTestReport) {
+        let mut content = ArrayString::<128>::new();
+
+        if report.serialize(&mut content).is_err() {
+            warn!(
+                "Self-test report does not fit in {} bytes",
+                content.capacity()
+            );
+            return;
+        }
+
+        self.send_pub(socket, SELFTEST_TOPIC, content.as_bytes(), true);
+    }
+
+    /// Queues an alert for publishing. If too many are waiting for the
+    /// broker, the oldest is dropped.
+    pub fn queue_alert(&mut self, alert: AlertEvent) {
+        if self.queued_alerts.is_full() {
+            warn_throttled!(
+                clock::millis(),
+                "Too many alerts queued, dropping the oldest"
+            );
+            self.queued_alerts.remove(0);
+        }
+        self.queued_alerts.push(alert);
+    }
+
+    fn send_alert(&mut self, socket: &mut dyn TcpConnection, alert: AlertEvent) {
+        let mut content = ArrayString::<{ 192 + HMAC_FIELD_LEN }>::new();
+
+        if alert
+            .serialize(&mut content)
+            .and_then(|_| self.sign(&mut content))
+            .is_err()
+        {
+            warn!("Alert does not fit in {} bytes", content.capacity());
+            return;
+        }
+
+        self.send_pub(socket, ALERTS_TOPIC, content.as_bytes(), false);
+    }
+
+    /// Adds an `hmac` field to a JSON object, holding the HMAC-SHA256 of the
+    /// object as it was without the field, if a key is configured. Consumers
+    /// sharing the key can verify it by removing the field again, so other
+    /// clients of the broker can't pass off their own usage data as ours.
+    fn sign<const CAP: usize>(&self, content: &mut ArrayString<CAP>) -> fmt::Result {
+        if self.config.hmac_key.is_empty() {
+            return Ok(());
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.config.hmac_key.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(content.as_bytes());
+        let tag = mac.finalize().into_bytes();
+
+        let separator = if content.len() > 2 { "," } else { "" };
+        if content.pop() != Some('}') {
+            return Err(fmt::Error);
+        }
+        write!(content, "{}\"hmac\": \"", separator)?;
+        for byte in tag {
+            write!(content, "{:02x}", byte)?;
+        }
+        write!(content, "\"}}")
+    }
+
+    /// Returns `false` if the sample should be published again later.
+    fn send_backfill(&mut self, socket: &mut dyn TcpConnection, sample: Sample) -> bool {
+        let mut content = ArrayString::<{ 256 + HMAC_FIELD_LEN }>::new();
+
+        if sample
+            .serialize(&mut content)
+            .and_then(|_| self.sign(&mut content))
+            .is_err()
+        {
+            warn!(
+                "Backfill sample does not fit in {} bytes",
+                content.capacity()
+            );
+            return true;
+        }
 
-        self.send_pub(socket, USAGE_TOPIC, content.as_bytes());
+        self.send_pub(socket, BACKFILL_TOPIC, content.as_bytes(), false)
     }
 
-    fn send_pub(&mut self, socket: SocketRef<TcpSocket>, topic: &str, payload: &[u8]) {
-        log::info!("Publishing {} bytes to {}", payload.len(), topic);
+    /// Returns whether the packet was handed to the socket. Messages that
+    /// describe the current state are retained, so new subscribers get them
+    /// right away; events are not, or they'd be replayed to every one of them.
+    fn send_pub(
+        &mut self,
+        socket: &mut dyn TcpConnection,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> bool {
+        info!("Publishing {} bytes to {}", payload.len(), topic);
         let header = variable_header::publish::Publish::new(topic, None);
 
         let mut flags = PublishFlags::default();
-        flags.set_retain(true);
+        flags.set_retain(retain);
         match Packet::publish(flags, header, payload).map(|p| self.send_packet(socket, p)) {
-            Err(err) => log::warn!("Failed to encode publish packet: {}", err),
-            Ok(Err(err)) => log::warn!("Failed to send publish packet: {}", err),
-            Ok(Ok(())) => {}
+            Err(err) => warn!("Failed to encode publish packet: {}", Display2Format(&err)),
+            Ok(Err(err)) => warn!("Failed to send publish packet: {}", Display2Format(&err)),
+            Ok(Ok(())) => return true,
         }
+        false
     }
 
     fn send_packet(
         &mut self,
-        mut socket: SocketRef<TcpSocket>,
+        socket: &mut dyn TcpConnection,
         packet: Packet,
     ) -> smoltcp::Result<()> {
-        log::info!("Sending {:?}: {:?}", packet.fixed_header().r#type(), packet);
-        socket.send(|buf| match packet.encode(buf) {
-            Ok(bytes) => {
-                log::info!("Sent {} bytes", bytes);
-                (bytes, ())
-            }
-            Err(err) => {
-                log::warn!("Failed to decode connect packet: {}", err);
-                (0, ())
-            }
-        })
+        info!(
+            "Sending {:?}: {:?}",
+            Debug2Format(&packet.fixed_header().r#type()),
+            Debug2Format(&packet)
+        );
+        socket
+            .send_with(&mut |buf| match packet.encode(buf) {
+                Ok(bytes) => {
+                    info!("Sent {} bytes", bytes);
+                    bytes
+                }
+                Err(err) => {
+                    warn!("Failed to decode connect packet: {}", Display2Format(&err));
+                    0
+                }
+            })
+            .map(|_| ())
     }
 
-    fn handle_packet(&mut self, packet: Packet) {
-        log::debug!("{:#?}", packet);
+    fn handle_packet(&mut self, packet: Packet, timestamp: Instant) {
+        debug!("{:?}", Debug2Format(&packet));
         match packet.fixed_header().r#type() {
-            PacketType::Connack => self.handle_connack(packet),
-            PacketType::Pingresp => {}
+            PacketType::Connack => self.handle_connack(packet, timestamp),
+            PacketType::Pingresp | PacketType::Suback => {}
+            PacketType::Publish => self.handle_publish(packet),
             _ => self.invalid_packet(packet),
         }
     }
 
+    fn handle_publish(&mut self, packet: Packet) {
+        match packet.variable_header() {
+            Some(VariableHeader::Publish(publish)) if publish.topic_name() == COMMAND_TOPIC => {}
+            Some(VariableHeader::Publish(publish)) => {
+                debug!("Ignoring message on {}", publish.topic_name());
+                return;
+            }
+            _ => return self.invalid_packet(packet),
+        }
+        let command: &[u8] = match packet.payload() {
+            Some(payload::Payload::Bytes(bytes)) => bytes,
+            _ => return self.invalid_packet(packet),
+        };
+        self.command = match command {
+            b"ota" => Some(Command::EnableOta),
+            b"reboot" => Some(Command::Reboot),
+            b"selftest" => Some(Command::SelfTest),
+            other => match parse_log_level(other) {
+                Some(level) => Some(Command::SetLogLevel(level)),
+                None => {
+                    warn!(
+                        "Unknown command: {:?}",
+                        Debug2Format(&core::str::from_utf8(other))
+                    );
+                    return;
+                }
+            },
+        };
+        info!("Received command {:?}", Debug2Format(&self.command));
+    }
+
     fn invalid_packet(&mut self, packet: Packet) {
-        log::warn!(
-            "Received invalid packet for state {}:\n{:#?}",
-            self.mqtt_state,
-            packet
+        warn!(
+            "Received invalid packet for state {}: {:?}",
+            Display2Format(&self.mqtt_state),
+            Debug2Format(&packet)
         );
         self.mqtt_state = MqttState::Invalid;
     }
 
-    fn handle_connack(&mut self, packet: Packet) {
+    fn handle_connack(&mut self, packet: Packet, timestamp: Instant) {
         if self.mqtt_state != MqttState::Connecting {
-            log::warn!(
+            warn!(
                 "Received unexpected CONNACK, current state: {}",
-                self.mqtt_state
+                Display2Format(&self.mqtt_state)
             );
             self.mqtt_state = MqttState::Invalid;
             return;
@@ -257,40 +963,180 @@ impl MqttClient {
         match packet.variable_header() {
             Some(VariableHeader::Connack(connack)) => match connack.return_code() {
                 connack::ReturnCode::Accepted => {
-                    log::debug!("MQTT State: Connecting -> Connected");
+                    debug!("MQTT State: Connecting -> Connected");
                     self.mqtt_state = MqttState::Connected;
                 }
                 other => {
-                    log::warn!("MQTT Connection request denied: {:?}", other);
+                    warn!("MQTT Connection request denied: {:?}", Debug2Format(&other));
                     self.mqtt_state = MqttState::Invalid;
+                    // Retrying right away is likely to be denied again.
+                    let quiet = Duration::from_secs(self.config.reject_quiet_secs as u64);
+                    self.next_attempt = self.next_attempt.max(timestamp + quiet);
                 }
             },
             _ => self.invalid_packet(packet),
         }
     }
 
-    fn try_connect(&mut self, mut socket: SocketRef<TcpSocket>, random: &mut Random) {
-        if self.current_backoff > 0 {
-            self.current_backoff -= 1;
-            return;
+    fn broker(&self) -> IpEndpoint {
+        IpEndpoint::new(IpAddress::Ipv4(self.config.broker), self.config.port)
+    }
+
+    fn try_connect(&mut self, timestamp: Instant, random: &mut Random) -> TcpAction {
+        if timestamp < self.next_attempt {
+            return TcpAction::Nothing;
         }
-        socket.set_timeout(Some(Duration::from_secs(120)));
-        socket.set_keep_alive(Some(Duration::from_secs(30)));
-        self.current_backoff = self.next_backoff;
-        self.next_backoff = self.next_backoff.saturating_mul(2).min(BACKOFF_CAP);
-
-        let local = stack::generate_local_port(random);
-        let remote = IpAddress::Ipv4(Ipv4Address(REMOTE_HOST));
-        let remote = IpEndpoint::new(remote, REMOTE_PORT);
-        log::debug!(
-            "Socket inactive, trying to connect 0.0.0.0:{} -> {}, backoff {} if connect fails",
-            local,
-            remote,
-            self.current_backoff,
+        self.next_attempt = timestamp + self.jitter(self.next_backoff, random);
+        self.next_backoff =
+            Duration::from_millis((self.next_backoff.total_millis() * 2).min(BACKOFF_CAP_MS));
+
+        let remote = self.proxy.endpoint(self.broker());
+        self.proxy.reset();
+        debug!(
+            "Socket inactive, trying to connect to {}, backoff {} if connect fails",
+            Display2Format(&remote),
+            Display2Format(&(self.next_attempt - timestamp)),
         );
-        let result = socket.connect(remote, local);
-        if let Err(err) = result {
-            log::warn!("Failed to connect: {}", err);
+        TcpAction::Connect {
+            remote,
+            timeout: Some(Duration::from_secs(120)),
+            keep_alive: Some(Duration::from_secs(30)),
+        }
+    }
+
+    /// Randomly lengthens or shortens a backoff by up to the configured
+    /// percentage, so readers that lost the broker at the same time don't
+    /// all reconnect at the same time.
+    fn jitter(&self, backoff: Duration, random: &mut Random) -> Duration {
+        let spread = backoff.total_millis() * self.config.reconnect_jitter_pct as u64 / 100;
+        let offset = random.next(2 * spread as u32 + 1) as u64;
+        Duration::from_millis(backoff.total_millis() + offset - spread)
+    }
+}
+
+/// Parses a `log <level>` command.
+fn parse_log_level(command: &[u8]) -> Option<log::LevelFilter> {
+    let level = core::str::from_utf8(command).ok()?.strip_prefix("log ")?;
+    level.trim().parse().ok()
+}
+
+/// Writes the fixed header and the variable header of a publish packet with
+/// QoS 0, which is retained, like `send_pub()` sends the reports.
+fn write_publish_header(writer: &mut PacketWriter, topic: &str, payload_len: usize) {
+    const PUBLISH_RETAINED: u8 = 0x31;
+    writer.write_bytes(&[PUBLISH_RETAINED]);
+    // The remaining length is encoded 7 bits at a time, least significant
+    // first, with the high bit set on all but the last byte.
+    let mut remaining = 2 + topic.len() + payload_len;
+    loop {
+        let byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining == 0 {
+            writer.write_bytes(&[byte]);
+            break;
+        }
+        writer.write_bytes(&[byte | 0x80]);
+    }
+    writer.write_bytes(&(topic.len() as u16).to_be_bytes());
+    writer.write_bytes(topic.as_bytes());
+}
+
+/// Writes a packet into part of a buffer, without keeping a copy of it. Of
+/// everything written, only the bytes from offset `skip` on that fit in
+/// `window` end up there, so a packet can be written in parts by writing all
+/// of it for each part, and measured by writing it into an empty window.
+struct PacketWriter<'a> {
+    skip: usize,
+    window: &'a mut [u8],
+    /// Number of bytes written, whether they ended up in the window or not.
+    len: usize,
+    last: Option<u8>,
+    /// Hashes everything written while it's set.
+    mac: Option<Hmac<Sha256>>,
+}
+
+impl<'a> PacketWriter<'a> {
+    fn new(skip: usize, window: &'a mut [u8]) -> Self {
+        Self {
+            skip,
+            window,
+            len: 0,
+            last: None,
+            mac: None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Number of bytes that ended up in the window.
+    fn written(&self) -> usize {
+        self.len.saturating_sub(self.skip).min(self.window.len())
+    }
+
+    /// What goes before the next member of a JSON object that's being
+    /// written.
+    fn separator(&self) -> &'static str {
+        if self.last == Some(b'{') {
+            ""
+        } else {
+            ","
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let start = self.len;
+        self.len += bytes.len();
+        let from = start.max(self.skip);
+        let to = self.len.min(self.skip + self.window.len());
+        if from < to {
+            self.window[from - self.skip..to - self.skip]
+                .copy_from_slice(&bytes[from - start..to - start]);
+        }
+        if let Some(mac) = &mut self.mac {
+            mac.update(bytes);
+        }
+        if let Some(byte) = bytes.last() {
+            self.last = Some(*byte);
+        }
+    }
+}
+
+impl Write for PacketWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Passes a JSON object on without its closing brace, so more members can be
+/// added to it. The brace is held back until something follows it.
+struct Unclosed<'w, W> {
+    writer: &'w mut W,
+    brace: bool,
+}
+
+impl<'w, W: Write> Unclosed<'w, W> {
+    fn new(writer: &'w mut W) -> Self {
+        Self {
+            writer,
+            brace: false,
+        }
+    }
+}
+
+impl<W: Write> Write for Unclosed<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if core::mem::replace(&mut self.brace, false) {
+            self.writer.write_str("}")?;
+        }
+        match s.strip_suffix('}') {
+            Some(rest) => {
+                self.brace = true;
+                self.writer.write_str(rest)
+            }
+            None => self.writer.write_str(s),
         }
     }
 }
@@ -0,0 +1,145 @@
+use arrayvec::{ArrayString, ArrayVec};
+use dsmr42::SerializeOptions;
+use smoltcp::{
+    socket::{SocketHandle, SocketRef, UdpSocket},
+    time::Instant,
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{
+    network::{client::UdpClient, stack},
+    random::Random,
+    telemetry::{TelemetryRecord, TelemetrySink},
+};
+
+// Fits a full telegram in either format, and stays below the Ethernet MTU.
+const DATAGRAM_SZ: usize = 1024;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MulticastFormat {
+    /// The telegram as a JSON object, like it's published to MQTT.
+    Json,
+    /// The compact binary encoding from `dsmr42::binary`.
+    Binary,
+}
+
+impl MulticastFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            MulticastFormat::Json => "json",
+            MulticastFormat::Binary => "binary",
+        }
+    }
+}
+
+/// Where to send readings to. Nothing is sent until a group has been set.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MulticastConfig {
+    pub group: Ipv4Address,
+    pub port: u16,
+    pub format: MulticastFormat,
+    /// Minimum time between two datagrams, 0 to send every telegram.
+    pub interval_ms: i64,
+}
+
+impl Default for MulticastConfig {
+    fn default() -> Self {
+        Self {
+            group: Ipv4Address::UNSPECIFIED,
+            port: 5005,
+            format: MulticastFormat::Json,
+            interval_ms: 0,
+        }
+    }
+}
+
+impl MulticastConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.group.is_unspecified()
+    }
+}
+
+/// Sends telegrams to a multicast group, a datagram each, so displays and
+/// other devices on the local network can follow them without a broker.
+/// Nothing is received, so the group is never joined.
+pub struct MulticastClient {
+    config: MulticastConfig,
+    handle: Option<SocketHandle>,
+    queued: ArrayVec<u8, DATAGRAM_SZ>,
+    next_send: i64,
+}
+
+impl UdpClient for MulticastClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll(&mut self, mut socket: SocketRef<UdpSocket>, timestamp: Instant, random: &mut Random) {
+        let now = timestamp.total_millis();
+        if !self.config.is_enabled() || self.queued.is_empty() || now < self.next_send {
+            return;
+        }
+        if !socket.is_open() {
+            let local = stack::generate_local_port(random);
+            if let Err(err) = socket.bind(local) {
+                log::warn!("Failed to bind multicast socket: {}", err);
+                return;
+            }
+        }
+        if !socket.can_send() {
+            log::warn!("Multicast socket buffer full, dropping telegram");
+        } else {
+            let remote = IpEndpoint::new(IpAddress::Ipv4(self.config.group), self.config.port);
+            if let Err(err) = socket.send_slice(&self.queued, remote) {
+                log::warn!("Failed to multicast telegram: {}", err);
+            }
+        }
+        self.queued.clear();
+        self.next_send = now + self.config.interval_ms;
+    }
+}
+
+impl MulticastClient {
+    pub fn new(config: MulticastConfig) -> Self {
+        Self {
+            config,
+            handle: None,
+            queued: ArrayVec::new(),
+            next_send: 0,
+        }
+    }
+}
+
+impl TelemetrySink for MulticastClient {
+    /// Queues the telegram, replacing any that hasn't been sent yet, so the
+    /// newest one goes out once the interval has passed.
+    fn accept(&mut self, record: &TelemetryRecord) {
+        if !self.config.is_enabled() {
+            return;
+        }
+        self.queued.clear();
+        match self.config.format {
+            MulticastFormat::Json => {
+                let mut json = ArrayString::<DATAGRAM_SZ>::new();
+                record.telegram.serialize(&mut json, &SerializeOptions::ALL);
+                // Members that don't fit are left out, including the brace.
+                if !json.ends_with('}') {
+                    log::warn!("Telegram does not fit in {} bytes", DATAGRAM_SZ);
+                    return;
+                }
+                let _ = self.queued.try_extend_from_slice(json.as_bytes());
+            }
+            MulticastFormat::Binary => {
+                let mut buf = [0; DATAGRAM_SZ];
+                match record.telegram.encode(&mut buf) {
+                    Ok(len) => {
+                        let _ = self.queued.try_extend_from_slice(&buf[..len]);
+                    }
+                    Err(_) => log::warn!("Telegram does not fit in {} bytes", DATAGRAM_SZ),
+                }
+            }
+        }
+    }
+}
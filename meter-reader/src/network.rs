@@ -1,5 +1,10 @@
-pub mod client;
-pub mod driver;
-pub mod stack;
+//! The stack itself lives in the `enc28j60-smoltcp` crate. Its modules are
+//! re-exported here, next to the drivers for each board and the proxies.
+pub use enc28j60_smoltcp::{client, dhcp, driver, events, filter, neighbours, stack};
+#[cfg(feature = "teensy40")]
+pub mod enc28j60;
+#[cfg(feature = "teensy41")]
+pub mod enet;
+pub mod proxy;
 
-pub use stack::BackingStore;
+pub use enc28j60_smoltcp::{BackingStore, NetStatus};
@@ -0,0 +1,62 @@
+#![allow(deprecated)] // Required because enc28j60 depends on v1.
+
+use ::enc28j60::Enc28j60;
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v1::OutputPin,
+};
+use enc28j60_smoltcp::{read_revision, registers::Bus, Enc28j60Driver};
+use teensy4_bsp::SysTick;
+
+use super::driver::TX_BUF;
+use crate::{logging::Display2Format, spi_bus::BusError};
+
+const RX_BUF: usize = ::enc28j60::BUF_SZ as usize - TX_BUF;
+
+// The `Driver` implementation and the errata workarounds are in the
+// `enc28j60-smoltcp` crate.
+
+pub type DriverError = ::enc28j60::Error<SpiError>;
+// The ENC28J60 shares its SPI bus, see `spi_bus.rs`.
+type SpiError = BusError<teensy4_bsp::hal::spi::Error>;
+
+pub fn create_enc28j60<SPI, PNCS, PRST>(
+    delay: &mut SysTick,
+    bus: &'static Bus<SPI, PNCS>,
+    mut rst: PRST,
+    addr: [u8; 6],
+) -> Result<Enc28j60Driver<SPI, PNCS, ::enc28j60::Unconnected, PRST>, DriverError>
+where
+    SPI: Transfer<u8, Error = SpiError> + Write<u8, Error = SpiError> + 'static,
+    PNCS: OutputPin + 'static,
+    PRST: OutputPin + 'static,
+{
+    debug!("Initialising ENC28J60 driver");
+    // Ensure the reset pin is high on startup
+    rst.set_high();
+    delay.delay(1);
+
+    // Reading the revision first tells a missing or miswired chip apart from
+    // one that fails to initialise.
+    let revision = read_revision(bus).map_err(::enc28j60::Error::Spi)?;
+    if revision.is_absent() {
+        warn!(
+            "ENC28J60 does not respond, read EREVID {}; check the SPI wiring",
+            Display2Format(&revision)
+        );
+        return Err(::enc28j60::Error::ErevidIsZero);
+    }
+
+    let enc28j60 = Enc28j60::new(
+        bus.spi(),
+        bus.ncs(),
+        ::enc28j60::Unconnected, // Interrupt
+        rst,
+        delay,
+        RX_BUF as u16,
+        addr,
+    )?;
+    delay.delay(100);
+    debug!("ENC28J60 setup done");
+    Ok(Enc28j60Driver::new(enc28j60, bus, revision))
+}
@@ -0,0 +1,487 @@
+//! Driver for the Ethernet MAC of the i.MX RT1062, with the DP83825 PHY the
+//! Teensy 4.1 has on board. See chapter 41 of the i.MX RT1060 reference
+//! manual, and the Teensy 4.1 schematic for the pin assignments.
+
+use core::ptr;
+
+use teensy4_bsp::SysTick;
+
+use super::{driver::Driver, filter::FrameFilter};
+
+// Both a multiple of 64, so every buffer is suitably aligned for the DMA.
+const BUF_SZ: usize = 1536;
+const RX_RING_SZ: usize = 8;
+const TX_RING_SZ: usize = 4;
+
+const ENET: usize = 0x402D_8000;
+const ENET_EIR: usize = ENET + 0x004;
+const ENET_EIMR: usize = ENET + 0x008;
+const ENET_RDAR: usize = ENET + 0x010;
+const ENET_TDAR: usize = ENET + 0x014;
+const ENET_ECR: usize = ENET + 0x024;
+const ENET_MMFR: usize = ENET + 0x040;
+const ENET_MSCR: usize = ENET + 0x044;
+const ENET_RCR: usize = ENET + 0x084;
+const ENET_TCR: usize = ENET + 0x0C4;
+const ENET_PALR: usize = ENET + 0x0E4;
+const ENET_PAUR: usize = ENET + 0x0E8;
+const ENET_GAUR: usize = ENET + 0x120;
+const ENET_GALR: usize = ENET + 0x124;
+const ENET_TFWR: usize = ENET + 0x144;
+const ENET_RDSR: usize = ENET + 0x180;
+const ENET_TDSR: usize = ENET + 0x184;
+const ENET_MRBR: usize = ENET + 0x188;
+
+const EIR_MII: u32 = 1 << 23;
+const EIR_RXF: u32 = 1 << 25;
+const DAR_ACTIVE: u32 = 1 << 24;
+const ECR_RESET: u32 = 1 << 0;
+const ECR_ETHEREN: u32 = 1 << 1;
+// Little-endian buffer descriptors.
+const ECR_DBSWP: u32 = 1 << 8;
+// Reserved bits that read as 1.
+const ECR_RESERVED: u32 = 0x7000_0000;
+const RCR_MII_MODE: u32 = 1 << 2;
+const RCR_BC_REJ: u32 = 1 << 4;
+const RCR_RMII_MODE: u32 = 1 << 8;
+// Strip the CRC from received frames.
+const RCR_CRCFWD: u32 = 1 << 14;
+const RCR_MAX_FL_SHIFT: u32 = 16;
+const TCR_FDEN: u32 = 1 << 2;
+const TFWR_STRFWD: u32 = 1 << 8;
+// The PHY accepts MDC up to 2.5 MHz: 150 MHz IPG clock / ((29 + 1) * 2).
+const MSCR_MII_SPEED: u32 = 29 << 1;
+// Type field of the PAUR register, which must be left at its reset value.
+const PAUR_TYPE: u32 = 0x8808;
+const MAX_FRAME_LENGTH: u32 = 1518;
+
+// MMFR fields for a clause 22 MDIO frame.
+const MMFR_START: u32 = 0b01 << 30;
+const MMFR_WRITE: u32 = 0b01 << 28;
+const MMFR_READ: u32 = 0b10 << 28;
+const MMFR_TURNAROUND: u32 = 0b10 << 16;
+// The PHY address straps are pulled low.
+const PHY_ADDR: u32 = 0;
+const MDIO_TIMEOUT_POLLS: u32 = 100_000;
+
+const PHY_BMSR: u32 = 0x01;
+const BMSR_LINK_STATUS: u16 = 1 << 2;
+// DP83825 registers, see its datasheet.
+const PHY_RCSR: u32 = 0x17;
+const PHY_LEDCR: u32 = 0x18;
+// Take the 50 MHz reference clock from the MAC.
+const RCSR_50MHZ_CLOCK: u16 = 0x0081;
+// Show link status on the LED, active high.
+const LEDCR_LINK_ACTIVE_HIGH: u16 = 0x0280;
+
+// Buffer descriptor status bits, shared by the MAC and us.
+const RX_EMPTY: u16 = 1 << 15;
+const RX_WRAP: u16 = 1 << 13;
+const RX_TRUNCATED: u16 = 1 << 0;
+const RX_OVERRUN: u16 = 1 << 1;
+const RX_CRC_ERROR: u16 = 1 << 2;
+const RX_NON_OCTET: u16 = 1 << 4;
+const RX_TOO_LONG: u16 = 1 << 5;
+const TX_READY: u16 = 1 << 15;
+const TX_WRAP: u16 = 1 << 13;
+const TX_LAST: u16 = 1 << 11;
+const TX_APPEND_CRC: u16 = 1 << 10;
+
+const CCM_CCGR1: usize = 0x400F_C06C;
+const CCGR1_ENET: u32 = 0b11 << 10;
+const CCM_ANALOG_PLL_ENET: usize = 0x400D_80E0;
+const CCM_ANALOG_PLL_ENET_SET: usize = CCM_ANALOG_PLL_ENET + 0x4;
+const CCM_ANALOG_PLL_ENET_CLR: usize = CCM_ANALOG_PLL_ENET + 0x8;
+const PLL_ENET_DIV_SELECT: u32 = 0b11;
+// Divider for the 50 MHz RMII reference clock.
+const PLL_ENET_DIV_50MHZ: u32 = 0b01;
+const PLL_ENET_POWERDOWN: u32 = 1 << 12;
+const PLL_ENET_ENABLE: u32 = 1 << 13;
+const PLL_ENET_BYPASS: u32 = 1 << 16;
+const PLL_ENET_25M_REF_EN: u32 = 1 << 21;
+const PLL_ENET_LOCK: u32 = 1 << 31;
+
+const IOMUXC_GPR_GPR1: usize = 0x400A_C004;
+const GPR1_ENET1_CLK_SEL: u32 = 1 << 13;
+const GPR1_ENET1_TX_CLK_DIR: u32 = 1 << 17;
+const GPR1_ENET_IPG_CLK_S_EN: u32 = 1 << 23;
+// Selects GPIO7 instead of GPIO2 for the GPIO_B0 and GPIO_B1 pads.
+const IOMUXC_GPR_GPR27: usize = 0x400A_C06C;
+
+const IOMUXC: usize = 0x401F_8000;
+const MUX_GPIO_B0: usize = IOMUXC + 0x13C;
+const MUX_GPIO_B1: usize = IOMUXC + 0x17C;
+const PAD_GPIO_B1: usize = IOMUXC + 0x36C;
+const ENET_IPG_CLK_RMII_SELECT_INPUT: usize = IOMUXC + 0x42C;
+const ENET_MDIO_SELECT_INPUT: usize = IOMUXC + 0x430;
+const ENET0_RXDATA_SELECT_INPUT: usize = IOMUXC + 0x434;
+const ENET1_RXDATA_SELECT_INPUT: usize = IOMUXC + 0x438;
+const ENET_RXEN_SELECT_INPUT: usize = IOMUXC + 0x43C;
+const ENET_RXERR_SELECT_INPUT: usize = IOMUXC + 0x440;
+const MUX_ALT_ENET: u32 = 3;
+const MUX_ALT_GPIO: u32 = 5;
+const MUX_ALT_REFCLK: u32 = 6;
+const MUX_SION: u32 = 1 << 4;
+const PAD_PULLDOWN: u32 = 0x30E9;
+const PAD_PULLUP: u32 = 0xB0E9;
+const PAD_CLOCK: u32 = 0x0031;
+
+const GPIO7: usize = 0x4200_4000;
+const GPIO7_GDIR: usize = GPIO7 + 0x04;
+const GPIO7_DR_SET: usize = GPIO7 + 0x84;
+// PHY reset and power enable, on GPIO_B0_14 and GPIO_B0_15.
+const PHY_RESET_PIN: u32 = 14;
+const PHY_POWER_PIN: u32 = 15;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EnetError {
+    /// The PHY did not complete an MDIO transfer.
+    MdioTimeout,
+    /// The PHY does not use the clock we supply.
+    PhyConfig(u16),
+    /// All transmit descriptors are still in use by the MAC.
+    TransmitBusy,
+    FrameTooLong(usize),
+}
+
+/// Legacy buffer descriptor. `status` is owned by the MAC while its
+/// `RX_EMPTY` or `TX_READY` bit is set.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct BufferDescriptor {
+    len: u16,
+    status: u16,
+    buffer: u32,
+}
+
+impl BufferDescriptor {
+    const EMPTY: Self = Self {
+        len: 0,
+        status: 0,
+        buffer: 0,
+    };
+}
+
+#[repr(C, align(64))]
+struct Rings {
+    rx: [BufferDescriptor; RX_RING_SZ],
+    tx: [BufferDescriptor; TX_RING_SZ],
+}
+
+#[repr(C, align(64))]
+struct Buffers<const N: usize>([[u8; BUF_SZ]; N]);
+
+// These live in DTCM, which the MAC can access directly, so no cache
+// maintenance is needed.
+static mut RINGS: Rings = Rings {
+    rx: [BufferDescriptor::EMPTY; RX_RING_SZ],
+    tx: [BufferDescriptor::EMPTY; TX_RING_SZ],
+};
+static mut RX_BUFFERS: Buffers<RX_RING_SZ> = Buffers([[0; BUF_SZ]; RX_RING_SZ]);
+static mut TX_BUFFERS: Buffers<TX_RING_SZ> = Buffers([[0; BUF_SZ]; TX_RING_SZ]);
+
+/// The onboard Ethernet of the Teensy 4.1. There is only one, so only create
+/// it once.
+pub struct Enet {
+    rx_index: usize,
+    tx_index: usize,
+    check_crc: bool,
+}
+
+impl Driver for Enet {
+    type Error = EnetError;
+    type TransmitError = EnetError;
+
+    fn pending_packets(&mut self) -> Result<u8, EnetError> {
+        // Bad frames are dropped here, so `receive()` never sees them.
+        loop {
+            let status = rx_status(self.rx_index);
+            if status & RX_EMPTY != 0 || status & self.rx_error_mask() == 0 {
+                break;
+            }
+            debug!("Dropping bad frame, status {:#06x}", status);
+            self.release_rx();
+        }
+        let pending = (0..RX_RING_SZ)
+            .take_while(|i| rx_status((self.rx_index + i) % RX_RING_SZ) & RX_EMPTY == 0)
+            .count();
+        Ok(pending as u8)
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<u16, EnetError> {
+        if rx_status(self.rx_index) & RX_EMPTY != 0 {
+            return Ok(0);
+        }
+        let len = unsafe { ptr::read_volatile(&RINGS.rx[self.rx_index].len) } as usize;
+        let len = len.min(buffer.len());
+        buffer[..len].copy_from_slice(unsafe { &RX_BUFFERS.0[self.rx_index][..len] });
+        self.release_rx();
+        Ok(len as u16)
+    }
+
+    fn transmit(&mut self, buffer: &[u8]) -> Result<(), EnetError> {
+        if buffer.len() > BUF_SZ {
+            return Err(EnetError::FrameTooLong(buffer.len()));
+        }
+        let index = self.tx_index;
+        let descriptor = unsafe { &mut RINGS.tx[index] };
+        if unsafe { ptr::read_volatile(&descriptor.status) } & TX_READY != 0 {
+            return Err(EnetError::TransmitBusy);
+        }
+        unsafe { TX_BUFFERS.0[index][..buffer.len()].copy_from_slice(buffer) };
+        let wrap = if index == TX_RING_SZ - 1 { TX_WRAP } else { 0 };
+        unsafe {
+            ptr::write_volatile(&mut descriptor.len, buffer.len() as u16);
+            ptr::write_volatile(
+                &mut descriptor.status,
+                TX_READY | TX_LAST | TX_APPEND_CRC | wrap,
+            );
+        }
+        // The descriptor must be complete before the MAC looks at it.
+        cortex_m::asm::dsb();
+        write_reg(ENET_TDAR, DAR_ACTIVE);
+        self.tx_index = (index + 1) % TX_RING_SZ;
+        Ok(())
+    }
+
+    fn set_frame_filter(&mut self, filter: &FrameFilter) -> Result<(), EnetError> {
+        // Frames sent to our own address are always accepted, the MAC has no
+        // way to turn that off. It also hashes multicast addresses differently
+        // from the ENC28J60, so instead of translating the table, we accept
+        // all multicast frames once any group is subscribed. smoltcp drops
+        // the ones we don't need.
+        let multicast = filter.all_multicast || filter.hash_table() != [0; 8];
+        debug!(
+            "Setting frame filter: broadcast {}, multicast {}",
+            filter.broadcast, multicast
+        );
+        let rcr = read_reg(ENET_RCR);
+        if filter.broadcast {
+            write_reg(ENET_RCR, rcr & !RCR_BC_REJ);
+        } else {
+            write_reg(ENET_RCR, rcr | RCR_BC_REJ);
+        }
+        let hash = if multicast { u32::max_value() } else { 0 };
+        write_reg(ENET_GAUR, hash);
+        write_reg(ENET_GALR, hash);
+        self.check_crc = filter.check_crc;
+        Ok(())
+    }
+
+    fn is_link_up(&mut self) -> Result<bool, EnetError> {
+        // The link status bit latches low, so read it twice to get the
+        // current state.
+        mdio_read(PHY_BMSR)?;
+        Ok(mdio_read(PHY_BMSR)? & BMSR_LINK_STATUS != 0)
+    }
+}
+
+impl Enet {
+    /// Raises the ENET interrupt for every received frame, so the network
+    /// can be polled right away instead of on a timer.
+    pub fn enable_rx_interrupt(&mut self) {
+        write_reg(ENET_EIMR, EIR_RXF);
+    }
+
+    fn rx_error_mask(&self) -> u16 {
+        let mask = RX_TRUNCATED | RX_OVERRUN | RX_NON_OCTET | RX_TOO_LONG;
+        if self.check_crc {
+            mask | RX_CRC_ERROR
+        } else {
+            mask
+        }
+    }
+
+    /// Hands the current receive descriptor back to the MAC.
+    fn release_rx(&mut self) {
+        let wrap = if self.rx_index == RX_RING_SZ - 1 {
+            RX_WRAP
+        } else {
+            0
+        };
+        unsafe { ptr::write_volatile(&mut RINGS.rx[self.rx_index].status, RX_EMPTY | wrap) };
+        self.rx_index = (self.rx_index + 1) % RX_RING_SZ;
+        // Reception stops when the MAC runs out of descriptors, so let it
+        // know there is one available again.
+        cortex_m::asm::dsb();
+        write_reg(ENET_RDAR, DAR_ACTIVE);
+    }
+}
+
+/// Clears the receive interrupt, returning whether a frame came in.
+pub fn clear_rx_interrupt() -> bool {
+    let received = read_reg(ENET_EIR) & EIR_RXF != 0;
+    // The interrupt flag is write-1-to-clear.
+    write_reg(ENET_EIR, EIR_RXF);
+    received
+}
+
+pub fn create_enet(delay: &mut SysTick, addr: [u8; 6]) -> Result<Enet, EnetError> {
+    debug!("Initialising ENET driver");
+    init_clock();
+    init_phy(delay);
+    init_pins();
+
+    // Reset the MAC, and configure it for RMII at 100 Mbit/s, full duplex.
+    write_reg(ENET_ECR, ECR_RESERVED | ECR_RESET);
+    while read_reg(ENET_ECR) & ECR_RESET != 0 {}
+    write_reg(ENET_EIMR, 0);
+    write_reg(ENET_MSCR, MSCR_MII_SPEED);
+
+    let rcsr = mdio_read(PHY_RCSR)?;
+    mdio_write(PHY_RCSR, RCSR_50MHZ_CLOCK)?;
+    mdio_write(PHY_LEDCR, LEDCR_LINK_ACTIVE_HIGH)?;
+    let rcsr_new = mdio_read(PHY_RCSR)?;
+    if rcsr_new != RCSR_50MHZ_CLOCK {
+        return Err(EnetError::PhyConfig(rcsr_new));
+    }
+    debug!("PHY RCSR {:#06x} -> {:#06x}", rcsr, rcsr_new);
+
+    write_reg(
+        ENET_RCR,
+        MAX_FRAME_LENGTH << RCR_MAX_FL_SHIFT | RCR_CRCFWD | RCR_RMII_MODE | RCR_MII_MODE,
+    );
+    write_reg(ENET_TCR, TCR_FDEN);
+    write_reg(
+        ENET_PALR,
+        u32::from_be_bytes([addr[0], addr[1], addr[2], addr[3]]),
+    );
+    write_reg(
+        ENET_PAUR,
+        u32::from(addr[4]) << 24 | u32::from(addr[5]) << 16 | PAUR_TYPE,
+    );
+    write_reg(ENET_GAUR, 0);
+    write_reg(ENET_GALR, 0);
+    write_reg(ENET_TFWR, TFWR_STRFWD);
+
+    unsafe {
+        for i in 0..RX_RING_SZ {
+            RINGS.rx[i] = BufferDescriptor {
+                len: 0,
+                status: RX_EMPTY | if i == RX_RING_SZ - 1 { RX_WRAP } else { 0 },
+                buffer: RX_BUFFERS.0[i].as_ptr() as u32,
+            };
+        }
+        for i in 0..TX_RING_SZ {
+            RINGS.tx[i] = BufferDescriptor {
+                len: 0,
+                status: if i == TX_RING_SZ - 1 { TX_WRAP } else { 0 },
+                buffer: TX_BUFFERS.0[i].as_ptr() as u32,
+            };
+        }
+        write_reg(ENET_RDSR, RINGS.rx.as_ptr() as u32);
+        write_reg(ENET_TDSR, RINGS.tx.as_ptr() as u32);
+    }
+    write_reg(ENET_MRBR, BUF_SZ as u32);
+
+    write_reg(ENET_ECR, ECR_RESERVED | ECR_DBSWP | ECR_ETHEREN);
+    write_reg(ENET_RDAR, DAR_ACTIVE);
+    debug!("ENET setup done");
+    Ok(Enet {
+        rx_index: 0,
+        tx_index: 0,
+        check_crc: true,
+    })
+}
+
+/// Enables the MAC clock, and has PLL6 generate the 50 MHz RMII reference
+/// clock, which we supply to the PHY.
+fn init_clock() {
+    write_reg(CCM_CCGR1, read_reg(CCM_CCGR1) | CCGR1_ENET);
+    write_reg(
+        CCM_ANALOG_PLL_ENET_CLR,
+        PLL_ENET_POWERDOWN | PLL_ENET_BYPASS | PLL_ENET_DIV_SELECT,
+    );
+    write_reg(
+        CCM_ANALOG_PLL_ENET_SET,
+        PLL_ENET_ENABLE | PLL_ENET_25M_REF_EN | PLL_ENET_DIV_50MHZ,
+    );
+    while read_reg(CCM_ANALOG_PLL_ENET) & PLL_ENET_LOCK == 0 {}
+    let gpr1 = read_reg(IOMUXC_GPR_GPR1);
+    write_reg(
+        IOMUXC_GPR_GPR1,
+        (gpr1 & !(GPR1_ENET1_CLK_SEL | GPR1_ENET_IPG_CLK_S_EN)) | GPR1_ENET1_TX_CLK_DIR,
+    );
+}
+
+/// Sets the PHY straps, and powers it up.
+fn init_phy(delay: &mut SysTick) {
+    // The PHY samples these when it comes out of reset: address 0, RMII
+    // slave mode, and auto MDI-X.
+    let straps = [
+        (4, PAD_PULLDOWN),
+        (5, PAD_PULLUP),
+        (6, PAD_PULLDOWN),
+        (7, PAD_PULLUP),
+        (8, PAD_PULLUP),
+        (9, PAD_PULLUP),
+        (10, PAD_CLOCK),
+        (11, PAD_PULLDOWN),
+    ];
+    for (pad, ctl) in straps.iter() {
+        write_reg(PAD_GPIO_B1 + 4 * pad, *ctl);
+    }
+
+    let pins = 1 << PHY_RESET_PIN | 1 << PHY_POWER_PIN;
+    write_reg(IOMUXC_GPR_GPR27, read_reg(IOMUXC_GPR_GPR27) | pins);
+    write_reg(MUX_GPIO_B0 + 4 * PHY_RESET_PIN as usize, MUX_ALT_GPIO);
+    write_reg(MUX_GPIO_B0 + 4 * PHY_POWER_PIN as usize, MUX_ALT_GPIO);
+    write_reg(GPIO7_GDIR, read_reg(GPIO7_GDIR) | pins);
+    write_reg(GPIO7_DR_SET, 1 << PHY_POWER_PIN);
+    delay.delay(1);
+    write_reg(GPIO7_DR_SET, 1 << PHY_RESET_PIN);
+    delay.delay(50);
+}
+
+fn init_pins() {
+    for pad in [4, 5, 6, 7, 8, 9, 11].iter() {
+        write_reg(MUX_GPIO_B1 + 4 * pad, MUX_ALT_ENET);
+    }
+    write_reg(MUX_GPIO_B1 + 4 * 10, MUX_ALT_REFCLK | MUX_SION);
+    // MDC and MDIO.
+    write_reg(MUX_GPIO_B1 + 4 * 14, 0);
+    write_reg(MUX_GPIO_B1 + 4 * 15, 0);
+    write_reg(PAD_GPIO_B1 + 4 * 15, PAD_PULLUP);
+    write_reg(ENET_IPG_CLK_RMII_SELECT_INPUT, 1);
+    write_reg(ENET_MDIO_SELECT_INPUT, 2);
+    write_reg(ENET0_RXDATA_SELECT_INPUT, 1);
+    write_reg(ENET1_RXDATA_SELECT_INPUT, 1);
+    write_reg(ENET_RXEN_SELECT_INPUT, 1);
+    write_reg(ENET_RXERR_SELECT_INPUT, 1);
+}
+
+fn mdio_read(reg: u32) -> Result<u16, EnetError> {
+    mdio(MMFR_READ | reg << 18)
+}
+
+fn mdio_write(reg: u32, value: u16) -> Result<(), EnetError> {
+    mdio(MMFR_WRITE | reg << 18 | u32::from(value)).map(|_| ())
+}
+
+fn mdio(frame: u32) -> Result<u16, EnetError> {
+    // The interrupt flag is write-1-to-clear.
+    write_reg(ENET_EIR, EIR_MII);
+    write_reg(
+        ENET_MMFR,
+        MMFR_START | MMFR_TURNAROUND | PHY_ADDR << 23 | frame,
+    );
+    for _ in 0..MDIO_TIMEOUT_POLLS {
+        if read_reg(ENET_EIR) & EIR_MII != 0 {
+            write_reg(ENET_EIR, EIR_MII);
+            return Ok(read_reg(ENET_MMFR) as u16);
+        }
+    }
+    Err(EnetError::MdioTimeout)
+}
+
+fn rx_status(index: usize) -> u16 {
+    unsafe { ptr::read_volatile(&RINGS.rx[index].status) }
+}
+
+fn read_reg(addr: usize) -> u32 {
+    unsafe { ptr::read_volatile(addr as *const u32) }
+}
+
+fn write_reg(addr: usize, value: u32) {
+    unsafe { ptr::write_volatile(addr as *mut u32, value) }
+}
@@ -0,0 +1,293 @@
+use core::{
+    fmt::{self, Display, Write},
+    str::FromStr,
+};
+
+use arrayvec::ArrayString;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+use crate::{logging::Display2Format, network::client::TcpConnection};
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_DOMAIN: u8 = 3;
+const SOCKS_IPV6: u8 = 4;
+// The proxy's response must fit in the socket's receive buffer, and HTTP
+// proxies don't say much when they let us through.
+const MAX_HTTP_RESPONSE_SZ: usize = 512;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ProxyKind {
+    /// Connect directly.
+    None,
+    /// Tunnel through an HTTP proxy with `CONNECT`.
+    Http,
+    /// Tunnel through a SOCKS5 proxy, without authentication.
+    Socks5,
+}
+
+/// A proxy to tunnel a TCP connection through. Written as `none`,
+/// `http://<address>:<port>` or `socks5://<address>:<port>`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    pub host: Ipv4Address,
+    pub port: u16,
+}
+
+impl ProxyConfig {
+    pub const NONE: ProxyConfig = ProxyConfig {
+        kind: ProxyKind::None,
+        host: Ipv4Address::UNSPECIFIED,
+        port: 0,
+    };
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+impl Display for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ProxyKind::None => write!(f, "none"),
+            ProxyKind::Http => write!(f, "http://{}:{}", self.host, self.port),
+            ProxyKind::Socks5 => write!(f, "socks5://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl FromStr for ProxyConfig {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        if s == "none" {
+            return Ok(Self::NONE);
+        }
+        let (kind, endpoint) = if let Some(endpoint) = s.strip_prefix("http://") {
+            (ProxyKind::Http, endpoint)
+        } else if let Some(endpoint) = s.strip_prefix("socks5://") {
+            (ProxyKind::Socks5, endpoint)
+        } else {
+            return Err(());
+        };
+        let mut parts = endpoint.splitn(2, ':');
+        let host = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let port = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        Ok(Self { kind, host, port })
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HandshakeStatus {
+    /// Waiting for the proxy; don't use the socket yet.
+    Pending,
+    /// The socket is connected to the target.
+    Ready,
+    /// The proxy refused, or sent something we don't understand. The
+    /// connection should be aborted.
+    Failed,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    Start,
+    AwaitingHttpResponse,
+    AwaitingSocksMethod,
+    AwaitingSocksReply,
+    Established,
+}
+
+/// Sets up a tunnel through a proxy, once the socket is connected to it.
+/// Without a proxy, the socket is used as is.
+pub struct ProxyHandshake {
+    config: ProxyConfig,
+    state: State,
+}
+
+impl ProxyHandshake {
+    pub fn new(config: ProxyConfig) -> Self {
+        Self {
+            config,
+            state: State::Start,
+        }
+    }
+
+    /// The endpoint to connect the socket to, to reach `target`.
+    pub fn endpoint(&self, target: IpEndpoint) -> IpEndpoint {
+        match self.config.kind {
+            ProxyKind::None => target,
+            _ => IpEndpoint::new(IpAddress::Ipv4(self.config.host), self.config.port),
+        }
+    }
+
+    /// Starts over, for a new connection.
+    pub fn reset(&mut self) {
+        self.state = State::Start;
+    }
+
+    /// Drives the handshake. Call this whenever the socket may send, until it
+    /// returns `Ready`.
+    pub fn poll(&mut self, socket: &mut dyn TcpConnection, target: IpEndpoint) -> HandshakeStatus {
+        let res = match (self.config.kind, self.state) {
+            (ProxyKind::None, _) | (_, State::Established) => return HandshakeStatus::Ready,
+            (ProxyKind::Http, State::Start) => self.send_http_connect(socket, target),
+            (ProxyKind::Socks5, State::Start) => self.send(
+                socket,
+                &[SOCKS_VERSION, 1, SOCKS_NO_AUTH],
+                State::AwaitingSocksMethod,
+            ),
+            (_, State::AwaitingHttpResponse) => self.receive_http_response(socket),
+            (_, State::AwaitingSocksMethod) => self.receive_socks_method(socket, target),
+            (_, State::AwaitingSocksReply) => self.receive_socks_reply(socket),
+        };
+        match res {
+            Ok(()) if self.state == State::Established => {
+                info!(
+                    "Connected to {} through proxy {}",
+                    Display2Format(&target),
+                    Display2Format(&self.config)
+                );
+                HandshakeStatus::Ready
+            }
+            Ok(()) => HandshakeStatus::Pending,
+            Err(err) => {
+                warn!(
+                    "Proxy handshake with {} failed: {}",
+                    Display2Format(&self.config),
+                    err
+                );
+                HandshakeStatus::Failed
+            }
+        }
+    }
+
+    fn send(
+        &mut self,
+        socket: &mut dyn TcpConnection,
+        data: &[u8],
+        next: State,
+    ) -> Result<(), &'static str> {
+        // Requests are small, so they go out in one piece or not at all.
+        if socket.send_free() < data.len() {
+            return Ok(());
+        }
+        socket.send_slice(data).map_err(|_| "send failed")?;
+        self.state = next;
+        Ok(())
+    }
+
+    fn send_http_connect(
+        &mut self,
+        socket: &mut dyn TcpConnection,
+        target: IpEndpoint,
+    ) -> Result<(), &'static str> {
+        let mut request = ArrayString::<128>::new();
+        write!(request, "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target)
+            .map_err(|_| "request too long")?;
+        self.send(socket, request.as_bytes(), State::AwaitingHttpResponse)
+    }
+
+    fn receive_http_response(
+        &mut self,
+        socket: &mut dyn TcpConnection,
+    ) -> Result<(), &'static str> {
+        // Only take the response itself, anything after it is for the client.
+        let (len, res) = socket
+            .peek()
+            .map(|buf| match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(end) => (end + 4, Some(http_status_ok(&buf[..end]))),
+                None if buf.len() >= MAX_HTTP_RESPONSE_SZ => (0, Some(false)),
+                None => (0, None),
+            })
+            .map_err(|_| "receive failed")?;
+        socket.consume(len).map_err(|_| "receive failed")?;
+        match res {
+            Some(true) => self.state = State::Established,
+            Some(false) => return Err("proxy refused CONNECT"),
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn receive_socks_method(
+        &mut self,
+        socket: &mut dyn TcpConnection,
+        target: IpEndpoint,
+    ) -> Result<(), &'static str> {
+        if socket.recv_queue() < 2 {
+            return Ok(());
+        }
+        let mut reply = [0; 2];
+        socket
+            .recv_slice(&mut reply)
+            .map_err(|_| "receive failed")?;
+        if reply != [SOCKS_VERSION, SOCKS_NO_AUTH] {
+            return Err("proxy requires authentication");
+        }
+        let addr = match target.addr {
+            IpAddress::Ipv4(addr) => addr,
+            _ => return Err("unsupported target address"),
+        };
+        let port = target.port.to_be_bytes();
+        let mut request = [
+            SOCKS_VERSION,
+            SOCKS_CONNECT,
+            0,
+            SOCKS_IPV4,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        request[4..8].copy_from_slice(addr.as_bytes());
+        request[8..].copy_from_slice(&port);
+        self.send(socket, &request, State::AwaitingSocksReply)
+    }
+
+    fn receive_socks_reply(&mut self, socket: &mut dyn TcpConnection) -> Result<(), &'static str> {
+        // Version, reply code, reserved, and the bound address and port, of
+        // which the length depends on the type of address.
+        let (len, res) = socket
+            .peek()
+            .map(|buf| {
+                let len = match buf.get(3) {
+                    Some(&SOCKS_IPV4) => 4 + 4 + 2,
+                    Some(&SOCKS_IPV6) => 4 + 16 + 2,
+                    Some(&SOCKS_DOMAIN) => match buf.get(4) {
+                        Some(&len) => 4 + 1 + len as usize + 2,
+                        None => return (0, None),
+                    },
+                    Some(_) => return (0, Some(Err("invalid reply"))),
+                    None => return (0, None),
+                };
+                if buf.len() < len {
+                    return (0, None);
+                }
+                match (buf[0], buf[1]) {
+                    (SOCKS_VERSION, 0) => (len, Some(Ok(()))),
+                    _ => (len, Some(Err("proxy refused connection"))),
+                }
+            })
+            .map_err(|_| "receive failed")?;
+        socket.consume(len).map_err(|_| "receive failed")?;
+        match res {
+            Some(Ok(())) => self.state = State::Established,
+            Some(Err(err)) => return Err(err),
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// Whether an HTTP response head has a 2xx status.
+fn http_status_ok(head: &[u8]) -> bool {
+    let status = head.split(|b| *b == b' ').nth(1).unwrap_or_default();
+    status.len() == 3 && status[0] == b'2'
+}
@@ -0,0 +1,107 @@
+//! A bit-banged 1-Wire bus on pin 23, for the temperature sensors. See the
+//! DS18B20 datasheet for the timing of the slots.
+//!
+//! The pin is never driven high: it's pulled low by making it an output, and
+//! released by making it an input again, leaving the pull-up to raise it.
+//! Only the parts of a slot where timing matters run with interrupts
+//! disabled, which is at most 70 µs at a time.
+
+use core::ptr;
+
+use cortex_m::{
+    interrupt,
+    peripheral::{DCB, DWT},
+};
+use teensy4_bsp::hal::{
+    ccm::PLL1,
+    gpio::{Input, GPIO},
+};
+
+use crate::temperature::OneWire;
+
+// GPIO1_IO25 on both boards.
+pub type Pin = GPIO<crate::board::P23, Input>;
+
+const GPIO1: usize = 0x401B_8000;
+const GDIR: usize = GPIO1 + 0x04;
+const DR_CLEAR: usize = GPIO1 + 0x88;
+const PIN_MASK: u32 = 1 << 25;
+const CYCLES_PER_US: u32 = PLL1::ARM_HZ / 1_000_000;
+
+pub struct OneWirePin {
+    pin: Pin,
+}
+
+impl OneWirePin {
+    /// Takes the pin, and starts the cycle counter that times the slots.
+    pub fn new(pin: Pin, dcb: &mut DCB, dwt: &mut DWT) -> Self {
+        dcb.enable_trace();
+        dwt.enable_cycle_counter();
+        // The output level is always low, only the direction changes.
+        unsafe { ptr::write_volatile(DR_CLEAR as *mut u32, PIN_MASK) };
+        Self { pin }
+    }
+
+    fn pull_low(&mut self) {
+        unsafe {
+            let gdir = ptr::read_volatile(GDIR as *const u32);
+            ptr::write_volatile(GDIR as *mut u32, gdir | PIN_MASK);
+        }
+    }
+
+    fn release(&mut self) {
+        unsafe {
+            let gdir = ptr::read_volatile(GDIR as *const u32);
+            ptr::write_volatile(GDIR as *mut u32, gdir & !PIN_MASK);
+        }
+    }
+}
+
+impl OneWire for OneWirePin {
+    fn reset(&mut self) -> bool {
+        interrupt::free(|_| self.pull_low());
+        // Interrupts only make this longer, which is fine.
+        delay_us(480);
+        let present = interrupt::free(|_| {
+            self.release();
+            delay_us(70);
+            // Devices answer by holding the line low for at least 60 µs.
+            !self.pin.is_set()
+        });
+        delay_us(410);
+        present
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        interrupt::free(|_| {
+            self.pull_low();
+            if bit {
+                delay_us(6);
+                self.release();
+                delay_us(64);
+            } else {
+                delay_us(60);
+                self.release();
+                delay_us(10);
+            }
+        });
+    }
+
+    fn read_bit(&mut self) -> bool {
+        interrupt::free(|_| {
+            self.pull_low();
+            delay_us(6);
+            self.release();
+            delay_us(9);
+            let bit = self.pin.is_set();
+            delay_us(55);
+            bit
+        })
+    }
+}
+
+fn delay_us(us: u32) {
+    let start = DWT::get_cycle_count();
+    let cycles = us * CYCLES_PER_US;
+    while DWT::get_cycle_count().wrapping_sub(start) < cycles {}
+}
@@ -0,0 +1,265 @@
+use arrayvec::ArrayVec;
+use ed25519_compact::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+use smoltcp::{socket::SocketHandle, time::Instant};
+
+use crate::{
+    flash::{self, PAGE_SZ, SECTOR_SZ},
+    network::client::{TcpAction, TcpClient, TcpConnection},
+    random::Random,
+};
+
+const LISTEN_PORT: u16 = 2002;
+
+// New images are staged in the upper half of flash, which leaves the lower
+// half for the running firmware.
+const STAGING_OFFSET: u32 = flash::FLASH_SZ / 2;
+const MAX_IMAGE_SZ: u32 = flash::FIRMWARE_MAX_SZ - STAGING_OFFSET;
+
+// Images are preceded by this, followed by the image length, little-endian,
+// and the Ed25519 signature of the SHA-256 digest of the image.
+const MAGIC: [u8; 4] = *b"MROT";
+const SIGNATURE_SZ: usize = 64;
+const HEADER_SZ: usize = 8 + SIGNATURE_SZ;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    /// Not accepting images.
+    Disabled,
+    /// Waiting for the header.
+    Listening,
+    Receiving {
+        len: u32,
+        written: u32,
+    },
+    /// A verified image is staged, waiting for the connection to close.
+    Staged {
+        len: u32,
+    },
+    /// The connection has closed, the image can be applied.
+    Ready {
+        len: u32,
+    },
+}
+
+/// Receives firmware images over TCP on `LISTEN_PORT`.
+///
+/// Images are only accepted after `enable()` has been called, and only if the
+/// firmware was built with `ota.public_key`. The image is written to the
+/// staging area in flash as it arrives, and verified against the signature in
+/// its header, which must have been made with the matching private key. Once
+/// that succeeds, `ready_image()` returns its length, and `apply()` copies it
+/// over the running firmware.
+///
+/// To send an image: `(printf 'MROT'; <length as u32 LE>; <signature>; cat
+/// firmware.bin) | nc <address> 2002`. The receiver replies with `OK` or
+/// `ERR` before closing the connection.
+pub struct OtaReceiver {
+    handle: Option<SocketHandle>,
+    public_key: Option<PublicKey>,
+    state: State,
+    header: ArrayVec<u8, HEADER_SZ>,
+    page: ArrayVec<u8, PAGE_SZ>,
+}
+
+impl TcpClient for OtaReceiver {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll(
+        &mut self,
+        socket: &mut dyn TcpConnection,
+        _timestamp: Instant,
+        _random: &mut Random,
+    ) -> TcpAction {
+        match self.state {
+            State::Disabled | State::Ready { .. } => return TcpAction::Nothing,
+            State::Staged { len } => {
+                if !socket.is_active() {
+                    self.state = State::Ready { len };
+                }
+                return TcpAction::Nothing;
+            }
+            _ => {}
+        }
+
+        if !socket.is_open() {
+            log::info!("Waiting for firmware image on port {}", LISTEN_PORT);
+            return TcpAction::Listen(LISTEN_PORT);
+        }
+
+        if socket.can_recv() {
+            let res = socket.peek().map(|buf| {
+                let mut consumed = 0;
+                while consumed < buf.len() && self.is_receiving() {
+                    consumed += self.receive(&buf[consumed..]);
+                }
+                consumed
+            });
+            if let Err(err) = res.and_then(|consumed| socket.consume(consumed)) {
+                log::warn!("Failed to receive firmware image: {}", err);
+            }
+        }
+
+        if let State::Staged { .. } = self.state {
+            let _ = socket.send_slice(b"OK\n");
+            TcpAction::Close
+        } else if self.state == State::Disabled {
+            let _ = socket.send_slice(b"ERR\n");
+            TcpAction::Close
+        } else if !socket.may_recv() && socket.is_active() {
+            log::warn!("Firmware image upload ended early");
+            self.reset();
+            TcpAction::Close
+        } else {
+            TcpAction::Nothing
+        }
+    }
+}
+
+impl OtaReceiver {
+    /// Verifies images with `public_key`, or refuses them all without one.
+    pub fn new(public_key: Option<[u8; 32]>) -> Self {
+        Self {
+            handle: None,
+            public_key: public_key.map(PublicKey::new),
+            state: State::Disabled,
+            header: ArrayVec::new(),
+            page: ArrayVec::new(),
+        }
+    }
+
+    /// Starts accepting a firmware image.
+    pub fn enable(&mut self) {
+        if self.public_key.is_none() {
+            log::warn!("Not enabling firmware updates without ota.public_key to verify them");
+        } else if !flash::can_replace_firmware() {
+            log::error!("Not enabling firmware updates, the flash routines aren't in ITCM");
+        } else if self.state == State::Disabled {
+            log::info!("Enabling firmware updates");
+            self.reset();
+            self.state = State::Listening;
+        }
+    }
+
+    /// Length of the verified image in the staging area, once the upload has
+    /// finished.
+    pub fn ready_image(&self) -> Option<u32> {
+        match self.state {
+            State::Ready { len } => Some(len),
+            _ => None,
+        }
+    }
+
+    /// Replaces the running firmware with the staged image, and resets.
+    pub fn apply(&self, len: u32) -> ! {
+        log::info!("Installing new firmware ({} bytes)", len);
+        flash::replace_firmware(STAGING_OFFSET, len)
+    }
+
+    fn is_receiving(&self) -> bool {
+        matches!(self.state, State::Listening | State::Receiving { .. })
+    }
+
+    fn reset(&mut self) {
+        self.header.clear();
+        self.page.clear();
+        self.state = State::Disabled;
+    }
+
+    /// Processes (part of) `data`, and returns the number of bytes used.
+    fn receive(&mut self, data: &[u8]) -> usize {
+        match self.state {
+            State::Listening => {
+                let n = data.len().min(self.header.remaining_capacity());
+                let _ = self.header.try_extend_from_slice(&data[..n]);
+                if self.header.is_full() {
+                    self.start_image();
+                }
+                n
+            }
+            State::Receiving { len, written } => {
+                let left = (len - written) as usize - self.page.len();
+                let n = data.len().min(self.page.remaining_capacity()).min(left);
+                let _ = self.page.try_extend_from_slice(&data[..n]);
+                if self.page.is_full() || n == left {
+                    self.write_page(len, written);
+                }
+                n
+            }
+            _ => data.len(),
+        }
+    }
+
+    fn start_image(&mut self) {
+        let len = u32::from_le_bytes([
+            self.header[4],
+            self.header[5],
+            self.header[6],
+            self.header[7],
+        ]);
+        if self.header[..4] != MAGIC || len == 0 || len > MAX_IMAGE_SZ {
+            log::warn!("Invalid firmware image header, length {}", len);
+            self.reset();
+            return;
+        }
+        log::info!("Receiving firmware image of {} bytes", len);
+        self.state = State::Receiving { len, written: 0 };
+    }
+
+    fn write_page(&mut self, len: u32, written: u32) {
+        let offset = STAGING_OFFSET + written;
+        let mut page = [0xFF; PAGE_SZ];
+        page[..self.page.len()].copy_from_slice(&self.page);
+        let written = written + self.page.len() as u32;
+        self.page.clear();
+
+        // Sectors are erased as we go, to spread the time this takes.
+        let res = if offset % SECTOR_SZ == 0 {
+            flash::erase(offset, SECTOR_SZ)
+        } else {
+            Ok(())
+        };
+        if let Err(err) = res.and_then(|_| flash::program_page(offset, &page)) {
+            log::error!("Failed to write firmware image: {:?}", err);
+            self.reset();
+            return;
+        }
+
+        if written < len {
+            self.state = State::Receiving { len, written };
+        } else if self.verify(len) {
+            log::info!("Firmware image verified");
+            self.state = State::Staged { len };
+        } else {
+            log::warn!("Firmware image signature mismatch, discarding it");
+            self.reset();
+        }
+    }
+
+    /// Checks the image as it ended up in flash against the signature in its
+    /// header.
+    fn verify(&self, len: u32) -> bool {
+        let public_key = match &self.public_key {
+            Some(public_key) => public_key,
+            None => return false,
+        };
+        let signature = match Signature::from_slice(&self.header[8..]) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        let mut digest = Sha256::new();
+        let mut page = [0; PAGE_SZ];
+        let mut offset = 0;
+        while offset < len {
+            let n = (len - offset).min(PAGE_SZ as u32) as usize;
+            flash::read(STAGING_OFFSET + offset, &mut page[..n]);
+            digest.update(&page[..n]);
+            offset += n as u32;
+        }
+        public_key.verify(digest.finalize(), &signature).is_ok()
+    }
+}
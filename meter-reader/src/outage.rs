@@ -0,0 +1,201 @@
+use core::fmt::{self, Write};
+
+use dsmr42::{Line, Telegram};
+
+use crate::{
+    counters::{self, Counters},
+    telemetry::{TelemetryRecord, TelemetrySink},
+};
+
+// At one sample a minute, this covers eight and a half hours.
+const CAPACITY: usize = 512;
+const SAMPLE_INTERVAL_SECS: u32 = 60;
+pub const SAMPLE_LEN: usize = 28;
+
+/// The key readings of a telegram received while the broker was unreachable.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Sample {
+    pub unix_time: u32,
+    pub counters: Counters,
+    /// Power being consumed and produced, in W.
+    pub consuming: u32,
+    pub producing: u32,
+}
+
+impl Sample {
+    fn from_telegram(telegram: &Telegram) -> Option<Self> {
+        let mut sample = Sample {
+            unix_time: counters::telegram_unix_time(telegram)? as u32,
+            counters: Counters::from_telegram(telegram)?,
+            ..Sample::default()
+        };
+        for line in telegram.lines.iter() {
+            match line {
+                Line::TotalConsuming(w) => sample.consuming = *w,
+                Line::TotalProducing(w) => sample.producing = *w,
+                _ => {}
+            }
+        }
+        Some(sample)
+    }
+
+    pub fn encode(&self) -> [u8; SAMPLE_LEN] {
+        let values = [
+            self.unix_time,
+            self.counters.consumed[0],
+            self.counters.consumed[1],
+            self.counters.produced[0],
+            self.counters.produced[1],
+            self.consuming,
+            self.producing,
+        ];
+        let mut record = [0; SAMPLE_LEN];
+        for (chunk, value) in record.chunks_exact_mut(4).zip(values.iter()) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        record
+    }
+
+    pub fn decode(record: &[u8; SAMPLE_LEN]) -> Self {
+        let mut values = [0; SAMPLE_LEN / 4];
+        for (value, chunk) in values.iter_mut().zip(record.chunks_exact(4)) {
+            *value = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        Sample {
+            unix_time: values[0],
+            counters: Counters {
+                consumed: [values[1], values[2]],
+                produced: [values[3], values[4]],
+            },
+            consuming: values[5],
+            producing: values[6],
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> fmt::Result {
+        // Poor man's JSON, same as the telegrams
+        write!(
+            writer,
+            "{{\"unix_time\": {}, \"tariff_1_consumed\": {}, \"tariff_2_consumed\": {}, \
+            \"tariff_1_produced\": {}, \"tariff_2_produced\": {}, \"total_consuming\": {}, \
+            \"total_producing\": {}}}",
+            self.unix_time,
+            self.counters.consumed[0],
+            self.counters.consumed[1],
+            self.counters.produced[0],
+            self.counters.produced[1],
+            self.consuming,
+            self.producing,
+        )
+    }
+}
+
+/// Keeps a sample of the telegrams received while the broker can't be
+/// reached, so they can be published once it can. Samples are taken at most
+/// once a minute, going by the telegram timestamps. When the buffer is full,
+/// the oldest samples are overwritten.
+///
+/// With an SPI flash, the samples are journalled in the flash log as well, see
+/// `FlashLog`, so they survive a reboot.
+pub struct OutageBuffer {
+    records: [[u8; SAMPLE_LEN]; CAPACITY],
+    // Index of the oldest sample.
+    head: usize,
+    len: usize,
+    last_sample: Option<u32>,
+    // Not journalled yet.
+    added: Option<Sample>,
+    drained: bool,
+}
+
+impl OutageBuffer {
+    pub fn new() -> Self {
+        Self {
+            records: [[0; SAMPLE_LEN]; CAPACITY],
+            head: 0,
+            len: 0,
+            last_sample: None,
+            added: None,
+            drained: false,
+        }
+    }
+
+    /// The oldest sample that hasn't been published yet.
+    pub fn oldest(&self) -> Option<Sample> {
+        if self.len == 0 {
+            None
+        } else {
+            Some(Sample::decode(&self.records[self.head]))
+        }
+    }
+
+    /// Removes the oldest sample, once it has been published.
+    pub fn remove_oldest(&mut self) {
+        if self.len > 0 {
+            self.head = (self.head + 1) % CAPACITY;
+            self.len -= 1;
+        }
+        if self.len == 0 {
+            self.last_sample = None;
+            self.drained = true;
+        }
+    }
+
+    /// Puts back a sample that was buffered before a reboot.
+    #[cfg_attr(feature = "sim", allow(dead_code))]
+    pub fn restore(&mut self, sample: Sample) {
+        self.push(sample);
+    }
+
+    /// Forgets all samples, for when they were published before a reboot.
+    #[cfg_attr(feature = "sim", allow(dead_code))]
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.last_sample = None;
+    }
+
+    /// The sample added since the last call, which samples at most once a
+    /// minute.
+    #[cfg_attr(feature = "sim", allow(dead_code))]
+    pub fn take_added(&mut self) -> Option<Sample> {
+        self.added.take()
+    }
+
+    /// Whether the buffer was emptied since the last call.
+    #[cfg_attr(feature = "sim", allow(dead_code))]
+    pub fn take_drained(&mut self) -> bool {
+        core::mem::take(&mut self.drained)
+    }
+
+    fn push(&mut self, sample: Sample) {
+        if self.len == CAPACITY {
+            self.head = (self.head + 1) % CAPACITY;
+            self.len -= 1;
+        }
+        self.records[(self.head + self.len) % CAPACITY] = sample.encode();
+        self.len += 1;
+        self.last_sample = Some(sample.unix_time);
+    }
+
+    /// Number of samples waiting to be published.
+    pub fn pending(&self) -> usize {
+        self.len
+    }
+}
+
+impl TelemetrySink for OutageBuffer {
+    fn accept(&mut self, record: &TelemetryRecord) {
+        let sample = match Sample::from_telegram(record.telegram) {
+            Some(sample) => sample,
+            None => return,
+        };
+        match self.last_sample {
+            Some(last)
+                if sample.unix_time >= last && sample.unix_time - last < SAMPLE_INTERVAL_SECS => {}
+            _ => {
+                self.push(sample);
+                self.added = Some(sample);
+            }
+        }
+    }
+}
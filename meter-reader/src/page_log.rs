@@ -0,0 +1,111 @@
+use crate::{
+    crc::crc32,
+    flash::{self, FlashError, PAGE_SZ, SECTOR_SZ},
+};
+
+// Magic and sequence number.
+const HEADER_SZ: usize = 8;
+const CRC_SZ: usize = 4;
+/// Space for data in each record.
+pub const PAYLOAD_SZ: usize = PAGE_SZ - HEADER_SZ - CRC_SZ;
+const SLOTS_PER_BANK: usize = SECTOR_SZ as usize / PAGE_SZ;
+
+pub type Payload = [u8; PAYLOAD_SZ];
+
+/// Persists small records of a single page in two flash sectors, in the same
+/// way as `ConfigStore`: each save goes to the next free page, and a sector
+/// is only erased when the other one is full, so a power loss never loses
+/// both the old and the new record.
+pub struct PageLog {
+    banks: [u32; 2],
+    magic: [u8; 4],
+    sequence: u32,
+    // Bank and slot of the newest valid record.
+    latest: Option<(usize, usize)>,
+}
+
+impl PageLog {
+    /// Finds the newest valid record in the sectors at `banks`. Records
+    /// start with `magic`, which should be unique to each log.
+    pub fn load(banks: [u32; 2], magic: [u8; 4]) -> (Self, Option<Payload>) {
+        let mut log = Self {
+            banks,
+            magic,
+            sequence: 0,
+            latest: None,
+        };
+        let mut payload = None;
+        for bank in 0..banks.len() {
+            for slot in 0..SLOTS_PER_BANK {
+                if let Some((sequence, p)) = log.decode(&log.read_page(bank, slot)) {
+                    if log.latest.is_none() || sequence > log.sequence {
+                        log.sequence = sequence;
+                        log.latest = Some((bank, slot));
+                        payload = Some(p);
+                    }
+                }
+            }
+        }
+        (log, payload)
+    }
+
+    pub fn save(&mut self, payload: &Payload) -> Result<(), FlashError> {
+        let sequence = self.sequence.wrapping_add(1);
+        let (bank, slot) = match self.latest {
+            Some((bank, slot)) if slot + 1 < SLOTS_PER_BANK && self.is_erased(bank, slot + 1) => {
+                (bank, slot + 1)
+            }
+            Some((bank, _)) => (1 - bank, 0),
+            None => (0, 0),
+        };
+        if slot == 0 {
+            flash::erase(self.banks[bank], SECTOR_SZ)?;
+        }
+        flash::program_page(
+            self.page_offset(bank, slot),
+            &self.encode(payload, sequence),
+        )?;
+        if self.decode(&self.read_page(bank, slot)).map(|(s, _)| s) != Some(sequence) {
+            return Err(FlashError::Verify);
+        }
+        self.sequence = sequence;
+        self.latest = Some((bank, slot));
+        Ok(())
+    }
+
+    fn encode(&self, payload: &Payload, sequence: u32) -> [u8; PAGE_SZ] {
+        let mut page = [0xFF; PAGE_SZ];
+        page[..4].copy_from_slice(&self.magic);
+        page[4..HEADER_SZ].copy_from_slice(&sequence.to_le_bytes());
+        page[HEADER_SZ..PAGE_SZ - CRC_SZ].copy_from_slice(payload);
+        let crc = crc32(&page[..PAGE_SZ - CRC_SZ]);
+        page[PAGE_SZ - CRC_SZ..].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    fn decode(&self, page: &[u8; PAGE_SZ]) -> Option<(u32, Payload)> {
+        let mut crc = [0; CRC_SZ];
+        crc.copy_from_slice(&page[PAGE_SZ - CRC_SZ..]);
+        if page[..4] != self.magic || crc32(&page[..PAGE_SZ - CRC_SZ]) != u32::from_le_bytes(crc) {
+            return None;
+        }
+        let sequence = u32::from_le_bytes([page[4], page[5], page[6], page[7]]);
+        let mut payload = [0; PAYLOAD_SZ];
+        payload.copy_from_slice(&page[HEADER_SZ..PAGE_SZ - CRC_SZ]);
+        Some((sequence, payload))
+    }
+
+    fn page_offset(&self, bank: usize, slot: usize) -> u32 {
+        self.banks[bank] + (slot * PAGE_SZ) as u32
+    }
+
+    fn read_page(&self, bank: usize, slot: usize) -> [u8; PAGE_SZ] {
+        let mut page = [0; PAGE_SZ];
+        flash::read(self.page_offset(bank, slot), &mut page);
+        page
+    }
+
+    fn is_erased(&self, bank: usize, slot: usize) -> bool {
+        self.read_page(bank, slot).iter().all(|b| *b == 0xFF)
+    }
+}
@@ -1,21 +1,42 @@
-use core::panic::PanicInfo;
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::{self, Display};
 
-#[cfg(debug_assertions)]
-use core::sync::atomic::{self, Ordering};
+#[cfg(not(feature = "sim"))]
+mod handler;
 
-#[cfg(debug_assertions)]
-#[inline(never)]
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    log::error!("PANIC {}", info);
-    loop {
-        atomic::compiler_fence(Ordering::SeqCst);
+#[cfg(not(feature = "sim"))]
+pub use handler::take_last;
+
+const MESSAGE_SZ: usize = 256;
+const BACKTRACE_SZ: usize = 8;
+
+/// What survives a panic: the message, and the return addresses found on the
+/// stack. The latter are a guess, since we can't unwind, but are usually
+/// enough to find the culprit with `addr2line`.
+#[cfg_attr(feature = "sim", allow(dead_code))]
+#[derive(Clone, Debug)]
+pub struct PanicReport {
+    pub message: ArrayString<MESSAGE_SZ>,
+    pub backtrace: ArrayVec<u32, BACKTRACE_SZ>,
+}
+
+impl Display for PanicReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if !self.backtrace.is_empty() {
+            write!(f, " (backtrace:")?;
+            for addr in self.backtrace.iter() {
+                write!(f, " {:#010x}", addr)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
     }
 }
 
-#[cfg(not(debug_assertions))]
-#[inline(never)]
-#[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    cortex_m::peripheral::SCB::sys_reset()
+/// Panics on the host are handled by the standard library, so there's never
+/// anything left behind.
+#[cfg(feature = "sim")]
+pub fn take_last() -> Option<PanicReport> {
+    None
 }
@@ -0,0 +1,165 @@
+use arrayvec::{ArrayString, ArrayVec};
+use core::{
+    fmt::{self, Write},
+    mem::MaybeUninit,
+    panic::PanicInfo,
+    ptr,
+};
+
+#[cfg(debug_assertions)]
+use core::sync::atomic::{self, Ordering};
+
+use super::{PanicReport, BACKTRACE_SZ, MESSAGE_SZ};
+use crate::{crc::crc32, flash};
+
+// How far up the stack to look for return addresses.
+const STACK_SCAN_WORDS: usize = 1024;
+const MAGIC: u32 = 0x504E_4943;
+
+// Code runs from ITCM, but may call into functions left in flash.
+const ITCM: (u32, u32) = (0x0000_0100, 0x0008_0000);
+const FLASH: (u32, u32) = (0x6000_0000, 0x6000_0000 + flash::FLASH_SZ);
+
+extern "C" {
+    static _stack_start: u32;
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct PanicRecord {
+    magic: u32,
+    crc: u32,
+    message_len: u32,
+    message: [u8; MESSAGE_SZ],
+    backtrace_len: u32,
+    backtrace: [u32; BACKTRACE_SZ],
+}
+
+impl PanicRecord {
+    fn checksum(&self) -> u32 {
+        let mut buf = [0; 8 + MESSAGE_SZ + 4 * BACKTRACE_SZ];
+        buf[..4].copy_from_slice(&self.message_len.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.backtrace_len.to_le_bytes());
+        buf[8..8 + MESSAGE_SZ].copy_from_slice(&self.message);
+        for (i, addr) in self.backtrace.iter().enumerate() {
+            let start = 8 + MESSAGE_SZ + 4 * i;
+            buf[start..start + 4].copy_from_slice(&addr.to_le_bytes());
+        }
+        crc32(&buf)
+    }
+}
+
+// Placed in a section that is not zeroed at startup, so the record survives a
+// reset (but not a power cycle, which is why it carries a checksum).
+#[link_section = ".uninit.PANIC_RECORD"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+/// Returns the report left behind by a panic before the last reset, if any,
+/// and clears it.
+pub fn take_last() -> Option<PanicReport> {
+    let record = unsafe {
+        let record = ptr::read_volatile(PANIC_RECORD.as_ptr());
+        ptr::write_volatile(ptr::addr_of_mut!((*PANIC_RECORD.as_mut_ptr()).magic), 0);
+        record
+    };
+    if record.magic != MAGIC
+        || record.message_len as usize > MESSAGE_SZ
+        || record.backtrace_len as usize > BACKTRACE_SZ
+        || record.crc != record.checksum()
+    {
+        return None;
+    }
+
+    let message = core::str::from_utf8(&record.message[..record.message_len as usize]).ok()?;
+    let mut report = PanicReport {
+        message: ArrayString::new(),
+        backtrace: ArrayVec::new(),
+    };
+    report.message.push_str(message);
+    report.backtrace.extend(
+        record.backtrace[..record.backtrace_len as usize]
+            .iter()
+            .copied(),
+    );
+    Some(report)
+}
+
+/// Writes as much as fits, instead of failing when the message is too long.
+struct Truncating<'a>(&'a mut ArrayString<MESSAGE_SZ>);
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.0.try_push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn record(info: &PanicInfo) {
+    let mut message = ArrayString::<MESSAGE_SZ>::new();
+    let _ = write!(Truncating(&mut message), "{}", info);
+
+    let mut record = PanicRecord {
+        magic: MAGIC,
+        crc: 0,
+        message_len: message.len() as u32,
+        message: [0; MESSAGE_SZ],
+        backtrace_len: 0,
+        backtrace: [0; BACKTRACE_SZ],
+    };
+    record.message[..message.len()].copy_from_slice(message.as_bytes());
+    for addr in scan_stack() {
+        record.backtrace[record.backtrace_len as usize] = addr;
+        record.backtrace_len += 1;
+        if record.backtrace_len as usize == BACKTRACE_SZ {
+            break;
+        }
+    }
+    record.crc = record.checksum();
+
+    unsafe {
+        ptr::write_volatile(PANIC_RECORD.as_mut_ptr(), record);
+        // Make sure the record reaches RAM before we reset.
+        let mut core = cortex_m::Peripherals::steal();
+        core.SCB.clean_dcache_by_address(
+            PANIC_RECORD.as_ptr() as usize,
+            core::mem::size_of::<PanicRecord>(),
+        );
+    }
+}
+
+/// Yields the words on the stack that look like return addresses: odd
+/// (Thumb) addresses pointing into code.
+fn scan_stack() -> impl Iterator<Item = u32> {
+    let sp = cortex_m::register::msp::read() as usize;
+    let top = unsafe { &_stack_start as *const u32 as usize };
+    let words = (top.saturating_sub(sp) / 4).min(STACK_SCAN_WORDS);
+    (0..words)
+        .map(move |i| unsafe { ptr::read_volatile((sp as *const u32).add(i)) })
+        .filter(|word| {
+            let in_code = |(start, end): (u32, u32)| *word >= start && *word < end;
+            word & 1 == 1 && (in_code(ITCM) || in_code(FLASH))
+        })
+}
+
+#[cfg(debug_assertions)]
+#[inline(never)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    record(info);
+    log::error!("PANIC {}", info);
+    loop {
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+}
+
+#[cfg(not(debug_assertions))]
+#[inline(never)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    record(info);
+    cortex_m::peripheral::SCB::sys_reset()
+}
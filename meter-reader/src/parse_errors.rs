@@ -0,0 +1,165 @@
+use core::fmt::{self, Write};
+
+use arrayvec::ArrayString;
+use dsmr42::{ObisCode, TelegramParseError};
+
+/// Number of parse failures that are kept.
+pub const CAPACITY: usize = 4;
+// Bytes kept around the offset where parsing failed.
+const DUMP_LEN: usize = 16;
+
+/// What went wrong with a telegram.
+#[derive(Copy, Clone, Debug)]
+pub enum FailureKind {
+    Crc {
+        calculated: u16,
+        read: u16,
+    },
+    InvalidUtf8,
+    /// The nom error kind, as text.
+    Syntax(ArrayString<16>),
+    DuplicateLine(ObisCode),
+    TooLong,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FailureKind::Crc { calculated, read } => write!(
+                f,
+                "CRC mismatch (calculated {:04X}, read {:04X})",
+                calculated, read
+            ),
+            FailureKind::InvalidUtf8 => write!(f, "invalid UTF-8"),
+            FailureKind::Syntax(kind) => write!(f, "syntax error ({})", kind),
+            FailureKind::DuplicateLine(obis) => write!(
+                f,
+                "duplicate line {}-{}:{}.{}.{}*{}",
+                obis[0], obis[1], obis[2], obis[3], obis[4], obis[5]
+            ),
+            FailureKind::TooLong => write!(f, "too long"),
+        }
+    }
+}
+
+impl FailureKind {
+    /// Short name, for the diagnostics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FailureKind::Crc { .. } => "crc",
+            FailureKind::InvalidUtf8 => "utf8",
+            FailureKind::Syntax(_) => "syntax",
+            FailureKind::DuplicateLine(_) => "duplicate",
+            FailureKind::TooLong => "too_long",
+        }
+    }
+}
+
+/// A telegram that could not be parsed, with the bytes around the place
+/// where it went wrong.
+#[derive(Copy, Clone, Debug)]
+pub struct ParseFailure {
+    /// The `Clock` time at which it happened.
+    pub at: i64,
+    pub kind: FailureKind,
+    /// Where in the receive buffer parsing failed, if known.
+    pub offset: Option<usize>,
+    /// Number of bytes in the receive buffer.
+    pub buffer_len: usize,
+    dump_start: usize,
+    dump: [u8; DUMP_LEN],
+    dump_len: usize,
+}
+
+impl ParseFailure {
+    /// Returns `None` for `TelegramParseError::Incomplete`, which is not a
+    /// failure.
+    pub fn new(at: i64, err: &TelegramParseError, buffer: &[u8]) -> Option<Self> {
+        let (kind, offset) = match err {
+            TelegramParseError::Incomplete => return None,
+            // Point at the checksum itself.
+            TelegramParseError::CrcMismatch(mismatch) => (
+                FailureKind::Crc {
+                    calculated: mismatch.calculated,
+                    read: mismatch.read,
+                },
+                buffer.iter().position(|b| *b == b'!'),
+            ),
+            TelegramParseError::InvalidUtf8 => (
+                FailureKind::InvalidUtf8,
+                core::str::from_utf8(buffer).err().map(|e| e.valid_up_to()),
+            ),
+            TelegramParseError::ParseError(offset, code) => {
+                let mut kind = ArrayString::new();
+                // Truncated if it doesn't fit, which is fine.
+                let _ = write!(kind, "{:?}", code);
+                (FailureKind::Syntax(kind), Some(*offset))
+            }
+            TelegramParseError::DuplicateLine(obis) => (FailureKind::DuplicateLine(*obis), None),
+            TelegramParseError::TooLong => (FailureKind::TooLong, None),
+        };
+        let dump_start = offset
+            .unwrap_or(0)
+            .saturating_sub(DUMP_LEN / 2)
+            .min(buffer.len());
+        let region = &buffer[dump_start..];
+        let region = &region[..region.len().min(DUMP_LEN)];
+        let mut dump = [0; DUMP_LEN];
+        dump[..region.len()].copy_from_slice(region);
+        Some(Self {
+            at,
+            kind,
+            offset,
+            buffer_len: buffer.len(),
+            dump_start,
+            dump,
+            dump_len: region.len(),
+        })
+    }
+
+    /// Offset of the first byte in `dump()`.
+    pub fn dump_start(&self) -> usize {
+        self.dump_start
+    }
+
+    /// Up to 16 bytes of the receive buffer, from around the offset.
+    pub fn dump(&self) -> &[u8] {
+        &self.dump[..self.dump_len]
+    }
+
+    /// Writes `dump()` as hexadecimal bytes, separated by `separator`.
+    pub fn write_hex<W: Write>(&self, writer: &mut W, separator: &str) -> fmt::Result {
+        for (i, b) in self.dump().iter().enumerate() {
+            if i > 0 {
+                writer.write_str(separator)?;
+            }
+            write!(writer, "{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// The most recent parse failures, oldest first, so intermittent corruption
+/// on the serial line can be looked into after the fact.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ParseErrorLog {
+    entries: [Option<ParseFailure>; CAPACITY],
+    // Where the next one goes.
+    next: usize,
+}
+
+impl ParseErrorLog {
+    pub fn record(&mut self, failure: ParseFailure) {
+        self.entries[self.next] = Some(failure);
+        self.next = (self.next + 1) % CAPACITY;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ParseFailure> {
+        let (newer, older) = self.entries.split_at(self.next);
+        older.iter().chain(newer.iter()).flatten()
+    }
+
+    pub fn last(&self) -> Option<&ParseFailure> {
+        self.entries[(self.next + CAPACITY - 1) % CAPACITY].as_ref()
+    }
+}
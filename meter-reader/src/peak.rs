@@ -0,0 +1,105 @@
+use core::fmt::{self, Write};
+
+use arrayvec::ArrayVec;
+use dsmr42::Telegram;
+
+use crate::{
+    counters::{self, Counters},
+    wall_clock::LocalTime,
+};
+
+// Capacity tariffs are based on the average power over 15 minutes.
+const WINDOW_SECS: i64 = 15 * 60;
+// Readings are kept once a minute, so the window is between 15 and 16
+// minutes long.
+const SAMPLE_INTERVAL_SECS: i64 = 60;
+const SAMPLES: usize = (WINDOW_SECS / SAMPLE_INTERVAL_SECS) as usize + 2;
+
+/// The highest 15-minute average import power this month.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PeakReport {
+    pub watts: u32,
+    /// End of the window in which the peak occurred.
+    pub at: LocalTime,
+}
+
+impl PeakReport {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> fmt::Result {
+        // Poor man's JSON, same as the telegrams
+        write!(
+            writer,
+            "{{\"month\": \"{:04}-{:02}\", \"peak_w\": {}, \"at\": \"{}\"}}",
+            self.at.year, self.at.month, self.watts, self.at
+        )
+    }
+}
+
+/// Tracks the monthly peak of the average power consumed over a rolling
+/// 15-minute window, as used for capacity tariffs in Belgium.
+///
+/// The average is derived from the cumulative counters rather than the
+/// instantaneous power, so nothing between telegrams is missed. Times come
+/// from the telegrams, and the peak starts over each month, and at boot.
+pub struct PeakTracker {
+    // Unix time and total energy consumed, in Wh, oldest first.
+    samples: ArrayVec<(i64, u32), SAMPLES>,
+    peak: Option<PeakReport>,
+}
+
+impl PeakTracker {
+    pub fn new() -> Self {
+        Self {
+            samples: ArrayVec::new(),
+            peak: None,
+        }
+    }
+
+    pub fn update(&mut self, telegram: &Telegram) {
+        let (now, consumed) = match (
+            counters::telegram_unix_time(telegram),
+            Counters::from_telegram(telegram),
+        ) {
+            (Some(now), Some(counters)) => (now, counters.consumed.iter().sum::<u32>()),
+            _ => return,
+        };
+
+        match self.samples.last() {
+            // The meter's clock went back, so the samples can't be trusted.
+            Some((last, _)) if now < *last => self.samples.clear(),
+            Some((last, _)) if now - last < SAMPLE_INTERVAL_SECS => {}
+            _ => {
+                if self.samples.is_full() {
+                    self.samples.remove(0);
+                }
+                self.samples.push((now, consumed));
+            }
+        }
+        // Keep only a single sample older than the window.
+        while self.samples.len() > 1 && now - self.samples[1].0 >= WINDOW_SECS {
+            self.samples.remove(0);
+        }
+
+        let at = LocalTime::from_unix(now);
+        if let Some(peak) = self.peak {
+            if (peak.at.year, peak.at.month) != (at.year, at.month) {
+                log::info!("New month, resetting peak of {} W", peak.watts);
+                self.peak = None;
+            }
+        }
+
+        let (start, start_consumed) = match self.samples.first() {
+            Some(sample) if now - sample.0 >= WINDOW_SECS => *sample,
+            _ => return,
+        };
+        let wh = consumed.saturating_sub(start_consumed) as i64;
+        let watts = (wh * 3600 / (now - start)) as u32;
+        if self.peak.map_or(true, |peak| watts > peak.watts) {
+            self.peak = Some(PeakReport { watts, at });
+        }
+    }
+
+    /// The peak so far this month, once a full window has been seen.
+    pub fn report(&self) -> Option<PeakReport> {
+        self.peak
+    }
+}
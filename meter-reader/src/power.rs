@@ -0,0 +1,33 @@
+// Keep polling the network for this long after a telegram, so it can be
+// published and acknowledged before we go to sleep.
+const ACTIVE_MS: i64 = 1000;
+// Wake up this long before the next telegram is due.
+const WAKE_EARLY_MS: i64 = 500;
+// Meters that send telegrams more often than this keep us busy anyway.
+const MIN_INTERVAL_MS: i64 = 5000;
+
+/// In low-power mode, decides until when the network task can leave the CPU
+/// idle between two telegrams. Returns `None` if it should keep polling at the
+/// usual interval: right after a telegram, when the next one is almost due,
+/// or when the meter's interval isn't known yet.
+///
+/// While asleep, the CPU only wakes up for the UART, timers, and on the
+/// Teensy 4.1, received frames. Frames arriving at the ENC28J60 wait in its
+/// buffer until the next poll, and may be dropped if it fills up.
+pub fn sleep_until(
+    now: i64,
+    last_telegram: Option<i64>,
+    interval_secs: Option<u32>,
+) -> Option<i64> {
+    let last_telegram = last_telegram?;
+    let interval = interval_secs? as i64 * 1000;
+    if interval < MIN_INTERVAL_MS {
+        return None;
+    }
+    let wake = last_telegram + interval - WAKE_EARLY_MS;
+    if now < last_telegram + ACTIVE_MS || now >= wake {
+        None
+    } else {
+        Some(wake)
+    }
+}
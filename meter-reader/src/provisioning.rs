@@ -0,0 +1,307 @@
+use arrayvec::{ArrayString, ArrayVec};
+use smoltcp::{
+    socket::{SocketHandle, SocketRef, UdpSocket},
+    time::Instant,
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{
+    config::Config,
+    network::{client::UdpClient, stack},
+    random::Random,
+};
+
+const TFTP_PORT: u16 = 69;
+const OP_RRQ: u16 = 1;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const BLOCK_SZ: usize = 512;
+// Opcode and block number, followed by a block.
+const MAX_PACKET_SZ: usize = 4 + BLOCK_SZ;
+/// Largest configuration file that is accepted.
+pub const MAX_FILE_LEN: usize = 2048;
+// Sends the last packet again if the server hasn't answered in this time.
+const RETRY_INTERVAL_MS: i64 = 2000;
+const MAX_ATTEMPTS: u8 = 5;
+// The settings a provisioning file may change. Anyone on the network can
+// answer a TFTP request, so secrets, and where the reader sends its data or
+// gets its address and time from, can only be set on the console.
+const PROVISIONABLE_KEYS: [&str; 43] = [
+    "mqtt.client_id",
+    "mqtt.usage_topic",
+    "mqtt.status_topic",
+    "mqtt.diagnostics_topic",
+    "mqtt.costs_topic",
+    "mqtt.totals_topic",
+    "mqtt.peak_topic",
+    "mqtt.gas_topic",
+    "mqtt.fields",
+    "mqtt.reconnect_jitter",
+    "mqtt.reject_quiet_secs",
+    "uart.baud",
+    "uart.data_bits",
+    "uart.parity",
+    "uart.inverted",
+    "uart.autodetect",
+    "uart.data_request",
+    "uart.retransmit",
+    "uart.watchdog_mins",
+    "telegram_interval_ms",
+    "diagnostics_interval_ms",
+    "low_power",
+    "costs.tariff1_consumed",
+    "costs.tariff2_consumed",
+    "costs.tariff1_produced",
+    "costs.tariff2_produced",
+    "influx.org",
+    "influx.bucket",
+    "graphite.prefix",
+    "statsd.prefix",
+    "multicast.format",
+    "multicast.interval_ms",
+    "sd_log",
+    "aggregate",
+    "alerts.1",
+    "alerts.2",
+    "alerts.3",
+    "alerts.4",
+    "alerts.hysteresis",
+    "s0.enabled",
+    "s0.name",
+    "s0.pulses_per_unit",
+    "temperature_interval_ms",
+];
+
+/// Where to fetch the configuration from at boot.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct ProvisioningConfig {
+    /// The file to request from the TFTP server advertised through DHCP. If
+    /// empty, which is the default, nothing is fetched.
+    pub file: ArrayString<32>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    /// Waiting for the first poll, after the network is up.
+    Starting,
+    Requesting {
+        server: Ipv4Address,
+    },
+    /// The server answers from a port of its own choosing.
+    Receiving {
+        peer: IpEndpoint,
+        block: u16,
+    },
+    Finished,
+}
+
+/// Fetches a configuration file over TFTP once, at boot, from the server
+/// advertised through DHCP (option 150 or 66). Each line of the file is a
+/// setting, as `key value` or `key = value`, applied to the configuration in
+/// use. Only the settings in `PROVISIONABLE_KEYS` are applied. Lines starting
+/// with `#` are ignored.
+///
+/// If that changes anything, the result is picked up with `take_config()`, to
+/// be saved and applied by rebooting. The next boot fetches the same file and
+/// finds nothing to change, so this only reboots once per change.
+pub struct Provisioner {
+    config: ProvisioningConfig,
+    base: Config,
+    dhcp_server: Option<Ipv4Address>,
+    handle: Option<SocketHandle>,
+    state: State,
+    sent_at: i64,
+    attempts: u8,
+    contents: ArrayVec<u8, MAX_FILE_LEN>,
+    result: Option<Config>,
+}
+
+impl UdpClient for Provisioner {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll(&mut self, mut socket: SocketRef<UdpSocket>, timestamp: Instant, random: &mut Random) {
+        let now = timestamp.total_millis();
+        match self.state {
+            State::Finished => return,
+            State::Starting => {
+                // DHCP options are picked up before the address is, so the
+                // server is known by now if there is one.
+                let server = match self.dhcp_server {
+                    Some(server) if !self.config.file.is_empty() => server,
+                    _ => {
+                        log::debug!("No TFTP server to provision from");
+                        self.state = State::Finished;
+                        return;
+                    }
+                };
+                if !socket.is_open() {
+                    let local = stack::generate_local_port(random);
+                    if let Err(err) = socket.bind(local) {
+                        log::warn!("Failed to bind provisioning socket: {}", err);
+                        self.state = State::Finished;
+                        return;
+                    }
+                }
+                log::info!("Fetching {} from {}", self.config.file, server);
+                self.state = State::Requesting { server };
+                self.send(&mut socket, now);
+                return;
+            }
+            _ => {}
+        }
+        self.receive(&mut socket, now);
+        if self.state == State::Finished || now - self.sent_at < RETRY_INTERVAL_MS {
+            return;
+        }
+        if self.attempts >= MAX_ATTEMPTS {
+            log::warn!("TFTP server stopped answering, keeping the current configuration");
+            self.state = State::Finished;
+        } else {
+            self.send(&mut socket, now);
+        }
+    }
+}
+
+impl Provisioner {
+    /// `base` is the configuration in use, which the file is applied to.
+    pub fn new(config: ProvisioningConfig, base: Config) -> Self {
+        Self {
+            config,
+            base,
+            dhcp_server: None,
+            handle: None,
+            state: State::Starting,
+            sent_at: 0,
+            attempts: 0,
+            contents: ArrayVec::new(),
+            result: None,
+        }
+    }
+
+    /// Updates the TFTP server advertised through DHCP.
+    pub fn set_dhcp_server(&mut self, server: Option<Ipv4Address>) {
+        self.dhcp_server = server;
+    }
+
+    /// The provisioned configuration, if it differs from the one in use.
+    pub fn take_config(&mut self) -> Option<Config> {
+        self.result.take()
+    }
+
+    /// Sends the request, or acknowledges the last block received.
+    fn send(&mut self, socket: &mut SocketRef<UdpSocket>, now: i64) {
+        // Opcode, file name and mode, each terminated by a zero.
+        let mut packet = ArrayVec::<u8, { 2 + 32 + 1 + 6 }>::new();
+        let remote = match self.state {
+            State::Requesting { server } => {
+                packet.extend(OP_RRQ.to_be_bytes().iter().copied());
+                packet.extend(self.config.file.bytes());
+                packet.push(0);
+                packet.extend(b"octet\0".iter().copied());
+                IpEndpoint::new(IpAddress::Ipv4(server), TFTP_PORT)
+            }
+            State::Receiving { peer, block } => {
+                packet.extend(OP_ACK.to_be_bytes().iter().copied());
+                packet.extend(block.to_be_bytes().iter().copied());
+                peer
+            }
+            State::Starting | State::Finished => return,
+        };
+        if let Err(err) = socket.send_slice(&packet, remote) {
+            log::warn!("Failed to send TFTP packet: {}", err);
+        }
+        self.sent_at = now;
+        self.attempts += 1;
+    }
+
+    fn receive(&mut self, socket: &mut SocketRef<UdpSocket>, now: i64) {
+        let mut packet = [0; MAX_PACKET_SZ];
+        while let Ok((len, from)) = socket.recv_slice(&mut packet) {
+            let (expected_block, from_peer) = match self.state {
+                State::Requesting { server } => (1, from.addr == IpAddress::Ipv4(server)),
+                State::Receiving { peer, block } => (block.wrapping_add(1), from == peer),
+                State::Starting | State::Finished => return,
+            };
+            if !from_peer || len < 4 {
+                continue;
+            }
+            let opcode = u16::from_be_bytes([packet[0], packet[1]]);
+            let block = u16::from_be_bytes([packet[2], packet[3]]);
+            match opcode {
+                // Anything else is a retransmission, answered when we send
+                // our last acknowledgement again.
+                OP_DATA if block == expected_block => {
+                    let data = &packet[4..len];
+                    if self.contents.try_extend_from_slice(data).is_err() {
+                        log::warn!("{} is larger than {} bytes", self.config.file, MAX_FILE_LEN);
+                        self.state = State::Finished;
+                        return;
+                    }
+                    self.state = State::Receiving { peer: from, block };
+                    self.attempts = 0;
+                    self.send(socket, now);
+                    if data.len() < BLOCK_SZ {
+                        self.state = State::Finished;
+                        self.apply();
+                        return;
+                    }
+                }
+                OP_ERROR => {
+                    let message = packet[4..len].split(|b| *b == 0).next().unwrap_or(&[]);
+                    log::warn!(
+                        "TFTP server refused {}: {}",
+                        self.config.file,
+                        core::str::from_utf8(message).unwrap_or("")
+                    );
+                    self.state = State::Finished;
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn apply(&mut self) {
+        let text = match core::str::from_utf8(&self.contents) {
+            Ok(text) => text,
+            Err(_) => {
+                log::warn!("{} is not valid UTF-8", self.config.file);
+                return;
+            }
+        };
+        let mut config = self.base;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once(' ') {
+                Some((key, value)) => (key, value.trim_start().trim_start_matches("= ")),
+                None => (line, ""),
+            };
+            // As printed by `show config`, which doesn't reveal secrets.
+            if value == "(hidden)" {
+                continue;
+            }
+            if !PROVISIONABLE_KEYS.contains(&key) {
+                log::warn!(
+                    "Ignoring provisioned setting {}, it can only be set on the console",
+                    key
+                );
+                continue;
+            }
+            if let Err(err) = config.set(key, value.trim()) {
+                log::warn!("Ignoring provisioned setting {}: {:?}", key, err);
+            }
+        }
+        if config == self.base {
+            log::info!("Provisioned configuration matches the current one");
+        } else {
+            self.result = Some(config);
+        }
+    }
+}
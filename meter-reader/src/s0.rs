@@ -0,0 +1,142 @@
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use arrayvec::ArrayString;
+
+use crate::{
+    flash::{self, SECTOR_SZ},
+    page_log::{PageLog, PAYLOAD_SZ},
+};
+
+// The two sectors after the boot count, see `boot_count.rs`.
+const BANK_OFFSETS: [u32; 2] = [
+    flash::FIRMWARE_MAX_SZ + 6 * SECTOR_SZ,
+    flash::FIRMWARE_MAX_SZ + 7 * SECTOR_SZ,
+];
+const MAGIC: [u8; 4] = *b"MRS0";
+// S0 pulses last at least 30 ms, and contacts bounce for a few, so the line
+// must have been stable for this long before a falling edge counts.
+const DEBOUNCE_MS: u32 = 20;
+// Each save takes a flash page, so each sector is erased about every five
+// hours at most.
+const SAVE_INTERVAL_MS: i64 = 10 * 60 * 1000;
+
+// The pulse count, restored from flash at boot.
+static PULSES: AtomicU32 = AtomicU32::new(0);
+// `Clock` time of the last edge, counted or not.
+static LAST_EDGE: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct S0Config {
+    pub enabled: bool,
+    /// What the meter measures, which names the values in the telegrams.
+    pub name: ArrayString<16>,
+    /// Pulses per unit of what the meter measures, like 1000 for a water
+    /// meter with a pulse per litre that should be reported in m³.
+    pub pulses_per_unit: u32,
+}
+
+impl Default for S0Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            name: ArrayString::from("water").unwrap_or_default(),
+            pulses_per_unit: 1000,
+        }
+    }
+}
+
+/// Called from the interrupt handler on every edge of the S0 input, with
+/// whether the line is now low. The pulse is counted on its falling edge,
+/// if the line was high for long enough before.
+pub fn on_edge(low: bool, now: i64) {
+    let now = now as u32;
+    let last = LAST_EDGE.swap(now, Ordering::Relaxed);
+    if low && now.wrapping_sub(last) >= DEBOUNCE_MS {
+        PULSES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The count of an S0 meter, as published along with a telegram.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PulseReading {
+    name: ArrayString<16>,
+    pulses: u32,
+    pulses_per_unit: u32,
+}
+
+impl PulseReading {
+    /// Writes the reading as JSON object members, like `"water": 123.456`
+    /// and `"water_pulses": 123456`. `separator` goes before the first one.
+    pub fn serialize<W: Write>(&self, writer: &mut W, separator: &str) -> fmt::Result {
+        let per_unit = self.pulses_per_unit.max(1);
+        let fraction = (self.pulses % per_unit) as u64 * 1000 / per_unit as u64;
+        write!(
+            writer,
+            "{}\"{}\": {}.{:03},\"{}_pulses\": {}",
+            separator,
+            self.name,
+            self.pulses / per_unit,
+            fraction,
+            self.name,
+            self.pulses
+        )
+    }
+}
+
+/// Keeps the count of an S0 pulse output, like that of a water meter, and
+/// saves it to flash every few minutes, so a reset loses at most the pulses
+/// since.
+pub struct PulseCounter {
+    config: S0Config,
+    store: PageLog,
+    saved: u32,
+    next_save: i64,
+}
+
+impl PulseCounter {
+    /// Loads the count from flash. Call this before the interrupt is
+    /// enabled.
+    pub fn load(config: S0Config) -> Self {
+        let (store, payload) = PageLog::load(BANK_OFFSETS, MAGIC);
+        let saved = payload.map_or(0, |p| u32::from_le_bytes([p[0], p[1], p[2], p[3]]));
+        if config.enabled {
+            log::info!("Counting S0 pulses from {}", saved);
+        }
+        PULSES.store(saved, Ordering::Relaxed);
+        Self {
+            config,
+            store,
+            saved,
+            next_save: 0,
+        }
+    }
+
+    pub fn reading(&self) -> Option<PulseReading> {
+        if !self.config.enabled {
+            return None;
+        }
+        Some(PulseReading {
+            name: self.config.name,
+            pulses: PULSES.load(Ordering::Relaxed),
+            pulses_per_unit: self.config.pulses_per_unit,
+        })
+    }
+
+    /// Saves the count if it changed, at most once per `SAVE_INTERVAL_MS`.
+    pub fn poll(&mut self, now: i64) {
+        let pulses = PULSES.load(Ordering::Relaxed);
+        if pulses == self.saved || now < self.next_save {
+            return;
+        }
+        let mut payload = [0xFF; PAYLOAD_SZ];
+        payload[..4].copy_from_slice(&pulses.to_le_bytes());
+        if let Err(err) = self.store.save(&payload) {
+            log::warn!("Failed to save S0 pulse count: {:?}", err);
+        }
+        self.saved = pulses;
+        self.next_save = now + SAVE_INTERVAL_MS;
+    }
+}
@@ -0,0 +1,255 @@
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use arrayvec::ArrayString;
+use dsmr42::{Line, SerializeOptions, Telegram};
+use embedded_sdmmc::{
+    Block, BlockCount, BlockDevice, BlockIdx, Directory, Error, Mode, TimeSource, Timestamp,
+    Volume, VolumeIdx, VolumeManager,
+};
+use enc28j60_smoltcp::warn_throttled;
+
+use crate::{
+    clock, counters,
+    logging::Debug2Format,
+    metrics::metrics,
+    telemetry::{TelemetryRecord, TelemetrySink},
+    wall_clock::LocalTime,
+};
+
+// Room for a telegram with every field, as JSON.
+const MAX_LINE_SZ: usize = 1024;
+
+/// How telegrams are written to the SD card.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SdLogFormat {
+    Off,
+    /// A row with the timestamp and the numeric values of each telegram,
+    /// after a header row with their names.
+    Csv,
+    /// Each telegram as a JSON object with every field, one per line.
+    Ndjson,
+}
+
+impl SdLogFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            SdLogFormat::Off => "off",
+            SdLogFormat::Csv => "csv",
+            SdLogFormat::Ndjson => "ndjson",
+        }
+    }
+
+    // Only 8.3 file names are supported.
+    fn extension(self) -> &'static str {
+        match self {
+            SdLogFormat::Csv => "CSV",
+            _ => "JSN",
+        }
+    }
+}
+
+/// A block device for boards without an SD card slot, which can't be
+/// constructed.
+pub enum Unavailable {}
+
+impl BlockDevice for Unavailable {
+    type Error = core::convert::Infallible;
+
+    fn read(
+        &self,
+        _blocks: &mut [Block],
+        _start: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        match *self {}
+    }
+
+    fn write(&self, _blocks: &[Block], _start: BlockIdx) -> Result<(), Self::Error> {
+        match *self {}
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        match *self {}
+    }
+}
+
+// Unix time of the telegram being written.
+static FILE_TIME: AtomicU32 = AtomicU32::new(0);
+
+/// Dates files by the telegram being written to them, which works without
+/// a network to set the clock from.
+struct TelegramTime;
+
+impl TimeSource for TelegramTime {
+    fn get_timestamp(&self) -> Timestamp {
+        let time = LocalTime::from_unix(FILE_TIME.load(Ordering::Relaxed) as i64);
+        Timestamp {
+            year_since_1970: (time.year - 1970).clamp(0, 255) as u8,
+            zero_indexed_month: time.month - 1,
+            zero_indexed_day: time.day - 1,
+            hours: time.hour,
+            minutes: time.minute,
+            seconds: time.second,
+        }
+    }
+}
+
+struct Card<D: BlockDevice> {
+    volumes: VolumeManager<D, TelegramTime>,
+    volume: Volume,
+}
+
+/// Appends every telegram to a file on the SD card, named after the meter's
+/// date, like `20261017.CSV`, so a new file is started each day. Does nothing
+/// without a card.
+pub struct SdLog<D: BlockDevice> {
+    format: SdLogFormat,
+    card: Option<Card<D>>,
+    // The day of the file being written to, in days since the epoch.
+    day: Option<i64>,
+}
+
+impl<D: BlockDevice> SdLog<D> {
+    pub fn new(device: Option<D>, format: SdLogFormat) -> Self {
+        let card = match device {
+            Some(device) if format != SdLogFormat::Off => mount(device),
+            _ => None,
+        };
+        Self {
+            format,
+            card,
+            day: None,
+        }
+    }
+
+    fn write(&mut self, telegram: &Telegram) -> Result<(), Error<D::Error>> {
+        let card = match &mut self.card {
+            Some(card) => card,
+            None => return Ok(()),
+        };
+        let unix_time = match counters::telegram_unix_time(telegram) {
+            Some(unix_time) => unix_time,
+            None => {
+                warn_throttled!(
+                    clock::millis(),
+                    "Telegram has no timestamp, not writing it to the SD card"
+                );
+                return Ok(());
+            }
+        };
+        FILE_TIME.store(unix_time as u32, Ordering::Relaxed);
+        let date = LocalTime::from_unix(unix_time);
+        let mut name = ArrayString::<12>::new();
+        let _ = write!(
+            name,
+            "{:04}{:02}{:02}.{}",
+            date.year,
+            date.month,
+            date.day,
+            self.format.extension()
+        );
+        if self.day != Some(date.days_since_epoch()) {
+            log::info!("Writing telegrams to {} on the SD card", name);
+            self.day = Some(date.days_since_epoch());
+        }
+        let dir = card.volumes.open_root_dir(&card.volume)?;
+        let result = card.append(&dir, &name, self.format, telegram);
+        card.volumes.close_dir(&card.volume, dir);
+        result
+    }
+}
+
+impl<D: BlockDevice> Card<D> {
+    fn append(
+        &mut self,
+        dir: &Directory,
+        name: &str,
+        format: SdLogFormat,
+        telegram: &Telegram,
+    ) -> Result<(), Error<D::Error>> {
+        let mut file = self.volumes.open_file_in_dir(
+            &mut self.volume,
+            dir,
+            name,
+            Mode::ReadWriteCreateOrAppend,
+        )?;
+        let mut line = ArrayString::<MAX_LINE_SZ>::new();
+        let formatted = match format {
+            SdLogFormat::Csv if file.length() == 0 => write_csv_header(&mut line, telegram)
+                .and_then(|_| write_csv_row(&mut line, telegram)),
+            SdLogFormat::Csv => write_csv_row(&mut line, telegram),
+            SdLogFormat::Ndjson => {
+                telegram.serialize(&mut line, &SerializeOptions::ALL);
+                writeln!(line)
+            }
+            SdLogFormat::Off => Ok(()),
+        };
+        let result = match formatted {
+            Ok(()) => self
+                .volumes
+                .write(&mut self.volume, &mut file, line.as_bytes())
+                .map(|_| ()),
+            Err(_) => {
+                log::warn!("Telegram is too long to write to the SD card");
+                Ok(())
+            }
+        };
+        // Closing updates the directory entry, so a power cut costs at most
+        // the telegram being written.
+        self.volumes.close_file(&self.volume, file)?;
+        result
+    }
+}
+
+impl<D: BlockDevice> TelemetrySink for SdLog<D> {
+    /// Appends the telegram to the file of its day.
+    fn accept(&mut self, record: &TelemetryRecord) {
+        if let Err(err) = self.write(record.telegram) {
+            warn_throttled!(
+                clock::millis(),
+                "Failed to write telegram to the SD card: {:?}",
+                Debug2Format(&err)
+            );
+        }
+    }
+}
+
+fn mount<D: BlockDevice>(device: D) -> Option<Card<D>> {
+    let mut volumes = VolumeManager::new(device, TelegramTime);
+    match volumes.get_volume(VolumeIdx(0)) {
+        Ok(volume) => Some(Card { volumes, volume }),
+        Err(err) => {
+            log::warn!(
+                "No FAT volume on the SD card ({:?}), not logging telegrams to it",
+                err
+            );
+            None
+        }
+    }
+}
+
+/// The names of the columns: the timestamp, and the numeric values in
+/// `telegram`. The first telegram of the day sets them for the whole file.
+fn write_csv_header<W: Write>(writer: &mut W, telegram: &Telegram) -> fmt::Result {
+    write!(writer, "timestamp")?;
+    for (name, _) in metrics(telegram) {
+        write!(writer, ",{}", name)?;
+    }
+    writeln!(writer)
+}
+
+fn write_csv_row<W: Write>(writer: &mut W, telegram: &Telegram) -> fmt::Result {
+    if let Some(timestamp) = telegram.lines.iter().find_map(|line| match line {
+        Line::Timestamp(ts) => Some(ts),
+        _ => None,
+    }) {
+        write!(writer, "{}", timestamp)?;
+    }
+    for (_, value) in metrics(telegram) {
+        write!(writer, ",{}", value)?;
+    }
+    writeln!(writer)
+}
@@ -0,0 +1,145 @@
+use core::fmt::{self, Display, Write};
+
+use crate::events::{Event, EventConsumer};
+
+// How long to wait for a telegram and the broker before giving up. Long
+// enough for a DSMR 4 meter to send a couple of telegrams.
+const WINDOW_MS: i64 = 30_000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    Pass,
+    Fail,
+}
+
+impl Outcome {
+    fn from_bool(pass: bool) -> Self {
+        if pass {
+            Outcome::Pass
+        } else {
+            Outcome::Fail
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// The outcome of each check of a self-test.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SelfTestReport {
+    /// The network controller answered a register read.
+    pub driver: Outcome,
+    /// A telegram was received from the meter.
+    pub uart: Outcome,
+    /// The clock moved forward.
+    pub clock: Outcome,
+    /// We were connected to the MQTT broker.
+    pub broker: Outcome,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        [self.driver, self.uart, self.clock, self.broker]
+            .iter()
+            .all(|o| *o == Outcome::Pass)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> fmt::Result {
+        // Poor man's JSON, same as the telegrams
+        write!(
+            writer,
+            "{{\"driver\": \"{}\", \"uart\": \"{}\", \"clock\": \"{}\", \"broker\": \"{}\", \
+            \"result\": \"{}\"}}",
+            self.driver,
+            self.uart,
+            self.clock,
+            self.broker,
+            Outcome::from_bool(self.passed()),
+        )
+    }
+}
+
+impl Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "driver {}, uart {}, clock {}, broker {}",
+            self.driver, self.uart, self.clock, self.broker
+        )
+    }
+}
+
+/// Checks that the hardware and the connection to the broker work. Runs at
+/// boot, and whenever a `SelfTestRequested` event comes in.
+///
+/// The test waits until every check has passed, or until it times out, so a
+/// telegram has a chance to arrive and the broker to accept the connection.
+pub struct SelfTest {
+    started_at: Option<i64>,
+    driver: bool,
+    telegram: bool,
+}
+
+impl SelfTest {
+    pub fn new() -> Self {
+        Self {
+            started_at: None,
+            driver: false,
+            telegram: false,
+        }
+    }
+
+    pub fn start(&mut self, now: i64) {
+        log::info!("Running self-test");
+        self.started_at = Some(now);
+        self.driver = false;
+        self.telegram = false;
+    }
+
+    /// Runs the checks that haven't passed yet. Returns the report once they
+    /// all pass, or once the test times out.
+    pub fn poll(
+        &mut self,
+        now: i64,
+        check_driver: impl FnOnce() -> bool,
+        broker_connected: bool,
+    ) -> Option<SelfTestReport> {
+        let started_at = self.started_at?;
+        if !self.driver {
+            self.driver = check_driver();
+        }
+        let report = SelfTestReport {
+            driver: Outcome::from_bool(self.driver),
+            uart: Outcome::from_bool(self.telegram),
+            clock: Outcome::from_bool(now > started_at),
+            broker: Outcome::from_bool(broker_connected),
+        };
+        if !report.passed() && now < started_at + WINDOW_MS {
+            return None;
+        }
+        self.started_at = None;
+        if report.passed() {
+            log::info!("Self-test passed: {}", report);
+        } else {
+            log::warn!("Self-test failed: {}", report);
+        }
+        Some(report)
+    }
+}
+
+impl EventConsumer for SelfTest {
+    fn on_event(&mut self, event: Event, now: i64) {
+        match event {
+            Event::TelegramParsed => self.telegram = true,
+            Event::SelfTestRequested => self.start(now),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,422 @@
+//! Runs the firmware on the host, for testing changes to the pipeline, the
+//! network glue and the clients without a Teensy or a meter.
+//!
+//! The hardware is replaced as follows:
+//! - the P1 port replays a capture (`sim/uart.rs`),
+//! - the ENC28J60 is replaced by a TAP device (`sim/driver.rs`),
+//! - the clock and flash are swapped out for `sim/clock.rs` and
+//!   `sim/flash.rs`,
+//! - the console reads from stdin, and logging goes to stderr.
+//!
+//! Usage: `meter-reader <capture> [tap device] [key=value...]`, where the
+//! `key=value` arguments override settings, like `set` on the console.
+
+pub mod console;
+pub mod driver;
+#[cfg(test)]
+mod tests;
+pub mod uart;
+
+use std::{env, path::Path, process, thread, time::Duration};
+
+use crate::{
+    boot_count,
+    clock::Clock,
+    config::{defaults, Config, ConfigStore},
+    console::Console,
+    costs::CostTracker,
+    diagnostics::{Diagnostics, EventStats},
+    events::EventQueue,
+    graphite::GraphiteClient,
+    influx::InfluxClient,
+    led::{Colour, Indicator, StatusLed},
+    log_stream::LogStreamClient,
+    logging,
+    memstats::MemStats,
+    mqtt::MqttClient,
+    multicast::MulticastClient,
+    network::{
+        self,
+        client::{TcpClient, TcpClientStore, UdpClientStore},
+        driver::Driver,
+        filter::FrameFilter,
+        stack::NetworkStack,
+    },
+    ota::OtaReceiver,
+    peak::PeakTracker,
+    provisioning::Provisioner,
+    random::Random,
+    s0::PulseCounter,
+    selftest::SelfTest,
+    sntp::SntpClient,
+    statsd::StatsdClient,
+    system_info::SystemInfo,
+    tasks::{self, Clients, Trackers},
+    telegram_server::TelegramServer,
+    telemetry::Pipeline,
+    totals::DailyTotals,
+    uart::{DataRequest, DsmrUart, NoDataRequest},
+    wall_clock::WallClock,
+};
+
+const DEFAULT_TAP: &str = "tap0";
+// DSMR 5 meters send a telegram every second.
+const TELEGRAM_INTERVAL_MS: i64 = 1000;
+
+/// Logs LED changes, since there is no LED to show them on.
+struct LogIndicator;
+
+impl Indicator for LogIndicator {
+    fn set(&mut self, colour: Colour) {
+        log::trace!("LED: {:?}", colour);
+    }
+}
+
+pub fn run() {
+    logging::init().unwrap();
+    log::set_max_level(crate::LOG_LEVEL);
+
+    let mut args = env::args().skip(1);
+    let capture = args.next().unwrap_or_else(|| {
+        eprintln!("Usage: meter-reader <capture> [tap device] [key=value...]");
+        process::exit(2);
+    });
+    let mut tap = DEFAULT_TAP.to_owned();
+    let mut config = Config::default();
+    for arg in args {
+        match arg.split_once('=') {
+            Some((key, value)) => {
+                if let Err(err) = config.set(key, value) {
+                    eprintln!("Invalid setting {}: {:?}", arg, err);
+                    process::exit(2);
+                }
+            }
+            None => tap = arg,
+        }
+    }
+
+    let port =
+        uart::ReplayPort::open(Path::new(&capture), TELEGRAM_INTERVAL_MS).unwrap_or_else(|err| {
+            eprintln!("Failed to open {}: {}", capture, err);
+            process::exit(1);
+        });
+    let driver = driver::TapDriver::open(&tap).unwrap_or_else(|err| {
+        eprintln!("Failed to open TAP device {}: {}", tap, err);
+        process::exit(1);
+    });
+    let mut sim = Sim::new(config, port, driver, Some(console::Reader::stdin()));
+
+    log::info!("Entering main loop");
+    loop {
+        sim.poll();
+        thread::sleep(Duration::from_millis(crate::MAX_POLL_INTERVAL_MS as u64));
+    }
+}
+
+/// Everything the RTIC tasks in `main.rs` keep as resources, driven by a
+/// single loop instead.
+pub struct Sim<D: Driver> {
+    clock: Clock,
+    config: Config,
+    system_info: SystemInfo,
+    console: Console,
+    pipeline: Pipeline<NoDataRequest>,
+    random: Random,
+    network: NetworkStack<'static, D>,
+    led: StatusLed<LogIndicator>,
+    events: EventQueue,
+    event_stats: EventStats,
+    client: MqttClient,
+    telegram_server: TelegramServer,
+    influx: InfluxClient,
+    graphite: GraphiteClient,
+    statsd: StatsdClient,
+    multicast: MulticastClient,
+    sntp: SntpClient,
+    provisioner: Provisioner,
+    ota: OtaReceiver,
+    log_stream: LogStreamClient,
+    wall_clock: WallClock,
+    costs: CostTracker,
+    totals: DailyTotals,
+    peak: PeakTracker,
+    pulses: PulseCounter,
+    selftest: SelfTest,
+    next_diagnostics: i64,
+    memory: MemStats,
+}
+
+impl<D: Driver> Sim<D> {
+    pub fn new(
+        config: Config,
+        port: uart::ReplayPort,
+        driver: D,
+        console_reader: Option<console::Reader>,
+    ) -> Self {
+        let mut clock = Clock::init();
+        let system_info = SystemInfo::read();
+        // Flash starts out empty, so this only sets up the store.
+        let (config_store, _) = ConfigStore::load();
+        let console = Console::new(console_reader, config, config_store);
+
+        let data_request = DataRequest::new(NoDataRequest, config.data_request);
+        let mut dsmr_uart = DsmrUart::new(port, config.uart, data_request);
+        dsmr_uart.set_retransmit(config.uart_retransmit);
+        dsmr_uart.set_watchdog(config.uart_watchdog_mins);
+        if config.uart_autodetect {
+            dsmr_uart.start_probe(clock.millis());
+        }
+        let pipeline = Pipeline::new(
+            dsmr_uart,
+            config.min_telegram_interval_ms,
+            config.aggregate,
+            config.alerts,
+        );
+
+        let random = Random::new(clock.ticks());
+        let store = Box::leak(Box::new(network::BackingStore::new()));
+        let mut network = NetworkStack::new(
+            driver,
+            &mut clock,
+            store,
+            defaults::ETH_ADDR,
+            FrameFilter::default(),
+        );
+        if let Some(cidr) = config.network.static_address {
+            network.set_static_address(cidr, config.network.gateway);
+        }
+
+        let mut events = EventQueue::new();
+        let mut client = MqttClient::new(config.mqtt, boot_count::increment());
+        network.add_client(
+            &mut client,
+            Box::leak(Box::new(TcpClientStore::<
+                { crate::MQTT_RX_BUF_SZ },
+                { crate::MQTT_TX_BUF_SZ },
+            >::new())),
+        );
+        events.watch_mqtt(client.get_socket_handle());
+        let mut telegram_server = TelegramServer::new();
+        network.add_client(
+            &mut telegram_server,
+            Box::leak(Box::new(TcpClientStore::<
+                { crate::TELEGRAM_SERVER_RX_BUF_SZ },
+                { crate::TELEGRAM_SERVER_TX_BUF_SZ },
+            >::new())),
+        );
+        let mut influx = InfluxClient::new(config.influx);
+        network.add_client(
+            &mut influx,
+            Box::leak(Box::new(TcpClientStore::<
+                { crate::INFLUX_RX_BUF_SZ },
+                { crate::INFLUX_TX_BUF_SZ },
+            >::new())),
+        );
+        let mut graphite = GraphiteClient::new(config.graphite);
+        network.add_client(
+            &mut graphite,
+            Box::leak(Box::new(TcpClientStore::<
+                { crate::GRAPHITE_RX_BUF_SZ },
+                { crate::GRAPHITE_TX_BUF_SZ },
+            >::new())),
+        );
+        let mut statsd = StatsdClient::new(config.statsd);
+        network.add_udp_client(
+            &mut statsd,
+            Box::leak(Box::new(UdpClientStore::<
+                { crate::STATSD_RX_BUF_SZ },
+                { crate::STATSD_TX_BUF_SZ },
+            >::new())),
+        );
+        let mut multicast = MulticastClient::new(config.multicast);
+        network.add_udp_client(
+            &mut multicast,
+            Box::leak(Box::new(UdpClientStore::<
+                { crate::MULTICAST_RX_BUF_SZ },
+                { crate::MULTICAST_TX_BUF_SZ },
+            >::new())),
+        );
+        let mut sntp = SntpClient::new(config.sntp);
+        network.add_udp_client(
+            &mut sntp,
+            Box::leak(Box::new(UdpClientStore::<
+                { crate::SNTP_RX_BUF_SZ },
+                { crate::SNTP_TX_BUF_SZ },
+            >::new())),
+        );
+        let mut provisioner = Provisioner::new(config.provisioning, config);
+        network.add_udp_client(
+            &mut provisioner,
+            Box::leak(Box::new(UdpClientStore::<
+                { crate::PROVISIONING_RX_BUF_SZ },
+                { crate::PROVISIONING_TX_BUF_SZ },
+            >::new())),
+        );
+        let mut ota = OtaReceiver::new(defaults::OTA_PUBLIC_KEY);
+        network.add_client(
+            &mut ota,
+            Box::leak(Box::new(TcpClientStore::<
+                { crate::OTA_RX_BUF_SZ },
+                { crate::OTA_TX_BUF_SZ },
+            >::new())),
+        );
+        let mut log_stream = LogStreamClient::new();
+        network.add_client(
+            &mut log_stream,
+            Box::leak(Box::new(TcpClientStore::<
+                { crate::LOG_STREAM_RX_BUF_SZ },
+                { crate::LOG_STREAM_TX_BUF_SZ },
+            >::new())),
+        );
+
+        let mut selftest = SelfTest::new();
+        selftest.start(clock.millis());
+        let next_diagnostics = clock.millis() + config.diagnostics_interval_ms;
+        Self {
+            clock,
+            config,
+            system_info,
+            console,
+            pipeline,
+            random,
+            network,
+            led: StatusLed::new(LogIndicator),
+            events,
+            event_stats: EventStats::default(),
+            client,
+            telegram_server,
+            influx,
+            graphite,
+            statsd,
+            multicast,
+            sntp,
+            provisioner,
+            ota,
+            log_stream,
+            wall_clock: WallClock::new(),
+            costs: CostTracker::new(config.costs),
+            totals: DailyTotals::load(),
+            peak: PeakTracker::new(),
+            pulses: PulseCounter::load(config.s0),
+            selftest,
+            next_diagnostics,
+            memory: MemStats::default(),
+        }
+    }
+
+    /// Does the work of each of the RTIC tasks in `main.rs` once.
+    pub fn poll(&mut self) {
+        if let Some(telegram) = self.pipeline.poll(self.clock.millis(), &mut self.events) {
+            tasks::handle_telegram(
+                &telegram,
+                self.pipeline.received_at(),
+                &self.pipeline,
+                self.pulses.reading(),
+                Trackers {
+                    wall_clock: &mut self.wall_clock,
+                    costs: &mut self.costs,
+                    totals: &mut self.totals,
+                    peak: &mut self.peak,
+                },
+                &mut [
+                    &mut self.client,
+                    &mut self.telegram_server,
+                    &mut self.influx,
+                    &mut self.graphite,
+                    &mut self.statsd,
+                    &mut self.multicast,
+                ],
+            );
+        }
+
+        self.network.poll(&mut self.clock, &mut self.events);
+        let mut clients = Clients {
+            mqtt: &mut self.client,
+            telegram_server: &mut self.telegram_server,
+            influx: &mut self.influx,
+            graphite: &mut self.graphite,
+            statsd: &mut self.statsd,
+            multicast: &mut self.multicast,
+            sntp: &mut self.sntp,
+            provisioner: &mut self.provisioner,
+            ota: &mut self.ota,
+            log_stream: &mut self.log_stream,
+        };
+        if let Some(config) = tasks::poll_clients(
+            &mut self.network,
+            &mut self.clock,
+            &mut self.random,
+            &mut clients,
+            &mut self.wall_clock,
+            &mut self.selftest,
+        ) {
+            if let Some(event) = self.console.provision(config) {
+                self.events.push(event);
+            }
+        }
+
+        while let Some(alert) = self.pipeline.take_alert() {
+            self.client.queue_alert(alert);
+        }
+        let now = self.clock.millis();
+        self.events.dispatch(
+            now,
+            &mut [
+                &mut self.led,
+                &mut self.event_stats,
+                &mut self.client,
+                &mut self.selftest,
+            ],
+        );
+        self.led.update(now);
+        let network = &mut self.network;
+        if let Some(report) = self.selftest.poll(
+            now,
+            || network.driver_responds(),
+            self.client.is_connected(),
+        ) {
+            self.client.queue_selftest(report);
+        }
+
+        let publish_diagnostics = now >= self.next_diagnostics;
+        if publish_diagnostics {
+            self.memory = MemStats::measure(
+                self.pipeline.uart().buffer_peak(),
+                self.client.queue_depth(),
+            );
+        }
+        let diagnostics = Diagnostics {
+            uart: self.pipeline.uart().stats(),
+            publish_latency: self.client.publish_latency(),
+            time: self.wall_clock.local_time(now),
+            clock_drift_ppm: self.wall_clock.drift_ppm(),
+            boot_reason: self.system_info.boot_reason,
+            ethernet_chip: self.system_info.ethernet_chip,
+            uptime_secs: self.system_info.uptime_secs(now),
+            memory: self.memory,
+            meter_interval_secs: self.pipeline.meter_interval_secs(),
+            network: self.network.status(),
+            cidr: self.network.cidr(),
+            gateway: self.network.gateway(),
+            neighbours: *self.network.neighbours(),
+            events: self.event_stats,
+            parse_errors: *self.pipeline.parse_errors(),
+            temperatures: Default::default(),
+        };
+        self.totals.tick(diagnostics.time);
+        self.pulses.poll(now);
+        if publish_diagnostics {
+            self.next_diagnostics += self.config.diagnostics_interval_ms;
+            tasks::queue_reports(
+                &mut self.client,
+                diagnostics,
+                &self.costs,
+                &self.totals,
+                &self.peak,
+            );
+        }
+        if let Some(event) = self.console.poll(&diagnostics) {
+            self.events.push(event);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Stands in for `clock.rs` when running in the simulator, see `sim.rs`.
+
+use smoltcp::time::Instant;
+use std::{sync::OnceLock, time::Instant as StdInstant};
+
+static START: OnceLock<StdInstant> = OnceLock::new();
+
+pub struct Clock;
+
+impl Clock {
+    pub fn init() -> Self {
+        START.get_or_init(StdInstant::now);
+        Self
+    }
+
+    pub fn ticks(&self) -> u32 {
+        start().elapsed().as_nanos() as u32
+    }
+
+    pub fn millis(&self) -> i64 {
+        millis()
+    }
+
+    pub fn instant(&self) -> Instant {
+        Instant::from_millis(self.millis())
+    }
+}
+
+impl enc28j60_smoltcp::Clock for Clock {
+    fn millis(&self) -> i64 {
+        millis()
+    }
+}
+
+/// Milliseconds since the clock was initialised.
+pub fn millis() -> i64 {
+    start().elapsed().as_millis() as i64
+}
+
+/// Microseconds since the clock was initialised.
+pub fn micros() -> i64 {
+    start().elapsed().as_micros() as i64
+}
+
+/// The host's clock is kept accurate already.
+pub fn set_drift_ppm(_drift_ppm: i32) {}
+
+fn start() -> StdInstant {
+    *START.get_or_init(StdInstant::now)
+}
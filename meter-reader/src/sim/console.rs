@@ -0,0 +1,41 @@
+use std::{
+    io::{self, Read},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+/// Reads console input from stdin, in place of the USB serial port.
+pub struct Reader {
+    rx: Receiver<u8>,
+}
+
+impl Reader {
+    pub fn stdin() -> Self {
+        let (tx, rx) = mpsc::channel();
+        // Reading stdin blocks, so leave that to a separate thread.
+        thread::spawn(move || {
+            for b in io::stdin().lock().bytes() {
+                match b {
+                    Ok(b) if tx.send(b).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        Self { rx }
+    }
+
+    /// Reads whatever input is available, without blocking.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.rx.try_recv() {
+                Ok(b) => {
+                    buf[read] = b;
+                    read += 1;
+                }
+                Err(_) => break,
+            }
+        }
+        read
+    }
+}
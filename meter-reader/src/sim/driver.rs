@@ -0,0 +1,71 @@
+use std::io;
+
+use tun_tap::{Iface, Mode};
+
+use crate::network::{
+    driver::{Driver, MAX_FRAME_LENGTH},
+    filter::FrameFilter,
+};
+
+/// Connects the network stack to a TAP device on the host.
+///
+/// The device must exist, be up, and be accessible to the current user, e.g.
+/// after `ip tuntap add dev tap0 mode tap user $USER && ip link set tap0 up`.
+pub struct TapDriver {
+    iface: Iface,
+    // The kernel queues frames for us, so we stage at most a single frame.
+    frame: Vec<u8>,
+    frame_len: Option<usize>,
+}
+
+impl TapDriver {
+    pub fn open(name: &str) -> io::Result<Self> {
+        let iface = Iface::without_packet_info(name, Mode::Tap)?;
+        iface.set_non_blocking()?;
+        log::info!("Opened TAP device {}", iface.name());
+        Ok(Self {
+            iface,
+            frame: vec![0; MAX_FRAME_LENGTH],
+            frame_len: None,
+        })
+    }
+}
+
+impl Driver for TapDriver {
+    type Error = io::Error;
+    type TransmitError = io::Error;
+
+    fn pending_packets(&mut self) -> Result<u8, io::Error> {
+        if self.frame_len.is_none() {
+            match self.iface.recv(&mut self.frame) {
+                Ok(len) => self.frame_len = Some(len),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self.frame_len.is_some() as u8)
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<u16, io::Error> {
+        let len = match self.frame_len.take() {
+            Some(len) => len.min(buffer.len()),
+            None => return Ok(0),
+        };
+        buffer[..len].copy_from_slice(&self.frame[..len]);
+        Ok(len as u16)
+    }
+
+    fn transmit(&mut self, buffer: &[u8]) -> Result<(), io::Error> {
+        self.iface.send(buffer).map(|_| ())
+    }
+
+    fn set_frame_filter(&mut self, _filter: &FrameFilter) -> Result<(), io::Error> {
+        // The kernel hands us everything on the device, and smoltcp drops
+        // frames that aren't meant for us.
+        Ok(())
+    }
+
+    fn is_link_up(&mut self) -> Result<bool, io::Error> {
+        Ok(true)
+    }
+}
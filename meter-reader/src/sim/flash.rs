@@ -0,0 +1,86 @@
+//! Stands in for `flash.rs` when running in the simulator, see `sim.rs`.
+//! Flash is kept in memory, so the configuration is lost on exit.
+
+use std::sync::Mutex;
+
+use crate::system_info;
+
+pub const SECTOR_SZ: u32 = 4096;
+pub const PAGE_SZ: usize = 256;
+pub const FLASH_SZ: u32 = 0x20_0000;
+pub const FIRMWARE_MAX_SZ: u32 = 0x1F_0000;
+
+static FLASH: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FlashError {
+    Erase(i32),
+    Program(i32),
+    /// The written data could not be read back.
+    Verify,
+}
+
+/// Reads from flash, starting `offset` bytes from its start.
+pub fn read(offset: u32, buf: &mut [u8]) {
+    with_flash(|flash| {
+        let offset = offset as usize;
+        buf.copy_from_slice(&flash[offset..offset + buf.len()]);
+    })
+}
+
+/// Erases the sectors covering `len` bytes from `offset`, which must be
+/// sector-aligned.
+pub fn erase(offset: u32, len: u32) -> Result<(), FlashError> {
+    if offset % SECTOR_SZ != 0 || offset + len > FLASH_SZ {
+        return Err(FlashError::Erase(-1));
+    }
+    let len = (len + SECTOR_SZ - 1) / SECTOR_SZ * SECTOR_SZ;
+    with_flash(|flash| {
+        let offset = offset as usize;
+        flash[offset..offset + len as usize].fill(0xFF);
+    });
+    Ok(())
+}
+
+/// Programs a single page at `offset`, which must be page-aligned and erased.
+pub fn program_page(offset: u32, page: &[u8; PAGE_SZ]) -> Result<(), FlashError> {
+    if offset as usize % PAGE_SZ != 0 || offset as usize + PAGE_SZ > FLASH_SZ as usize {
+        return Err(FlashError::Program(-1));
+    }
+    with_flash(|flash| {
+        let target = &mut flash[offset as usize..][..PAGE_SZ];
+        // Like real NOR flash, programming can only clear bits, which catches
+        // writes to pages that weren't erased.
+        for (b, new) in target.iter_mut().zip(page.iter()) {
+            *b &= new;
+        }
+        if target != &page[..] {
+            return Err(FlashError::Verify);
+        }
+        Ok(())
+    })
+}
+
+/// The simulator can always pretend to.
+pub fn can_replace_firmware() -> bool {
+    true
+}
+
+/// There is no firmware to replace, so this only reports the image, and
+/// exits.
+pub fn replace_firmware(src: u32, len: u32) -> ! {
+    log::info!(
+        "Would install {} bytes of firmware from offset {:#x}",
+        len,
+        src
+    );
+    system_info::reset()
+}
+
+fn with_flash<T>(f: impl FnOnce(&mut [u8]) -> T) -> T {
+    let mut flash = FLASH.lock().unwrap();
+    if flash.is_empty() {
+        flash.resize(FLASH_SZ as usize, 0xFF);
+    }
+    f(&mut flash)
+}
@@ -0,0 +1,262 @@
+//! Replays the telegrams in the `dsmr42` corpus through the simulator, with
+//! the mock driver standing in for the network and the broker on it, and
+//! checks what is published.
+
+use std::{
+    fs,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use enc28j60_smoltcp::mock::{Frame, MockDriver};
+use smoltcp::wire::{IpAddress, Ipv4Address, Ipv4Packet, TcpPacket};
+
+use super::{uart::ReplayPort, Sim};
+use crate::config::Config;
+
+// Meters of which the corpus telegram parses without registers of its own.
+const CORPUS: [&str; 3] = ["dsmr40_kaifa", "dsmr42_landis_gyr", "dsmr50_sagemcom"];
+const USAGE_TOPIC: &str = "smart_meter/usage";
+// Real time, since the replay runs on the simulator's clock.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const BROKER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const BROKER: [u8; 4] = [10, 0, 0, 1];
+const BROKER_PORT: u16 = 1883;
+// The broker's initial sequence number.
+const BROKER_ISN: u32 = 1000;
+// Accepts the connection.
+const CONNACK: [u8; 4] = [0x20, 2, 0, 0];
+
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+// Ethernet, and IPv4 without options.
+const TCP: usize = 14 + 20;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+fn config() -> Config {
+    let mut config = Config::default();
+    let settings = [
+        ("network.address", "10.0.0.10/24"),
+        ("mqtt.host", "10.0.0.1"),
+        ("mqtt.port", "1883"),
+        ("mqtt.usage_topic", USAGE_TOPIC),
+        // The replay sends at the configured baud rate already.
+        ("uart.autodetect", "false"),
+    ];
+    for &(key, value) in settings.iter() {
+        config.set(key, value).unwrap();
+    }
+    config
+}
+
+fn push(frame: &mut Frame, bytes: &[u8]) {
+    frame.try_extend_from_slice(bytes).unwrap();
+}
+
+/// Answers ARP requests for the broker, and stands in for the broker itself.
+/// A responder can't keep state, so its replies only depend on the frame
+/// they answer.
+fn broker(sent: &[u8]) -> Option<Frame> {
+    match sent.get(12..14)? {
+        ethertype if ethertype == ETHERTYPE_ARP => arp_reply(sent),
+        ethertype if ethertype == ETHERTYPE_IPV4 => tcp_reply(sent),
+        _ => None,
+    }
+}
+
+fn arp_reply(sent: &[u8]) -> Option<Frame> {
+    let arp = sent.get(14..42)?;
+    if arp[6..8] != [0, 1] || arp[24..28] != BROKER {
+        return None;
+    }
+    let mut frame = Frame::new();
+    push(&mut frame, &sent[6..12]);
+    push(&mut frame, &BROKER_MAC);
+    push(&mut frame, &ETHERTYPE_ARP);
+    push(&mut frame, &[0, 1, 0x08, 0x00, 6, 4, 0, 2]);
+    push(&mut frame, &BROKER_MAC);
+    push(&mut frame, &BROKER);
+    push(&mut frame, &arp[8..18]);
+    Some(frame)
+}
+
+/// Accepts the connection, answers the connection request with a CONNACK,
+/// and acknowledges everything sent after it.
+fn tcp_reply(sent: &[u8]) -> Option<Frame> {
+    let segment = Segment::parse(sent)?;
+    if segment.flags & SYN != 0 {
+        let ack = segment.seq.wrapping_add(1);
+        return Some(tcp_from_broker(sent, BROKER_ISN, ack, SYN | ACK, &[]));
+    }
+    let first = *segment.payload.first()?;
+    let ack = segment.seq.wrapping_add(segment.payload.len() as u32);
+    // The connection request is sent first, on its own.
+    if first == 0x10 {
+        Some(tcp_from_broker(
+            sent,
+            BROKER_ISN + 1,
+            ack,
+            PSH | ACK,
+            &CONNACK,
+        ))
+    } else {
+        let seq = BROKER_ISN + 1 + CONNACK.len() as u32;
+        Some(tcp_from_broker(sent, seq, ack, ACK, &[]))
+    }
+}
+
+/// A TCP segment from the broker, in reply to `sent`, with its checksums
+/// filled in.
+fn tcp_from_broker(sent: &[u8], seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Frame {
+    let tcp_len = 20 + payload.len() as u16;
+    let mut frame = Frame::new();
+    push(&mut frame, &sent[6..12]);
+    push(&mut frame, &BROKER_MAC);
+    push(&mut frame, &ETHERTYPE_IPV4);
+    push(&mut frame, &[0x45, 0]);
+    push(&mut frame, &(20 + tcp_len).to_be_bytes());
+    push(&mut frame, &[0, 0, 0, 0, 64, 6, 0, 0]);
+    push(&mut frame, &BROKER);
+    push(&mut frame, &sent[26..30]);
+    push(&mut frame, &BROKER_PORT.to_be_bytes());
+    push(&mut frame, &sent[TCP..TCP + 2]);
+    push(&mut frame, &seq.to_be_bytes());
+    push(&mut frame, &ack.to_be_bytes());
+    // No options, and the largest window there is without scaling.
+    push(&mut frame, &[5 << 4, flags, 0xFF, 0xFF, 0, 0, 0, 0]);
+    push(&mut frame, payload);
+    Ipv4Packet::new_unchecked(&mut frame[14..]).fill_checksum();
+    TcpPacket::new_unchecked(&mut frame[TCP..]).fill_checksum(
+        &IpAddress::Ipv4(Ipv4Address(BROKER)),
+        &IpAddress::Ipv4(Ipv4Address::from_bytes(&sent[26..30])),
+    );
+    frame
+}
+
+/// A TCP segment sent to the broker.
+struct Segment<'a> {
+    seq: u32,
+    flags: u8,
+    payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    fn parse(frame: &'a [u8]) -> Option<Self> {
+        if frame.get(12..14)? != ETHERTYPE_IPV4 || frame[23] != 6 || frame[30..34] != BROKER {
+            return None;
+        }
+        let ip_len = u16::from_be_bytes([frame[16], frame[17]]) as usize;
+        let tcp = frame.get(TCP..14 + ip_len)?;
+        if tcp[2..4] != BROKER_PORT.to_be_bytes() {
+            return None;
+        }
+        let header_len = (tcp[12] >> 4) as usize * 4;
+        Some(Self {
+            seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+            flags: tcp[13],
+            payload: tcp.get(header_len..)?,
+        })
+    }
+}
+
+/// Puts back together what was sent to the broker.
+#[derive(Default)]
+struct Stream {
+    bytes: Vec<u8>,
+    next_seq: Option<u32>,
+}
+
+impl Stream {
+    fn receive(&mut self, frame: &[u8]) {
+        let segment = match Segment::parse(frame) {
+            Some(segment) => segment,
+            None => return,
+        };
+        if segment.flags & SYN != 0 {
+            self.bytes.clear();
+            self.next_seq = Some(segment.seq.wrapping_add(1));
+        } else if Some(segment.seq) == self.next_seq {
+            // Anything else is a retransmission.
+            self.bytes.extend_from_slice(segment.payload);
+            self.next_seq = Some(segment.seq.wrapping_add(segment.payload.len() as u32));
+        }
+    }
+
+    /// The topic and payload of each complete PUBLISH packet so far.
+    fn published(&self) -> Vec<(String, String)> {
+        let mut published = Vec::new();
+        let mut rest = &self.bytes[..];
+        while let Some((&header, tail)) = rest.split_first() {
+            let (len, tail) = match remaining_length(tail) {
+                Some((len, tail)) if tail.len() >= len => (len, tail),
+                _ => break,
+            };
+            let (packet, tail) = tail.split_at(len);
+            if header >> 4 == 3 {
+                // Published at QoS 0, so without a packet identifier.
+                let topic_len = u16::from_be_bytes([packet[0], packet[1]]) as usize;
+                let (topic, payload) = packet[2..].split_at(topic_len);
+                published.push((
+                    String::from_utf8_lossy(topic).into_owned(),
+                    String::from_utf8_lossy(payload).into_owned(),
+                ));
+            }
+            rest = tail;
+        }
+        published
+    }
+}
+
+/// Decodes the remaining length of an MQTT packet, and returns it along with
+/// the bytes that follow it.
+fn remaining_length(bytes: &[u8]) -> Option<(usize, &[u8])> {
+    let mut len = 0;
+    for (i, byte) in bytes.iter().enumerate().take(4) {
+        len |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((len, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+#[test]
+fn corpus_is_published_to_broker() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("../dsmr42/tests/corpus");
+    for name in CORPUS.iter() {
+        let json = fs::read_to_string(corpus.join(format!("{}.json", name))).unwrap();
+        let port = ReplayPort::open(&corpus.join(format!("{}.txt", name)), 1000).unwrap();
+        let mut driver = MockDriver::new();
+        driver.respond_with(broker);
+        let mut sim = Sim::new(config(), port, driver, None);
+
+        let mut stream = Stream::default();
+        let deadline = Instant::now() + TIMEOUT;
+        let payload = loop {
+            sim.poll();
+            while let Some(frame) = sim.network.driver_mut().take_transmitted() {
+                stream.receive(&frame);
+            }
+            let usage = stream
+                .published()
+                .into_iter()
+                .find(|(topic, _)| topic == USAGE_TOPIC);
+            if let Some((_, payload)) = usage {
+                break payload;
+            }
+            assert!(Instant::now() < deadline, "{}: nothing published", name);
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        // The telegram as the corpus has it, followed by what the reader
+        // adds to it.
+        let telegram = json.trim_end().strip_suffix('}').unwrap();
+        assert!(payload.starts_with(telegram), "{}: {}", name, payload);
+        assert!(payload.contains(",\"sequence\": "), "{}: {}", name, payload);
+        assert!(payload.ends_with('}'), "{}: {}", name, payload);
+    }
+}
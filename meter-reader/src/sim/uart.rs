@@ -0,0 +1,131 @@
+use std::{convert::Infallible, fs, io, path::Path};
+
+use crate::clock;
+
+// Start, eight data bits and a stop bit.
+const BITS_PER_BYTE: u32 = 10;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Parity {
+    Even,
+    Odd,
+}
+
+/// Mirrors the error flags of the LPUART. The replay never sets any.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ReadErrorFlags(u8);
+
+impl ReadErrorFlags {
+    pub const OVERRUN: Self = Self(1 << 0);
+    pub const NOISY: Self = Self(1 << 1);
+    pub const FRAME_ERROR: Self = Self(1 << 2);
+    pub const PARITY: Self = Self(1 << 3);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ReadError {
+    pub flags: ReadErrorFlags,
+}
+
+/// Plays back a capture of the P1 port as if a meter was connected.
+///
+/// The capture is split into telegrams, which are sent one every
+/// `interval_ms`, starting over once all of them have been sent. Bytes
+/// arrive no faster than the configured baud rate allows.
+pub struct ReplayPort {
+    telegrams: Vec<Vec<u8>>,
+    interval_ms: i64,
+    baud: u32,
+    // Number of telegrams sent so far, and position within the next one.
+    sent: usize,
+    pos: usize,
+}
+
+impl ReplayPort {
+    pub fn open(path: &Path, interval_ms: i64) -> io::Result<Self> {
+        let capture = fs::read(path)?;
+        let telegrams = split_telegrams(&capture);
+        if telegrams.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "capture contains no telegrams",
+            ));
+        }
+        log::info!("Replaying {} telegrams from {:?}", telegrams.len(), path);
+        Ok(Self {
+            telegrams,
+            interval_ms,
+            baud: 115_200,
+            sent: 0,
+            pos: 0,
+        })
+    }
+
+    pub fn set_baud(&mut self, baud: u32) -> Result<(), Infallible> {
+        self.baud = baud;
+        Ok(())
+    }
+
+    pub fn set_parity(&mut self, _parity: Option<Parity>) {}
+
+    pub fn set_rx_inversion(&mut self, _inverted: bool) {}
+
+    pub fn set_rx_fifo(&mut self, _enable: bool) {}
+
+    pub fn set_receiver_interrupt(&mut self, _watermark: Option<u8>) {}
+}
+
+impl embedded_hal::serial::Read<u8> for ReplayPort {
+    type Error = ReadError;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        let telegram = &self.telegrams[self.sent % self.telegrams.len()];
+        let start = self.sent as i64 * self.interval_ms;
+        let byte_ms = (self.pos as u32 * BITS_PER_BYTE * 1000 / self.baud) as i64;
+        if clock::millis() < start + byte_ms {
+            return Err(nb::Error::WouldBlock);
+        }
+        let b = telegram[self.pos];
+        self.pos += 1;
+        if self.pos == telegram.len() {
+            self.sent += 1;
+            self.pos = 0;
+        }
+        Ok(b)
+    }
+}
+
+/// Written telegrams go nowhere.
+impl embedded_hal::serial::Write<u8> for ReplayPort {
+    type Error = Infallible;
+
+    fn write(&mut self, _word: u8) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Splits a capture at every `/` that starts a line.
+fn split_telegrams(capture: &[u8]) -> Vec<Vec<u8>> {
+    let mut telegrams = Vec::new();
+    let mut start = None;
+    for (i, &b) in capture.iter().enumerate() {
+        if b == b'/' && (i == 0 || capture[i - 1] == b'\n') {
+            if let Some(start) = start {
+                telegrams.push(capture[start..i].to_vec());
+            }
+            start = Some(i);
+        }
+    }
+    if let Some(start) = start {
+        telegrams.push(capture[start..].to_vec());
+    }
+    telegrams
+}
@@ -0,0 +1,165 @@
+use smoltcp::{
+    socket::{SocketHandle, SocketRef, UdpSocket},
+    time::Instant,
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{
+    network::{
+        client::UdpClient,
+        dhcp::{NtpServers, MAX_NTP_SERVERS},
+        stack,
+    },
+    random::Random,
+};
+
+const NTP_PORT: u16 = 123;
+const PACKET_SZ: usize = 48;
+// Leap indicator 0, version 4, client mode.
+const REQUEST_HEADER: u8 = 0x23;
+const MODE_SERVER: u8 = 4;
+// Seconds from the NTP epoch (1900) to the Unix epoch.
+const NTP_UNIX_OFFSET: i64 = 2_208_988_800;
+const SYNC_INTERVAL_MS: i64 = 3600 * 1000;
+// Moves on to the next server if there's no reply in this time.
+const RETRY_INTERVAL_MS: i64 = 30_000;
+
+/// Which NTP server to ask for the time.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SntpConfig {
+    /// If unspecified, the servers advertised through DHCP are used instead.
+    pub server: Ipv4Address,
+}
+
+impl Default for SntpConfig {
+    fn default() -> Self {
+        Self {
+            server: Ipv4Address::UNSPECIFIED,
+        }
+    }
+}
+
+/// Asks an NTP server for the time once an hour. The result is picked up with
+/// `take_time()`, and takes precedence over the telegram timestamps.
+pub struct SntpClient {
+    config: SntpConfig,
+    dhcp_servers: NtpServers,
+    handle: Option<SocketHandle>,
+    // Index of the DHCP server to ask next.
+    server_index: usize,
+    next_request: i64,
+    // When the outstanding request was sent, and to which server.
+    pending: Option<(i64, Ipv4Address)>,
+    time: Option<(i64, i64)>,
+}
+
+impl UdpClient for SntpClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll(&mut self, mut socket: SocketRef<UdpSocket>, timestamp: Instant, random: &mut Random) {
+        let now = timestamp.total_millis();
+        if let Some((sent_at, server)) = self.pending {
+            self.receive(&mut socket, now, sent_at, server);
+        }
+        if now < self.next_request {
+            return;
+        }
+        if self.pending.take().is_some() {
+            log::warn!("No reply from NTP server, trying again");
+            self.server_index += 1;
+        }
+        let server = match self.server() {
+            Some(server) => server,
+            None => return,
+        };
+        if !socket.is_open() {
+            let local = stack::generate_local_port(random);
+            if let Err(err) = socket.bind(local) {
+                log::warn!("Failed to bind SNTP socket: {}", err);
+                return;
+            }
+        }
+        let mut request = [0; PACKET_SZ];
+        request[0] = REQUEST_HEADER;
+        let remote = IpEndpoint::new(IpAddress::Ipv4(server), NTP_PORT);
+        match socket.send_slice(&request, remote) {
+            Ok(()) => {
+                log::debug!("Requesting time from {}", server);
+                self.pending = Some((now, server));
+                self.next_request = now + RETRY_INTERVAL_MS;
+            }
+            Err(err) => log::warn!("Failed to send SNTP request: {}", err),
+        }
+    }
+}
+
+impl SntpClient {
+    pub fn new(config: SntpConfig) -> Self {
+        Self {
+            config,
+            dhcp_servers: NtpServers::new(),
+            handle: None,
+            server_index: 0,
+            next_request: 0,
+            pending: None,
+            time: None,
+        }
+    }
+
+    /// Updates the servers advertised through DHCP.
+    pub fn set_dhcp_servers(&mut self, servers: &[Ipv4Address]) {
+        if self.dhcp_servers.as_slice() != servers {
+            self.dhcp_servers = servers.iter().copied().take(MAX_NTP_SERVERS).collect();
+            self.server_index = 0;
+        }
+    }
+
+    /// The Unix time in milliseconds, and the `Clock` time at which it was
+    /// that time, from the last reply that hasn't been taken yet.
+    pub fn take_time(&mut self) -> Option<(i64, i64)> {
+        self.time.take()
+    }
+
+    fn server(&self) -> Option<Ipv4Address> {
+        if !self.config.server.is_unspecified() {
+            return Some(self.config.server);
+        }
+        if self.dhcp_servers.is_empty() {
+            return None;
+        }
+        Some(self.dhcp_servers[self.server_index % self.dhcp_servers.len()])
+    }
+
+    fn receive(
+        &mut self,
+        socket: &mut SocketRef<UdpSocket>,
+        now: i64,
+        sent_at: i64,
+        server: Ipv4Address,
+    ) {
+        let mut reply = [0; PACKET_SZ];
+        while let Ok((len, from)) = socket.recv_slice(&mut reply) {
+            if from.addr != IpAddress::Ipv4(server) || from.port != NTP_PORT {
+                continue;
+            }
+            // A stratum of 0 means the server wants us to go away.
+            if len < PACKET_SZ || reply[0] & 0x7 != MODE_SERVER || reply[1] == 0 {
+                log::warn!("Ignoring invalid SNTP reply from {}", server);
+                continue;
+            }
+            // The transmit timestamp, in seconds and fractions since 1900.
+            let secs = u32::from_be_bytes([reply[40], reply[41], reply[42], reply[43]]) as i64;
+            let fraction = u32::from_be_bytes([reply[44], reply[45], reply[46], reply[47]]) as i64;
+            let unix_ms = (secs - NTP_UNIX_OFFSET) * 1000 + ((fraction * 1000) >> 32);
+            // Assume the reply took as long as the request.
+            self.time = Some((unix_ms, now - (now - sent_at) / 2));
+            self.pending = None;
+            self.next_request = now + SYNC_INTERVAL_MS;
+            return;
+        }
+    }
+}
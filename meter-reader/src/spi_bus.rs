@@ -0,0 +1,149 @@
+//! Lets several devices, each with its own chip select, share one SPI bus.
+//!
+//! Every device gets an `SpiDevice` for its transfers and a `ChipSelect` to
+//! hand to its driver in place of the chip select pin. Selecting a device
+//! claims the bus until it is deselected again, and transfers from a device
+//! that hasn't claimed the bus are refused. Drivers often keep chip select low
+//! across several transfers, for example to send a command and then read the
+//! reply, and this keeps another device's transfers from ending up in between.
+//!
+//! Claims are never waited on. All devices on a bus must be driven from tasks
+//! of the same priority, so that one transaction always finishes before the
+//! next one starts. Selecting a device while another one holds the bus is a
+//! bug, and fails with `BusError::Busy`.
+
+use core::cell::{Cell, RefCell};
+
+use cortex_m::interrupt::{self, Mutex};
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v2::OutputPin,
+};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BusError<E> {
+    /// Another device is selected.
+    Busy,
+    /// The device tried to transfer without being selected.
+    NotSelected,
+    /// The SPI peripheral or chip select pin failed.
+    Io(E),
+}
+
+type DeviceId = u8;
+
+/// A device's handles on the bus, as returned by `SharedBus::device`.
+pub type Device<'a, SPI, CS> = (SpiDevice<'a, SPI>, ChipSelect<'a, SPI, CS>);
+
+pub struct SharedBus<SPI> {
+    spi: Mutex<RefCell<SPI>>,
+    /// The device whose chip select is low, if any.
+    selected: Mutex<Cell<Option<DeviceId>>>,
+    devices: Mutex<Cell<DeviceId>>,
+}
+
+impl<SPI> SharedBus<SPI> {
+    pub fn new(spi: SPI) -> Self {
+        Self {
+            spi: Mutex::new(RefCell::new(spi)),
+            selected: Mutex::new(Cell::new(None)),
+            devices: Mutex::new(Cell::new(0)),
+        }
+    }
+
+    /// Adds a device, selected through `cs`. The pin is driven high straight
+    /// away, so the device ignores the bus until its driver selects it.
+    pub fn device<CS: OutputPin>(
+        &self,
+        mut pin: CS,
+    ) -> Result<Device<'_, SPI, CS>, BusError<CS::Error>> {
+        pin.set_high().map_err(BusError::Io)?;
+        let id = interrupt::free(|cs| {
+            let devices = self.devices.borrow(cs);
+            let id = devices.get();
+            devices.set(id + 1);
+            id
+        });
+        Ok((
+            SpiDevice { bus: self, id },
+            ChipSelect { bus: self, id, pin },
+        ))
+    }
+
+    fn with_spi<T, E>(
+        &self,
+        id: DeviceId,
+        f: impl FnOnce(&mut SPI) -> Result<T, E>,
+    ) -> Result<T, BusError<E>> {
+        interrupt::free(|cs| {
+            if self.selected.borrow(cs).get() != Some(id) {
+                return Err(BusError::NotSelected);
+            }
+            f(&mut self.spi.borrow(cs).borrow_mut()).map_err(BusError::Io)
+        })
+    }
+}
+
+/// Transfers to and from one device on a `SharedBus`.
+pub struct SpiDevice<'a, SPI> {
+    bus: &'a SharedBus<SPI>,
+    id: DeviceId,
+}
+
+impl<SPI, E> Transfer<u8> for SpiDevice<'_, SPI>
+where
+    SPI: Transfer<u8, Error = E>,
+{
+    type Error = BusError<E>;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        self.bus
+            .with_spi(self.id, |spi| spi.transfer(words).map(|_| ()))?;
+        Ok(words)
+    }
+}
+
+impl<SPI, E> Write<u8> for SpiDevice<'_, SPI>
+where
+    SPI: Write<u8, Error = E>,
+{
+    type Error = BusError<E>;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.bus.with_spi(self.id, |spi| spi.write(words))
+    }
+}
+
+/// The chip select pin of one device on a `SharedBus`. Driving it low claims
+/// the bus, driving it high releases it.
+pub struct ChipSelect<'a, SPI, CS> {
+    bus: &'a SharedBus<SPI>,
+    id: DeviceId,
+    pin: CS,
+}
+
+impl<SPI, CS: OutputPin> OutputPin for ChipSelect<'_, SPI, CS> {
+    type Error = BusError<CS::Error>;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        interrupt::free(|cs| {
+            let selected = self.bus.selected.borrow(cs);
+            match selected.get() {
+                Some(id) if id != self.id => return Err(BusError::Busy),
+                _ => selected.set(Some(self.id)),
+            }
+            self.pin.set_low().map_err(BusError::Io)
+        })
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        interrupt::free(|cs| {
+            let result = self.pin.set_high().map_err(BusError::Io);
+            let selected = self.bus.selected.borrow(cs);
+            if selected.get() == Some(self.id) {
+                selected.set(None);
+            }
+            result
+        })
+    }
+}
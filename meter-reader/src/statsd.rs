@@ -0,0 +1,109 @@
+use arrayvec::ArrayString;
+use core::fmt::Write;
+use smoltcp::{
+    socket::{SocketHandle, SocketRef, UdpSocket},
+    time::Instant,
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{
+    metrics::metrics,
+    network::{client::UdpClient, stack},
+    random::Random,
+    telemetry::{TelemetryRecord, TelemetrySink},
+};
+
+// Small enough to avoid fragmentation on any network.
+const DATAGRAM_SZ: usize = 512;
+
+/// Where to send gauges to. Nothing is sent until a host has been set.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct StatsdConfig {
+    pub host: Ipv4Address,
+    pub port: u16,
+    /// Prepended to every metric name, followed by a dot.
+    pub prefix: ArrayString<32>,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            host: Ipv4Address::UNSPECIFIED,
+            port: 8125,
+            prefix: ArrayString::from("meter").unwrap_or_default(),
+        }
+    }
+}
+
+impl StatsdConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.host.is_unspecified()
+    }
+}
+
+/// Sends the instantaneous power and currents in each telegram to statsd, as
+/// gauges in a single datagram. Counters are left out, since statsd has no
+/// use for them.
+pub struct StatsdClient {
+    config: StatsdConfig,
+    handle: Option<SocketHandle>,
+    queued: ArrayString<DATAGRAM_SZ>,
+}
+
+impl UdpClient for StatsdClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll(&mut self, mut socket: SocketRef<UdpSocket>, _timestamp: Instant, random: &mut Random) {
+        if !self.config.is_enabled() || self.queued.is_empty() {
+            return;
+        }
+        if !socket.is_open() {
+            let local = stack::generate_local_port(random);
+            if let Err(err) = socket.bind(local) {
+                log::warn!("Failed to bind statsd socket: {}", err);
+                return;
+            }
+        }
+        if !socket.can_send() {
+            log::warn!("Statsd socket buffer full, dropping gauges");
+        } else {
+            let remote = IpEndpoint::new(IpAddress::Ipv4(self.config.host), self.config.port);
+            if let Err(err) = socket.send_slice(self.queued.as_bytes(), remote) {
+                log::warn!("Failed to send gauges to statsd: {}", err);
+            }
+        }
+        self.queued.clear();
+    }
+}
+
+impl StatsdClient {
+    pub fn new(config: StatsdConfig) -> Self {
+        Self {
+            config,
+            handle: None,
+            queued: ArrayString::new(),
+        }
+    }
+}
+
+impl TelemetrySink for StatsdClient {
+    /// Queues the gauges in a telegram, replacing any that haven't been sent
+    /// yet.
+    fn accept(&mut self, record: &TelemetryRecord) {
+        if !self.config.is_enabled() {
+            return;
+        }
+        self.queued.clear();
+        for (name, value) in metrics(record.telegram).filter(|(name, _)| name.is_instantaneous()) {
+            if writeln!(self.queued, "{}.{}:{}|g", self.config.prefix, name, value).is_err() {
+                log::warn!("Gauges do not fit in {} bytes", self.queued.capacity());
+                self.queued.clear();
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use core::fmt::{self, Display};
+
+use enc28j60_smoltcp::errata::{Revision, Workarounds};
+
+// System Reset Controller reset status register. Bits are sticky across
+// resets until cleared by writing 1 to them.
+#[cfg(not(feature = "sim"))]
+const SRC_SRSR: usize = 0x400F_8008;
+const SRSR_IPP_RESET_B: u32 = 1 << 0;
+const SRSR_LOCKUP_SYSRESETREQ: u32 = 1 << 1;
+const SRSR_IPP_USER_RESET_B: u32 = 1 << 3;
+const SRSR_WDOG_RST_B: u32 = 1 << 4;
+const SRSR_WDOG3_RST_B: u32 = 1 << 7;
+#[cfg(not(feature = "sim"))]
+const SRSR_ALL: u32 = 0x1FF;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BootReason {
+    PowerOn,
+    Watchdog,
+    /// A software reset, which happens after a panic in release builds.
+    SoftwareReset,
+    ResetButton,
+    Unknown,
+}
+
+impl Default for BootReason {
+    fn default() -> Self {
+        BootReason::Unknown
+    }
+}
+
+impl Display for BootReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BootReason::PowerOn => "power_on",
+            BootReason::Watchdog => "watchdog",
+            BootReason::SoftwareReset => "software_reset",
+            BootReason::ResetButton => "reset_button",
+            BootReason::Unknown => "unknown",
+        })
+    }
+}
+
+/// The ENC28J60 found during startup.
+#[derive(Copy, Clone, Debug)]
+pub struct EthernetChip {
+    pub revision: Revision,
+    pub workarounds: Workarounds,
+}
+
+/// Information about the current boot.
+#[derive(Copy, Clone, Debug)]
+pub struct SystemInfo {
+    pub boot_reason: BootReason,
+    /// Set once the ENC28J60 is initialised. The Teensy 4.1 doesn't use one.
+    pub ethernet_chip: Option<EthernetChip>,
+}
+
+impl SystemInfo {
+    /// Determines why we booted. Call this once, early during startup: it
+    /// clears the reset status, so the next boot is reported correctly.
+    pub fn read() -> Self {
+        let srsr = take_reset_status();
+        // Several bits may be set if they weren't cleared before, so check
+        // the most specific ones first.
+        let boot_reason = if srsr & (SRSR_WDOG_RST_B | SRSR_WDOG3_RST_B) != 0 {
+            BootReason::Watchdog
+        } else if srsr & SRSR_LOCKUP_SYSRESETREQ != 0 {
+            BootReason::SoftwareReset
+        } else if srsr & SRSR_IPP_USER_RESET_B != 0 {
+            BootReason::ResetButton
+        } else if srsr & SRSR_IPP_RESET_B != 0 {
+            BootReason::PowerOn
+        } else {
+            BootReason::Unknown
+        };
+        log::info!("Boot reason: {} (SRSR: {:#05x})", boot_reason, srsr);
+        Self {
+            boot_reason,
+            ethernet_chip: None,
+        }
+    }
+
+    /// Seconds since boot, given the current `Clock` time. The clock starts
+    /// at zero during startup.
+    pub fn uptime_secs(&self, now: i64) -> i64 {
+        now / 1000
+    }
+}
+
+#[cfg(not(feature = "sim"))]
+fn take_reset_status() -> u32 {
+    unsafe {
+        let srsr = core::ptr::read_volatile(SRC_SRSR as *const u32);
+        core::ptr::write_volatile(SRC_SRSR as *mut u32, SRSR_ALL);
+        srsr
+    }
+}
+
+/// The simulator always starts from scratch.
+#[cfg(feature = "sim")]
+fn take_reset_status() -> u32 {
+    SRSR_IPP_RESET_B
+}
+
+/// Restarts the firmware. The simulator exits instead, since there's nothing
+/// to restart it.
+pub fn reset() -> ! {
+    #[cfg(not(feature = "sim"))]
+    cortex_m::peripheral::SCB::sys_reset();
+    #[cfg(feature = "sim")]
+    std::process::exit(1);
+}
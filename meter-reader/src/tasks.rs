@@ -0,0 +1,149 @@
+//! The work done for every telegram and every network poll, shared by the
+//! RTIC tasks in `main.rs` and the loop in `sim.rs`. Those only differ in
+//! where they keep their state, how they lock it, and the hardware around it.
+
+use dsmr42::Telegram;
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::{
+    clock::Clock,
+    config::Config,
+    costs::CostTracker,
+    diagnostics::Diagnostics,
+    graphite::GraphiteClient,
+    influx::InfluxClient,
+    log_stream::LogStreamClient,
+    logging,
+    mqtt::{Command, MqttClient},
+    multicast::MulticastClient,
+    network::{driver::Driver, stack::NetworkStack},
+    ota::OtaReceiver,
+    peak::PeakTracker,
+    provisioning::Provisioner,
+    random::Random,
+    s0::PulseReading,
+    selftest::SelfTest,
+    sntp::SntpClient,
+    statsd::StatsdClient,
+    system_info,
+    telegram_server::TelegramServer,
+    telemetry::{Pipeline, TelemetrySink},
+    totals::DailyTotals,
+    wall_clock::{TimeSource, WallClock},
+};
+
+/// What is kept up to date from every telegram.
+pub struct Trackers<'a> {
+    pub wall_clock: &'a mut WallClock,
+    pub costs: &'a mut CostTracker,
+    pub totals: &'a mut DailyTotals,
+    pub peak: &'a mut PeakTracker,
+}
+
+/// The clients polled on every pass over the network.
+pub struct Clients<'a> {
+    pub mqtt: &'a mut MqttClient,
+    pub telegram_server: &'a mut TelegramServer,
+    pub influx: &'a mut InfluxClient,
+    pub graphite: &'a mut GraphiteClient,
+    pub statsd: &'a mut StatsdClient,
+    pub multicast: &'a mut MulticastClient,
+    pub sntp: &'a mut SntpClient,
+    pub provisioner: &'a mut Provisioner,
+    pub ota: &'a mut OtaReceiver,
+    pub log_stream: &'a mut LogStreamClient,
+}
+
+/// Updates the trackers with the telegram last returned from
+/// `Pipeline::poll()`, received at `received_at`, and hands it to the sinks.
+pub fn handle_telegram<R: OutputPin>(
+    telegram: &Telegram,
+    received_at: Option<i64>,
+    pipeline: &Pipeline<R>,
+    pulses: Option<PulseReading>,
+    trackers: Trackers,
+    sinks: &mut [&mut dyn TelemetrySink],
+) {
+    if let Some(received_at) = received_at {
+        trackers
+            .wall_clock
+            .sync_from_telegram(telegram, received_at);
+    }
+    trackers.costs.update(telegram);
+    trackers.totals.update(telegram);
+    trackers.peak.update(telegram);
+    pipeline.publish(telegram, pulses, trackers.wall_clock, sinks);
+}
+
+/// Polls every client, and carries out the commands received over MQTT.
+/// Resets once the broker has been told we're going away, applying a new
+/// image first if one was received. Returns the configuration the
+/// provisioner fetched, if it did.
+pub fn poll_clients<D: Driver>(
+    network: &mut NetworkStack<D>,
+    clock: &mut Clock,
+    random: &mut Random,
+    clients: &mut Clients,
+    wall_clock: &mut WallClock,
+    selftest: &mut SelfTest,
+) -> Option<Config> {
+    network.poll_client(clock, random, clients.mqtt);
+    network.poll_client(clock, random, clients.telegram_server);
+    network.poll_client(clock, random, clients.influx);
+    network.poll_client(clock, random, clients.graphite);
+    network.poll_udp_client(clock, random, clients.statsd);
+    network.poll_udp_client(clock, random, clients.multicast);
+    clients.sntp.set_dhcp_servers(network.ntp_servers());
+    network.poll_udp_client(clock, random, clients.sntp);
+    if let Some((unix_ms, clock_ms)) = clients.sntp.take_time() {
+        wall_clock.sync(unix_ms, clock_ms, TimeSource::Sntp);
+    }
+    clients.provisioner.set_dhcp_server(network.tftp_server());
+    network.poll_udp_client(clock, random, clients.provisioner);
+    let provisioned = clients.provisioner.take_config();
+    network.poll_client(clock, random, clients.ota);
+    network.poll_client(clock, random, clients.log_stream);
+
+    let client = &mut *clients.mqtt;
+    match client.take_command() {
+        Some(Command::EnableOta) => clients.ota.enable(),
+        Some(Command::Reboot) => {
+            log::info!("Rebooting");
+            client.shut_down(clock.millis());
+        }
+        Some(Command::SetLogLevel(level)) => logging::set_level(level),
+        Some(Command::SelfTest) => selftest.start(clock.millis()),
+        None => {}
+    }
+    if clients.ota.ready_image().is_some() {
+        client.shut_down(clock.millis());
+    }
+    if client.is_shut_down(clock.millis()) {
+        if let Some(len) = clients.ota.ready_image() {
+            clients.ota.apply(len);
+        }
+        system_info::reset();
+    }
+    provisioned
+}
+
+/// Queues the diagnostics for publishing, along with the reports that go out
+/// with them.
+pub fn queue_reports(
+    client: &mut MqttClient,
+    diagnostics: Diagnostics,
+    costs: &CostTracker,
+    totals: &DailyTotals,
+    peak: &PeakTracker,
+) {
+    client.queue_diagnostics(diagnostics);
+    if let Some(report) = costs.report() {
+        client.queue_costs(report);
+    }
+    if let Some(report) = totals.report() {
+        client.queue_totals(report);
+    }
+    if let Some(report) = peak.report() {
+        client.queue_peak(report);
+    }
+}
@@ -0,0 +1,112 @@
+use arrayvec::ArrayVec;
+use smoltcp::{socket::SocketHandle, time::Instant};
+
+use crate::{
+    network::client::{TcpAction, TcpClient, TcpConnection},
+    random::Random,
+    telemetry::{TelemetryRecord, TelemetrySink},
+};
+
+// Most P1-over-LAN gateways listen on this port.
+const LISTEN_PORT: u16 = 2001;
+
+const MAX_TELEGRAM_SZ: usize = 1024;
+
+/// Streams raw telegrams, exactly as received from the meter, to whoever is
+/// connected to `LISTEN_PORT`.
+///
+/// Since a smoltcp socket can only hold a single connection, only one consumer
+/// can be connected at a time. Anything the consumer sends to us is discarded.
+pub struct TelegramServer {
+    handle: Option<SocketHandle>,
+    connected: bool,
+    queued_telegram: ArrayVec<u8, MAX_TELEGRAM_SZ>,
+}
+
+impl TcpClient for TelegramServer {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll(
+        &mut self,
+        socket: &mut dyn TcpConnection,
+        _timestamp: Instant,
+        _random: &mut Random,
+    ) -> TcpAction {
+        if !socket.is_open() {
+            log::debug!("Listening for telegram consumers on port {}", LISTEN_PORT);
+            return TcpAction::Listen(LISTEN_PORT);
+        }
+
+        if socket.may_send() && !self.connected {
+            self.connected = true;
+            // Don't send a stale telegram to a new consumer.
+            self.queued_telegram.clear();
+            log::info!("Telegram consumer connected: {}", socket.remote_endpoint());
+        } else if !socket.is_active() && self.connected {
+            self.connected = false;
+            log::info!("Telegram consumer disconnected");
+        }
+
+        if socket.can_recv() {
+            if let Err(err) = socket.consume(usize::MAX) {
+                log::warn!("Failed to discard received data: {}", err);
+            }
+        }
+
+        // The consumer closed its side, so close ours to make the socket
+        // available for the next one.
+        if socket.may_send() && !socket.may_recv() {
+            return TcpAction::Close;
+        }
+
+        if socket.can_send() && !self.queued_telegram.is_empty() {
+            let free = socket.send_free();
+            if free < self.queued_telegram.len() {
+                log::warn!(
+                    "Telegram consumer is too slow, dropping telegram ({} bytes, {} free)",
+                    self.queued_telegram.len(),
+                    free
+                );
+            } else if let Err(err) = socket.send_slice(&self.queued_telegram) {
+                log::warn!("Failed to send telegram to consumer: {}", err);
+            }
+            self.queued_telegram.clear();
+        }
+        TcpAction::Nothing
+    }
+}
+
+impl TelegramServer {
+    pub fn new() -> Self {
+        Self {
+            handle: None,
+            connected: false,
+            queued_telegram: ArrayVec::new(),
+        }
+    }
+}
+
+impl TelemetrySink for TelegramServer {
+    /// Queues the raw telegram for sending, replacing any telegram that hasn't
+    /// been sent yet. Telegrams are dropped if nobody is connected.
+    fn accept(&mut self, record: &TelemetryRecord) {
+        if !self.connected {
+            return;
+        }
+        self.queued_telegram.clear();
+        if self
+            .queued_telegram
+            .try_extend_from_slice(record.raw)
+            .is_err()
+        {
+            log::warn!(
+                "Raw telegram too large to queue ({} bytes)",
+                record.raw.len()
+            );
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use arrayvec::ArrayVec;
+use dsmr42::{
+    aggregate::{Aggregator, Summaries},
+    interval::IntervalEstimator,
+    Line, SerializeOptions, Telegram, TelegramParseError,
+};
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::{
+    alerts::{AlertConfig, AlertEvent, Alerts},
+    clock,
+    events::{Event, EventQueue},
+    gas::{GasReading, GasTracker},
+    parse_errors::{ParseErrorLog, ParseFailure},
+    s0::PulseReading,
+    uart::{DsmrUart, READ_BUF_SZ},
+    wall_clock::WallClock,
+};
+
+/// A telegram on its way out, along with what outputs may need besides the
+/// parsed telegram.
+pub struct TelemetryRecord<'a> {
+    pub telegram: &'a Telegram,
+    /// The telegram as it was received.
+    pub raw: &'a [u8],
+    /// The `Clock` time at which its first byte was received.
+    pub received_at: Option<i64>,
+    /// The same, as Unix time in milliseconds, if the wall clock is set.
+    pub received_unix_ms: Option<i64>,
+    /// Microseconds it took to parse.
+    pub parse_us: Option<u32>,
+    /// The gas reading in the telegram, if it's newer than the one in the
+    /// previous telegram.
+    pub gas: Option<GasReading>,
+    /// The instantaneous values of the aggregated fields, summarised over
+    /// this telegram and the ones dropped since the previous one.
+    pub summaries: &'a Summaries,
+    /// The count of the S0 input at the time the telegram is published, if
+    /// it's enabled.
+    pub pulses: Option<PulseReading>,
+}
+
+/// An output for telegrams, like a broker or a database. Sinks are handed
+/// every telegram that `Pipeline::poll()` returns, and usually queue it until
+/// the network is polled.
+pub trait TelemetrySink {
+    fn accept(&mut self, record: &TelemetryRecord);
+}
+
+/// Turns bytes received from the meter into telegrams.
+pub struct Pipeline<R> {
+    uart: DsmrUart<R>,
+    // Minimum time between two telegrams returned from `poll()`.
+    min_interval_ms: i64,
+    last_emitted: Option<i64>,
+    raw_telegram: ArrayVec<u8, READ_BUF_SZ>,
+    received_at: Option<i64>,
+    parse_us: Option<u32>,
+    meter_interval: IntervalEstimator,
+    gas_tracker: GasTracker,
+    gas: Option<GasReading>,
+    aggregator: Aggregator,
+    summaries: Summaries,
+    alerts: Alerts,
+    parse_errors: ParseErrorLog,
+}
+
+impl<R: OutputPin> Pipeline<R> {
+    /// Telegrams arriving less than `min_interval_ms` after the previously
+    /// returned one are dropped. Set it to 0 to keep all telegrams. The
+    /// instantaneous values of the `aggregate` fields in dropped telegrams
+    /// are summarised along with the next returned one. The `alerts` rules
+    /// are evaluated against every telegram, dropped or not.
+    pub fn new(
+        uart: DsmrUart<R>,
+        min_interval_ms: i64,
+        aggregate: SerializeOptions,
+        alerts: AlertConfig,
+    ) -> Self {
+        Self {
+            uart,
+            min_interval_ms,
+            last_emitted: None,
+            raw_telegram: ArrayVec::new(),
+            received_at: None,
+            parse_us: None,
+            meter_interval: IntervalEstimator::new(),
+            gas_tracker: GasTracker::new(),
+            gas: None,
+            aggregator: Aggregator::new(aggregate),
+            summaries: Summaries::default(),
+            alerts: Alerts::new(alerts),
+            parse_errors: ParseErrorLog::default(),
+        }
+    }
+
+    /// Reads from the UART, and returns the next telegram if one is complete.
+    /// Whether it could be parsed is reported to `events`.
+    pub fn poll(&mut self, now: i64, events: &mut EventQueue) -> Option<Telegram> {
+        self.uart.poll_timers(now);
+        if self.uart.take_reset_request() {
+            events.push(Event::ResetRequested);
+        }
+        self.uart.poll(now);
+        self.uart.poll_tx();
+        if !self.uart.data_ready(now) {
+            return None;
+        }
+
+        // One odd register shouldn't cost us the rest of the telegram.
+        let parse_start = clock::micros();
+        let (read, res) = dsmr42::parse_lenient(self.uart.get_buffer());
+        let parse_us = (clock::micros() - parse_start) as u32;
+        if let Err(err) = &res {
+            if let Some(failure) = ParseFailure::new(now, err, self.uart.get_buffer()) {
+                self.parse_errors.record(failure);
+            }
+        }
+        let telegram = match res {
+            Ok(telegram) => {
+                log::info!("Got new telegram: {}", telegram);
+                for line in telegram.lines.iter() {
+                    if let Line::Oversized(obis) = line {
+                        log::debug!("Skipped oversized line: {:?}", obis);
+                    }
+                }
+                self.uart.telegram_received(now);
+                events.push(Event::TelegramParsed);
+                self.raw_telegram.clear();
+                // Can't fail, since the read buffer is the same size.
+                let _ = self
+                    .raw_telegram
+                    .try_extend_from_slice(&self.uart.get_buffer()[..read]);
+                // Every valid telegram, also the ones dropped below.
+                self.uart.retransmit(&self.raw_telegram);
+                self.received_at = self.uart.telegram_received_at();
+                self.parse_us = Some(parse_us);
+                let known = self.meter_interval.interval_secs();
+                let estimate = self.meter_interval.observe_telegram(&telegram);
+                if let Some(secs) = estimate.filter(|e| Some(*e) != known) {
+                    log::info!("Meter sends a telegram every {} s", secs);
+                    self.uart.set_meter_interval(secs);
+                }
+                Some(telegram)
+            }
+            Err(TelegramParseError::Incomplete) => None,
+            Err(TelegramParseError::CrcMismatch(mismatch)) => {
+                log::warn!(
+                    "Telegram CRC mismatch: calculated {:04X}, read {:04X}",
+                    mismatch.calculated,
+                    mismatch.read
+                );
+                self.uart.clear();
+                events.push(Event::CrcFailed);
+                None
+            }
+            Err(err) => {
+                let buffer = self.uart.get_buffer();
+                log::warn!(
+                    "Failed to parse telegram ({} bytes): {:?}, buffer: {:?}",
+                    buffer.len(),
+                    err,
+                    core::str::from_utf8(buffer)
+                );
+                self.uart.clear();
+                events.push(Event::ParseFailed);
+                None
+            }
+        };
+        if read > 0 {
+            self.uart.consume(read);
+        }
+
+        let telegram = telegram?;
+        self.aggregator.observe(&telegram);
+        self.alerts.observe(&telegram, now);
+        match self.last_emitted {
+            Some(last) if now - last < self.min_interval_ms => {
+                log::debug!("Dropping telegram, last one was {} ms ago", now - last);
+                None
+            }
+            _ => {
+                self.last_emitted = Some(now);
+                // Only now, so a new reading isn't lost with a dropped telegram.
+                self.gas = self.gas_tracker.observe(&telegram);
+                self.summaries = self.aggregator.finish();
+                Some(telegram)
+            }
+        }
+    }
+
+    /// The raw bytes of the telegram last returned from `poll()`.
+    pub fn raw_telegram(&self) -> &[u8] {
+        &self.raw_telegram
+    }
+
+    /// The `Clock` time at which the first byte of the telegram last returned
+    /// from `poll()` was received.
+    pub fn received_at(&self) -> Option<i64> {
+        self.received_at
+    }
+
+    /// Hands the telegram last returned from `poll()` to each of the sinks,
+    /// in order, along with the S0 pulse count. `wall_clock` dates it.
+    pub fn publish(
+        &self,
+        telegram: &Telegram,
+        pulses: Option<PulseReading>,
+        wall_clock: &WallClock,
+        sinks: &mut [&mut dyn TelemetrySink],
+    ) {
+        let record = TelemetryRecord {
+            telegram,
+            raw: &self.raw_telegram,
+            received_at: self.received_at,
+            received_unix_ms: self
+                .received_at
+                .and_then(|received_at| wall_clock.unix_millis(received_at)),
+            parse_us: self.parse_us,
+            gas: self.gas,
+            summaries: &self.summaries,
+            pulses,
+        };
+        for sink in sinks.iter_mut() {
+            sink.accept(&record);
+        }
+    }
+
+    /// Takes the oldest alert that was raised or cleared, and hasn't been
+    /// taken yet.
+    pub fn take_alert(&mut self) -> Option<AlertEvent> {
+        self.alerts.take()
+    }
+
+    /// The most recent telegrams that could not be parsed.
+    pub fn parse_errors(&self) -> &ParseErrorLog {
+        &self.parse_errors
+    }
+
+    /// How often the meter sends a telegram, in seconds, once known.
+    pub fn meter_interval_secs(&self) -> Option<u32> {
+        self.meter_interval.interval_secs()
+    }
+
+    pub fn uart(&self) -> &DsmrUart<R> {
+        &self.uart
+    }
+
+    pub fn uart_mut(&mut self) -> &mut DsmrUart<R> {
+        &mut self.uart
+    }
+}
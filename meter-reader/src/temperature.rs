@@ -0,0 +1,267 @@
+use core::fmt::{self, Display, Write};
+
+use arrayvec::ArrayVec;
+use enc28j60_smoltcp::warn_throttled;
+
+use crate::clock;
+
+pub const MAX_SENSORS: usize = 4;
+const FAMILY_DS18B20: u8 = 0x28;
+const SEARCH_ROM: u8 = 0xF0;
+const MATCH_ROM: u8 = 0x55;
+const SKIP_ROM: u8 = 0xCC;
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+// A conversion at the default 12-bit resolution takes up to 750 ms.
+const CONVERSION_MS: i64 = 750;
+// The scratchpad holds this before a conversion has finished since power-up.
+const POWER_ON_RESET_RAW: i16 = 0x0550;
+
+/// The bus the sensors are on, driven a slot at a time.
+pub trait OneWire {
+    /// Sends a reset pulse, and returns whether any device answered it.
+    fn reset(&mut self) -> bool;
+
+    fn write_bit(&mut self, bit: bool);
+
+    fn read_bit(&mut self) -> bool;
+
+    fn write_byte(&mut self, byte: u8) {
+        for i in 0..8 {
+            self.write_bit(byte >> i & 1 != 0);
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        (0..8).fold(0, |byte, i| byte | (self.read_bit() as u8) << i)
+    }
+}
+
+/// The 64-bit ROM code of a sensor: its family code, a serial number and a
+/// CRC, least significant byte first.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Rom(pub [u8; 8]);
+
+impl Display for Rom {
+    /// Formats the code like Linux does, as the family code and the serial
+    /// number, like `28-0316a279c3ff`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}-", self.0[0])?;
+        for byte in self.0[1..7].iter().rev() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Temperature {
+    pub rom: Rom,
+    /// In hundredths of a degree Celsius.
+    pub centi_celsius: i32,
+}
+
+/// The last temperature read from each sensor.
+pub type Temperatures = [Option<Temperature>; MAX_SENSORS];
+
+/// Writes temperatures as a JSON object, like `{"28-0316a279c3ff": 21.56}`.
+pub fn serialize<W: Write>(writer: &mut W, temperatures: &Temperatures) -> fmt::Result {
+    write!(writer, "{{")?;
+    for (i, temperature) in temperatures.iter().flatten().enumerate() {
+        let sign = if temperature.centi_celsius < 0 {
+            "-"
+        } else {
+            ""
+        };
+        let abs = temperature.centi_celsius.unsigned_abs();
+        write!(
+            writer,
+            "{}\"{}\": {}{}.{:02}",
+            if i == 0 { "" } else { ", " },
+            temperature.rom,
+            sign,
+            abs / 100,
+            abs % 100
+        )?;
+    }
+    write!(writer, "}}")
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    Idle { next_read: i64 },
+    Converting { since: i64 },
+}
+
+/// Reads DS18B20 temperature sensors every `interval_ms`. Sensors are looked
+/// for before each conversion, so they can be added while running.
+///
+/// All sensors convert at the same time, so they must be powered through
+/// their VDD pin rather than parasitically.
+pub struct TemperatureSensors<B> {
+    bus: Option<B>,
+    interval_ms: i64,
+    state: State,
+    sensors: ArrayVec<Rom, MAX_SENSORS>,
+    readings: Temperatures,
+}
+
+impl<B: OneWire> TemperatureSensors<B> {
+    /// Does nothing without a bus, or if `interval_ms` is 0.
+    pub fn new(bus: Option<B>, interval_ms: i64) -> Self {
+        Self {
+            bus: bus.filter(|_| interval_ms > 0),
+            interval_ms,
+            state: State::Idle { next_read: 0 },
+            sensors: ArrayVec::new(),
+            readings: [None; MAX_SENSORS],
+        }
+    }
+
+    pub fn readings(&self) -> Temperatures {
+        self.readings
+    }
+
+    /// Starts a conversion when one is due, and reads the results once it
+    /// has finished.
+    pub fn poll(&mut self, now: i64) {
+        let bus = match &mut self.bus {
+            Some(bus) => bus,
+            None => return,
+        };
+        match self.state {
+            State::Idle { next_read } if now >= next_read => {
+                let found = search(bus, &mut self.sensors);
+                if found != self.sensors.len() {
+                    warn_throttled!(
+                        clock::millis(),
+                        "Found {} 1-Wire sensors, only reading {}",
+                        found,
+                        MAX_SENSORS
+                    );
+                }
+                if self.sensors.is_empty() || !bus.reset() {
+                    self.readings = [None; MAX_SENSORS];
+                    self.state = State::Idle {
+                        next_read: now + self.interval_ms,
+                    };
+                    return;
+                }
+                bus.write_byte(SKIP_ROM);
+                bus.write_byte(CONVERT_T);
+                self.state = State::Converting { since: now };
+            }
+            State::Converting { since } if now - since >= CONVERSION_MS => {
+                let mut readings = [None; MAX_SENSORS];
+                for (reading, rom) in readings.iter_mut().zip(self.sensors.iter()) {
+                    *reading = read_temperature(bus, rom).map(|centi_celsius| Temperature {
+                        rom: *rom,
+                        centi_celsius,
+                    });
+                    if reading.is_none() {
+                        warn_throttled!(clock::millis(), "Failed to read 1-Wire sensor {}", rom);
+                    }
+                }
+                self.readings = readings;
+                self.state = State::Idle {
+                    next_read: since + self.interval_ms,
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Finds the DS18B20 sensors on the bus, keeping the first `MAX_SENSORS`,
+/// and returns how many there are.
+///
+/// This is the search algorithm from Maxim's application note 187: each
+/// pass reads a bit of every device's ROM code and its complement, and where
+/// they differ, goes down the 0 branch first and the 1 branch on a later pass.
+fn search<B: OneWire>(bus: &mut B, sensors: &mut ArrayVec<Rom, MAX_SENSORS>) -> usize {
+    sensors.clear();
+    let mut found = 0;
+    let mut rom = [0; 8];
+    // The bit at which the 0 branch was taken last, where the next pass
+    // takes the 1 branch.
+    let mut last_discrepancy = None;
+    loop {
+        if !bus.reset() {
+            return found;
+        }
+        bus.write_byte(SEARCH_ROM);
+        let mut discrepancy = None;
+        for bit in 0..64 {
+            let (byte, mask) = (bit / 8, 1 << (bit % 8));
+            let id_bit = bus.read_bit();
+            let complement = bus.read_bit();
+            let direction = match (id_bit, complement) {
+                // Nothing answered, which a device leaving the bus can cause.
+                (true, true) => return found,
+                (false, false) => {
+                    let direction = match last_discrepancy {
+                        Some(last) if bit < last => rom[byte] & mask != 0,
+                        Some(last) => bit == last,
+                        None => false,
+                    };
+                    if !direction {
+                        discrepancy = Some(bit);
+                    }
+                    direction
+                }
+                (id_bit, _) => id_bit,
+            };
+            if direction {
+                rom[byte] |= mask;
+            } else {
+                rom[byte] &= !mask;
+            }
+            bus.write_bit(direction);
+        }
+        if crc8(&rom) == 0 && rom[0] == FAMILY_DS18B20 {
+            found += 1;
+            let _ = sensors.try_push(Rom(rom));
+        }
+        last_discrepancy = discrepancy;
+        if last_discrepancy.is_none() {
+            return found;
+        }
+    }
+}
+
+/// Reads the result of the last conversion from a sensor, in hundredths of a
+/// degree.
+fn read_temperature<B: OneWire>(bus: &mut B, rom: &Rom) -> Option<i32> {
+    if !bus.reset() {
+        return None;
+    }
+    bus.write_byte(MATCH_ROM);
+    for byte in rom.0.iter() {
+        bus.write_byte(*byte);
+    }
+    bus.write_byte(READ_SCRATCHPAD);
+    let mut scratchpad = [0; 9];
+    for byte in scratchpad.iter_mut() {
+        *byte = bus.read_byte();
+    }
+    // A sensor that doesn't answer reads as all ones, which fails the CRC.
+    if crc8(&scratchpad) != 0 {
+        return None;
+    }
+    let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+    if raw == POWER_ON_RESET_RAW {
+        return None;
+    }
+    // In sixteenths of a degree.
+    Some(raw as i32 * 100 / 16)
+}
+
+/// The Dallas/Maxim CRC-8, which is 0 over data that ends in its CRC.
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| match crc & 1 {
+            1 => crc >> 1 ^ 0x8C,
+            _ => crc >> 1,
+        })
+    })
+}
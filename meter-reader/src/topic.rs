@@ -0,0 +1,39 @@
+use arrayvec::ArrayString;
+use dsmr42::MAX_EQUIPMENT_ID_LEN;
+
+use crate::config::Topic;
+
+const DEVICE_ID: &str = "{device_id}";
+
+/// Room for a topic after expanding its template. Templates hold at most one
+/// variable, so expanding one always fits.
+pub type ExpandedTopic = ArrayString<{ 64 + MAX_EQUIPMENT_ID_LEN }>;
+
+/// Expands `{device_id}` in a topic template, like `meters/{device_id}/usage`.
+pub fn expand(template: &Topic, device_id: &str) -> ExpandedTopic {
+    let mut topic = ExpandedTopic::new();
+    let mut parts = template.splitn(2, DEVICE_ID);
+    // Can't fail, see `ExpandedTopic`.
+    let _ = topic.try_push_str(parts.next().unwrap_or(""));
+    if let Some(rest) = parts.next() {
+        let _ = topic.try_push_str(device_id);
+        let _ = topic.try_push_str(rest);
+    }
+    topic
+}
+
+/// Whether the template is one `expand()` accepts: it may hold `{device_id}`
+/// once, and no other braces.
+pub fn is_valid_template(template: &str) -> bool {
+    let mut parts = template.splitn(2, DEVICE_ID);
+    parts.all(|part| !part.contains(|c| c == '{' || c == '}'))
+}
+
+/// Whether an ID can be used in a topic as it is. Topics can't hold
+/// wildcards, and a `/` would add a level.
+pub fn is_topic_safe(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
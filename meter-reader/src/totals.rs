@@ -0,0 +1,126 @@
+mod store;
+
+use core::fmt::{self, Write};
+
+use dsmr42::Telegram;
+
+use crate::{counters::Counters, wall_clock::LocalTime};
+
+use store::SnapshotStore;
+
+/// The readings at the start of the current day and week. Days are counted
+/// from 1970-01-01 in local time, weeks start on Monday.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Snapshot {
+    day: i64,
+    day_counters: Counters,
+    week: i64,
+    week_counters: Counters,
+}
+
+/// Energy used today and this week, in Wh.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TotalsReport {
+    date: LocalTime,
+    consumed_today: u32,
+    produced_today: u32,
+    consumed_week: u32,
+    produced_week: u32,
+}
+
+impl TotalsReport {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> fmt::Result {
+        // Poor man's JSON, same as the telegrams
+        write!(
+            writer,
+            "{{\"date\": \"{:04}-{:02}-{:02}\", \
+            \"consumed_today_wh\": {}, \"produced_today_wh\": {}, \
+            \"consumed_week_wh\": {}, \"produced_week_wh\": {}}}",
+            self.date.year,
+            self.date.month,
+            self.date.day,
+            self.consumed_today,
+            self.produced_today,
+            self.consumed_week,
+            self.produced_week,
+        )
+    }
+}
+
+/// Tracks how much energy has been used today and this week, by taking a
+/// snapshot of the cumulative counters at local midnight.
+///
+/// Snapshots are kept in flash, so the totals survive a reset during the
+/// day. If the reader was off at midnight, the snapshot is taken from the
+/// first reading after, so the totals only cover the time since.
+pub struct DailyTotals {
+    store: SnapshotStore,
+    snapshot: Option<Snapshot>,
+    latest: Option<Counters>,
+    today: Option<LocalTime>,
+}
+
+impl DailyTotals {
+    /// Loads the last snapshot from flash.
+    pub fn load() -> Self {
+        let (store, snapshot) = SnapshotStore::load();
+        Self {
+            store,
+            snapshot,
+            latest: None,
+            today: None,
+        }
+    }
+
+    pub fn update(&mut self, telegram: &Telegram) {
+        if let Some(counters) = Counters::from_telegram(telegram) {
+            self.latest = Some(counters);
+        }
+    }
+
+    /// Takes a new snapshot if a day has started since the last one. Call
+    /// this regularly, with the current local time if it is known.
+    pub fn tick(&mut self, time: Option<LocalTime>) {
+        let (time, latest) = match (time, self.latest) {
+            (Some(time), Some(latest)) => (time, latest),
+            _ => return,
+        };
+        self.today = Some(time);
+        let day = time.days_since_epoch();
+        // 1970-01-01 was a Thursday.
+        let week = day - (day + 3).rem_euclid(7);
+        let (week_counters, day_counters) = match self.snapshot {
+            Some(snapshot) if snapshot.day == day => return,
+            Some(snapshot) if snapshot.week == week => (snapshot.week_counters, latest),
+            _ => (latest, latest),
+        };
+        let snapshot = Snapshot {
+            day,
+            day_counters,
+            week,
+            week_counters,
+        };
+        log::info!("New day, taking snapshot: {:?}", snapshot);
+        if let Err(err) = self.store.save(&snapshot) {
+            log::warn!("Failed to save snapshot: {:?}", err);
+        }
+        self.snapshot = Some(snapshot);
+    }
+
+    /// The current totals, once a snapshot has been taken for today.
+    pub fn report(&self) -> Option<TotalsReport> {
+        let (snapshot, latest, today) = (self.snapshot?, self.latest?, self.today?);
+        if snapshot.day != today.days_since_epoch() {
+            return None;
+        }
+        let (consumed_today, produced_today) = latest.since(&snapshot.day_counters);
+        let (consumed_week, produced_week) = latest.since(&snapshot.week_counters);
+        Some(TotalsReport {
+            date: today,
+            consumed_today,
+            produced_today,
+            consumed_week,
+            produced_week,
+        })
+    }
+}
@@ -0,0 +1,89 @@
+use arrayvec::ArrayVec;
+
+use super::Snapshot;
+use crate::{
+    counters::{Counters, TARIFFS},
+    flash::{self, FlashError, SECTOR_SZ},
+    page_log::{PageLog, Payload, PAYLOAD_SZ},
+};
+
+// The two sectors after the configuration, see `config/store.rs`.
+const BANK_OFFSETS: [u32; 2] = [
+    flash::FIRMWARE_MAX_SZ + 2 * SECTOR_SZ,
+    flash::FIRMWARE_MAX_SZ + 3 * SECTOR_SZ,
+];
+const MAGIC: [u8; 4] = *b"MRSN";
+// A day number, and the consumed and produced counters for each tariff, as
+// little-endian words.
+const PERIOD_WORDS: usize = 1 + 2 * TARIFFS;
+
+/// Persists snapshots in flash.
+pub struct SnapshotStore {
+    log: PageLog,
+}
+
+impl SnapshotStore {
+    pub fn load() -> (Self, Option<Snapshot>) {
+        let (log, payload) = PageLog::load(BANK_OFFSETS, MAGIC);
+        let snapshot = payload.map(|payload| decode(&payload));
+        if let Some(snapshot) = &snapshot {
+            log::info!("Loaded snapshot: {:?}", snapshot);
+        }
+        (Self { log }, snapshot)
+    }
+
+    pub fn save(&mut self, snapshot: &Snapshot) -> Result<(), FlashError> {
+        self.log.save(&encode(snapshot))
+    }
+}
+
+fn encode(snapshot: &Snapshot) -> Payload {
+    let mut words = ArrayVec::<u32, { 2 * PERIOD_WORDS }>::new();
+    for (day, counters) in [
+        (snapshot.day, snapshot.day_counters),
+        (snapshot.week, snapshot.week_counters),
+    ]
+    .iter()
+    {
+        words.push(*day as u32);
+        words.extend(
+            counters
+                .consumed
+                .iter()
+                .chain(counters.produced.iter())
+                .copied(),
+        );
+    }
+    let mut payload = [0xFF; PAYLOAD_SZ];
+    for (chunk, word) in payload.chunks_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    payload
+}
+
+fn decode(payload: &Payload) -> Snapshot {
+    let word = |index: usize| {
+        let b = &payload[4 * index..4 * index + 4];
+        u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+    };
+    let period = |start: usize| {
+        let mut counters = Counters::default();
+        for (index, wh) in counters
+            .consumed
+            .iter_mut()
+            .chain(counters.produced.iter_mut())
+            .enumerate()
+        {
+            *wh = word(start + 1 + index);
+        }
+        (word(start) as i32 as i64, counters)
+    };
+    let (day, day_counters) = period(0);
+    let (week, week_counters) = period(PERIOD_WORDS);
+    Snapshot {
+        day,
+        day_counters,
+        week,
+        week_counters,
+    }
+}
@@ -1,42 +1,604 @@
+mod data_request;
+#[cfg(feature = "dma-uart")]
+mod dma;
+mod retransmit;
+
 use core::cmp;
 
+use arrayvec::ArrayVec;
+use embedded_hal::digital::v2::OutputPin;
+
+#[cfg(not(feature = "dma-uart"))]
 use embedded_hal::serial::Read;
-use teensy4_bsp::hal::{iomuxc::prelude::consts, uart::UART};
+#[cfg(all(not(feature = "dma-uart"), not(feature = "sim")))]
+use teensy4_bsp::hal::uart::ReadErrorFlags;
+#[cfg(not(feature = "sim"))]
+use teensy4_bsp::hal::{
+    iomuxc::prelude::consts,
+    uart::{self, UART},
+};
+
+use crate::fault::{self, Action, FaultPolicy, Severity, Subsystem};
+#[cfg(feature = "sim")]
+use crate::sim::uart::{self, ReadErrorFlags};
+
+/// The UART the meter is connected to.
+#[cfg(not(feature = "sim"))]
+pub type Port = UART<consts::U2>;
+#[cfg(feature = "sim")]
+pub use crate::sim::uart::ReplayPort as Port;
+
+pub use data_request::{DataRequest, DataRequestMode, NoDataRequest};
+use retransmit::Retransmitter;
+
+pub const READ_BUF_SZ: usize = 1024;
+
+// DSMR 2 to 4 meters send a telegram every 10 seconds, so give each
+// configuration long enough to see at least one complete telegram.
+const PROBE_TIMEOUT_MS: i64 = 25_000;
+// If no telegram has been received in this time, assume the meter has
+// stalled, possibly in the middle of a telegram. Once the meter's interval is
+// known, the timeout is a few intervals instead, but never less than the minimum.
+const STALL_TIMEOUT_MS: i64 = 30_000;
+const STALL_INTERVALS: i64 = 3;
+const MIN_STALL_TIMEOUT_MS: i64 = 5_000;
+// Maximum number of telegram starts in the read buffer we keep track of.
+const MAX_TELEGRAM_STARTS: usize = 4;
+// Time the rest of the firmware gets to reset once the watchdog asks for it,
+// before the watchdog resets by itself.
+const RESET_GRACE_MS: i64 = 10_000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DataBits {
+    Seven,
+    Eight,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Serial settings for the P1 port.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct UartConfig {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    /// Set this if the signal is not inverted by external hardware.
+    pub inverted: bool,
+}
+
+impl UartConfig {
+    /// DSMR 4 and later: 115200 baud, 8N1.
+    pub const DSMR_4: UartConfig = UartConfig {
+        baud: 115200,
+        data_bits: DataBits::Eight,
+        parity: Parity::None,
+        inverted: false,
+    };
+
+    /// DSMR 2.x and 3: 9600 baud, 7E1.
+    pub const DSMR_2: UartConfig = UartConfig {
+        baud: 9600,
+        data_bits: DataBits::Seven,
+        parity: Parity::Even,
+        inverted: false,
+    };
+
+    /// All configurations tried when probing, in order.
+    const PROBE_CANDIDATES: [UartConfig; 4] = [
+        UartConfig::DSMR_4,
+        UartConfig {
+            inverted: true,
+            ..UartConfig::DSMR_4
+        },
+        UartConfig::DSMR_2,
+        UartConfig {
+            inverted: true,
+            ..UartConfig::DSMR_2
+        },
+    ];
+}
+
+/// State of an in-progress configuration probe.
+struct Probe {
+    candidate: usize,
+    switch_at: i64,
+}
+
+/// Line errors detected on a single read.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct LineErrors {
+    pub overrun: bool,
+    pub framing: bool,
+    pub parity: bool,
+    pub noise: bool,
+}
+
+impl LineErrors {
+    fn any(&self) -> bool {
+        self.overrun || self.framing || self.parity || self.noise
+    }
+}
+
+/// Running totals of line errors, for diagnostics.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct UartStats {
+    pub overruns: u32,
+    pub framing_errors: u32,
+    pub parity_errors: u32,
+    pub noise_errors: u32,
+    pub buffer_overflows: u32,
+    pub meter_stalls: u32,
+}
+
+impl UartStats {
+    fn line_errors(&self) -> u32 {
+        self.overruns + self.framing_errors + self.parity_errors + self.noise_errors
+    }
+}
 
-const READ_BUF_SZ: usize = 1024;
+/// Recovers from a UART that stops receiving, by reinitialising it once no
+/// valid telegram has come in for a while, and resetting if that doesn't
+/// help either. The reset is asked for first, so the MQTT client can say
+/// goodbye to the broker, and only forced if that takes too long.
+///
+/// Only armed once a telegram has been received, so a reader without a meter
+/// doesn't keep resetting. While line errors keep being counted, data is
+/// coming in, so the meter or the cable is to blame and nothing is done.
+struct Watchdog {
+    timeout_ms: i64,
+    // Start of the current wait for a telegram, once armed.
+    since: Option<i64>,
+    line_errors: u32,
+    reinitialised: bool,
+    // When a reset was asked for, and whether that's been passed on.
+    reset_requested_at: Option<i64>,
+    reset_taken: bool,
+}
 
-pub struct DsmrUart {
-    uart: UART<consts::U2>,
+pub struct DsmrUart<R> {
+    #[cfg_attr(feature = "dma-uart", allow(dead_code))]
+    uart: Port,
+    #[cfg(feature = "dma-uart")]
+    rx: dma::PingPongReceiver,
+    // Set when a DMA buffer has been received, until `data_ready()` is called.
+    #[cfg(feature = "dma-uart")]
+    new_data: bool,
     read_buffer: [u8; READ_BUF_SZ],
     read_buffer_pos: usize,
+    // Most bytes ever held in the read buffer.
+    read_buffer_peak: usize,
+    config: UartConfig,
+    stats: UartStats,
+    // Set after a line error, to drop data until the start of the next telegram.
+    resync: bool,
+    faults: FaultPolicy,
+    probe: Option<Probe>,
+    data_request: DataRequest<R>,
+    // Only set if telegrams are sent on to another P1 consumer.
+    retransmitter: Option<Retransmitter>,
+    last_telegram: i64,
+    stall_timeout_ms: i64,
+    watchdog: Watchdog,
+    // Receive times of each telegram start marker in the read buffer.
+    telegram_starts: ArrayVec<i64, MAX_TELEGRAM_STARTS>,
 }
 
-impl DsmrUart {
-    pub fn new(mut uart: UART<consts::U2>) -> Self {
+impl<R: OutputPin> DsmrUart<R> {
+    /// With the `dma-uart` feature, the DMA clock must be enabled before
+    /// calling this.
+    pub fn new(mut uart: Port, config: UartConfig, data_request: DataRequest<R>) -> Self {
         uart.set_rx_fifo(true);
-        Self {
+        let mut dsmr_uart = Self {
             uart,
+            #[cfg(feature = "dma-uart")]
+            rx: dma::PingPongReceiver::start(),
+            #[cfg(feature = "dma-uart")]
+            new_data: false,
             read_buffer: [0; READ_BUF_SZ],
             read_buffer_pos: 0,
+            read_buffer_peak: 0,
+            config,
+            stats: UartStats::default(),
+            resync: false,
+            faults: FaultPolicy::new(Subsystem::Uart),
+            probe: None,
+            data_request,
+            retransmitter: None,
+            last_telegram: 0,
+            stall_timeout_ms: STALL_TIMEOUT_MS,
+            watchdog: Watchdog {
+                timeout_ms: 0,
+                since: None,
+                line_errors: 0,
+                reinitialised: false,
+                reset_requested_at: None,
+                reset_taken: false,
+            },
+            telegram_starts: ArrayVec::new(),
+        };
+        dsmr_uart.configure(config);
+        dsmr_uart
+    }
+
+    /// Applies new serial settings, discarding any data received so far.
+    pub fn configure(&mut self, config: UartConfig) {
+        log::info!("Configuring UART: {:?}", config);
+        if let Err(err) = self.uart.set_baud(config.baud) {
+            log::error!("Failed to set baud rate to {}: {:?}", config.baud, err);
+        }
+        // The LPUART only supports parity on 8 data bits. 7-bit frames with
+        // parity are the same length as 8-bit frames without, so we receive
+        // those as 8N1 and check the parity ourselves.
+        let parity = match (config.data_bits, config.parity) {
+            (DataBits::Eight, Parity::Even) => Some(uart::Parity::Even),
+            (DataBits::Eight, Parity::Odd) => Some(uart::Parity::Odd),
+            _ => None,
+        };
+        self.uart.set_parity(parity);
+        self.uart.set_rx_inversion(config.inverted);
+        retransmit::set_inversion(config.inverted);
+        self.config = config;
+        self.clear();
+    }
+
+    pub fn config(&self) -> UartConfig {
+        self.config
+    }
+
+    /// Enables sending valid telegrams on to another P1 consumer, on the
+    /// TX pin of the UART.
+    pub fn set_retransmit(&mut self, enable: bool) {
+        self.retransmitter = match enable {
+            true => Some(Retransmitter::new()),
+            false => None,
+        };
+    }
+
+    /// Reinitialises the UART if no valid telegram has been received for
+    /// `timeout_mins`, and resets if there still isn't one after as long
+    /// again. The timeout is never shorter than the stall timeout. 0 disables
+    /// this.
+    pub fn set_watchdog(&mut self, timeout_mins: u16) {
+        self.watchdog.timeout_ms = timeout_mins as i64 * 60 * 1000;
+    }
+
+    /// Returns `true` once when the watchdog wants the firmware to reset.
+    pub fn take_reset_request(&mut self) -> bool {
+        let watchdog = &mut self.watchdog;
+        let requested = watchdog.reset_requested_at.is_some() && !watchdog.reset_taken;
+        watchdog.reset_taken |= requested;
+        requested
+    }
+
+    /// Queues a valid telegram for sending on, if that's enabled.
+    pub fn retransmit(&mut self, telegram: &[u8]) {
+        if let Some(retransmitter) = &mut self.retransmitter {
+            retransmitter.queue(telegram, self.config);
+        }
+    }
+
+    /// Writes as much of the telegram being sent on as the UART has room
+    /// for. Called from the UART interrupt, which the transmitter triggers
+    /// as well while there's something to send.
+    pub fn poll_tx(&mut self) {
+        if let Some(retransmitter) = &mut self.retransmitter {
+            retransmitter.poll(&mut self.uart);
+        }
+    }
+
+    /// Starts cycling through the common P1 configurations, starting with
+    /// the current one, until a telegram is received.
+    pub fn start_probe(&mut self, now: i64) {
+        log::info!("Probing for P1 serial configuration");
+        let candidate = UartConfig::PROBE_CANDIDATES
+            .iter()
+            .position(|c| *c == self.config)
+            .unwrap_or(0);
+        self.probe = Some(Probe {
+            candidate,
+            switch_at: now + PROBE_TIMEOUT_MS,
+        });
+        self.configure(UartConfig::PROBE_CANDIDATES[candidate]);
+    }
+
+    /// Drives everything that happens on a timer: probing and requesting
+    /// telegrams from the meter.
+    pub fn poll_timers(&mut self, now: i64) {
+        self.poll_probe(now);
+        self.poll_stall(now);
+        self.poll_watchdog(now);
+        self.data_request.poll(now);
+    }
+
+    /// Call this once a telegram with a valid CRC has been received.
+    pub fn telegram_received(&mut self, now: i64) {
+        self.last_telegram = now;
+        self.watchdog.since = Some(now);
+        self.watchdog.reinitialised = false;
+        self.faults.succeeded();
+        self.lock();
+        self.data_request.telegram_received();
+    }
+
+    /// Adjusts the stall timeout to the interval at which the meter sends
+    /// telegrams.
+    pub fn set_meter_interval(&mut self, interval_secs: u32) {
+        self.stall_timeout_ms =
+            (interval_secs as i64 * 1000 * STALL_INTERVALS).max(MIN_STALL_TIMEOUT_MS);
+    }
+
+    /// Discards partial telegrams if the meter has gone silent, so they
+    /// don't sit in the buffer forever, and tries to wake the meter up.
+    fn poll_stall(&mut self, now: i64) {
+        if now - self.last_telegram < self.stall_timeout_ms {
+            return;
+        }
+        self.stats.meter_stalls += 1;
+        log::warn!(
+            "No telegram received in {} seconds, discarding {} buffered bytes",
+            (now - self.last_telegram) / 1000,
+            self.read_buffer_pos
+        );
+        self.last_telegram = now;
+        self.clear();
+        self.resync_meter(now);
+    }
+
+    fn poll_watchdog(&mut self, now: i64) {
+        let watchdog = &mut self.watchdog;
+        let since = match watchdog.since {
+            Some(since) if watchdog.timeout_ms > 0 => since,
+            _ => return,
+        };
+        let line_errors = self.stats.line_errors();
+        if line_errors != watchdog.line_errors {
+            watchdog.line_errors = line_errors;
+            watchdog.since = Some(now);
+            return;
+        }
+        if now - since < watchdog.timeout_ms.max(self.stall_timeout_ms) {
+            return;
+        }
+        if watchdog.reinitialised {
+            match watchdog.reset_requested_at {
+                None => {
+                    log::error!("No valid telegram since reinitialising UART, resetting");
+                    watchdog.reset_requested_at = Some(now);
+                }
+                Some(at) if now - at >= RESET_GRACE_MS => {
+                    fault::fatal(Subsystem::Uart, &"no valid telegram since reinitialising")
+                }
+                Some(_) => {}
+            }
+            return;
         }
+        log::warn!(
+            "No valid telegram in {} minutes, reinitialising UART",
+            (now - since) / 60_000
+        );
+        watchdog.since = Some(now);
+        watchdog.reinitialised = true;
+        self.resync = false;
+        self.configure(self.config);
     }
 
-    pub fn poll(&mut self) {
+    /// Toggles the Data Request line to get a wedged meter to start sending
+    /// telegrams again.
+    pub fn resync_meter(&mut self, now: i64) {
+        self.data_request.resync(now);
+    }
+
+    /// Moves on to the next configuration if the current one hasn't produced
+    /// a valid telegram in time.
+    fn poll_probe(&mut self, now: i64) {
+        let probe = match &mut self.probe {
+            Some(probe) if now >= probe.switch_at => probe,
+            _ => return,
+        };
+        probe.candidate = (probe.candidate + 1) % UartConfig::PROBE_CANDIDATES.len();
+        probe.switch_at = now + PROBE_TIMEOUT_MS;
+        let config = UartConfig::PROBE_CANDIDATES[probe.candidate];
+        log::info!("No valid telegram received, trying next configuration");
+        self.configure(config);
+    }
+
+    /// Ends probing, keeping the current configuration.
+    fn lock(&mut self) {
+        if self.probe.take().is_some() {
+            log::info!("Locked onto P1 serial configuration {:?}", self.config);
+        }
+    }
+
+    #[cfg(feature = "dma-uart")]
+    pub fn poll(&mut self, now: i64) {
+        // The DMA engine doesn't tell us which byte an error occurred on,
+        // so discard everything we've got so far.
+        let errors = self.rx.take_errors();
+        if errors.any() {
+            self.handle_errors(errors);
+        }
+        // Only swap once the meter has finished sending, so the parser sees
+        // complete telegrams, unless the DMA buffer is about to overflow.
+        if !self.rx.take_idle() && !self.rx.is_full() {
+            return;
+        }
+        if let Some(filled) = self.rx.swap() {
+            self.receive_all(&filled, now);
+            self.rx.release(filled);
+            self.new_data = true;
+        }
+    }
+
+    /// Appends a DMA buffer to the read buffer, copying it in one go if
+    /// no bytes need to be checked or dropped.
+    #[cfg(feature = "dma-uart")]
+    fn receive_all(&mut self, data: &[u8], now: i64) {
+        let end = self.read_buffer_pos + data.len();
+        if self.config.data_bits != DataBits::Eight || self.resync || end > READ_BUF_SZ {
+            for b in data {
+                self.receive(*b, now);
+            }
+            return;
+        }
+        for _ in data.iter().filter(|b| **b == b'/') {
+            if self.telegram_starts.try_push(now).is_err() {
+                log::debug!("Too many telegram starts in buffer, not timestamping");
+            }
+        }
+        self.read_buffer[self.read_buffer_pos..end].copy_from_slice(data);
+        self.read_buffer_pos = end;
+        self.read_buffer_peak = cmp::max(self.read_buffer_peak, end);
+    }
+
+    #[cfg(not(feature = "dma-uart"))]
+    pub fn poll(&mut self, now: i64) {
         loop {
             match self.uart.read() {
-                Ok(b) => {
-                    self.read_buffer[self.read_buffer_pos] = b;
-                    self.read_buffer_pos += 1;
-                }
+                Ok(b) => self.receive(b, now),
                 Err(nb::Error::WouldBlock) => break,
                 Err(nb::Error::Other(e)) => {
-                    log::warn!("Error during polling: {:?}", e);
+                    self.handle_errors(LineErrors {
+                        overrun: e.flags.contains(ReadErrorFlags::OVERRUN),
+                        framing: e.flags.contains(ReadErrorFlags::FRAME_ERROR),
+                        parity: e.flags.contains(ReadErrorFlags::PARITY),
+                        noise: e.flags.contains(ReadErrorFlags::NOISY),
+                    });
                     break;
                 }
             }
         }
     }
 
+    /// Strips and checks the software parity bit for 7-bit frames.
+    fn receive(&mut self, b: u8, now: i64) {
+        if self.config.data_bits == DataBits::Eight {
+            self.push(b, now);
+            return;
+        }
+        let parity_ok = match self.config.parity {
+            Parity::None => true,
+            Parity::Even => b.count_ones() % 2 == 0,
+            Parity::Odd => b.count_ones() % 2 == 1,
+        };
+        if parity_ok {
+            self.push(b & 0x7F, now);
+        } else {
+            self.handle_errors(LineErrors {
+                parity: true,
+                ..LineErrors::default()
+            });
+        }
+    }
+
+    fn push(&mut self, b: u8, now: i64) {
+        if self.resync {
+            if b != b'/' {
+                return;
+            }
+            log::debug!("Found start of next telegram, resuming reception");
+            self.resync = false;
+        }
+        if self.read_buffer_pos == READ_BUF_SZ {
+            self.handle_overflow();
+        }
+        if b == b'/' && self.telegram_starts.try_push(now).is_err() {
+            log::debug!("Too many telegram starts in buffer, not timestamping");
+        }
+        self.read_buffer[self.read_buffer_pos] = b;
+        self.read_buffer_pos += 1;
+        self.read_buffer_peak = cmp::max(self.read_buffer_peak, self.read_buffer_pos);
+    }
+
+    /// The time (in `Clock` milliseconds) at which the first byte of the
+    /// telegram at the start of the buffer was received.
+    ///
+    /// Bytes are timestamped when they are taken from the UART, which may be
+    /// a few milliseconds after they actually arrived.
+    pub fn telegram_received_at(&self) -> Option<i64> {
+        match self.read_buffer.first() {
+            Some(b'/') if self.read_buffer_pos > 0 => self.telegram_starts.first().copied(),
+            _ => None,
+        }
+    }
+
+    /// Makes room in a full read buffer by discarding everything before the
+    /// start of the most recent telegram, or everything if that telegram
+    /// already fills the entire buffer.
+    fn handle_overflow(&mut self) {
+        self.stats.buffer_overflows += 1;
+        // This tends to happen on every telegram once it happens at all
+        // (e.g. with the wrong baud rate), so only warn about it once.
+        if self.stats.buffer_overflows == 1 {
+            log::warn!("UART read buffer overflowed, discarding data");
+        } else {
+            log::debug!("UART read buffer overflowed, discarding data");
+        }
+        match self.read_buffer.iter().rposition(|b| *b == b'/') {
+            Some(start) if start > 0 => self.consume(start),
+            _ => self.clear(),
+        }
+    }
+
+    /// Counts the errors, and discards the telegram they occurred in.
+    fn handle_errors(&mut self, errors: LineErrors) {
+        log::warn!("UART line error: {:?}", errors);
+        self.stats.overruns += errors.overrun as u32;
+        self.stats.framing_errors += errors.framing as u32;
+        self.stats.parity_errors += errors.parity as u32;
+        self.stats.noise_errors += errors.noise as u32;
+        self.clear();
+        self.resync = true;
+        if self.faults.failed(Severity::Recoverable, &errors) == Action::Reinit {
+            self.configure(self.config);
+        }
+    }
+
+    pub fn stats(&self) -> UartStats {
+        self.stats
+    }
+
+    /// Most bytes ever held in the read buffer, out of `READ_BUF_SZ`.
+    pub fn buffer_peak(&self) -> usize {
+        self.read_buffer_peak
+    }
+
+    /// Enables or disables the receive interrupt, which fires as soon as
+    /// a byte is waiting in the FIFO.
+    #[cfg(not(feature = "dma-uart"))]
+    pub fn set_interrupt_enable(&mut self, enable: bool) {
+        self.uart
+            .set_receiver_interrupt(if enable { Some(0) } else { None });
+    }
+
+    /// Enables or disables the idle line interrupt, which fires once the
+    /// meter has finished sending a burst of data.
+    #[cfg(feature = "dma-uart")]
+    pub fn set_interrupt_enable(&mut self, enable: bool) {
+        self.rx.set_idle_interrupt_enable(enable);
+    }
+
+    /// Returns `true` if new data may be available for parsing.
+    ///
+    /// With DMA reception, this is only the case once the line has gone idle,
+    /// so we don't try to parse a telegram while it's still coming in.
+    #[cfg(feature = "dma-uart")]
+    pub fn data_ready(&mut self, now: i64) -> bool {
+        // The line may have gone idle after the last poll, so make sure
+        // we've got the tail end of the telegram as well.
+        self.poll(now);
+        core::mem::replace(&mut self.new_data, false)
+    }
+
+    #[cfg(not(feature = "dma-uart"))]
+    pub fn data_ready(&mut self, _now: i64) -> bool {
+        true
+    }
+
     pub fn get_buffer(&self) -> &[u8] {
         &self.read_buffer[..self.read_buffer_pos]
     }
@@ -44,6 +606,12 @@ impl DsmrUart {
     /// Advances the read buffer by `count` bytes.
     pub fn consume(&mut self, count: usize) {
         let count = cmp::min(count, self.read_buffer_pos);
+        let starts = self.read_buffer[..count]
+            .iter()
+            .filter(|b| **b == b'/')
+            .count();
+        self.telegram_starts
+            .drain(..cmp::min(starts, self.telegram_starts.len()));
         self.read_buffer.copy_within(count.., 0);
         self.read_buffer_pos -= count;
     }
@@ -51,5 +619,6 @@ impl DsmrUart {
     pub fn clear(&mut self) {
         self.read_buffer = [0; READ_BUF_SZ];
         self.read_buffer_pos = 0;
+        self.telegram_starts.clear();
     }
 }
@@ -0,0 +1,104 @@
+use core::convert::Infallible;
+
+use embedded_hal::digital::v2::OutputPin;
+
+// How long to keep the line low when resyncing, so the meter reliably
+// notices the request being withdrawn.
+const RESYNC_LOW_MS: i64 = 1000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DataRequestMode {
+    /// Keep the line raised, so the meter sends telegrams as often as it can.
+    Continuous,
+    /// Raise the line every `interval_ms`, and lower it again as soon as
+    /// a telegram has been received.
+    Interval { interval_ms: i64 },
+}
+
+/// For installations where the Data Request line is permanently tied high.
+pub struct NoDataRequest;
+
+impl OutputPin for NoDataRequest {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Drives the P1 Data Request line. The meter only sends telegrams while
+/// this line is high.
+pub struct DataRequest<R> {
+    pin: R,
+    mode: DataRequestMode,
+    raised: bool,
+    next_request: i64,
+    resync_until: Option<i64>,
+}
+
+impl<R: OutputPin> DataRequest<R> {
+    pub fn new(pin: R, mode: DataRequestMode) -> Self {
+        let mut data_request = Self {
+            pin,
+            mode,
+            raised: false,
+            next_request: 0,
+            resync_until: None,
+        };
+        data_request.set(mode == DataRequestMode::Continuous);
+        data_request
+    }
+
+    pub fn poll(&mut self, now: i64) {
+        if let Some(until) = self.resync_until {
+            if now < until {
+                return;
+            }
+            log::debug!("Resync done, raising Data Request");
+            self.resync_until = None;
+            self.next_request = now;
+            if self.mode == DataRequestMode::Continuous {
+                self.set(true);
+            }
+        }
+        if let DataRequestMode::Interval { interval_ms } = self.mode {
+            if now >= self.next_request {
+                self.next_request = now + interval_ms;
+                self.set(true);
+            }
+        }
+    }
+
+    pub fn telegram_received(&mut self) {
+        if let DataRequestMode::Interval { .. } = self.mode {
+            self.set(false);
+        }
+    }
+
+    /// Lowers the line for a while before raising it again, which gets some
+    /// meters out of a state in which they stopped sending telegrams.
+    pub fn resync(&mut self, now: i64) {
+        log::info!("Resyncing meter, lowering Data Request");
+        self.set(false);
+        self.resync_until = Some(now + RESYNC_LOW_MS);
+    }
+
+    fn set(&mut self, high: bool) {
+        if high == self.raised {
+            return;
+        }
+        let res = if high {
+            self.pin.set_high()
+        } else {
+            self.pin.set_low()
+        };
+        match res {
+            Ok(()) => self.raised = high,
+            Err(_) => log::warn!("Failed to drive Data Request line"),
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use core::{
+    ops::Deref,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::LineErrors;
+
+// Size of each of the two receive buffers. Should fit an entire telegram.
+pub const HALF_SZ: usize = 1024;
+
+// Reserved for UART reception; nothing else may use this channel.
+const DMA_CHANNEL: usize = 7;
+const DMAMUX_SOURCE_LPUART2_RX: u32 = 67;
+
+const DMA0: usize = 0x400E_8000;
+const DMA_SERQ: usize = DMA0 + 0x1B;
+const DMA_CERQ: usize = DMA0 + 0x1A;
+const DMA_TCD: usize = DMA0 + 0x1000 + 32 * DMA_CHANNEL;
+const TCD_SADDR: usize = DMA_TCD;
+const TCD_SOFF: usize = DMA_TCD + 0x04;
+const TCD_ATTR: usize = DMA_TCD + 0x06;
+const TCD_NBYTES: usize = DMA_TCD + 0x08;
+const TCD_SLAST: usize = DMA_TCD + 0x0C;
+const TCD_DADDR: usize = DMA_TCD + 0x10;
+const TCD_DOFF: usize = DMA_TCD + 0x14;
+const TCD_CITER: usize = DMA_TCD + 0x16;
+const TCD_DLAST_SGA: usize = DMA_TCD + 0x18;
+const TCD_CSR: usize = DMA_TCD + 0x1C;
+const TCD_BITER: usize = DMA_TCD + 0x1E;
+const CITER_MASK: u16 = 0x7FFF;
+const CSR_DREQ: u16 = 1 << 3;
+const CSR_ACTIVE: u16 = 1 << 6;
+const CSR_DONE: u16 = 1 << 7;
+
+const DMAMUX_CHCFG: usize = 0x400E_C000 + 4 * DMA_CHANNEL;
+const DMAMUX_ENBL: u32 = 1 << 31;
+
+const LPUART2: usize = 0x4018_8000;
+const LPUART_BAUD: usize = LPUART2 + 0x10;
+const LPUART_STAT: usize = LPUART2 + 0x14;
+const LPUART_CTRL: usize = LPUART2 + 0x18;
+const LPUART_DATA: usize = LPUART2 + 0x1C;
+const BAUD_RDMAE: u32 = 1 << 21;
+const STAT_IDLE: u32 = 1 << 20;
+const STAT_OR: u32 = 1 << 19;
+const STAT_NF: u32 = 1 << 18;
+const STAT_FE: u32 = 1 << 17;
+const STAT_PF: u32 = 1 << 16;
+const CTRL_ILIE: u32 = 1 << 20;
+// Start counting idle characters after the stop bit, and require 4 of them
+// before flagging the line as idle.
+const CTRL_IDLECFG_4: u32 = 0b010 << 8;
+const CTRL_ILT: u32 = 1 << 2;
+
+static mut HALVES: [[u8; HALF_SZ]; 2] = [[0; HALF_SZ]; 2];
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Double-buffered DMA reception for LPUART2.
+///
+/// The eDMA channel copies received bytes into one half, while the other
+/// half is available to the parser. Once the line goes idle, or the half
+/// being filled is full, the halves are swapped. Nothing is copied out of
+/// DMA memory byte by byte, and the UART keeps being drained even if the
+/// main loop stalls.
+///
+/// The imxrt-hal DMA API only supports one-shot transfers into buffers it
+/// owns, so the channel is programmed through its registers directly.
+pub struct PingPongReceiver {
+    // Owned by the DMA engine, so never dereferenced by us.
+    filling: *mut u8,
+    // The half that will be filled next. `None` while it's handed out.
+    spare: Option<&'static mut [u8; HALF_SZ]>,
+}
+
+/// A half that has been filled by the DMA engine. Hand it back through
+/// `PingPongReceiver::release()` once done with it.
+pub struct Filled {
+    buf: &'static mut [u8; HALF_SZ],
+    len: usize,
+}
+
+impl Deref for Filled {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl PingPongReceiver {
+    /// Starts reception. The DMA clock must already be enabled, and LPUART2
+    /// must already be configured for reception.
+    ///
+    /// Panics if called more than once.
+    pub fn start() -> Self {
+        if STARTED.swap(true, Ordering::AcqRel) {
+            panic!("DMA reception already started");
+        }
+        // Safe, because we've just made sure this only happens once.
+        let [first, second] = unsafe { &mut *ptr::addr_of_mut!(HALVES) };
+        let mut receiver = Self {
+            filling: first.as_mut_ptr(),
+            spare: Some(second),
+        };
+        unsafe {
+            write_u8(DMA_CERQ, DMA_CHANNEL as u8);
+
+            write_u32(TCD_SADDR, LPUART_DATA as u32);
+            write_u16(TCD_SOFF, 0);
+            // 8-bit source and destination transfers.
+            write_u16(TCD_ATTR, 0);
+            write_u32(TCD_NBYTES, 1);
+            write_u32(TCD_SLAST, 0);
+            write_u16(TCD_DOFF, 1);
+            write_u32(TCD_DLAST_SGA, 0);
+
+            write_u32(DMAMUX_CHCFG, 0);
+            write_u32(DMAMUX_CHCFG, DMAMUX_ENBL | DMAMUX_SOURCE_LPUART2_RX);
+
+            modify_u32(LPUART_CTRL, |ctrl| ctrl | CTRL_IDLECFG_4 | CTRL_ILT);
+            modify_u32(LPUART_BAUD, |baud| baud | BAUD_RDMAE);
+            receiver.arm(receiver.filling);
+        }
+        log::debug!(
+            "Started double-buffered DMA reception on channel {}",
+            DMA_CHANNEL
+        );
+        receiver
+    }
+
+    /// Points the channel at the start of `buf`, and enables requests.
+    unsafe fn arm(&mut self, buf: *mut u8) {
+        write_u32(TCD_DADDR, buf as u32);
+        write_u16(TCD_CITER, HALF_SZ as u16);
+        write_u16(TCD_BITER, HALF_SZ as u16);
+        // No interrupts, and stop once the half is full instead of
+        // overwriting it. This also clears DONE.
+        write_u16(TCD_CSR, CSR_DREQ);
+        write_u8(DMA_SERQ, DMA_CHANNEL as u8);
+    }
+
+    /// Returns `true` if the half being filled has no room left.
+    pub fn is_full(&self) -> bool {
+        unsafe { read_u16(TCD_CSR) & CSR_DONE != 0 }
+    }
+
+    /// Hands the half that was being filled to the caller, and continues
+    /// reception into the other one.
+    ///
+    /// Returns `None` if the other half hasn't been released yet.
+    pub fn swap(&mut self) -> Option<Filled> {
+        let next = self.spare.take()?;
+        unsafe {
+            write_u8(DMA_CERQ, DMA_CHANNEL as u8);
+            while read_u16(TCD_CSR) & CSR_ACTIVE != 0 {}
+            // CITER is reloaded from BITER at the end of the major loop.
+            let len = if self.is_full() {
+                HALF_SZ
+            } else {
+                HALF_SZ - (read_u16(TCD_CITER) & CITER_MASK) as usize
+            };
+            let filled = self.filling;
+            self.filling = next.as_mut_ptr();
+            self.arm(self.filling);
+            Some(Filled {
+                // The DMA engine no longer writes to this half, and it is
+                // the only other half besides `next`.
+                buf: &mut *(filled as *mut [u8; HALF_SZ]),
+                len,
+            })
+        }
+    }
+
+    /// Makes a filled half available for reception again.
+    pub fn release(&mut self, filled: Filled) {
+        self.spare = Some(filled.buf);
+    }
+
+    /// Returns `true` once if the line went idle after receiving data,
+    /// which usually means the meter has finished sending a telegram.
+    pub fn take_idle(&mut self) -> bool {
+        unsafe {
+            if read_u32(LPUART_STAT) & STAT_IDLE != 0 {
+                // IDLE is write-1-to-clear, and so are some of the other flags,
+                // so only write back the IDLE bit.
+                write_u32(LPUART_STAT, STAT_IDLE);
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    /// Reads and clears the line error flags.
+    pub fn take_errors(&mut self) -> LineErrors {
+        unsafe {
+            let stat = read_u32(LPUART_STAT) & (STAT_OR | STAT_NF | STAT_FE | STAT_PF);
+            if stat != 0 {
+                write_u32(LPUART_STAT, stat);
+            }
+            LineErrors {
+                overrun: stat & STAT_OR != 0,
+                framing: stat & STAT_FE != 0,
+                parity: stat & STAT_PF != 0,
+                noise: stat & STAT_NF != 0,
+            }
+        }
+    }
+
+    /// Enables or disables the idle line interrupt, which fires once a burst
+    /// of data has been received.
+    pub fn set_idle_interrupt_enable(&mut self, enable: bool) {
+        unsafe {
+            modify_u32(LPUART_CTRL, |ctrl| {
+                if enable {
+                    ctrl | CTRL_ILIE
+                } else {
+                    ctrl & !CTRL_ILIE
+                }
+            });
+        }
+    }
+}
+
+unsafe fn read_u16(addr: usize) -> u16 {
+    ptr::read_volatile(addr as *const u16)
+}
+
+unsafe fn read_u32(addr: usize) -> u32 {
+    ptr::read_volatile(addr as *const u32)
+}
+
+unsafe fn write_u8(addr: usize, val: u8) {
+    ptr::write_volatile(addr as *mut u8, val)
+}
+
+unsafe fn write_u16(addr: usize, val: u16) {
+    ptr::write_volatile(addr as *mut u16, val)
+}
+
+unsafe fn write_u32(addr: usize, val: u32) {
+    ptr::write_volatile(addr as *mut u32, val)
+}
+
+unsafe fn modify_u32(addr: usize, f: impl FnOnce(u32) -> u32) {
+    write_u32(addr, f(read_u32(addr)))
+}
@@ -0,0 +1,99 @@
+#[cfg(not(feature = "sim"))]
+use core::ptr;
+
+use arrayvec::ArrayVec;
+use embedded_hal::serial::Write;
+
+use super::{DataBits, Parity, UartConfig, READ_BUF_SZ};
+
+#[cfg(not(feature = "sim"))]
+const LPUART2: usize = 0x4018_8000;
+#[cfg(not(feature = "sim"))]
+const LPUART_CTRL: usize = LPUART2 + 0x18;
+#[cfg(not(feature = "sim"))]
+const CTRL_TXINV: u32 = 1 << 28;
+#[cfg(not(feature = "sim"))]
+const CTRL_TIE: u32 = 1 << 23;
+
+/// Sends valid telegrams back out on the TX pin of the meter's UART, with
+/// the same serial settings, so another P1 consumer can be connected as if
+/// through a splitter.
+///
+/// Bytes are written as the transmit interrupt asks for them, so sending
+/// doesn't hold up anything else. If the meter sends faster than the
+/// telegrams can be sent on, the ones that don't fit are skipped rather than
+/// sent in pieces.
+pub struct Retransmitter {
+    buffer: ArrayVec<u8, READ_BUF_SZ>,
+    pos: usize,
+}
+
+impl Retransmitter {
+    pub fn new() -> Self {
+        Self {
+            buffer: ArrayVec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Queues a telegram, unless the previous one is still being sent.
+    pub fn queue(&mut self, telegram: &[u8], config: UartConfig) {
+        if self.pos < self.buffer.len() {
+            log::debug!("Still retransmitting the previous telegram, skipping this one");
+            return;
+        }
+        self.buffer.clear();
+        self.pos = 0;
+        // The parity bit of 7-bit frames is checked and stripped on
+        // reception, so it has to be added back.
+        let parity_bit = |b: u8| match (config.data_bits, config.parity) {
+            (DataBits::Seven, Parity::Even) => (b.count_ones() % 2 == 1) as u8,
+            (DataBits::Seven, Parity::Odd) => (b.count_ones() % 2 == 0) as u8,
+            _ => 0,
+        };
+        for b in telegram {
+            let _ = self.buffer.try_push(b | parity_bit(*b) << 7);
+        }
+        set_transmit_interrupt(true);
+    }
+
+    /// Fills the transmitter with what's left of the telegram.
+    pub fn poll<W: Write<u8>>(&mut self, port: &mut W) {
+        while let Some(b) = self.buffer.get(self.pos) {
+            if port.write(*b).is_err() {
+                return;
+            }
+            self.pos += 1;
+        }
+        set_transmit_interrupt(false);
+    }
+}
+
+/// Inverts the transmitted signal along with the received one, so the output
+/// needs the same hardware as the input to be read by a P1 consumer.
+#[cfg(not(feature = "sim"))]
+pub fn set_inversion(inverted: bool) {
+    set_ctrl(CTRL_TXINV, inverted);
+}
+
+#[cfg(feature = "sim")]
+pub fn set_inversion(_inverted: bool) {}
+
+// The HAL doesn't expose the transmit interrupt, which fires whenever the
+// transmitter has room for another byte, so it's set through the register.
+#[cfg(not(feature = "sim"))]
+fn set_transmit_interrupt(enable: bool) {
+    set_ctrl(CTRL_TIE, enable);
+}
+
+#[cfg(feature = "sim")]
+fn set_transmit_interrupt(_enable: bool) {}
+
+#[cfg(not(feature = "sim"))]
+fn set_ctrl(bits: u32, set: bool) {
+    cortex_m::interrupt::free(|_| unsafe {
+        let ctrl = ptr::read_volatile(LPUART_CTRL as *const u32);
+        let ctrl = if set { ctrl | bits } else { ctrl & !bits };
+        ptr::write_volatile(LPUART_CTRL as *mut u32, ctrl);
+    });
+}
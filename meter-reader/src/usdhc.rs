@@ -0,0 +1,464 @@
+//! Driver for the SD card slot of the Teensy 4.1, through the uSDHC1
+//! controller of the i.MX RT1062 in 4-bit mode. See chapter 58 of the i.MX
+//! RT1060 reference manual, and the SD Physical Layer Simplified
+//! Specification for the commands.
+//!
+//! Data is moved through the controller's buffer by the CPU rather than by
+//! DMA, a block at a time. That's slower, but a telegram is a few hundred
+//! bytes, and it keeps the cache out of the picture.
+
+use core::ptr;
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+
+use crate::clock;
+
+const USDHC1: usize = 0x402C_0000;
+const BLK_ATT: usize = USDHC1 + 0x04;
+const CMD_ARG: usize = USDHC1 + 0x08;
+const CMD_XFR_TYP: usize = USDHC1 + 0x0C;
+const CMD_RSP0: usize = USDHC1 + 0x10;
+const CMD_RSP1: usize = USDHC1 + 0x14;
+const CMD_RSP2: usize = USDHC1 + 0x18;
+const CMD_RSP3: usize = USDHC1 + 0x1C;
+const DATA_BUFF_ACC_PORT: usize = USDHC1 + 0x20;
+const PRES_STATE: usize = USDHC1 + 0x24;
+const PROT_CTRL: usize = USDHC1 + 0x28;
+const SYS_CTRL: usize = USDHC1 + 0x2C;
+const INT_STATUS: usize = USDHC1 + 0x30;
+const INT_STATUS_EN: usize = USDHC1 + 0x34;
+const INT_SIGNAL_EN: usize = USDHC1 + 0x38;
+const WTMK_LVL: usize = USDHC1 + 0x44;
+const MIX_CTRL: usize = USDHC1 + 0x48;
+
+const XFR_TYP_RSP_136: u32 = 0b01 << 16;
+const XFR_TYP_RSP_48: u32 = 0b10 << 16;
+const XFR_TYP_RSP_48_BUSY: u32 = 0b11 << 16;
+const XFR_TYP_CCCEN: u32 = 1 << 19;
+const XFR_TYP_CICEN: u32 = 1 << 20;
+const XFR_TYP_DPSEL: u32 = 1 << 21;
+const XFR_TYP_CMDINX_SHIFT: u32 = 24;
+const MIX_CTRL_BCEN: u32 = 1 << 1;
+const MIX_CTRL_AC12EN: u32 = 1 << 2;
+const MIX_CTRL_DTDSEL: u32 = 1 << 4;
+const MIX_CTRL_MSBSEL: u32 = 1 << 5;
+const MIX_CTRL_TRANSFER: u32 = 0x3F;
+const PRES_STATE_CIHB: u32 = 1 << 0;
+const PRES_STATE_CDIHB: u32 = 1 << 1;
+const PRES_STATE_SDSTB: u32 = 1 << 3;
+const PROT_CTRL_DTW_MASK: u32 = 0b11 << 1;
+const PROT_CTRL_DTW_4BIT: u32 = 0b01 << 1;
+const SYS_CTRL_DVS_SHIFT: u32 = 4;
+const SYS_CTRL_SDCLKFS_SHIFT: u32 = 8;
+const SYS_CTRL_CLOCK_MASK: u32 = 0xFFF0;
+const SYS_CTRL_DTOCV: u32 = 0xE << 16;
+const SYS_CTRL_RSTA: u32 = 1 << 24;
+const SYS_CTRL_RSTC: u32 = 1 << 25;
+const SYS_CTRL_RSTD: u32 = 1 << 26;
+const SYS_CTRL_INITA: u32 = 1 << 27;
+const INT_CC: u32 = 1 << 0;
+const INT_TC: u32 = 1 << 1;
+const INT_BWR: u32 = 1 << 4;
+const INT_BRR: u32 = 1 << 5;
+const INT_CTOE: u32 = 1 << 16;
+const INT_CMD_ERRORS: u32 = 0b1111 << 16;
+const INT_DATA_ERRORS: u32 = 0b111 << 20 | 1 << 24;
+const INT_ALL: u32 = INT_CC | INT_TC | INT_BWR | INT_BRR | INT_CMD_ERRORS | INT_DATA_ERRORS;
+// Words the buffer holds before a read or write is signalled: a whole block.
+const WTMK_BLOCK: u32 = 128 << 16 | 128;
+
+// The root clock is PLL2 PFD2 (396 MHz) divided by two, and the card clock
+// is that divided by the prescaler (`SDCLKFS`, a power of two) and the
+// divisor (`DVS + 1`). Cards start out at 400 kHz at most.
+const CLOCK_INIT: u32 = 0x20 << SYS_CTRL_SDCLKFS_SHIFT | 7 << SYS_CTRL_DVS_SHIFT;
+// 198 MHz / 2 / 4, just under the 25 MHz of default speed mode.
+const CLOCK_TRANSFER: u32 = 0x01 << SYS_CTRL_SDCLKFS_SHIFT | 3 << SYS_CTRL_DVS_SHIFT;
+
+const CCM_CSCMR1: usize = 0x400F_C01C;
+const CSCMR1_USDHC1_CLK_SEL: u32 = 1 << 16;
+const CCM_CSCDR1: usize = 0x400F_C024;
+const CSCDR1_USDHC1_PODF_MASK: u32 = 0b111 << 11;
+const CSCDR1_USDHC1_PODF_2: u32 = 0b001 << 11;
+const CCM_CCGR6: usize = 0x400F_C080;
+const CCGR6_USDHC1: u32 = 0b11 << 2;
+
+// GPIO_SD_B0_00 to _05: CMD, CLK and DATA0 to DATA3.
+const IOMUXC: usize = 0x401F_8000;
+const MUX_GPIO_SD_B0: usize = IOMUXC + 0x1BC;
+const PAD_GPIO_SD_B0: usize = IOMUXC + 0x3AC;
+const MUX_ALT_USDHC: u32 = 0;
+const CLK_PAD: usize = 1;
+// 47k pull-up, 100 MHz, drive strength 4, fast slew rate. The clock is
+// driven, so it gets no pull-up.
+const PAD_DATA: u32 = 0x7061;
+const PAD_CLOCK: u32 = 0x0061;
+
+const CMD_GO_IDLE_STATE: u32 = 0;
+const CMD_ALL_SEND_CID: u32 = 2;
+const CMD_SEND_RELATIVE_ADDR: u32 = 3;
+const ACMD_SET_BUS_WIDTH: u32 = 6;
+const CMD_SELECT_CARD: u32 = 7;
+const CMD_SEND_IF_COND: u32 = 8;
+const CMD_SEND_CSD: u32 = 9;
+const CMD_SEND_STATUS: u32 = 13;
+const CMD_SET_BLOCKLEN: u32 = 16;
+const CMD_READ_SINGLE_BLOCK: u32 = 17;
+const CMD_READ_MULTIPLE_BLOCK: u32 = 18;
+const CMD_WRITE_BLOCK: u32 = 24;
+const CMD_WRITE_MULTIPLE_BLOCK: u32 = 25;
+const ACMD_SD_SEND_OP_COND: u32 = 41;
+const CMD_APP_CMD: u32 = 55;
+
+// 2.7 to 3.6 V, and the check pattern.
+const IF_COND: u32 = 0x1AA;
+// 3.2 to 3.4 V, and high capacity cards are supported.
+const OP_COND: u32 = 0x4030_0000;
+const OCR_BUSY: u32 = 1 << 31;
+const OCR_CCS: u32 = 1 << 30;
+const STATUS_READY_FOR_DATA: u32 = 1 << 8;
+const BUS_WIDTH_4BIT: u32 = 2;
+const BLOCK_SZ: u32 = 512;
+
+const COMMAND_TIMEOUT_MS: i64 = 10;
+// Cards may take up to a second to power up, and a write up to 250 ms.
+const POWER_UP_TIMEOUT_MS: i64 = 1000;
+const DATA_TIMEOUT_MS: i64 = 500;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UsdhcError {
+    /// The card didn't answer a command, usually because there is no card.
+    NoResponse(u32),
+    /// The response to a command failed its CRC or index check.
+    Command(u32),
+    /// A data transfer failed its CRC check, or timed out.
+    Data,
+    /// The card didn't finish powering up or programming in time.
+    Timeout,
+    /// The card isn't an SD card this driver supports.
+    Unsupported,
+}
+
+/// The card in the SD slot of the Teensy 4.1. There is only one slot, so only
+/// create it once.
+pub struct Usdhc {
+    rca: u32,
+    blocks: u32,
+    // Standard capacity cards are addressed in bytes, the others in blocks.
+    high_capacity: bool,
+}
+
+impl Usdhc {
+    /// Powers up the card in the slot, and switches it to 4-bit transfers.
+    pub fn init() -> Result<Self, UsdhcError> {
+        init_clock();
+        init_pins();
+        write_reg(SYS_CTRL, read_reg(SYS_CTRL) | SYS_CTRL_RSTA);
+        wait_clear(SYS_CTRL, SYS_CTRL_RSTA)?;
+        write_reg(INT_STATUS_EN, INT_ALL);
+        write_reg(INT_SIGNAL_EN, 0);
+        write_reg(WTMK_LVL, WTMK_BLOCK);
+        set_card_clock(CLOCK_INIT)?;
+        // At least 74 clock cycles before the first command.
+        write_reg(SYS_CTRL, read_reg(SYS_CTRL) | SYS_CTRL_INITA);
+        wait_clear(SYS_CTRL, SYS_CTRL_INITA)?;
+
+        command(CMD_GO_IDLE_STATE, 0, 0)?;
+        // Cards older than version 2 don't know this command.
+        let v2 = match command(CMD_SEND_IF_COND, IF_COND, XFR_TYP_RSP_48 | checked()) {
+            Ok(()) if read_reg(CMD_RSP0) & 0xFFF == IF_COND => true,
+            Ok(()) => return Err(UsdhcError::Unsupported),
+            Err(UsdhcError::NoResponse(_)) => false,
+            Err(err) => return Err(err),
+        };
+        let op_cond = if v2 { OP_COND } else { OP_COND & !OCR_CCS };
+        let deadline = clock::millis() + POWER_UP_TIMEOUT_MS;
+        let ocr = loop {
+            app_command(0)?;
+            // The OCR comes without a CRC or command index.
+            command(ACMD_SD_SEND_OP_COND, op_cond, XFR_TYP_RSP_48)?;
+            let ocr = read_reg(CMD_RSP0);
+            if ocr & OCR_BUSY != 0 {
+                break ocr;
+            }
+            if clock::millis() > deadline {
+                return Err(UsdhcError::Timeout);
+            }
+        };
+
+        command(CMD_ALL_SEND_CID, 0, XFR_TYP_RSP_136 | XFR_TYP_CCCEN)?;
+        command(CMD_SEND_RELATIVE_ADDR, 0, XFR_TYP_RSP_48 | checked())?;
+        let rca = read_reg(CMD_RSP0) & 0xFFFF_0000;
+        command(CMD_SEND_CSD, rca, XFR_TYP_RSP_136 | XFR_TYP_CCCEN)?;
+        let blocks = csd_blocks()?;
+        command(CMD_SELECT_CARD, rca, XFR_TYP_RSP_48_BUSY | checked())?;
+        app_command(rca)?;
+        command(
+            ACMD_SET_BUS_WIDTH,
+            BUS_WIDTH_4BIT,
+            XFR_TYP_RSP_48 | checked(),
+        )?;
+        write_reg(
+            PROT_CTRL,
+            read_reg(PROT_CTRL) & !PROT_CTRL_DTW_MASK | PROT_CTRL_DTW_4BIT,
+        );
+        let high_capacity = ocr & OCR_CCS != 0;
+        if !high_capacity {
+            command(CMD_SET_BLOCKLEN, BLOCK_SZ, XFR_TYP_RSP_48 | checked())?;
+        }
+        set_card_clock(CLOCK_TRANSFER)?;
+        log::info!(
+            "Found SD card of {} MiB ({})",
+            blocks / 2048,
+            if high_capacity { "SDHC/SDXC" } else { "SDSC" }
+        );
+        Ok(Self {
+            rca,
+            blocks,
+            high_capacity,
+        })
+    }
+
+    fn address(&self, block: u32) -> u32 {
+        if self.high_capacity {
+            block
+        } else {
+            block * BLOCK_SZ
+        }
+    }
+
+    /// Waits until the card is done programming the last write.
+    fn wait_ready(&self) -> Result<(), UsdhcError> {
+        let deadline = clock::millis() + DATA_TIMEOUT_MS;
+        loop {
+            command(CMD_SEND_STATUS, self.rca, XFR_TYP_RSP_48 | checked())?;
+            if read_reg(CMD_RSP0) & STATUS_READY_FOR_DATA != 0 {
+                return Ok(());
+            }
+            if clock::millis() > deadline {
+                return Err(UsdhcError::Timeout);
+            }
+        }
+    }
+}
+
+impl BlockDevice for Usdhc {
+    type Error = UsdhcError;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let (index, mix) = match blocks.len() {
+            1 => (CMD_READ_SINGLE_BLOCK, MIX_CTRL_DTDSEL),
+            _ => (CMD_READ_MULTIPLE_BLOCK, MIX_CTRL_DTDSEL | multi_block()),
+        };
+        start_transfer(blocks.len(), mix);
+        command(
+            index,
+            self.address(start_block_idx.0),
+            XFR_TYP_RSP_48 | checked() | XFR_TYP_DPSEL,
+        )
+        .map_err(reset_data)?;
+        for block in blocks.iter_mut() {
+            wait_data(INT_BRR)?;
+            for word in block.contents.chunks_exact_mut(4) {
+                word.copy_from_slice(&read_reg(DATA_BUFF_ACC_PORT).to_le_bytes());
+            }
+        }
+        wait_data(INT_TC)
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        let (index, mix) = match blocks.len() {
+            1 => (CMD_WRITE_BLOCK, 0),
+            _ => (CMD_WRITE_MULTIPLE_BLOCK, multi_block()),
+        };
+        start_transfer(blocks.len(), mix);
+        command(
+            index,
+            self.address(start_block_idx.0),
+            XFR_TYP_RSP_48 | checked() | XFR_TYP_DPSEL,
+        )
+        .map_err(reset_data)?;
+        for block in blocks.iter() {
+            wait_data(INT_BWR)?;
+            for word in block.contents.chunks_exact(4) {
+                write_reg(
+                    DATA_BUFF_ACC_PORT,
+                    u32::from_le_bytes([word[0], word[1], word[2], word[3]]),
+                );
+            }
+        }
+        wait_data(INT_TC)?;
+        self.wait_ready()
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        Ok(BlockCount(self.blocks))
+    }
+}
+
+/// Enables the uSDHC1 clock, at 198 MHz from PLL2 PFD2.
+fn init_clock() {
+    write_reg(CCM_CCGR6, read_reg(CCM_CCGR6) & !CCGR6_USDHC1);
+    write_reg(CCM_CSCMR1, read_reg(CCM_CSCMR1) & !CSCMR1_USDHC1_CLK_SEL);
+    write_reg(
+        CCM_CSCDR1,
+        read_reg(CCM_CSCDR1) & !CSCDR1_USDHC1_PODF_MASK | CSCDR1_USDHC1_PODF_2,
+    );
+    write_reg(CCM_CCGR6, read_reg(CCM_CCGR6) | CCGR6_USDHC1);
+}
+
+fn init_pins() {
+    for pad in 0..6 {
+        write_reg(MUX_GPIO_SD_B0 + 4 * pad, MUX_ALT_USDHC);
+        let ctl = if pad == CLK_PAD { PAD_CLOCK } else { PAD_DATA };
+        write_reg(PAD_GPIO_SD_B0 + 4 * pad, ctl);
+    }
+}
+
+fn set_card_clock(clock: u32) -> Result<(), UsdhcError> {
+    write_reg(
+        SYS_CTRL,
+        read_reg(SYS_CTRL) & !SYS_CTRL_CLOCK_MASK | clock | SYS_CTRL_DTOCV,
+    );
+    let deadline = clock::millis() + COMMAND_TIMEOUT_MS;
+    while read_reg(PRES_STATE) & PRES_STATE_SDSTB == 0 {
+        if clock::millis() > deadline {
+            return Err(UsdhcError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+/// Response checks for commands answered with an R1, R6 or R7 response.
+fn checked() -> u32 {
+    XFR_TYP_CCCEN | XFR_TYP_CICEN
+}
+
+/// Transfer mode for a multi-block transfer, which the controller ends with
+/// an automatic CMD12.
+fn multi_block() -> u32 {
+    MIX_CTRL_MSBSEL | MIX_CTRL_BCEN | MIX_CTRL_AC12EN
+}
+
+fn app_command(rca: u32) -> Result<(), UsdhcError> {
+    command(CMD_APP_CMD, rca, XFR_TYP_RSP_48 | checked())
+}
+
+fn command(index: u32, arg: u32, flags: u32) -> Result<(), UsdhcError> {
+    let inhibit = if flags & XFR_TYP_DPSEL != 0 {
+        PRES_STATE_CIHB | PRES_STATE_CDIHB
+    } else {
+        PRES_STATE_CIHB
+    };
+    let deadline = clock::millis() + DATA_TIMEOUT_MS;
+    while read_reg(PRES_STATE) & inhibit != 0 {
+        if clock::millis() > deadline {
+            return Err(UsdhcError::Timeout);
+        }
+    }
+    // Status flags are write-1-to-clear.
+    write_reg(INT_STATUS, INT_ALL);
+    if flags & XFR_TYP_DPSEL == 0 {
+        write_reg(MIX_CTRL, read_reg(MIX_CTRL) & !MIX_CTRL_TRANSFER);
+    }
+    write_reg(CMD_ARG, arg);
+    write_reg(CMD_XFR_TYP, index << XFR_TYP_CMDINX_SHIFT | flags);
+    let deadline = clock::millis() + COMMAND_TIMEOUT_MS;
+    loop {
+        let status = read_reg(INT_STATUS);
+        if status & INT_CMD_ERRORS != 0 {
+            write_reg(INT_STATUS, INT_ALL);
+            write_reg(SYS_CTRL, read_reg(SYS_CTRL) | SYS_CTRL_RSTC);
+            wait_clear(SYS_CTRL, SYS_CTRL_RSTC)?;
+            return Err(if status & INT_CTOE != 0 {
+                UsdhcError::NoResponse(index)
+            } else {
+                UsdhcError::Command(index)
+            });
+        }
+        if status & INT_CC != 0 {
+            write_reg(INT_STATUS, INT_CC);
+            return Ok(());
+        }
+        if clock::millis() > deadline {
+            return Err(UsdhcError::NoResponse(index));
+        }
+    }
+}
+
+fn start_transfer(blocks: usize, mix: u32) {
+    write_reg(BLK_ATT, (blocks as u32) << 16 | BLOCK_SZ);
+    write_reg(MIX_CTRL, read_reg(MIX_CTRL) & !MIX_CTRL_TRANSFER | mix);
+}
+
+/// Waits for `flag`, failing on a data error.
+fn wait_data(flag: u32) -> Result<(), UsdhcError> {
+    let deadline = clock::millis() + DATA_TIMEOUT_MS;
+    loop {
+        let status = read_reg(INT_STATUS);
+        if status & INT_DATA_ERRORS != 0 || clock::millis() > deadline {
+            return Err(reset_data(UsdhcError::Data));
+        }
+        if status & flag != 0 {
+            write_reg(INT_STATUS, flag);
+            return Ok(());
+        }
+    }
+}
+
+/// Resets the data lines after a failed transfer, so the next one can start.
+fn reset_data(err: UsdhcError) -> UsdhcError {
+    write_reg(INT_STATUS, INT_ALL);
+    write_reg(SYS_CTRL, read_reg(SYS_CTRL) | SYS_CTRL_RSTD);
+    match wait_clear(SYS_CTRL, SYS_CTRL_RSTD) {
+        Ok(()) => err,
+        Err(timeout) => timeout,
+    }
+}
+
+/// The size of the card in blocks, from the CSD register. The controller
+/// leaves out the CRC, so bit `n` of the CSD is bit `n - 8` of the response.
+fn csd_blocks() -> Result<u32, UsdhcError> {
+    let rsp1 = read_reg(CMD_RSP1);
+    let rsp2 = read_reg(CMD_RSP2);
+    let rsp3 = read_reg(CMD_RSP3);
+    match (rsp3 >> 22) & 0b11 {
+        // Version 1: (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) * 2^READ_BL_LEN bytes.
+        0 => {
+            let c_size = (rsp2 & 0b11) << 10 | rsp1 >> 22;
+            let mult = (rsp1 >> 7) & 0b111;
+            let bl_len = (rsp2 >> 8) & 0xF;
+            if bl_len < 9 {
+                return Err(UsdhcError::Unsupported);
+            }
+            Ok((c_size + 1) << (mult + 2 + bl_len - 9))
+        }
+        // Version 2: (C_SIZE + 1) * 512 KiB.
+        1 => Ok(((rsp1 >> 8) & 0x3F_FFFF)
+            .saturating_add(1)
+            .saturating_mul(1024)),
+        _ => Err(UsdhcError::Unsupported),
+    }
+}
+
+fn wait_clear(addr: usize, mask: u32) -> Result<(), UsdhcError> {
+    let deadline = clock::millis() + COMMAND_TIMEOUT_MS;
+    while read_reg(addr) & mask != 0 {
+        if clock::millis() > deadline {
+            return Err(UsdhcError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+fn read_reg(addr: usize) -> u32 {
+    unsafe { ptr::read_volatile(addr as *const u32) }
+}
+
+fn write_reg(addr: usize, value: u32) {
+    unsafe { ptr::write_volatile(addr as *mut u32, value) }
+}
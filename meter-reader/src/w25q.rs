@@ -0,0 +1,162 @@
+//! Driver for the Winbond W25Q series of SPI NOR flash, as found on most
+//! breakout boards.
+//!
+//! Only 24-bit addressing is implemented, so at most the first 16 MiB of a
+//! chip are used.
+
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v2::OutputPin,
+};
+
+use crate::{
+    clock,
+    log_store::{Storage, SECTOR_SZ},
+};
+
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_READ_DATA: u8 = 0x03;
+const CMD_READ_STATUS_1: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+const CMD_JEDEC_ID: u8 = 0x9F;
+const CMD_RELEASE_POWER_DOWN: u8 = 0xAB;
+const STATUS_BUSY: u8 = 1 << 0;
+
+const MANUFACTURER_WINBOND: u8 = 0xEF;
+const PAGE_SZ: u32 = 256;
+const MAX_CAPACITY: u32 = 1 << 24;
+// The datasheet maxima are 3 ms for programming a page and 400 ms for erasing
+// a sector. We busy-wait for both.
+const PROGRAM_TIMEOUT_MS: i64 = 5;
+const ERASE_TIMEOUT_MS: i64 = 500;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum W25qError<E> {
+    Spi(E),
+    /// Chip select could not be driven, for instance because another device
+    /// holds the bus.
+    Select,
+    /// No W25Q answered. Holds the JEDEC ID that was read instead.
+    NotFound([u8; 3]),
+    /// The chip stayed busy for longer than the datasheet allows.
+    Timeout,
+}
+
+pub struct W25q<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+    capacity: u32,
+}
+
+impl<SPI, E, CS> W25q<SPI, CS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin,
+{
+    /// Wakes the chip up and checks that it's a W25Q, to tell a missing chip
+    /// apart from one that misbehaves later.
+    pub fn probe(spi: SPI, cs: CS) -> Result<Self, W25qError<E>> {
+        let mut flash = Self {
+            spi,
+            cs,
+            capacity: 0,
+        };
+        flash.transaction(|spi| spi.write(&[CMD_RELEASE_POWER_DOWN]))?;
+        let mut id = [CMD_JEDEC_ID, 0, 0, 0];
+        flash.transaction(|spi| spi.transfer(&mut id).map(|_| ()))?;
+        let id = [id[1], id[2], id[3]];
+        // The last byte is the base 2 logarithm of the size in bytes.
+        if id[0] != MANUFACTURER_WINBOND || !(16..32).contains(&id[2]) {
+            return Err(W25qError::NotFound(id));
+        }
+        flash.capacity = (1 << id[2]).min(MAX_CAPACITY);
+        log::info!(
+            "Found W25Q flash, device {:#06x}, {} KiB",
+            u16::from_be_bytes([id[1], id[2]]),
+            flash.capacity / 1024
+        );
+        Ok(flash)
+    }
+
+    fn transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut SPI) -> Result<T, E>,
+    ) -> Result<T, W25qError<E>> {
+        self.cs.set_low().map_err(|_| W25qError::Select)?;
+        let result = f(&mut self.spi).map_err(W25qError::Spi);
+        self.cs.set_high().map_err(|_| W25qError::Select)?;
+        result
+    }
+
+    fn command(&mut self, command: u8, offset: u32, data: &[u8]) -> Result<(), W25qError<E>> {
+        let [_, a2, a1, a0] = offset.to_be_bytes();
+        self.transaction(|spi| {
+            spi.write(&[command, a2, a1, a0])?;
+            if !data.is_empty() {
+                spi.write(data)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_enable(&mut self) -> Result<(), W25qError<E>> {
+        self.transaction(|spi| spi.write(&[CMD_WRITE_ENABLE]))
+    }
+
+    fn wait_idle(&mut self, timeout_ms: i64) -> Result<(), W25qError<E>> {
+        let deadline = clock::millis() + timeout_ms;
+        loop {
+            let mut status = [CMD_READ_STATUS_1, 0];
+            self.transaction(|spi| spi.transfer(&mut status).map(|_| ()))?;
+            if status[1] & STATUS_BUSY == 0 {
+                return Ok(());
+            }
+            if clock::millis() > deadline {
+                return Err(W25qError::Timeout);
+            }
+        }
+    }
+}
+
+impl<SPI, E, CS> Storage for W25q<SPI, CS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    E: core::fmt::Debug,
+    CS: OutputPin,
+{
+    type Error = W25qError<E>;
+
+    fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let [_, a2, a1, a0] = offset.to_be_bytes();
+        self.transaction(|spi| {
+            spi.write(&[CMD_READ_DATA, a2, a1, a0])?;
+            spi.transfer(buf).map(|_| ())
+        })
+    }
+
+    fn program(&mut self, mut offset: u32, mut data: &[u8]) -> Result<(), Self::Error> {
+        // A page program wraps around at the end of the page, so split the
+        // data on page boundaries.
+        while !data.is_empty() {
+            let len = ((PAGE_SZ - offset % PAGE_SZ) as usize).min(data.len());
+            self.write_enable()?;
+            self.command(CMD_PAGE_PROGRAM, offset, &data[..len])?;
+            self.wait_idle(PROGRAM_TIMEOUT_MS)?;
+            offset += len as u32;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Self::Error> {
+        debug_assert_eq!(offset % SECTOR_SZ, 0);
+        self.write_enable()?;
+        self.command(CMD_SECTOR_ERASE, offset, &[])?;
+        self.wait_idle(ERASE_TIMEOUT_MS)
+    }
+}
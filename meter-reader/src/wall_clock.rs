@@ -0,0 +1,200 @@
+use core::fmt::{self, Display};
+
+use dsmr42::{Line, Telegram};
+
+use crate::{calibration::Calibration, clock};
+
+// Telegram timestamps have a resolution of one second, so don't adjust the
+// clock for differences smaller than this.
+const TELEGRAM_TOLERANCE_MS: i64 = 2000;
+// Once synchronised with SNTP, ignore telegram timestamps for this long.
+const SNTP_PREFERRED_MS: i64 = 24 * 3600 * 1000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TimeSource {
+    Telegram,
+    Sntp,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Sync {
+    unix_ms: i64,
+    clock_ms: i64,
+    source: TimeSource,
+}
+
+/// Keeps track of the absolute time, by pairing a `Clock` time with a Unix
+/// time received from an external source. The same times are used to
+/// calibrate the `Clock`.
+pub struct WallClock {
+    sync: Option<Sync>,
+    calibration: Calibration,
+}
+
+impl WallClock {
+    pub fn new() -> Self {
+        Self {
+            sync: None,
+            calibration: Calibration::new(),
+        }
+    }
+
+    /// Records that it was `unix_ms` at `Clock` time `clock_ms`.
+    pub fn sync(&mut self, unix_ms: i64, clock_ms: i64, source: TimeSource) {
+        self.calibrate(unix_ms, clock_ms, source);
+        self.set(unix_ms, clock_ms, source);
+    }
+
+    fn set(&mut self, unix_ms: i64, clock_ms: i64, source: TimeSource) {
+        if let Some(current) = self.unix_millis(clock_ms) {
+            log::info!(
+                "Adjusting wall clock by {} ms ({:?})",
+                unix_ms - current,
+                source
+            );
+        } else {
+            log::info!("Wall clock set from {:?}", source);
+        }
+        self.sync = Some(Sync {
+            unix_ms,
+            clock_ms,
+            source,
+        });
+    }
+
+    /// Synchronises with the timestamp in a telegram of which the first byte
+    /// was received at `received_at`, if it's far enough off.
+    pub fn sync_from_telegram(&mut self, telegram: &Telegram, received_at: i64) {
+        let unix_ms = match telegram.lines.iter().find_map(|line| match line {
+            Line::Timestamp(ts) => Some(ts.unix_time() * 1000),
+            _ => None,
+        }) {
+            Some(unix_ms) => unix_ms,
+            None => return,
+        };
+        self.calibrate(unix_ms, received_at, TimeSource::Telegram);
+        if let Some(sync) = self.sync {
+            if sync.source == TimeSource::Sntp && received_at - sync.clock_ms < SNTP_PREFERRED_MS {
+                return;
+            }
+            let current = sync.unix_ms + received_at - sync.clock_ms;
+            if (unix_ms - current).abs() < TELEGRAM_TOLERANCE_MS {
+                return;
+            }
+        }
+        self.set(unix_ms, received_at, TimeSource::Telegram);
+    }
+
+    fn calibrate(&mut self, unix_ms: i64, clock_ms: i64, source: TimeSource) {
+        if let Some(drift_ppm) = self.calibration.observe(unix_ms, clock_ms, source) {
+            clock::set_drift_ppm(drift_ppm);
+        }
+    }
+
+    /// How many ppm the `Clock` was found to drift before it was corrected.
+    pub fn drift_ppm(&self) -> Option<i32> {
+        self.calibration.drift_ppm()
+    }
+
+    /// Unix time in milliseconds at `Clock` time `now`, if known.
+    pub fn unix_millis(&self, now: i64) -> Option<i64> {
+        self.sync.map(|sync| sync.unix_ms + now - sync.clock_ms)
+    }
+
+    /// Local time in the Netherlands at `Clock` time `now`, if known.
+    pub fn local_time(&self, now: i64) -> Option<LocalTime> {
+        self.unix_millis(now)
+            .map(|unix_ms| LocalTime::from_unix(unix_ms.div_euclid(1000)))
+    }
+}
+
+/// A time in the Europe/Amsterdam time zone.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LocalTime {
+    pub year: i64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub dst: bool,
+}
+
+impl LocalTime {
+    /// Days since 1970-01-01, counting in local time.
+    pub fn days_since_epoch(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day)
+    }
+
+    pub fn from_unix(unix_secs: i64) -> Self {
+        let dst = is_dst(unix_secs);
+        let local = unix_secs + if dst { 2 * 3600 } else { 3600 };
+        let (year, month, day) = civil_from_days(local.div_euclid(86400));
+        let secs_of_day = local.rem_euclid(86400);
+        Self {
+            year,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+            dst,
+        }
+    }
+}
+
+impl Display for LocalTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            if self.dst { "+02:00" } else { "+01:00" }
+        )
+    }
+}
+
+/// Summer time in the EU starts on the last Sunday of March, and ends on the
+/// last Sunday of October, both at 01:00 UTC.
+fn is_dst(unix_secs: i64) -> bool {
+    let (year, _, _) = civil_from_days(unix_secs.div_euclid(86400));
+    let start = last_sunday(year, 3) * 86400 + 3600;
+    let end = last_sunday(year, 10) * 86400 + 3600;
+    unix_secs >= start && unix_secs < end
+}
+
+/// Days since 1970-01-01 of the last Sunday in a month with 31 days.
+fn last_sunday(year: i64, month: u8) -> i64 {
+    let last_day = days_from_civil(year, month, 31);
+    // 1970-01-01 was a Thursday.
+    let weekday = (last_day + 4).rem_euclid(7);
+    last_day - weekday
+}
+
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
@@ -0,0 +1,215 @@
+//! Publishes telegrams to an MQTT broker from the host, parsed and serialized
+//! with `dsmr42` like the firmware does, for checking dashboards and other
+//! consumers of the broker before flashing a change.
+//!
+//! Telegrams are read from either a capture of the P1 port, which is played
+//! back one telegram every `interval_ms`, or a serial device connected to a
+//! meter, of which every telegram is published as it arrives.
+//!
+//! Usage: `p1-replay <capture or device> <broker[:port]> [key=value...]`,
+//! where the settings are:
+//! - `topic`, defaulting to `smart_meter/usage`,
+//! - `client_id`, defaulting to `p1-replay`,
+//! - `interval_ms`, defaulting to 1000,
+//! - `repeat`, which starts the capture over once it's done if `true`,
+//! - `baud`, for serial devices, defaulting to 115200.
+
+mod mqtt;
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::fs::FileTypeExt,
+    path::Path,
+    process::{self, Command},
+    thread,
+    time::Duration,
+};
+
+use dsmr42::{SerializeOptions, TelegramParseError};
+
+use crate::mqtt::Connection;
+
+const MQTT_PORT: u16 = 1883;
+
+struct Settings {
+    topic: String,
+    client_id: String,
+    interval_ms: u64,
+    repeat: bool,
+    baud: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            topic: "smart_meter/usage".to_owned(),
+            client_id: "p1-replay".to_owned(),
+            interval_ms: 1000,
+            repeat: false,
+            baud: 115_200,
+        }
+    }
+}
+
+impl Settings {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value for {}", key);
+        match key {
+            "topic" => self.topic = value.to_owned(),
+            "client_id" => self.client_id = value.to_owned(),
+            "interval_ms" => self.interval_ms = value.parse().map_err(|_| invalid())?,
+            "repeat" => self.repeat = value.parse().map_err(|_| invalid())?,
+            "baud" => self.baud = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+        Ok(())
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let (source, broker) = match (args.next(), args.next()) {
+        (Some(source), Some(broker)) => (source, broker),
+        _ => usage(),
+    };
+    let mut settings = Settings::default();
+    for arg in args {
+        let result = match arg.split_once('=') {
+            Some((key, value)) => settings.set(key, value),
+            None => Err(format!("expected key=value, got {}", arg)),
+        };
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            usage();
+        }
+    }
+    let broker = if broker.contains(':') {
+        broker
+    } else {
+        format!("{}:{}", broker, MQTT_PORT)
+    };
+
+    if let Err(err) = run(Path::new(&source), &broker, &settings) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: p1-replay <capture or device> <broker[:port]> [key=value...]");
+    process::exit(2);
+}
+
+fn run(source: &Path, broker: &str, settings: &Settings) -> io::Result<()> {
+    let mut mqtt = Connection::connect(broker, &settings.client_id)?;
+    eprintln!("Connected to {}", broker);
+    let mut publisher = Publisher {
+        mqtt: &mut mqtt,
+        topic: &settings.topic,
+        published: 0,
+    };
+
+    if fs::metadata(source)?.file_type().is_char_device() {
+        return follow_device(source, settings.baud, &mut publisher);
+    }
+    let capture = fs::read(source)?;
+    loop {
+        let mut rest = &capture[..];
+        let before = publisher.published;
+        let mut published = before;
+        while let Some(len) = publisher.publish_next(rest)? {
+            rest = &rest[len..];
+            if publisher.published > published {
+                published = publisher.published;
+                thread::sleep(Duration::from_millis(settings.interval_ms));
+            }
+        }
+        if published == before {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "capture contains no valid telegrams",
+            ));
+        }
+        if !settings.repeat {
+            eprintln!("Published {} telegrams", publisher.published);
+            return Ok(());
+        }
+    }
+}
+
+/// Publishes telegrams from a serial device as the meter sends them.
+fn follow_device(device: &Path, baud: u32, publisher: &mut Publisher) -> io::Result<()> {
+    // Std has no way to configure a serial port, so leave it to stty. P1
+    // ports send 8N1 since DSMR 4, and nothing should be echoed back.
+    let status = Command::new("stty")
+        .arg("-F")
+        .arg(device)
+        .args([
+            &baud.to_string(),
+            "raw",
+            "-echo",
+            "cs8",
+            "-parenb",
+            "-cstopb",
+        ])
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "stty failed to configure {:?}",
+            device
+        )));
+    }
+
+    let mut port = File::open(device)?;
+    eprintln!("Reading telegrams from {:?} at {} baud", device, baud);
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let read = port.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+        while let Some(len) = publisher.publish_next(&buf)? {
+            buf.drain(..len);
+        }
+    }
+}
+
+struct Publisher<'a> {
+    mqtt: &'a mut Connection,
+    topic: &'a str,
+    published: usize,
+}
+
+impl Publisher<'_> {
+    /// Parses the telegram at the start of `input`, and publishes it if it's
+    /// valid. Returns how much of `input` to skip, or `None` if it's waiting
+    /// for the rest of the telegram.
+    fn publish_next(&mut self, input: &[u8]) -> io::Result<Option<usize>> {
+        // Whatever comes before a telegram, like the rest of one that was
+        // cut off, is skipped without complaining about it.
+        if input.first() != Some(&b'/') {
+            let len = input.iter().position(|b| *b == b'/').unwrap_or(input.len());
+            return Ok(Some(len).filter(|len| *len > 0));
+        }
+        // One odd register shouldn't cost us the rest of the telegram.
+        let (len, result) = dsmr42::parse_lenient(input);
+        match result {
+            Ok(telegram) => {
+                let mut json = String::new();
+                telegram.serialize(&mut json, &SerializeOptions::ALL);
+                self.mqtt.publish(self.topic, json.as_bytes())?;
+                self.published += 1;
+                eprintln!("Published {} bytes to {}", json.len(), self.topic);
+            }
+            Err(TelegramParseError::Incomplete) => return Ok(None),
+            Err(err) => eprintln!("Skipping invalid telegram: {:?}", err),
+        }
+        // Some errors don't say how far to skip, which means what's there
+        // can't be used at all.
+        Ok(Some(len).filter(|len| *len > 0))
+    }
+}
@@ -0,0 +1,106 @@
+//! Just enough of MQTT 3.1.1 to publish at QoS 0, which is all the firmware
+//! does with its telegrams as well.
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const RETAIN: u8 = 0x01;
+// Protocol level 4 is MQTT 3.1.1.
+const PROTOCOL_LEVEL: u8 = 4;
+const CLEAN_SESSION: u8 = 0x02;
+
+pub struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    /// Connects to the broker at `addr`, and waits for it to accept the
+    /// connection. Keep-alive is disabled, so the broker doesn't close the
+    /// connection while telegrams are waited for.
+    pub fn connect(addr: &str, client_id: &str) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        let mut body = Vec::new();
+        write_str(&mut body, "MQTT");
+        body.push(PROTOCOL_LEVEL);
+        body.push(CLEAN_SESSION);
+        body.extend_from_slice(&0u16.to_be_bytes());
+        write_str(&mut body, client_id);
+        stream.write_all(&packet(CONNECT, &body))?;
+
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack)?;
+        match connack {
+            [CONNACK, 2, _, 0] => Ok(Self { stream }),
+            [CONNACK, 2, _, code] => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("broker refused the connection with code {}", code),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a CONNACK packet",
+            )),
+        }
+    }
+
+    /// Publishes `payload` to `topic` at QoS 0, retained like the firmware's
+    /// telegrams.
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> io::Result<()> {
+        let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+        write_str(&mut body, topic);
+        body.extend_from_slice(payload);
+        self.stream.write_all(&packet(PUBLISH | RETAIN, &body))
+    }
+}
+
+/// Prefixes a packet body with its fixed header.
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    // The remaining length is encoded 7 bits at a time, least significant
+    // first, with the high bit set on all but the last byte.
+    let mut remaining = body.len();
+    loop {
+        let byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Writes a length-prefixed UTF-8 string.
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_length_spans_bytes() {
+        assert_eq!(packet(PUBLISH, &[]), [PUBLISH, 0]);
+        let long = packet(PUBLISH, &[0; 321]);
+        assert_eq!(long[..3], [PUBLISH, 0xC1, 0x02]);
+        assert_eq!(long.len(), 3 + 321);
+    }
+
+    #[test]
+    fn strings_are_length_prefixed() {
+        let mut buf = Vec::new();
+        write_str(&mut buf, "smart_meter/usage");
+        assert_eq!(buf[..2], [0, 17]);
+        assert_eq!(&buf[2..], b"smart_meter/usage");
+    }
+}